@@ -0,0 +1,63 @@
+//! What a memory operation learned about its request, for the audit log.
+
+use crate::kernel::KernelProcess;
+use shared::{
+    audit::{image_field, AUDIT_IMAGE_SIZE},
+    ipc::AuditRecord,
+    protocol::{Opcode, Target},
+    sha256::DIGEST_SIZE,
+};
+
+/// Filled in by a memory operation's handler. The dispatch routine records it
+/// once the final status is known, whether the request succeeded, was denied, or
+/// failed halfway.
+pub struct AuditEntry {
+    record: AuditRecord,
+}
+
+impl AuditEntry {
+    /// Starts an entry for a request `caller` issued at `timestamp`.
+    pub fn new(opcode: Opcode, caller: &impl KernelProcess, timestamp: u64) -> Self {
+        Self {
+            record: AuditRecord {
+                sequence: 0,
+                timestamp,
+                caller_pid: caller.id(),
+                target_pid: 0,
+                opcode: opcode as u16,
+                reserved: 0,
+                status: 0,
+                address: 0,
+                size: 0,
+                caller_image: image_field(caller.image_file_name()),
+                target_image: [0; AUDIT_IMAGE_SIZE],
+                prev_hash: [0; DIGEST_SIZE],
+                hash: [0; DIGEST_SIZE],
+            },
+        }
+    }
+
+    /// Notes the decoded request. A target named by handle gets its PID once
+    /// it is resolved.
+    pub fn request(&mut self, target: Target, address: u64, size: u64) {
+        if let Target::Pid(process_id) = target {
+            self.record.target_pid = process_id;
+        }
+        self.record.address = address;
+        self.record.size = size;
+    }
+
+    /// Notes the resolved target process.
+    pub fn process(&mut self, process: &impl KernelProcess) {
+        self.record.target_pid = process.id();
+        self.record.target_image = image_field(process.image_file_name());
+    }
+
+    /// The record of the request with its final `status`, still to be sealed
+    /// into the log.
+    pub fn finish(self, status: i32) -> AuditRecord {
+        let mut record = self.record;
+        record.status = status;
+        record
+    }
+}
@@ -0,0 +1,463 @@
+//! Memory operations: reads, writes, lossy reads, batches of either, pointer
+//! chains, and compare-exchanges.
+
+use crate::{
+    audit::AuditEntry,
+    kernel::KernelOps,
+    request::{
+        check_range, decode_payload, protocol_error_to_status, require_output, Completion, Context,
+        Ioctl,
+    },
+    target::open_target,
+};
+use alloc::vec::Vec;
+use shared::{
+    batch::{
+        batch_output_size, batch_response_size, decode_batch, decode_write_batch,
+        encode_batch_response,
+    },
+    chain::{chain_output_size, decode_chain, encode_chain_response, CHAIN_VALUE_OFFSET},
+    exchange::{decode_exchange, encode_exchange_response, first_difference, EXCHANGE_OUTPUT_SIZE},
+    ipc::{BatchEntryResult, ChainReply, ExchangeReply, Request, Response},
+    lossy::{lossy_response, page_count, page_spans, PageMap},
+    protocol::{Opcode, Target, DATA_OFFSET},
+    status::{
+        nt_success, STATUS_ACCESS_VIOLATION, STATUS_BUFFER_TOO_SMALL, STATUS_CANCELLED,
+        STATUS_COMPARE_MISMATCH, STATUS_INVALID_DEVICE_REQUEST, STATUS_PARTIAL_COPY,
+        STATUS_SUCCESS,
+    },
+};
+
+/// Bytes a plain read copies between checks for cancellation.
+pub const READ_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Runs the memory operation `opcode`, which is what the IOCTL code stands for;
+/// the request header must agree. An `Err` leaves the output buffer unanswered.
+pub fn dispatch<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    opcode: Opcode,
+    ioctl: &mut Ioctl<'_>,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    match opcode {
+        Opcode::Read => read(kernel, context, ioctl, audit),
+        Opcode::Write => write(kernel, context, ioctl, audit),
+        Opcode::ReadLossy => read_lossy(kernel, context, ioctl, audit),
+        Opcode::ReadBatch => batch(kernel, context, ioctl, false, audit),
+        Opcode::WriteBatch => batch(kernel, context, ioctl, true, audit),
+        Opcode::ReadChain => read_chain(kernel, context, ioctl, audit),
+        Opcode::CompareExchange => compare_exchange(kernel, context, ioctl, audit),
+        _ => Err(STATUS_INVALID_DEVICE_REQUEST),
+    }
+}
+
+fn decode_memory_request(input: &[u8], expected: Opcode) -> Result<(Target, Request), i32> {
+    let (header, payload) = decode_payload(input, expected)?;
+
+    let request = Request::from_bytes(payload).map_err(protocol_error_to_status)?;
+    let target = header
+        .target(request.process_id)
+        .map_err(protocol_error_to_status)?;
+
+    Ok((target, request))
+}
+
+fn read<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    ioctl: &mut Ioctl<'_>,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    let (target, request) = decode_memory_request(ioctl.input, Opcode::Read)?;
+    let address = request.address;
+    let size = request.size;
+    audit.request(target, address, size);
+
+    // `Request::from_bytes` capped `size` at `MAX_TRANSFER_SIZE`
+    require_output(ioctl.output, DATA_OFFSET + size as usize)?;
+
+    let process = open_target(
+        kernel,
+        context,
+        target,
+        request.create_time,
+        false,
+        size,
+        audit,
+    )?;
+    check_range(address, size)?;
+
+    // in chunks, so a cancelled read stops without copying the rest
+    let buffer = &mut ioctl.output[DATA_OFFSET..DATA_OFFSET + size as usize];
+    let mut bytes_read = 0;
+    let mut status = STATUS_SUCCESS;
+    while bytes_read < size && nt_success(status) {
+        if (ioctl.cancelled)() {
+            return Err(STATUS_CANCELLED);
+        }
+
+        let chunk = (size - bytes_read).min(READ_CHUNK_SIZE);
+        let destination = &mut buffer[bytes_read as usize..(bytes_read + chunk) as usize];
+        let (chunk_status, copied) =
+            kernel.read_memory(&process, address + bytes_read, destination);
+        status = chunk_status;
+        bytes_read += copied;
+    }
+
+    let response = if nt_success(status) {
+        Response::success(bytes_read)
+    } else {
+        Response::failure(status, bytes_read, bytes_read)
+    };
+
+    Ok(Completion::respond(ioctl.output, &response, bytes_read))
+}
+
+fn write<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    ioctl: &mut Ioctl<'_>,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    let (target, request) = decode_memory_request(ioctl.input, Opcode::Write)?;
+    let address = request.address;
+    let size = request.size;
+    audit.request(target, address, size);
+
+    // `Request::from_bytes` capped `size` at `MAX_TRANSFER_SIZE`
+    let Some(data) = ioctl.data.get(..size as usize) else {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    };
+
+    let process = open_target(
+        kernel,
+        context,
+        target,
+        request.create_time,
+        true,
+        size,
+        audit,
+    )?;
+    check_range(address, size)?;
+
+    let (status, bytes_written) = kernel.write_memory(&process, address, data);
+
+    // There is no output to put a `Response` in: the status says how the write
+    // went, and `information` how far it got.
+    Ok(Completion {
+        status,
+        information: bytes_written as usize,
+    })
+}
+
+/// Copies the range one page at a time. A page that cannot be read in full is
+/// zero-filled and left clear in the page map, which sits between the response
+/// and the data.
+fn read_lossy<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    ioctl: &mut Ioctl<'_>,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    let (target, request) = decode_memory_request(ioctl.input, Opcode::ReadLossy)?;
+    let address = request.address;
+    let size = request.size;
+    audit.request(target, address, size);
+
+    let mut pages = PageMap::new(page_count(address, size));
+    let data_offset = DATA_OFFSET + pages.as_bytes().len();
+
+    // `Request::from_bytes` capped `size` at `MAX_TRANSFER_SIZE`
+    require_output(ioctl.output, data_offset + size as usize)?;
+
+    let process = open_target(
+        kernel,
+        context,
+        target,
+        request.create_time,
+        false,
+        size,
+        audit,
+    )?;
+    check_range(address, size)?;
+
+    for (page, (offset, len)) in page_spans(address, size).enumerate() {
+        if (ioctl.cancelled)() {
+            return Err(STATUS_CANCELLED);
+        }
+
+        let start = data_offset + offset as usize;
+        let destination = &mut ioctl.output[start..start + len as usize];
+
+        let (status, _) = kernel.read_memory(&process, address + offset, destination);
+        if nt_success(status) {
+            pages.set_readable(page as u64);
+        } else {
+            // drop whatever part of the page made it across
+            destination.fill(0);
+        }
+    }
+    ioctl.output[DATA_OFFSET..data_offset].copy_from_slice(pages.as_bytes());
+
+    // Unreadable pages are zero-filled, so the whole data area is always valid.
+    let response = lossy_response(address, size, &pages);
+    let data_len = pages.as_bytes().len() as u64 + size;
+
+    Ok(Completion::respond(ioctl.output, &response, data_len))
+}
+
+/// Runs every entry of a batch against one resolved process. A failing entry
+/// does not stop the batch; its status is reported in its own result slot.
+fn batch<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    ioctl: &mut Ioctl<'_>,
+    write: bool,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    let opcode = if write {
+        Opcode::WriteBatch
+    } else {
+        Opcode::ReadBatch
+    };
+    let (header, payload) = decode_payload(ioctl.input, opcode)?;
+    // A write batch carries its data behind the entries; a read batch has the
+    // data area behind the reply filled in.
+    let (batch, entries, data) = if write {
+        decode_write_batch(payload)
+    } else {
+        decode_batch(payload).map(|(batch, entries)| (batch, entries, &[][..]))
+    }
+    .map_err(protocol_error_to_status)?;
+    let target = header
+        .target(batch.process_id)
+        .map_err(protocol_error_to_status)?;
+
+    let first_address = entries.first().map_or(0, |entry| entry.address);
+    audit.request(target, first_address, batch.buffer_len);
+
+    // `decode_batch` capped `buffer_len` at `MAX_TRANSFER_SIZE` and kept every
+    // entry inside it, so the data area below covers all of them.
+    let reply_len = batch_response_size(entries.len());
+    let data_len = if write { 0 } else { batch.buffer_len };
+    require_output(
+        ioctl.output,
+        batch_output_size(entries.len(), data_len as usize),
+    )?;
+
+    let process = open_target(
+        kernel,
+        context,
+        target,
+        batch.create_time,
+        write,
+        batch.buffer_len,
+        audit,
+    )?;
+
+    let mut results = Vec::with_capacity(entries.len());
+    let mut total = 0;
+    let mut first_failure = None;
+
+    for (index, entry) in entries.iter().enumerate() {
+        if (ioctl.cancelled)() {
+            return Err(STATUS_CANCELLED);
+        }
+
+        let offset = entry.offset as usize;
+        let length = entry.length as usize;
+
+        let (status, bytes_transferred) = if check_range(entry.address, length as u64).is_err() {
+            (STATUS_ACCESS_VIOLATION, 0)
+        } else if write {
+            kernel.write_memory(&process, entry.address, &data[offset..offset + length])
+        } else {
+            let start = reply_len + offset;
+            let client = &mut ioctl.output[start..start + length];
+            kernel.read_memory(&process, entry.address, client)
+        };
+
+        if !nt_success(status) && first_failure.is_none() {
+            first_failure = Some(index as u64);
+        }
+
+        total += bytes_transferred;
+        results.push(BatchEntryResult {
+            status,
+            reserved: 0,
+            bytes_transferred,
+        });
+    }
+
+    // For batches, `failing_offset` is the index of the first failed entry.
+    let response = match first_failure {
+        None => Response::success(total),
+        Some(index) => Response::failure(STATUS_PARTIAL_COPY, total, index),
+    };
+
+    let reply = encode_batch_response(&response, &results);
+
+    Ok(Completion::reply(
+        ioctl.output,
+        &reply,
+        response.status,
+        data_len,
+    ))
+}
+
+/// Follows a pointer chain inside the target, then reads the value at its end.
+/// The walk stops at the first hop that cannot be read, and the reply says
+/// which one it was and where it tried to read.
+fn read_chain<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    ioctl: &mut Ioctl<'_>,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    let (header, payload) = decode_payload(ioctl.input, Opcode::ReadChain)?;
+    let (chain, offsets) = decode_chain(payload).map_err(protocol_error_to_status)?;
+    let target = header
+        .target(chain.process_id)
+        .map_err(protocol_error_to_status)?;
+
+    let size = u64::from(chain.read_size);
+    audit.request(target, chain.base, size);
+
+    // `decode_chain` capped `read_size` at `MAX_CHAIN_READ_SIZE`
+    require_output(ioctl.output, chain_output_size(chain.read_size))?;
+
+    let process = open_target(
+        kernel,
+        context,
+        target,
+        chain.create_time,
+        false,
+        size,
+        audit,
+    )?;
+
+    // `decode_chain` made sure there is at least one hop
+    let last = offsets.len() - 1;
+    let width = chain.pointer_width as usize;
+    let mut address = chain.base;
+    let mut bytes_read = 0;
+    let mut failure = None;
+
+    for (hop, &offset) in offsets.iter().enumerate() {
+        if (ioctl.cancelled)() {
+            return Err(STATUS_CANCELLED);
+        }
+
+        let Some(hop_address) = address.checked_add_signed(offset) else {
+            failure = Some((hop, STATUS_ACCESS_VIOLATION));
+            break;
+        };
+        address = hop_address;
+
+        // every hop but the last reads the next pointer, zero-extended if it is
+        // a 32-bit one; the last reads the value
+        let mut pointer = [0; 8];
+        let destination = if hop == last {
+            &mut ioctl.output[CHAIN_VALUE_OFFSET..CHAIN_VALUE_OFFSET + size as usize]
+        } else {
+            &mut pointer[..width]
+        };
+
+        let (status, copied) = if check_range(address, destination.len() as u64).is_err() {
+            (STATUS_ACCESS_VIOLATION, 0)
+        } else {
+            kernel.read_memory(&process, address, destination)
+        };
+
+        if hop == last {
+            bytes_read = copied;
+        }
+        if !nt_success(status) {
+            failure = Some((hop, status));
+            break;
+        }
+        if hop < last {
+            address = u64::from_le_bytes(pointer);
+        }
+    }
+
+    // As for batches, `failing_offset` is an index: that of the failing hop.
+    let (response, status) = match failure {
+        None => (Response::success(bytes_read), STATUS_SUCCESS),
+        Some((hop, status)) => (
+            Response::failure(STATUS_PARTIAL_COPY, bytes_read, hop as u64),
+            status,
+        ),
+    };
+    let reply = ChainReply {
+        address,
+        status,
+        reserved: 0,
+    };
+
+    Ok(Completion::reply(
+        ioctl.output,
+        &encode_chain_response(&response, &reply),
+        response.status,
+        bytes_read,
+    ))
+}
+
+/// Replaces the value if it still holds the expected one. The kernel does the
+/// compare and the write in one locked instruction, so the target's own
+/// threads cannot slip a write in between.
+fn compare_exchange<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    ioctl: &mut Ioctl<'_>,
+    audit: &mut AuditEntry,
+) -> Result<Completion, i32> {
+    let (header, payload) = decode_payload(ioctl.input, Opcode::CompareExchange)?;
+    let exchange = decode_exchange(payload).map_err(protocol_error_to_status)?;
+    let target = header
+        .target(exchange.process_id)
+        .map_err(protocol_error_to_status)?;
+
+    let size = u64::from(exchange.size);
+    audit.request(target, exchange.address, size);
+
+    require_output(ioctl.output, EXCHANGE_OUTPUT_SIZE)?;
+
+    let process = open_target(
+        kernel,
+        context,
+        target,
+        exchange.create_time,
+        true,
+        size,
+        audit,
+    )?;
+    check_range(exchange.address, size)?;
+
+    // `decode_exchange` checked the size, the alignment, and that both values fit
+    let (response, observed) = match kernel.compare_exchange(
+        &process,
+        exchange.address,
+        exchange.size,
+        exchange.expected,
+        exchange.new,
+    ) {
+        Ok(observed) => {
+            let response = match first_difference(observed, exchange.expected) {
+                None => Response::success(size),
+                Some(index) => Response::failure(STATUS_COMPARE_MISMATCH, 0, index),
+            };
+            (response, observed)
+        }
+        // nothing was written
+        Err(status) => (Response::failure(status, 0, 0), 0),
+    };
+
+    Ok(Completion::reply(
+        ioctl.output,
+        &encode_exchange_response(&response, &ExchangeReply { observed }),
+        response.status,
+        0,
+    ))
+}
@@ -0,0 +1,63 @@
+use shared::protocol::Target;
+
+/// A referenced process. Cloning it takes another reference, dropping it
+/// releases one.
+pub trait KernelProcess: Clone {
+    fn id(&self) -> u32;
+
+    /// Creation time, in 100ns intervals since 1601-01-01 UTC. Together with the
+    /// PID, this names a process uniquely.
+    fn create_time(&self) -> u64;
+
+    /// Whether the process has exited. Its object lives on while referenced.
+    fn has_exited(&self) -> bool;
+
+    /// The kernel's (possibly truncated) image name, e.g. `b"lsass.exe"`.
+    fn image_file_name(&self) -> &[u8];
+
+    /// Why the kernel considers this process critical, or `None` for an ordinary
+    /// process. Image names are left to the policy, which can override them.
+    fn critical_reason(&self) -> Option<&'static str>;
+}
+
+/// What the handlers need from the kernel.
+pub trait KernelOps {
+    type Process: KernelProcess;
+
+    /// Resolves `target`, which must have been created at `create_time` unless
+    /// that is `ANY_CREATE_TIME`. A handle must grant the caller read access to
+    /// the process, or write access if `write`. Never asked for `Target::Session`,
+    /// which the handlers resolve themselves.
+    fn open_process(
+        &self,
+        target: Target,
+        write: bool,
+        create_time: u64,
+    ) -> Result<Self::Process, i32>;
+
+    /// The process that sent the request being handled.
+    fn current_process(&self) -> Self::Process;
+
+    /// Copies `buffer.len()` bytes out of `process`, starting at `address`.
+    /// Returns the status and how many bytes made it, which on failure says how
+    /// far the copy got.
+    fn read_memory(&self, process: &Self::Process, address: u64, buffer: &mut [u8]) -> (i32, u64);
+
+    /// Copies `data` into `process`, starting at `address`. Returns like
+    /// `read_memory`.
+    fn write_memory(&self, process: &Self::Process, address: u64, data: &[u8]) -> (i32, u64);
+
+    /// Replaces the `size`-byte value at `address` in `process` with `new` if it
+    /// equals `expected`, as one locked instruction on the target's memory, and
+    /// returns the value it held. `size` is 1, 2, 4, or 8 and `address` is
+    /// aligned to it; values are zero-extended. Fails if the page is not
+    /// committed and writable.
+    fn compare_exchange(
+        &self,
+        process: &Self::Process,
+        address: u64,
+        size: u32,
+        expected: u64,
+        new: u64,
+    ) -> Result<u64, i32>;
+}
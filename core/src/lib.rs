@@ -0,0 +1,22 @@
+//! Request handling of the Erebus driver, kept apart from the kernel so it can be
+//! tested on any host.
+//!
+//! The handlers validate a request, check it against the policy, run it, and
+//! build the response in the caller's output buffer. What they need from the
+//! kernel goes through `KernelOps`: the driver implements it on the real kernel,
+//! the tests on a simulated address space.
+
+#![no_std]
+extern crate alloc;
+
+pub mod audit;
+pub mod handlers;
+pub mod kernel;
+pub mod policy;
+pub mod request;
+pub mod target;
+
+pub use handlers::dispatch;
+pub use kernel::{KernelOps, KernelProcess};
+pub use request::{Completion, Context, Ioctl};
+pub use target::Attachment;
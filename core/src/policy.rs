@@ -0,0 +1,100 @@
+//! The policy's verdicts on a request, as the statuses it fails with.
+
+use crate::kernel::KernelProcess;
+use shared::{
+    policy::{Denial, Policy},
+    protocol::Target,
+    status::{STATUS_CRITICAL_PROCESS_DENIED, STATUS_POLICY_DENIED},
+};
+
+/// Checks what can be decided before the target is resolved.
+pub fn authorize(policy: &Policy, target: Target, write: bool, size: u64) -> Result<(), i32> {
+    policy.check_request(target, write, size).map_err(deny)
+}
+
+/// Checks a resolved target: critical processes and the image allowlist.
+pub fn check_process(policy: &Policy, process: &impl KernelProcess) -> Result<(), i32> {
+    policy
+        .check_image(
+            process.image_file_name(),
+            process.critical_reason().is_some(),
+        )
+        .map_err(deny)
+}
+
+pub fn deny(denial: Denial) -> i32 {
+    match denial {
+        Denial::CriticalProcess => STATUS_CRITICAL_PROCESS_DENIED,
+        Denial::ReadOnly
+        | Denial::TransferTooLarge
+        | Denial::HandleRequired
+        | Denial::ImageNotAllowed => STATUS_POLICY_DENIED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+
+    #[derive(Clone)]
+    struct Named(&'static [u8], bool);
+
+    impl KernelProcess for Named {
+        fn id(&self) -> u32 {
+            4242
+        }
+
+        fn create_time(&self) -> u64 {
+            1
+        }
+
+        fn has_exited(&self) -> bool {
+            false
+        }
+
+        fn image_file_name(&self) -> &[u8] {
+            self.0
+        }
+
+        fn critical_reason(&self) -> Option<&'static str> {
+            self.1.then_some("a protected process")
+        }
+    }
+
+    #[test]
+    fn denials_map_to_driver_statuses() {
+        let read_only = Policy {
+            read_only: true,
+            ..Policy::default()
+        };
+        assert_eq!(
+            authorize(&read_only, Target::Pid(4), true, 8),
+            Err(STATUS_POLICY_DENIED)
+        );
+        assert_eq!(authorize(&read_only, Target::Pid(4), false, 8), Ok(()));
+    }
+
+    #[test]
+    fn critical_processes_need_an_override() {
+        let policy = Policy::default();
+        assert_eq!(check_process(&policy, &Named(b"game.exe", false)), Ok(()));
+        assert_eq!(
+            check_process(&policy, &Named(b"game.exe", true)),
+            Err(STATUS_CRITICAL_PROCESS_DENIED)
+        );
+        assert_eq!(
+            check_process(&policy, &Named(b"lsass.exe", false)),
+            Err(STATUS_CRITICAL_PROCESS_DENIED)
+        );
+
+        let overridden = Policy {
+            critical_overrides: vec!["game.exe".to_string()],
+            ..Policy::default()
+        };
+        assert_eq!(
+            check_process(&overridden, &Named(b"game.exe", true)),
+            Ok(())
+        );
+    }
+}
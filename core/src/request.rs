@@ -0,0 +1,154 @@
+//! What a handler is given, and how it answers.
+
+use crate::target::Attachment;
+use shared::{
+    address::check_user_range,
+    ipc::{RequestHeader, Response},
+    policy::Policy,
+    protocol::{decode_request, Opcode, ProtocolError, RESPONSE_SIZE},
+    status::{
+        STATUS_ACCESS_VIOLATION, STATUS_BUFFER_TOO_SMALL, STATUS_DATATYPE_MISALIGNMENT,
+        STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_PARAMETER, STATUS_REVISION_MISMATCH,
+    },
+};
+
+/// The buffers of an IOCTL.
+pub struct Ioctl<'a> {
+    /// A request header and its payload.
+    pub input: &'a [u8],
+    /// The caller's output buffer, mapped. Memory operations use direct I/O: the
+    /// `Response` goes at its front, and the data area follows.
+    pub output: &'a mut [u8],
+    /// The data of a `METHOD_IN_DIRECT` IOCTL, mapped for reading only. Such an
+    /// IOCTL has no `output`, and answers with its status and `information`.
+    pub data: &'a [u8],
+    /// Whether the caller cancelled the request, or closed its handle. Polled
+    /// between chunks of work.
+    pub cancelled: &'a dyn Fn() -> bool,
+}
+
+/// What a request is judged by: the policy in force, taken once for the whole
+/// request, and the process the handle's session is attached to.
+pub struct Context<'a, P> {
+    pub policy: &'a Policy,
+    pub attachment: Option<&'a Attachment<P>>,
+}
+
+/// How a handler left a request it answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    /// Status to complete the request with. On a warning, the caller still gets
+    /// the output.
+    pub status: i32,
+    /// Bytes of the output buffer filled in, for `IoStatus.Information`.
+    pub information: usize,
+}
+
+impl Completion {
+    /// Writes `reply` to the front of `output`. `data_len` is the part of the
+    /// data area behind it that the caller should consider filled in.
+    pub(crate) fn reply(output: &mut [u8], reply: &[u8], status: i32, data_len: u64) -> Self {
+        output[..reply.len()].copy_from_slice(reply);
+
+        Self {
+            status,
+            information: reply.len() + data_len as usize,
+        }
+    }
+
+    /// Writes `response` to the front of `output`, and completes with its status.
+    pub(crate) fn respond(output: &mut [u8], response: &Response, data_len: u64) -> Self {
+        debug_assert!(output.len() >= RESPONSE_SIZE);
+        Self::reply(output, &response.to_bytes(), response.status, data_len)
+    }
+}
+
+pub fn protocol_error_to_status(err: ProtocolError) -> i32 {
+    match err {
+        ProtocolError::BufferTooSmall { .. } | ProtocolError::PayloadLength { .. } => {
+            STATUS_BUFFER_TOO_SMALL
+        }
+        ProtocolError::VersionMismatch { .. } => STATUS_REVISION_MISMATCH,
+        ProtocolError::UnknownOpcode(_) => STATUS_INVALID_DEVICE_REQUEST,
+        ProtocolError::Misaligned { .. } => STATUS_DATATYPE_MISALIGNMENT,
+        ProtocolError::BadMagic(_)
+        | ProtocolError::TooManyEntries { .. }
+        | ProtocolError::InvalidEntry { .. }
+        | ProtocolError::TrailingBytes(_)
+        | ProtocolError::InvalidField(_) => STATUS_INVALID_PARAMETER,
+    }
+}
+
+/// Decodes the request header, which must carry the opcode the IOCTL code
+/// stands for, and returns it along with the payload.
+pub fn decode_payload(input: &[u8], expected: Opcode) -> Result<(RequestHeader, &[u8]), i32> {
+    let (header, payload) = decode_request(input).map_err(protocol_error_to_status)?;
+
+    let opcode = header.opcode().map_err(protocol_error_to_status)?;
+    if opcode != expected {
+        return Err(STATUS_INVALID_DEVICE_REQUEST);
+    }
+
+    Ok((header, payload))
+}
+
+/// Refuses target ranges outside of user space. Faults inside it are caught by
+/// the kernel's copy and come back as a status, so no probing is needed.
+pub fn check_range(address: u64, size: u64) -> Result<(), i32> {
+    check_user_range(address, size).map_err(|_| STATUS_ACCESS_VIOLATION)
+}
+
+/// Fails unless the output buffer holds at least `required` bytes.
+pub fn require_output(output: &[u8], required: usize) -> Result<(), i32> {
+    if output.len() < required {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::protocol::{encode_request, PROTOCOL_VERSION};
+
+    #[test]
+    fn payload_must_match_the_ioctl() {
+        let input = encode_request(Opcode::Write, 0, &[0; 8]).unwrap();
+
+        let (header, payload) = decode_payload(&input, Opcode::Write).unwrap();
+        assert_eq!(header.opcode(), Ok(Opcode::Write));
+        assert_eq!(payload, &[0; 8]);
+
+        assert_eq!(
+            decode_payload(&input, Opcode::Read).unwrap_err(),
+            STATUS_INVALID_DEVICE_REQUEST
+        );
+    }
+
+    #[test]
+    fn protocol_errors_map_to_statuses() {
+        let too_short = decode_payload(&[0; 4], Opcode::Read).unwrap_err();
+        assert_eq!(too_short, STATUS_BUFFER_TOO_SMALL);
+
+        let mismatch = ProtocolError::VersionMismatch {
+            expected: PROTOCOL_VERSION,
+            actual: 0,
+        };
+        assert_eq!(protocol_error_to_status(mismatch), STATUS_REVISION_MISMATCH);
+        assert_eq!(
+            protocol_error_to_status(ProtocolError::TrailingBytes(1)),
+            STATUS_INVALID_PARAMETER
+        );
+    }
+
+    #[test]
+    fn ranges_stay_in_user_space() {
+        assert_eq!(check_range(0x1_0000, 0x1000), Ok(()));
+        assert_eq!(check_range(0, 0x1000), Err(STATUS_ACCESS_VIOLATION));
+        assert_eq!(
+            check_range(0xFFFF_8000_0000_0000, 8),
+            Err(STATUS_ACCESS_VIOLATION)
+        );
+    }
+}
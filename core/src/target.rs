@@ -0,0 +1,72 @@
+//! Resolving the process a memory operation acts on.
+
+use crate::{
+    audit::AuditEntry,
+    kernel::{KernelOps, KernelProcess},
+    policy,
+    request::Context,
+};
+use shared::{
+    protocol::{Target, ANY_CREATE_TIME},
+    status::{
+        STATUS_ACCESS_DENIED, STATUS_INVALID_DEVICE_STATE, STATUS_PROCESS_IDENTITY_MISMATCH,
+        STATUS_PROCESS_IS_TERMINATING,
+    },
+};
+
+/// The process a session is bound to, referenced until the session is detached.
+pub struct Attachment<P> {
+    pub process: P,
+    /// How the caller named the process when attaching, which is what the
+    /// policy judges later requests by.
+    pub origin: Target,
+    /// Whether writes were asked for, and checked against the handle, at attach time.
+    pub write: bool,
+}
+
+/// Fails with `STATUS_PROCESS_IDENTITY_MISMATCH` unless `process` was created at
+/// `expected`, or `expected` is `ANY_CREATE_TIME`.
+pub fn check_create_time(process: &impl KernelProcess, expected: u64) -> Result<(), i32> {
+    if expected == ANY_CREATE_TIME || expected == process.create_time() {
+        return Ok(());
+    }
+
+    Err(STATUS_PROCESS_IDENTITY_MISMATCH)
+}
+
+/// Resolves the target of a memory operation moving `size` bytes and checks it
+/// against the policy, before any memory of it is touched.
+pub fn open_target<K: KernelOps>(
+    kernel: &K,
+    context: &Context<'_, K::Process>,
+    target: Target,
+    create_time: u64,
+    write: bool,
+    size: u64,
+    audit: &mut AuditEntry,
+) -> Result<K::Process, i32> {
+    let process = if target == Target::Session {
+        let attachment = context.attachment.ok_or(STATUS_INVALID_DEVICE_STATE)?;
+
+        policy::authorize(context.policy, attachment.origin, write, size)?;
+
+        if write && !attachment.write {
+            return Err(STATUS_ACCESS_DENIED);
+        }
+
+        check_create_time(&attachment.process, create_time)?;
+        attachment.process.clone()
+    } else {
+        policy::authorize(context.policy, target, write, size)?;
+        kernel.open_process(target, write, create_time)?
+    };
+    audit.process(&process);
+
+    if process.has_exited() {
+        return Err(STATUS_PROCESS_IS_TERMINATING);
+    }
+
+    policy::check_process(context.policy, &process)?;
+
+    Ok(process)
+}
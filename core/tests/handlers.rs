@@ -0,0 +1,725 @@
+//! The driver's memory operations, run against a simulated kernel.
+
+mod sim;
+
+use erebus_core::{audit::AuditEntry, dispatch, Attachment, Completion, Context, Ioctl};
+use shared::{
+    audit::image_name,
+    batch::{
+        batch_output_size, decode_batch_response, encode_batch, encode_write_batch, pack_entries,
+    },
+    chain::{chain_output_size, decode_chain_response, encode_chain},
+    exchange::{decode_exchange_response, EXCHANGE_OUTPUT_SIZE},
+    ipc::{AuditRecord, BatchHeader, ChainHeader, ExchangeRequest, Request, Response},
+    lossy::{decode_lossy_response, lossy_output_size, PAGE_SIZE},
+    policy::Policy,
+    protocol::{encode_request, Opcode, Target, ANY_CREATE_TIME, DATA_OFFSET, RESPONSE_SIZE},
+    status::{
+        STATUS_ACCESS_DENIED, STATUS_ACCESS_VIOLATION, STATUS_BUFFER_TOO_SMALL, STATUS_CANCELLED,
+        STATUS_COMPARE_MISMATCH, STATUS_CRITICAL_PROCESS_DENIED, STATUS_INVALID_DEVICE_REQUEST,
+        STATUS_INVALID_DEVICE_STATE, STATUS_INVALID_PARAMETER, STATUS_PARTIAL_COPY,
+        STATUS_POLICY_DENIED, STATUS_PROCESS_IDENTITY_MISMATCH, STATUS_PROCESS_IS_TERMINATING,
+        STATUS_SUCCESS,
+    },
+};
+use sim::{SimKernel, SimProcess};
+
+const GAME_PID: u32 = 4242;
+const GAME_CREATED: u64 = 133_000_000_000_000_000;
+const BASE: u64 = 0x7FF6_0000_0000;
+
+fn game() -> (SimKernel, SimProcess) {
+    let mut kernel = SimKernel::new();
+    let process = SimProcess::new(GAME_PID, GAME_CREATED, "game.exe");
+    process.map(BASE, 2 * PAGE_SIZE, true, 0xAA);
+    kernel.add(&process);
+
+    (kernel, process)
+}
+
+fn memory_request(opcode: Opcode, target: Target, address: u64, size: u64) -> Vec<u8> {
+    let request = Request {
+        process_id: target.raw(),
+        reserved: 0,
+        address,
+        size,
+        create_time: ANY_CREATE_TIME,
+    };
+    encode_request(opcode, target.flags(), &request.to_bytes()).unwrap()
+}
+
+fn chain_request(
+    process_id: u32,
+    base: u64,
+    offsets: &[i64],
+    pointer_width: u32,
+    read_size: u32,
+) -> Vec<u8> {
+    let header = ChainHeader {
+        process_id,
+        offset_count: offsets.len() as u32,
+        base,
+        create_time: ANY_CREATE_TIME,
+        pointer_width,
+        read_size,
+    };
+    encode_request(Opcode::ReadChain, 0, &encode_chain(&header, offsets)).unwrap()
+}
+
+/// What one IOCTL left behind.
+struct Outcome {
+    result: Result<Completion, i32>,
+    output: Vec<u8>,
+    audit: AuditRecord,
+}
+
+impl Outcome {
+    fn status(&self) -> i32 {
+        match self.result {
+            Ok(completion) => completion.status,
+            Err(status) => status,
+        }
+    }
+
+    fn response(&self) -> Response {
+        Response::from_bytes(&self.output).unwrap()
+    }
+
+    fn data(&self, len: usize) -> &[u8] {
+        &self.output[DATA_OFFSET..DATA_OFFSET + len]
+    }
+}
+
+struct Call<'a> {
+    kernel: &'a SimKernel,
+    policy: Policy,
+    attachment: Option<Attachment<SimProcess>>,
+    cancelled: bool,
+}
+
+impl<'a> Call<'a> {
+    fn new(kernel: &'a SimKernel) -> Self {
+        Self {
+            kernel,
+            policy: Policy::default(),
+            attachment: None,
+            cancelled: false,
+        }
+    }
+
+    fn run(&self, opcode: Opcode, input: &[u8], output: Vec<u8>) -> Outcome {
+        self.run_with_data(opcode, input, &[], output)
+    }
+
+    /// `run`, for a `METHOD_IN_DIRECT` IOCTL handed `data` to read.
+    fn run_with_data(
+        &self,
+        opcode: Opcode,
+        input: &[u8],
+        data: &[u8],
+        mut output: Vec<u8>,
+    ) -> Outcome {
+        let context = Context {
+            policy: &self.policy,
+            attachment: self.attachment.as_ref(),
+        };
+        let mut audit = AuditEntry::new(opcode, self.kernel.caller(), 1);
+        let mut ioctl = Ioctl {
+            input,
+            output: &mut output,
+            data,
+            cancelled: &|| self.cancelled,
+        };
+
+        let result = dispatch(self.kernel, &context, opcode, &mut ioctl, &mut audit);
+        let status = match result {
+            Ok(completion) => completion.status,
+            Err(status) => status,
+        };
+
+        Outcome {
+            result,
+            output,
+            audit: audit.finish(status),
+        }
+    }
+
+    fn read(&self, target: Target, address: u64, size: u64) -> Outcome {
+        let input = memory_request(Opcode::Read, target, address, size);
+        self.run(Opcode::Read, &input, vec![0; DATA_OFFSET + size as usize])
+    }
+
+    fn write(&self, target: Target, address: u64, data: &[u8]) -> Outcome {
+        let input = memory_request(Opcode::Write, target, address, data.len() as u64);
+        self.run_with_data(Opcode::Write, &input, data, Vec::new())
+    }
+
+    fn compare_exchange(
+        &self,
+        target: Target,
+        address: u64,
+        size: u32,
+        expected: u64,
+        new: u64,
+    ) -> Outcome {
+        let exchange = ExchangeRequest {
+            process_id: target.raw(),
+            size,
+            address,
+            create_time: ANY_CREATE_TIME,
+            expected,
+            new,
+        };
+        let input = encode_request(
+            Opcode::CompareExchange,
+            target.flags(),
+            &exchange.to_bytes(),
+        )
+        .unwrap();
+        self.run(
+            Opcode::CompareExchange,
+            &input,
+            vec![0; EXCHANGE_OUTPUT_SIZE],
+        )
+    }
+}
+
+#[test]
+fn read_copies_into_the_data_area() {
+    let (kernel, game) = game();
+    game.poke(BASE + 0x10, b"erebus");
+
+    let outcome = Call::new(&kernel).read(Target::Pid(GAME_PID), BASE + 0x10, 6);
+
+    assert_eq!(
+        outcome.result,
+        Ok(Completion {
+            status: STATUS_SUCCESS,
+            information: RESPONSE_SIZE + 6,
+        })
+    );
+    assert_eq!(outcome.response(), Response::success(6));
+    assert_eq!(outcome.data(6), b"erebus");
+
+    assert_eq!(outcome.audit.caller_pid, 1000);
+    assert_eq!(outcome.audit.target_pid, GAME_PID);
+    assert_eq!(image_name(&outcome.audit.target_image), b"game.exe");
+    assert_eq!(outcome.audit.address, BASE + 0x10);
+    assert_eq!(outcome.audit.size, 6);
+    assert_eq!(outcome.audit.status, STATUS_SUCCESS);
+}
+
+#[test]
+fn read_stops_at_the_first_fault() {
+    let (kernel, _game) = game();
+
+    // the second page is the last one mapped
+    let address = BASE + 2 * PAGE_SIZE - 0x100;
+    let outcome = Call::new(&kernel).read(Target::Pid(GAME_PID), address, 0x200);
+
+    assert_eq!(outcome.status(), STATUS_PARTIAL_COPY);
+    assert_eq!(
+        outcome.response(),
+        Response::failure(STATUS_PARTIAL_COPY, 0x100, 0x100)
+    );
+    // the part that made it is still handed out
+    assert_eq!(outcome.result.unwrap().information, RESPONSE_SIZE + 0x100);
+    assert!(outcome.data(0x100).iter().all(|&byte| byte == 0xAA));
+}
+
+#[test]
+fn write_changes_the_target() {
+    let (kernel, game) = game();
+
+    let outcome = Call::new(&kernel).write(Target::Pid(GAME_PID), BASE + 0x20, b"patched");
+
+    // a write has no output: the status and `information` are the reply
+    assert_eq!(
+        outcome.result,
+        Ok(Completion {
+            status: STATUS_SUCCESS,
+            information: 7,
+        })
+    );
+    assert_eq!(game.peek(BASE + 0x20, 7), b"patched");
+}
+
+#[test]
+fn write_stops_at_a_read_only_page() {
+    let (kernel, game) = game();
+    game.map(BASE + 2 * PAGE_SIZE, PAGE_SIZE, false, 0);
+
+    let address = BASE + 2 * PAGE_SIZE - 4;
+    let outcome = Call::new(&kernel).write(Target::Pid(GAME_PID), address, &[1; 8]);
+
+    assert_eq!(
+        outcome.result,
+        Ok(Completion {
+            status: STATUS_PARTIAL_COPY,
+            information: 4,
+        })
+    );
+    assert_eq!(game.peek(address, 8), [1, 1, 1, 1, 0, 0, 0, 0]);
+}
+
+#[test]
+fn write_needs_all_of_its_data() {
+    let (kernel, game) = game();
+
+    let input = memory_request(Opcode::Write, Target::Pid(GAME_PID), BASE, 8);
+    let outcome = Call::new(&kernel).run_with_data(Opcode::Write, &input, &[1; 7], Vec::new());
+
+    assert_eq!(outcome.result, Err(STATUS_BUFFER_TOO_SMALL));
+    assert_eq!(game.peek(BASE, 8), [0xAA; 8]);
+}
+
+#[test]
+fn lossy_read_zero_fills_missing_pages() {
+    let (kernel, game) = game();
+    // pages 0, 1, and 3 are mapped, page 2 is not
+    game.map(BASE + 3 * PAGE_SIZE, PAGE_SIZE, true, 0xBB);
+
+    let size = 4 * PAGE_SIZE;
+    let input = memory_request(Opcode::ReadLossy, Target::Pid(GAME_PID), BASE, size);
+    let output = vec![0xFF; lossy_output_size(BASE, size)];
+    let outcome = Call::new(&kernel).run(Opcode::ReadLossy, &input, output);
+
+    assert_eq!(outcome.status(), STATUS_PARTIAL_COPY);
+    assert_eq!(
+        outcome.result.unwrap().information,
+        lossy_output_size(BASE, size)
+    );
+
+    let (response, pages, data) = decode_lossy_response(&outcome.output, BASE, size).unwrap();
+    assert_eq!(response.failing_offset, 2 * PAGE_SIZE);
+    assert_eq!(
+        (0..4)
+            .map(|page| pages.is_readable(page))
+            .collect::<Vec<_>>(),
+        [true, true, false, true]
+    );
+
+    let page = PAGE_SIZE as usize;
+    assert!(data[..2 * page].iter().all(|&byte| byte == 0xAA));
+    assert!(data[2 * page..3 * page].iter().all(|&byte| byte == 0));
+    assert!(data[3 * page..].iter().all(|&byte| byte == 0xBB));
+}
+
+#[test]
+fn batch_reports_every_entry() {
+    let (kernel, game) = game();
+    game.poke(BASE, b"first");
+    game.poke(BASE + PAGE_SIZE, b"second");
+
+    let (entries, buffer_len) = pack_entries(&[
+        (BASE, 5),
+        // not mapped
+        (BASE + 8 * PAGE_SIZE, 4),
+        (BASE + PAGE_SIZE, 6),
+        // kernel space
+        (0xFFFF_F800_0000_0000, 8),
+    ]);
+    let header = BatchHeader {
+        process_id: GAME_PID,
+        entry_count: entries.len() as u32,
+        buffer_len,
+        create_time: ANY_CREATE_TIME,
+    };
+    let input = encode_request(Opcode::ReadBatch, 0, &encode_batch(&header, &entries)).unwrap();
+    let output = vec![0; batch_output_size(entries.len(), buffer_len as usize)];
+    let outcome = Call::new(&kernel).run(Opcode::ReadBatch, &input, output);
+
+    assert_eq!(outcome.status(), STATUS_PARTIAL_COPY);
+    let (response, results) = decode_batch_response(&outcome.output, entries.len()).unwrap();
+    // `failing_offset` is the index of the first failed entry
+    assert_eq!(response, Response::failure(STATUS_PARTIAL_COPY, 11, 1));
+    assert_eq!(
+        results
+            .iter()
+            .map(|result| (result.status, result.bytes_transferred))
+            .collect::<Vec<_>>(),
+        [
+            (STATUS_SUCCESS, 5),
+            (STATUS_PARTIAL_COPY, 0),
+            (STATUS_SUCCESS, 6),
+            (STATUS_ACCESS_VIOLATION, 0),
+        ]
+    );
+
+    let data = &outcome.output[outcome.output.len() - buffer_len as usize..];
+    assert_eq!(&data[..5], b"first");
+    assert_eq!(&data[9..15], b"second");
+}
+
+#[test]
+fn write_batch_scatters_the_data_area() {
+    let (kernel, game) = game();
+
+    let (entries, buffer_len) = pack_entries(&[(BASE, 2), (BASE + PAGE_SIZE + 0x40, 3)]);
+    let header = BatchHeader {
+        process_id: GAME_PID,
+        entry_count: 2,
+        buffer_len,
+        create_time: ANY_CREATE_TIME,
+    };
+    let payload = encode_write_batch(&header, &entries, b"hiyou");
+    let input = encode_request(Opcode::WriteBatch, 0, &payload).unwrap();
+    // the output only has room for the reply
+    let output = vec![0; batch_output_size(2, 0)];
+    let outcome = Call::new(&kernel).run(Opcode::WriteBatch, &input, output);
+
+    assert_eq!(outcome.status(), STATUS_SUCCESS);
+    assert_eq!(outcome.result.unwrap().information, batch_output_size(2, 0));
+    assert_eq!(game.peek(BASE, 2), b"hi");
+    assert_eq!(game.peek(BASE + PAGE_SIZE + 0x40, 3), b"you");
+}
+
+#[test]
+fn chain_follows_every_hop() {
+    let (kernel, game) = game();
+    game.poke(BASE + 0x10, &(BASE + 0x100).to_le_bytes());
+    game.poke(BASE + 0x120, &(BASE + PAGE_SIZE).to_le_bytes());
+    game.poke(BASE + PAGE_SIZE - 8, b"value");
+
+    let input = chain_request(GAME_PID, BASE, &[0x10, 0x20, -8], 8, 5);
+    let outcome = Call::new(&kernel).run(Opcode::ReadChain, &input, vec![0; chain_output_size(5)]);
+
+    assert_eq!(
+        outcome.result,
+        Ok(Completion {
+            status: STATUS_SUCCESS,
+            information: chain_output_size(5),
+        })
+    );
+    let (response, reply, value) = decode_chain_response(&outcome.output, 5).unwrap();
+    assert_eq!(response, Response::success(5));
+    assert_eq!(reply.address, BASE + PAGE_SIZE - 8);
+    assert_eq!(reply.status, STATUS_SUCCESS);
+    assert_eq!(value, b"value");
+    assert_eq!(outcome.audit.address, BASE);
+}
+
+#[test]
+fn chain_follows_32_bit_pointers() {
+    let (mut kernel, _) = game();
+    // a WOW64 process keeps its pointers, and its whole address space, below 4 GiB
+    let wow64 = SimProcess::new(GAME_PID + 4, GAME_CREATED, "game32.exe");
+    wow64.map(0x40_0000, PAGE_SIZE, true, 0xCC);
+    wow64.poke(0x40_0010, &0x40_0100u32.to_le_bytes());
+    wow64.poke(0x40_0104, &7u32.to_le_bytes());
+    kernel.add(&wow64);
+
+    let input = chain_request(GAME_PID + 4, 0x40_0000, &[0x10, 4], 4, 4);
+    let outcome = Call::new(&kernel).run(Opcode::ReadChain, &input, vec![0; chain_output_size(4)]);
+
+    assert_eq!(outcome.status(), STATUS_SUCCESS);
+    let (_, reply, value) = decode_chain_response(&outcome.output, 4).unwrap();
+    assert_eq!(reply.address, 0x40_0104);
+    assert_eq!(value, 7u32.to_le_bytes());
+}
+
+#[test]
+fn chain_reports_the_failing_hop() {
+    let (kernel, game) = game();
+    // hop 1 lands on an unmapped page, hop 1 of the second chain on a null pointer
+    game.poke(BASE, &(BASE + 8 * PAGE_SIZE).to_le_bytes());
+    game.poke(BASE + 8, &0u64.to_le_bytes());
+
+    for (base, address, status) in [
+        (BASE, BASE + 8 * PAGE_SIZE + 0x18, STATUS_PARTIAL_COPY),
+        (BASE + 8, 0x18, STATUS_ACCESS_VIOLATION),
+    ] {
+        let input = chain_request(GAME_PID, base, &[0, 0x18, 0], 8, 4);
+        let outcome =
+            Call::new(&kernel).run(Opcode::ReadChain, &input, vec![0; chain_output_size(4)]);
+
+        // the reply still comes back, naming the hop
+        assert_eq!(outcome.status(), STATUS_PARTIAL_COPY);
+        let (response, reply, value) = decode_chain_response(&outcome.output, 4).unwrap();
+        assert_eq!(response, Response::failure(STATUS_PARTIAL_COPY, 0, 1));
+        assert_eq!(reply.address, address);
+        assert_eq!(reply.status, status);
+        assert!(value.is_empty());
+    }
+}
+
+#[test]
+fn compare_exchange_writes_on_a_match() {
+    let (kernel, game) = game();
+    game.poke(BASE + 0x10, &1u32.to_le_bytes());
+
+    let outcome = Call::new(&kernel).compare_exchange(Target::Pid(GAME_PID), BASE + 0x10, 4, 1, 2);
+
+    assert_eq!(outcome.status(), STATUS_SUCCESS);
+    let (response, reply) = decode_exchange_response(&outcome.output).unwrap();
+    assert_eq!(response, Response::success(4));
+    assert_eq!(reply.observed, 1);
+    assert_eq!(outcome.result.unwrap().information, EXCHANGE_OUTPUT_SIZE);
+    assert_eq!(game.peek(BASE + 0x10, 4), 2u32.to_le_bytes());
+    // the bytes around the value are left alone
+    assert_eq!(game.peek(BASE + 0x14, 4), [0xAA; 4]);
+}
+
+#[test]
+fn compare_exchange_returns_what_it_found_on_a_mismatch() {
+    let (kernel, game) = game();
+    game.poke(BASE + 0x10, &[1, 2, 3, 4]);
+
+    let outcome =
+        Call::new(&kernel).compare_exchange(Target::Pid(GAME_PID), BASE + 0x10, 4, 0x0909_0201, 0);
+
+    // a warning: the observed value still comes back
+    assert_eq!(outcome.status(), STATUS_COMPARE_MISMATCH);
+    let (response, reply) = decode_exchange_response(&outcome.output).unwrap();
+    assert_eq!(response.failing_offset, 2);
+    assert_eq!(response.bytes_transferred, 0);
+    assert_eq!(reply.observed, u64::from(u32::from_le_bytes([1, 2, 3, 4])));
+    assert_eq!(game.peek(BASE + 0x10, 4), [1, 2, 3, 4]);
+}
+
+#[test]
+fn compare_exchange_needs_a_writable_page() {
+    let (kernel, game) = game();
+    game.map(BASE + 2 * PAGE_SIZE, PAGE_SIZE, false, 0);
+
+    for address in [BASE + 2 * PAGE_SIZE, BASE + 8 * PAGE_SIZE] {
+        let outcome = Call::new(&kernel).compare_exchange(Target::Pid(GAME_PID), address, 8, 0, 1);
+
+        assert_eq!(outcome.status(), STATUS_ACCESS_VIOLATION);
+    }
+    assert_eq!(game.peek(BASE + 2 * PAGE_SIZE, 8), [0; 8]);
+}
+
+#[test]
+fn compare_exchange_takes_aligned_values_only() {
+    let (kernel, game) = game();
+
+    for (address, size) in [(BASE + 2, 4), (BASE, 3), (BASE, 16)] {
+        let outcome =
+            Call::new(&kernel).compare_exchange(Target::Pid(GAME_PID), address, size, 0xAA, 0);
+
+        assert_eq!(outcome.result, Err(STATUS_INVALID_PARAMETER));
+    }
+    assert_eq!(game.peek(BASE, 16), [0xAA; 16]);
+}
+
+#[test]
+fn compare_exchange_is_a_write() {
+    let (kernel, game) = game();
+    let mut call = Call::new(&kernel);
+    call.policy.read_only = true;
+
+    let outcome = call.compare_exchange(Target::Pid(GAME_PID), BASE, 1, 0xAA, 0);
+
+    assert_eq!(outcome.result, Err(STATUS_POLICY_DENIED));
+    assert_eq!(game.peek(BASE, 1), [0xAA]);
+}
+
+#[test]
+fn read_only_policy_refuses_writes() {
+    let (kernel, game) = game();
+    let mut call = Call::new(&kernel);
+    call.policy.read_only = true;
+
+    let outcome = call.write(Target::Pid(GAME_PID), BASE, b"nope");
+
+    assert_eq!(outcome.result, Err(STATUS_POLICY_DENIED));
+    assert_eq!(game.peek(BASE, 4), [0xAA; 4]);
+    // denied requests are audited too
+    assert_eq!(outcome.audit.status, STATUS_POLICY_DENIED);
+    assert_eq!(outcome.audit.target_pid, GAME_PID);
+}
+
+#[test]
+fn policy_limits_transfer_size() {
+    let (kernel, _game) = game();
+    let mut call = Call::new(&kernel);
+    call.policy.max_transfer_size = 0x100;
+
+    assert_eq!(
+        call.read(Target::Pid(GAME_PID), BASE, 0x101).result,
+        Err(STATUS_POLICY_DENIED)
+    );
+    assert_eq!(
+        call.read(Target::Pid(GAME_PID), BASE, 0x100).status(),
+        STATUS_SUCCESS
+    );
+}
+
+#[test]
+fn critical_processes_are_refused() {
+    let (mut kernel, _game) = game();
+    let protected = SimProcess::critical(600, "anticheat.exe", "a protected process");
+    protected.map(BASE, PAGE_SIZE, true, 0);
+    kernel.add(&protected);
+    let lsass = SimProcess::new(700, 1, "lsass.exe");
+    lsass.map(BASE, PAGE_SIZE, true, 0);
+    kernel.add(&lsass);
+
+    let mut call = Call::new(&kernel);
+    for pid in [600, 700] {
+        assert_eq!(
+            call.read(Target::Pid(pid), BASE, 8).result,
+            Err(STATUS_CRITICAL_PROCESS_DENIED)
+        );
+    }
+
+    call.policy.critical_overrides = vec!["anticheat.exe".to_string()];
+    assert_eq!(
+        call.read(Target::Pid(600), BASE, 8).status(),
+        STATUS_SUCCESS
+    );
+    assert_eq!(
+        call.read(Target::Pid(700), BASE, 8).result,
+        Err(STATUS_CRITICAL_PROCESS_DENIED)
+    );
+}
+
+#[test]
+fn allowlist_limits_targets() {
+    let (kernel, _game) = game();
+    let mut call = Call::new(&kernel);
+
+    call.policy.allowed_images = vec!["other.exe".to_string()];
+    assert_eq!(
+        call.read(Target::Pid(GAME_PID), BASE, 8).result,
+        Err(STATUS_POLICY_DENIED)
+    );
+
+    call.policy.allowed_images = vec!["GAME.EXE".to_string()];
+    assert_eq!(
+        call.read(Target::Pid(GAME_PID), BASE, 8).status(),
+        STATUS_SUCCESS
+    );
+}
+
+#[test]
+fn handles_must_grant_the_access() {
+    let (mut kernel, game) = game();
+    kernel.open_handle(0x44, GAME_PID, false);
+    kernel.open_handle(0x48, GAME_PID, true);
+    let call = Call::new(&kernel);
+
+    assert_eq!(
+        call.read(Target::Handle(0x44), BASE, 8).status(),
+        STATUS_SUCCESS
+    );
+    assert_eq!(
+        call.write(Target::Handle(0x44), BASE, b"x").result,
+        Err(STATUS_ACCESS_DENIED)
+    );
+
+    let outcome = call.write(Target::Handle(0x48), BASE, b"x");
+    assert_eq!(outcome.status(), STATUS_SUCCESS);
+    assert_eq!(game.peek(BASE, 1), b"x");
+    // the handle was resolved to the process behind it
+    assert_eq!(outcome.audit.target_pid, GAME_PID);
+}
+
+#[test]
+fn session_targets_need_an_attachment() {
+    let (kernel, game) = game();
+    let mut call = Call::new(&kernel);
+
+    assert_eq!(
+        call.read(Target::Session, BASE, 8).result,
+        Err(STATUS_INVALID_DEVICE_STATE)
+    );
+
+    call.attachment = Some(Attachment {
+        process: game.clone(),
+        origin: Target::Pid(GAME_PID),
+        write: false,
+    });
+    assert_eq!(call.read(Target::Session, BASE, 8).status(), STATUS_SUCCESS);
+    // attached for reading only
+    assert_eq!(
+        call.write(Target::Session, BASE, b"x").result,
+        Err(STATUS_ACCESS_DENIED)
+    );
+
+    // the policy judges the session by how it was attached
+    call.attachment.as_mut().unwrap().write = true;
+    call.policy.require_handle_for_write = true;
+    assert_eq!(
+        call.write(Target::Session, BASE, b"x").result,
+        Err(STATUS_POLICY_DENIED)
+    );
+}
+
+#[test]
+fn reused_pids_and_exited_processes_are_refused() {
+    let (kernel, game) = game();
+    let call = Call::new(&kernel);
+
+    let mut request = Request {
+        process_id: GAME_PID,
+        reserved: 0,
+        address: BASE,
+        size: 8,
+        create_time: GAME_CREATED + 1,
+    };
+    let read = |request: &Request| {
+        let input = encode_request(Opcode::Read, 0, &request.to_bytes()).unwrap();
+        call.run(Opcode::Read, &input, vec![0; DATA_OFFSET + 8])
+    };
+
+    assert_eq!(read(&request).result, Err(STATUS_PROCESS_IDENTITY_MISMATCH));
+
+    request.create_time = GAME_CREATED;
+    assert_eq!(read(&request).status(), STATUS_SUCCESS);
+
+    game.exit();
+    assert_eq!(read(&request).result, Err(STATUS_PROCESS_IS_TERMINATING));
+}
+
+#[test]
+fn malformed_requests_touch_nothing() {
+    let (kernel, game) = game();
+    let call = Call::new(&kernel);
+
+    // output too small for the data
+    let input = memory_request(Opcode::Read, Target::Pid(GAME_PID), BASE, 0x100);
+    let outcome = call.run(Opcode::Read, &input, vec![0; DATA_OFFSET + 0xFF]);
+    assert_eq!(outcome.result, Err(STATUS_BUFFER_TOO_SMALL));
+    assert!(outcome.output.iter().all(|&byte| byte == 0));
+
+    // kernel addresses
+    assert_eq!(
+        call.read(Target::Pid(GAME_PID), 0xFFFF_F800_0000_0000, 8)
+            .result,
+        Err(STATUS_ACCESS_VIOLATION)
+    );
+
+    // a header that does not match the IOCTL
+    let input = memory_request(Opcode::Write, Target::Pid(GAME_PID), BASE, 4);
+    let outcome = call.run(Opcode::Read, &input, vec![0; DATA_OFFSET + 4]);
+    assert_eq!(outcome.result, Err(STATUS_INVALID_DEVICE_REQUEST));
+    assert_eq!(game.peek(BASE, 4), [0xAA; 4]);
+
+    // not a memory operation
+    let input = encode_request(Opcode::Hello, 0, &[]).unwrap();
+    assert_eq!(
+        call.run(Opcode::Hello, &input, vec![0; 64]).result,
+        Err(STATUS_INVALID_DEVICE_REQUEST)
+    );
+}
+
+#[test]
+fn cancelled_requests_stop() {
+    let (kernel, _game) = game();
+    let mut call = Call::new(&kernel);
+    call.cancelled = true;
+
+    assert_eq!(
+        call.read(Target::Pid(GAME_PID), BASE, 8).result,
+        Err(STATUS_CANCELLED)
+    );
+
+    let input = memory_request(Opcode::ReadLossy, Target::Pid(GAME_PID), BASE, PAGE_SIZE);
+    let output = vec![0; lossy_output_size(BASE, PAGE_SIZE)];
+    assert_eq!(
+        call.run(Opcode::ReadLossy, &input, output).result,
+        Err(STATUS_CANCELLED)
+    );
+}
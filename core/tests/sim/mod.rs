@@ -0,0 +1,269 @@
+//! A simulated kernel for the host tests: processes with sparse, page-granular
+//! address spaces, and a handle table for the caller.
+
+use erebus_core::{KernelOps, KernelProcess};
+use shared::{
+    lossy::PAGE_SIZE,
+    protocol::{Target, ANY_CREATE_TIME},
+    status::{
+        STATUS_ACCESS_DENIED, STATUS_ACCESS_VIOLATION, STATUS_INVALID_PARAMETER,
+        STATUS_PARTIAL_COPY, STATUS_PROCESS_IDENTITY_MISMATCH, STATUS_SUCCESS,
+    },
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+};
+
+/// `STATUS_INVALID_CID`, what looking up an unknown PID fails with.
+pub const STATUS_INVALID_CID: i32 = 0xC000_000B_u32 as i32;
+
+/// `STATUS_INVALID_HANDLE`.
+pub const STATUS_INVALID_HANDLE: i32 = 0xC000_0008_u32 as i32;
+
+struct Page {
+    bytes: Vec<u8>,
+    writable: bool,
+}
+
+struct State {
+    id: u32,
+    create_time: u64,
+    image: Vec<u8>,
+    critical: Option<&'static str>,
+    exited: Cell<bool>,
+    pages: RefCell<BTreeMap<u64, Page>>,
+}
+
+/// A process of the simulation. Clones share the process, like references to
+/// the same kernel object.
+#[derive(Clone)]
+pub struct SimProcess(Rc<State>);
+
+impl SimProcess {
+    pub fn new(id: u32, create_time: u64, image: &str) -> Self {
+        Self::build(id, create_time, image, None)
+    }
+
+    /// A process the kernel considers critical for `reason`, whatever its name.
+    pub fn critical(id: u32, image: &str, reason: &'static str) -> Self {
+        Self::build(id, 1, image, Some(reason))
+    }
+
+    fn build(id: u32, create_time: u64, image: &str, critical: Option<&'static str>) -> Self {
+        Self(Rc::new(State {
+            id,
+            create_time,
+            image: image.as_bytes().to_vec(),
+            critical,
+            exited: Cell::new(false),
+            pages: RefCell::new(BTreeMap::new()),
+        }))
+    }
+
+    /// Commits the pages covering `address..address + len`, filled with `fill`.
+    pub fn map(&self, address: u64, len: u64, writable: bool, fill: u8) {
+        let mut pages = self.0.pages.borrow_mut();
+        let first = address / PAGE_SIZE;
+        let last = (address + len - 1) / PAGE_SIZE;
+        for page in first..=last {
+            pages.insert(
+                page,
+                Page {
+                    bytes: vec![fill; PAGE_SIZE as usize],
+                    writable,
+                },
+            );
+        }
+    }
+
+    /// Writes `data` at `address`, which must be committed, as the process itself would.
+    pub fn poke(&self, address: u64, data: &[u8]) {
+        for (index, &byte) in data.iter().enumerate() {
+            let at = address + index as u64;
+            let mut pages = self.0.pages.borrow_mut();
+            let page = pages.get_mut(&(at / PAGE_SIZE)).expect("page not mapped");
+            page.bytes[(at % PAGE_SIZE) as usize] = byte;
+        }
+    }
+
+    /// Reads `len` bytes at `address`, which must be committed.
+    pub fn peek(&self, address: u64, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        let (status, _) = self.copy_out(address, &mut buffer);
+        assert_eq!(status, STATUS_SUCCESS, "range not mapped");
+        buffer
+    }
+
+    pub fn exit(&self) {
+        self.0.exited.set(true);
+    }
+
+    /// Copies byte by byte, stopping at the first page that is not committed,
+    /// the way `MmCopyVirtualMemory` stops at the first fault.
+    fn copy_out(&self, address: u64, buffer: &mut [u8]) -> (i32, u64) {
+        let pages = self.0.pages.borrow();
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            let at = address + index as u64;
+            let Some(page) = pages.get(&(at / PAGE_SIZE)) else {
+                return (STATUS_PARTIAL_COPY, index as u64);
+            };
+            *byte = page.bytes[(at % PAGE_SIZE) as usize];
+        }
+
+        (STATUS_SUCCESS, buffer.len() as u64)
+    }
+
+    /// An aligned value never straddles a page, so one page decides whether the
+    /// whole exchange can happen, like the page the driver locks.
+    fn compare_exchange(
+        &self,
+        address: u64,
+        size: usize,
+        expected: u64,
+        new: u64,
+    ) -> Result<u64, i32> {
+        let mut pages = self.0.pages.borrow_mut();
+        let page = match pages.get_mut(&(address / PAGE_SIZE)) {
+            Some(page) if page.writable => page,
+            _ => return Err(STATUS_ACCESS_VIOLATION),
+        };
+
+        let start = (address % PAGE_SIZE) as usize;
+        let value = &mut page.bytes[start..start + size];
+        let mut observed = [0; 8];
+        observed[..size].copy_from_slice(value);
+        let observed = u64::from_le_bytes(observed);
+
+        if observed == expected {
+            value.copy_from_slice(&new.to_le_bytes()[..size]);
+        }
+        Ok(observed)
+    }
+
+    fn copy_in(&self, address: u64, data: &[u8]) -> (i32, u64) {
+        let mut pages = self.0.pages.borrow_mut();
+        for (index, &byte) in data.iter().enumerate() {
+            let at = address + index as u64;
+            match pages.get_mut(&(at / PAGE_SIZE)) {
+                Some(page) if page.writable => page.bytes[(at % PAGE_SIZE) as usize] = byte,
+                _ => return (STATUS_PARTIAL_COPY, index as u64),
+            }
+        }
+
+        (STATUS_SUCCESS, data.len() as u64)
+    }
+}
+
+impl KernelProcess for SimProcess {
+    fn id(&self) -> u32 {
+        self.0.id
+    }
+
+    fn create_time(&self) -> u64 {
+        self.0.create_time
+    }
+
+    fn has_exited(&self) -> bool {
+        self.0.exited.get()
+    }
+
+    fn image_file_name(&self) -> &[u8] {
+        &self.0.image
+    }
+
+    fn critical_reason(&self) -> Option<&'static str> {
+        self.0.critical
+    }
+}
+
+/// Access a handle in the caller's table grants.
+#[derive(Clone, Copy)]
+pub struct HandleAccess {
+    pub process_id: u32,
+    pub write: bool,
+}
+
+pub struct SimKernel {
+    caller: SimProcess,
+    processes: BTreeMap<u32, SimProcess>,
+    handles: BTreeMap<u32, HandleAccess>,
+}
+
+impl SimKernel {
+    pub fn new() -> Self {
+        Self {
+            caller: SimProcess::new(1000, 7, "um.exe"),
+            processes: BTreeMap::new(),
+            handles: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, process: &SimProcess) {
+        self.processes.insert(process.id(), process.clone());
+    }
+
+    /// Opens a handle to `process_id` in the caller's handle table.
+    pub fn open_handle(&mut self, handle: u32, process_id: u32, write: bool) {
+        self.handles
+            .insert(handle, HandleAccess { process_id, write });
+    }
+
+    pub fn caller(&self) -> &SimProcess {
+        &self.caller
+    }
+}
+
+impl KernelOps for SimKernel {
+    type Process = SimProcess;
+
+    fn open_process(
+        &self,
+        target: Target,
+        write: bool,
+        create_time: u64,
+    ) -> Result<SimProcess, i32> {
+        let process_id = match target {
+            Target::Pid(process_id) => process_id,
+            Target::Handle(handle) => {
+                let access = self.handles.get(&handle).ok_or(STATUS_INVALID_HANDLE)?;
+                if write && !access.write {
+                    return Err(STATUS_ACCESS_DENIED);
+                }
+                access.process_id
+            }
+            Target::Session => return Err(STATUS_INVALID_PARAMETER),
+        };
+
+        let process = self.processes.get(&process_id).ok_or(STATUS_INVALID_CID)?;
+        if create_time != ANY_CREATE_TIME && create_time != process.create_time() {
+            return Err(STATUS_PROCESS_IDENTITY_MISMATCH);
+        }
+
+        Ok(process.clone())
+    }
+
+    fn current_process(&self) -> SimProcess {
+        self.caller.clone()
+    }
+
+    fn read_memory(&self, process: &SimProcess, address: u64, buffer: &mut [u8]) -> (i32, u64) {
+        process.copy_out(address, buffer)
+    }
+
+    fn write_memory(&self, process: &SimProcess, address: u64, data: &[u8]) -> (i32, u64) {
+        process.copy_in(address, data)
+    }
+
+    fn compare_exchange(
+        &self,
+        process: &SimProcess,
+        address: u64,
+        size: u32,
+        expected: u64,
+        new: u64,
+    ) -> Result<u64, i32> {
+        process.compare_exchange(address, size as usize, expected, new)
+    }
+}
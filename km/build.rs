@@ -1,4 +1,20 @@
 fn main() -> Result<(), wdk_build::ConfigError> {
     println!("Starting build process...");
-    wdk_build::configure_wdk_binary_build()
+    let config = wdk_build::Config::from_env_auto()?;
+
+    // Kernel routines that raise exceptions are called through C, which can
+    // catch them.
+    println!("cargo:rerun-if-changed=src/seh.c");
+    let mut seh = cc::Build::new();
+    for (key, value) in config.get_preprocessor_definitions_iter() {
+        seh.define(&key, value.as_deref());
+    }
+    seh.includes(config.get_include_paths()?)
+        .flag("/kernel")
+        .file("src/seh.c")
+        .compile("seh");
+
+    // `IoCreateDeviceSecure` lives in a static library rather than ntoskrnl.
+    println!("cargo:rustc-link-lib=wdmsec");
+    config.configure_binary_build()
 }
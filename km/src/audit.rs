@@ -0,0 +1,71 @@
+//! Audit log of memory operations: a bounded ring of hash-chained records,
+//! handed out to user-land by `EREBUS_IOCTL_DRAIN_AUDIT`.
+
+use crate::{kernel::Kernel, sync::SpinLock};
+use alloc::{collections::VecDeque, vec::Vec};
+use erebus_core::{audit::AuditEntry, KernelOps};
+use shared::{audit::GENESIS_HASH, ipc::AuditRecord, protocol::Opcode, sha256::DIGEST_SIZE};
+use wdk_sys::{ntddk::KeQuerySystemTimePrecise, LARGE_INTEGER, NTSTATUS};
+
+/// Records kept until drained. Past this, the oldest record is dropped, which a
+/// reader sees as a gap in the sequence.
+const AUDIT_CAPACITY: usize = 1024;
+
+struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    next_sequence: u64,
+    last_hash: [u8; DIGEST_SIZE],
+}
+
+static LOG: SpinLock<AuditLog> = SpinLock::new(AuditLog {
+    records: VecDeque::new(),
+    next_sequence: 0,
+    last_hash: GENESIS_HASH,
+});
+
+/// Allocates the ring up front, so recording never allocates under the lock.
+pub fn init() {
+    let mut records = VecDeque::with_capacity(AUDIT_CAPACITY);
+    // the empty ring is released outside the lock
+    core::mem::swap(&mut LOG.lock().records, &mut records);
+}
+
+/// Starts the audit entry of a request issued by the current process, which
+/// for a deferred request is the one its worker attached to.
+pub fn entry(opcode: Opcode) -> AuditEntry {
+    let mut timestamp: LARGE_INTEGER = unsafe { core::mem::zeroed() };
+    unsafe { KeQuerySystemTimePrecise(&mut timestamp) };
+
+    AuditEntry::new(
+        opcode,
+        &Kernel.current_process(),
+        unsafe { timestamp.QuadPart } as u64,
+    )
+}
+
+/// Seals `entry` with the request's final `status` and appends it to the log.
+pub fn record(entry: AuditEntry, status: NTSTATUS) {
+    let mut record = entry.finish(status);
+
+    let mut log = LOG.lock();
+
+    record.seal(log.next_sequence, log.last_hash);
+    log.next_sequence += 1;
+    log.last_hash = record.hash;
+
+    if log.records.len() == AUDIT_CAPACITY {
+        log.records.pop_front();
+    }
+    log.records.push_back(record);
+}
+
+/// Removes and returns up to `max` of the oldest records.
+pub fn drain(max: usize) -> Vec<AuditRecord> {
+    let mut drained = Vec::with_capacity(max.min(AUDIT_CAPACITY));
+
+    let mut log = LOG.lock();
+    let count = max.min(log.records.len());
+    drained.extend(log.records.drain(..count));
+
+    drained
+}
@@ -2,23 +2,72 @@
 use alloc::format;
 
 use crate::{
+    audit,
+    events::{self, Wait},
+    ffi::MmGetSystemAddressForMdlSafe,
+    kernel::Kernel,
     logger::LogLevel,
-    memory::{is_valid_user_memory, ke_read_virtual_memory, ke_write_virtual_memory},
-    println,
-    process::Process,
+    modules, policy, println,
+    process::{Process, PROCESS_VM_READ, PROCESS_VM_WRITE},
+    processes, regions,
+    session::{Attachment, Session},
 };
+use alloc::sync::Arc;
 use core::{ffi::c_void, ptr::null_mut};
-use shared::ipc::Request;
-use wdk::nt_success;
+use erebus_core::{
+    audit::AuditEntry,
+    request::{decode_payload, protocol_error_to_status},
+    Context, Ioctl,
+};
+use shared::{
+    audit::AUDIT_RECORD_SIZE,
+    events::{events_output_size, EVENTS_OFFSET, EVENT_RECORD_SIZE, MAX_EVENTS_PER_REPLY},
+    ioctl::is_in_direct,
+    ipc::{
+        AttachRequest, AttachResponse, EventsRequest, HelloResponse, ModuleQuery, ModulesReply,
+        ProcessesReply, RegionQuery, RegionsReply, Response,
+    },
+    modules::{modules_output_size, MODULES_OFFSET, MODULES_REPLY_SIZE, MODULE_ENTRY_SIZE},
+    processes::{PROCESSES_OFFSET, PROCESSES_REPLY_SIZE, PROCESS_ENTRY_SIZE},
+    protocol::{
+        Opcode, ProtocolError, RequestHeader, Target, CAP_AUDIT, CAP_BATCH, CAP_COMPARE_EXCHANGE,
+        CAP_EVENTS, CAP_MODULES, CAP_PROCESSES, CAP_PROCESS_HANDLE, CAP_READ, CAP_READ_CHAIN,
+        CAP_READ_LOSSY, CAP_REGIONS, CAP_RELOAD_POLICY, CAP_SESSION, CAP_WRITE, DATA_OFFSET,
+        RESPONSE_SIZE,
+    },
+    regions::{regions_output_size, MEMORY_REGION_SIZE, REGIONS_OFFSET, REGIONS_REPLY_SIZE},
+};
 use wdk_sys::{
-    ntddk::{ProbeForRead, RtlCopyMemoryNonTemporal},
-    NTSTATUS, PIRP, STATUS_ACCESS_VIOLATION, STATUS_BUFFER_ALL_ZEROS, STATUS_SUCCESS,
-    STATUS_UNSUCCESSFUL, _IO_STACK_LOCATION,
+    ntddk::RtlCopyMemoryNonTemporal, MdlMappingNoExecute, _IO_STACK_LOCATION,
+    _MM_PAGE_PRIORITY::NormalPagePriority, NTSTATUS, PIRP, STATUS_BUFFER_ALL_ZEROS,
+    STATUS_BUFFER_TOO_SMALL, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_DEVICE_STATE,
+    STATUS_INVALID_PARAMETER, STATUS_PENDING, STATUS_SUCCESS, STATUS_UNSUCCESSFUL,
 };
 
+/// Operations this build of the driver advertises through `EREBUS_IOCTL_HELLO`.
+/// `CAP_WRITE` and `CAP_COMPARE_EXCHANGE` are withdrawn while the policy is read-only.
+const CAPABILITIES: u64 = CAP_READ
+    | CAP_WRITE
+    | CAP_BATCH
+    | CAP_PROCESS_HANDLE
+    | CAP_RELOAD_POLICY
+    | CAP_AUDIT
+    | CAP_SESSION
+    | CAP_READ_LOSSY
+    | CAP_REGIONS
+    | CAP_MODULES
+    | CAP_PROCESSES
+    | CAP_EVENTS
+    | CAP_READ_CHAIN
+    | CAP_COMPARE_EXCHANGE;
+
 struct IoctlBuffer {
     len: u32,
     buf: *mut c_void,
+    // system mapping of the caller's output buffer: a METHOD_OUT_DIRECT one to
+    // answer in, or the data of a METHOD_IN_DIRECT one, which is only read
+    out_len: usize,
+    out_buf: *mut u8,
     p_stack_location: *mut _IO_STACK_LOCATION,
     p_irp: PIRP,
 }
@@ -28,29 +77,43 @@ impl IoctlBuffer {
         IoctlBuffer {
             len: 0,
             buf: null_mut(),
+            out_len: 0,
+            out_buf: null_mut(),
             p_stack_location,
             p_irp,
         }
     }
 
-    fn get_buf_to_req(&mut self) -> Result<Request, NTSTATUS> {
+    fn get_payload(&mut self, expected: Opcode) -> Result<(RequestHeader, &[u8]), NTSTATUS> {
         self.receive()?;
 
+        // Safety: `receive` checked `SystemBuffer` is non-null, and the I/O manager sized
+        // it to at least `InputBufferLength`. Everything past here is bounds-checked.
         let input_buffer =
             unsafe { core::slice::from_raw_parts(self.buf as *const u8, self.len as usize) };
-        if input_buffer.is_empty() {
-            println!(LogLevel::Error, "Error reading string passed to IOCTL");
-            return Err(STATUS_UNSUCCESSFUL);
-        }
 
-        let input_buffer_ptr: *const [u8; size_of::<Request>()] =
-            input_buffer.as_ptr() as *const [u8; size_of::<Request>()];
+        decode_payload(input_buffer, expected).inspect_err(|status| {
+            println!(
+                LogLevel::Error,
+                "Rejected {:?} request: {:#x}", expected, status
+            );
+        })
+    }
 
-        let request = unsafe {
-            core::mem::transmute::<[u8; size_of::<Request>()], Request>(*input_buffer_ptr)
-        };
+    /// The session of the handle this IOCTL was sent on.
+    fn session(&self) -> Result<&Session, NTSTATUS> {
+        // Safety: the file object of an IRP in flight stays open until it completes.
+        unsafe { Session::from_file_object((*self.p_stack_location).FileObject) }.ok_or_else(|| {
+            println!(LogLevel::Error, "Handle has no session.");
+            STATUS_INVALID_DEVICE_STATE
+        })
+    }
 
-        Ok(request)
+    /// The process the handle's session is attached to, if any.
+    fn attachment(&self) -> Option<Arc<Attachment>> {
+        // Safety: as for `session`.
+        unsafe { Session::from_file_object((*self.p_stack_location).FileObject) }
+            .and_then(Session::attachment)
     }
 
     fn receive(&mut self) -> Result<(), NTSTATUS> {
@@ -73,26 +136,148 @@ impl IoctlBuffer {
         Ok(())
     }
 
-    fn send_str(&self, input_str: &str) -> Result<(), NTSTATUS> {
-        unsafe { (*self.p_irp).IoStatus.__bindgen_anon_1.Status = STATUS_SUCCESS };
+    /// Maps the output buffer of a direct I/O IOCTL into system space. The I/O
+    /// manager has already probed and locked it, so the pages stay valid until
+    /// the IRP completes, whatever the caller does to its address space. For
+    /// `METHOD_IN_DIRECT` it was only probed for reading: never write to it.
+    fn map_output(&mut self, required: usize) -> Result<(), NTSTATUS> {
+        let output_len = unsafe {
+            (*self.p_stack_location)
+                .Parameters
+                .DeviceIoControl
+                .OutputBufferLength
+        } as usize;
+
+        if output_len < required {
+            println!(
+                LogLevel::Error,
+                "Output buffer too small: {} < {}", output_len, required
+            );
+            return Err(STATUS_BUFFER_TOO_SMALL);
+        }
 
-        let response = input_str.as_bytes();
-        let response_len = response.len();
-        unsafe { (*self.p_irp).IoStatus.Information = response_len as u64 };
+        let mdl = unsafe { (*self.p_irp).MdlAddress };
+        if mdl.is_null() {
+            println!(LogLevel::Error, "Output buffer has no MDL.");
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+
+        let mapped = unsafe {
+            MmGetSystemAddressForMdlSafe(mdl, NormalPagePriority as u32 | MdlMappingNoExecute)
+        };
+        if mapped.is_null() {
+            println!(LogLevel::Error, "Could not map the output buffer.");
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+
+        self.out_len = output_len;
+        self.out_buf = mapped as *mut u8;
+
+        Ok(())
+    }
+
+    /// Maps the whole output buffer, unless the caller passed none, and returns
+    /// the input, the data to write, and the output. A `METHOD_IN_DIRECT` output
+    /// buffer is the data, handed out read-only, and there is no output; any
+    /// other IOCTL has no data. The handler checks the buffers are large enough.
+    fn buffers(&mut self) -> Result<(&[u8], &[u8], &mut [u8]), NTSTATUS> {
+        self.receive()?;
+
+        let parameters = unsafe { (*self.p_stack_location).Parameters.DeviceIoControl };
+        if parameters.OutputBufferLength != 0 {
+            self.map_output(0)?;
+        }
+
+        // Safety: `receive` checked `SystemBuffer`, which holds `InputBufferLength`
+        // bytes. The output is mapped for `out_len` bytes, a separate buffer.
+        let input =
+            unsafe { core::slice::from_raw_parts(self.buf as *const u8, self.len as usize) };
+
+        if self.out_buf.is_null() {
+            Ok((input, &[], &mut []))
+        } else if is_in_direct(parameters.IoControlCode) {
+            let data = unsafe { core::slice::from_raw_parts(self.out_buf, self.out_len) };
+            Ok((input, data, &mut []))
+        } else {
+            let output = unsafe { core::slice::from_raw_parts_mut(self.out_buf, self.out_len) };
+            Ok((input, &[], output))
+        }
+    }
+
+    /// Kernel pointer `offset` bytes into the mapped output buffer.
+    fn output_at(&self, offset: usize) -> *mut c_void {
+        debug_assert!(offset <= self.out_len);
+        unsafe { self.out_buf.add(offset) as *mut c_void }
+    }
+
+    /// Copies `bytes` into the mapped output buffer at `offset`.
+    fn copy_to_output(&self, offset: usize, bytes: &[u8]) {
+        debug_assert!(offset + bytes.len() <= self.out_len);
+        unsafe {
+            RtlCopyMemoryNonTemporal(
+                self.output_at(offset),
+                bytes.as_ptr() as *mut c_void,
+                bytes.len() as u64,
+            );
+        }
+    }
+
+    /// Writes the reply to the front of the mapped output buffer. `data_len` is the
+    /// part of the data area behind it that the caller should consider filled in.
+    fn reply_direct(&self, reply: &[u8], data_len: u64) -> Result<(), NTSTATUS> {
+        if self.out_buf.is_null() || self.out_len < reply.len() {
+            println!(LogLevel::Error, "Output buffer is not mapped.");
+            return Err(STATUS_UNSUCCESSFUL);
+        }
+
+        unsafe {
+            RtlCopyMemoryNonTemporal(
+                self.out_buf as *mut c_void,
+                reply.as_ptr() as *mut c_void,
+                reply.len() as u64,
+            );
+            (*self.p_irp).IoStatus.Information = reply.len() as u64 + data_len;
+        }
+
+        Ok(())
+    }
 
+    fn send_response(&self, response: &Response, data_len: u64) -> Result<(), NTSTATUS> {
         println!(
             LogLevel::Info,
-            "Sending a message back to user-land {:?}",
-            core::str::from_utf8(response).unwrap()
+            "Sending a response back to user-land {:?}", response
         );
 
+        self.reply_direct(&response.to_bytes(), data_len)
+    }
+
+    fn send_bytes(&self, response: &[u8]) -> Result<(), NTSTATUS> {
+        let output_len: u32 = unsafe {
+            (*self.p_stack_location)
+                .Parameters
+                .DeviceIoControl
+                .OutputBufferLength
+        };
+
+        let response_len = response.len();
+        if (output_len as usize) < response_len {
+            println!(
+                LogLevel::Error,
+                "Output buffer too small: {} < {}", output_len, response_len
+            );
+            return Err(STATUS_BUFFER_TOO_SMALL);
+        }
+
+        // The status itself is filled in by `handle_ioctl` once the handler returns.
+        unsafe { (*self.p_irp).IoStatus.Information = response_len as u64 };
+
         // Copy the data now into the buffer to send back to user-land.
         // The driver should not write directly to the buffer pointed to by Irp->UserBuffer.
         unsafe {
             if !(*self.p_irp).AssociatedIrp.SystemBuffer.is_null() {
                 RtlCopyMemoryNonTemporal(
                     (*self.p_irp).AssociatedIrp.SystemBuffer,
-                    response as *const _ as *mut c_void,
+                    response.as_ptr() as *mut c_void,
                     response_len as u64,
                 );
             } else {
@@ -108,136 +293,421 @@ impl IoctlBuffer {
     }
 }
 
-pub fn ioctl_handler_read(
+/// Resolves the target of a query, through the same checks as a memory
+/// operation moving `size` bytes.
+fn open_target(
+    ioctl_buffer: &IoctlBuffer,
+    target: Target,
+    create_time: u64,
+    write: bool,
+    size: u64,
+    audit: &mut AuditEntry,
+) -> Result<Process, NTSTATUS> {
+    let current_policy = policy::current();
+    let attachment = ioctl_buffer.attachment();
+    let context = Context {
+        policy: &current_policy,
+        attachment: attachment.as_deref(),
+    };
+
+    erebus_core::target::open_target(&Kernel, &context, target, create_time, write, size, audit)
+        .inspect_err(|status| {
+            println!(
+                LogLevel::Error,
+                "Could not open {:?}: {:#x}", target, status
+            );
+        })
+}
+
+/// Runs a memory operation, which `erebus_core` handles straight on the
+/// request's buffers.
+fn run_memory_op(
     p_stack_location: *mut _IO_STACK_LOCATION,
     p_irp: PIRP,
+    opcode: Opcode,
+    audit: &mut AuditEntry,
 ) -> Result<(), NTSTATUS> {
     let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let attachment = ioctl_buffer.attachment();
+    let (input, data, output) = ioctl_buffer.buffers()?;
+
+    let current_policy = policy::current();
+    let context = Context {
+        policy: &current_policy,
+        attachment: attachment.as_deref(),
+    };
+    let mut ioctl = Ioctl {
+        input,
+        output,
+        data,
+        // the caller cancelled the request, or closed its handle; only a request
+        // deferred to a worker can be cancelled while it runs
+        cancelled: &|| unsafe { (*p_irp).Cancel != 0 },
+    };
+
+    let completion = erebus_core::dispatch(&Kernel, &context, opcode, &mut ioctl, audit)
+        .inspect_err(|status| {
+            println!(LogLevel::Error, "{:?} failed: {:#x}", opcode, status);
+        })?;
+    println!(LogLevel::Info, "{:?} done: {:?}", opcode, completion);
+
+    unsafe { (*p_irp).IoStatus.Information = completion.information as u64 };
+
+    if completion.status != STATUS_SUCCESS {
+        // On a warning, the I/O manager still copies the output back.
+        return Err(completion.status);
+    }
+
+    Ok(())
+}
 
-    let request = ioctl_buffer.get_buf_to_req()?;
-    println!(LogLevel::Info, "Received Request: {:?}", request);
+pub fn ioctl_handler_hello(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+) -> Result<(), NTSTATUS> {
+    let ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
 
-    let Request {
-        process_id,
-        address,
-        buffer,
-        size,
-    } = request;
+    // The input is deliberately ignored: a client built against another protocol
+    // version must still be able to learn which version we speak.
+    let capabilities = if policy::current().read_only {
+        CAPABILITIES & !(CAP_WRITE | CAP_COMPARE_EXCHANGE)
+    } else {
+        CAPABILITIES
+    };
 
-    if size == 0 {
-        println!(
-            LogLevel::Error,
-            "Invalid size specified in IOCTL request: {}", size
-        );
-        return Err(STATUS_UNSUCCESSFUL);
+    let response = HelloResponse::current(capabilities);
+    println!(LogLevel::Info, "Answering hello: {:?}", response);
+
+    ioctl_buffer.send_bytes(&response.to_bytes())
+}
+
+pub fn ioctl_handler_reload_policy(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+) -> Result<(), NTSTATUS> {
+    let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (_, payload) = ioctl_buffer.get_payload(Opcode::ReloadPolicy)?;
+
+    if !payload.is_empty() {
+        return Err(protocol_error_to_status(ProtocolError::TrailingBytes(
+            payload.len(),
+        )));
     }
 
-    let Process { process } = Process::by_id(process_id)?;
+    policy::reload()?;
+    println!(LogLevel::Success, "Policy reloaded");
 
-    println!(
-        LogLevel::Success,
-        "Resolved process with PID {} and _EPROCESS at {:?}", process_id, process
-    );
+    ioctl_buffer.send_bytes(&Response::success(0).to_bytes())
+}
 
-    // Pre-checks before accessing unsafe memory
-    if !is_valid_user_memory(address as _, size as _) {
-        println!(
-            LogLevel::Error,
-            "Invalid memory range: {:p}+{:#x}", address, size
-        );
-        return Err(STATUS_ACCESS_VIOLATION);
+pub fn ioctl_handler_attach(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+) -> Result<(), NTSTATUS> {
+    let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (header, payload) = ioctl_buffer.get_payload(Opcode::Attach)?;
+
+    let request = AttachRequest::from_bytes(payload).and_then(|request| {
+        header
+            .target(request.process_id)
+            .map(|target| (target, request))
+    });
+    let (target, request) = request.map_err(|err| {
+        println!(LogLevel::Error, "Rejected attach: {}", err);
+        protocol_error_to_status(err)
+    })?;
+
+    if target == Target::Session {
+        println!(LogLevel::Error, "Cannot attach a session to itself.");
+        return Err(STATUS_INVALID_PARAMETER);
     }
 
-    // Ensure the address is valid and accessible
-    // If it's not, it raises an exception and since we don't have SEH, well, BSOD...
-    // TODO: figure out a way to make this safer
-    unsafe { ProbeForRead(address, size, 1) };
+    let session = ioctl_buffer.session()?;
+    let current_policy = policy::current();
+
+    // Transfer sizes are checked per operation; everything else can be settled now.
+    let write = request.write();
+    policy::authorize(&current_policy, target, write, 0)?;
+
+    let access = if write {
+        PROCESS_VM_READ | PROCESS_VM_WRITE
+    } else {
+        PROCESS_VM_READ
+    };
+    let process = Process::open(target, access, request.create_time)?;
+    policy::check_process(&current_policy, &process)?;
+
+    let response = AttachResponse {
+        process_id: process.id(),
+        reserved: 0,
+        create_time: process.create_time() as u64,
+    };
+    println!(LogLevel::Success, "Session attached to {:?}", response);
+
+    session.attach(Attachment {
+        process,
+        origin: target,
+        write,
+    });
+
+    ioctl_buffer.send_bytes(&response.to_bytes())
+}
 
-    let mut bytes_read = 0;
-    let status = unsafe { ke_read_virtual_memory(process, address, buffer, size, &mut bytes_read) };
+pub fn ioctl_handler_drain_audit(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+) -> Result<(), NTSTATUS> {
+    let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (_, payload) = ioctl_buffer.get_payload(Opcode::DrainAudit)?;
 
-    if !nt_success(status) {
-        println!(
-            LogLevel::Error,
-            "Error copying VirtualMemory! Error: {:#x}", status
-        );
-        return Err(STATUS_UNSUCCESSFUL);
+    if !payload.is_empty() {
+        return Err(protocol_error_to_status(ProtocolError::TrailingBytes(
+            payload.len(),
+        )));
     }
 
-    println!(
-        LogLevel::Success,
-        "Read {} bytes from {:p}", bytes_read, address
-    );
+    // Records are only taken out of the ring once the output is known to hold them.
+    ioctl_buffer.map_output(DATA_OFFSET)?;
+    let records = audit::drain((ioctl_buffer.out_len - DATA_OFFSET) / AUDIT_RECORD_SIZE);
 
-    ioctl_buffer.send_str(&format!("Copied {} bytes from {:p}!", bytes_read, address))?;
+    for (index, record) in records.iter().enumerate() {
+        ioctl_buffer.copy_to_output(DATA_OFFSET + index * AUDIT_RECORD_SIZE, &record.to_bytes());
+    }
 
-    Ok(())
+    println!(LogLevel::Info, "Drained {} audit records", records.len());
+
+    let data_len = (records.len() * AUDIT_RECORD_SIZE) as u64;
+    ioctl_buffer.send_response(&Response::success(data_len), data_len)
 }
 
-pub fn ioctl_handler_write(
+pub fn ioctl_handler_list_processes(
     p_stack_location: *mut _IO_STACK_LOCATION,
     p_irp: PIRP,
 ) -> Result<(), NTSTATUS> {
     let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (_, payload) = ioctl_buffer.get_payload(Opcode::ListProcesses)?;
 
-    let request = ioctl_buffer.get_buf_to_req()?;
-    println!(LogLevel::Info, "Received Request: {:?}", request);
+    if !payload.is_empty() {
+        return Err(protocol_error_to_status(ProtocolError::TrailingBytes(
+            payload.len(),
+        )));
+    }
 
-    let Request {
-        process_id,
-        address,
-        buffer,
-        size,
-    } = request;
+    ioctl_buffer.map_output(PROCESSES_OFFSET)?;
+    let room = (ioctl_buffer.out_len - PROCESSES_OFFSET) / PROCESS_ENTRY_SIZE;
 
-    if size == 0 {
-        println!(
-            LogLevel::Error,
-            "Invalid size specified in IOCTL request: {}", size
-        );
-        return Err(STATUS_UNSUCCESSFUL);
+    // the caller learns how many there are, and can ask again with more room
+    let listed = processes::list_processes()?;
+    let returned = &listed[..listed.len().min(room)];
+    for (index, process) in returned.iter().enumerate() {
+        let offset = PROCESSES_OFFSET + index * PROCESS_ENTRY_SIZE;
+        ioctl_buffer.copy_to_output(offset, &process.to_bytes());
     }
 
-    let Process { process } = Process::by_id(process_id)?;
+    let reply = ProcessesReply {
+        process_count: returned.len() as u32,
+        total_count: listed.len() as u32,
+    };
+    ioctl_buffer.copy_to_output(RESPONSE_SIZE, &reply.to_bytes());
 
     println!(
-        LogLevel::Success,
-        "Resolved process with PID {} and _EPROCESS at {:?}", process_id, process
+        LogLevel::Info,
+        "Listed {} of {} processes", reply.process_count, reply.total_count
     );
 
-    // Pre-checks before accessing unsafe memory
-    if !is_valid_user_memory(address as _, size as _) {
-        println!(
-            LogLevel::Error,
-            "Invalid memory range: {:p}+{:#x}", address, size
-        );
-        return Err(STATUS_ACCESS_VIOLATION);
+    let data_len = (PROCESSES_REPLY_SIZE + returned.len() * PROCESS_ENTRY_SIZE) as u64;
+    ioctl_buffer.send_response(&Response::success(data_len), data_len)
+}
+
+/// Hands out the session's queued events, or parks the request until there are
+/// some. A parked request makes this return `STATUS_PENDING`, which `handle_ioctl`
+/// must pass on without completing it.
+pub fn ioctl_handler_get_events(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+) -> Result<(), NTSTATUS> {
+    let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (_, payload) = ioctl_buffer.get_payload(Opcode::GetEvents)?;
+
+    let request = EventsRequest::from_bytes(payload).map_err(|err| {
+        println!(LogLevel::Error, "Rejected events request: {}", err);
+        protocol_error_to_status(err)
+    })?;
+
+    // a completed request always carries at least one event
+    ioctl_buffer.map_output(events_output_size(1))?;
+    let room =
+        ((ioctl_buffer.out_len - EVENTS_OFFSET) / EVENT_RECORD_SIZE).min(MAX_EVENTS_PER_REPLY);
+
+    // an attached session only hears about image loads and threads of its process
+    let session = ioctl_buffer.session()?;
+    let scope = session
+        .attachment()
+        .map(|attachment| attachment.process.id());
+    let subscriber = events::subscribe(session, request.mask, scope);
+
+    // Safety: the output buffer was checked to have room for `room` events, and
+    // the request is still ours.
+    match unsafe { events::wait(&subscriber, p_irp, room) }? {
+        Wait::Completed => Ok(()),
+        Wait::Pending => Err(STATUS_PENDING),
     }
+}
+
+pub fn ioctl_handler_read(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::Read, audit)
+}
+
+pub fn ioctl_handler_read_lossy(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::ReadLossy, audit)
+}
 
-    // Ensure the address is valid and accessible
-    // If it's not, it raises an exception and since we don't have SEH, well, BSOD...
-    // TODO: figure out a way to make this safer
-    unsafe { ProbeForRead(address, size, 1) };
+pub fn ioctl_handler_read_chain(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::ReadChain, audit)
+}
 
-    let mut bytes_written = 0;
-    let status =
-        unsafe { ke_write_virtual_memory(process, buffer, address, size, &mut bytes_written) };
+pub fn ioctl_handler_query_regions(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (header, payload) = ioctl_buffer.get_payload(Opcode::QueryRegions)?;
+
+    let query = RegionQuery::from_bytes(payload).and_then(|query| {
+        header
+            .target(query.process_id)
+            .map(|target| (target, query))
+    });
+    let (target, query) = query.map_err(|err| {
+        println!(LogLevel::Error, "Rejected region query: {}", err);
+        protocol_error_to_status(err)
+    })?;
+    audit.request(target, query.start_address, 0);
+
+    // Hand out as many regions as were asked for and fit, but at least one.
+    ioctl_buffer.map_output(regions_output_size(1))?;
+    let room = (ioctl_buffer.out_len - REGIONS_OFFSET) / MEMORY_REGION_SIZE;
+    let max_regions = query.max_regions.min(room as u32);
+
+    let process = open_target(&ioctl_buffer, target, query.create_time, false, 0, audit)?;
+
+    let (region_count, next_address) = regions::query_regions(
+        &process,
+        query.start_address,
+        max_regions,
+        |index, region| {
+            let offset = REGIONS_OFFSET + index as usize * MEMORY_REGION_SIZE;
+            ioctl_buffer.copy_to_output(offset, &region.to_bytes());
+        },
+    )?;
+
+    let reply = RegionsReply {
+        region_count,
+        reserved: 0,
+        next_address,
+    };
+    ioctl_buffer.copy_to_output(RESPONSE_SIZE, &reply.to_bytes());
 
+    println!(
+        LogLevel::Success,
+        "Described {} regions from {:#x}, next {:#x}",
+        region_count,
+        query.start_address,
+        next_address
+    );
 
-    if !nt_success(status) {
-        println!(
-            LogLevel::Error,
-            "Error copying VirtualMemory! Error: {:#x}", status
-        );
-        return Err(STATUS_UNSUCCESSFUL);
+    let data_len = (REGIONS_REPLY_SIZE + region_count as usize * MEMORY_REGION_SIZE) as u64;
+    ioctl_buffer.send_response(&Response::success(data_len), data_len)
+}
+
+pub fn ioctl_handler_query_modules(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    let mut ioctl_buffer = IoctlBuffer::new(p_stack_location, p_irp);
+    let (header, payload) = ioctl_buffer.get_payload(Opcode::QueryModules)?;
+
+    let query = ModuleQuery::from_bytes(payload).and_then(|query| {
+        header
+            .target(query.process_id)
+            .map(|target| (target, query))
+    });
+    let (target, query) = query.map_err(|err| {
+        println!(LogLevel::Error, "Rejected module query: {}", err);
+        protocol_error_to_status(err)
+    })?;
+    audit.request(target, 0, 0);
+
+    ioctl_buffer.map_output(modules_output_size(0))?;
+    let room = (ioctl_buffer.out_len - MODULES_OFFSET) / MODULE_ENTRY_SIZE;
+
+    let process = open_target(&ioctl_buffer, target, query.create_time, false, 0, audit)?;
+
+    // the caller learns how many there are, and can ask again with more room
+    let loaded = modules::loaded_modules(&process)?;
+    let returned = &loaded[..loaded.len().min(room)];
+    for (index, module) in returned.iter().enumerate() {
+        let offset = MODULES_OFFSET + index * MODULE_ENTRY_SIZE;
+        ioctl_buffer.copy_to_output(offset, &module.to_bytes());
     }
-    
+
+    let reply = ModulesReply {
+        module_count: returned.len() as u32,
+        total_count: loaded.len() as u32,
+    };
+    ioctl_buffer.copy_to_output(RESPONSE_SIZE, &reply.to_bytes());
+
     println!(
         LogLevel::Success,
-        "Wrote {} bytes to {:p}", bytes_written, address
+        "Listed {} of {} modules", reply.module_count, reply.total_count
     );
 
-    ioctl_buffer.send_str(&format!("Copied {} bytes to {:p}!", bytes_written, address))?;
+    let data_len = (MODULES_REPLY_SIZE + returned.len() * MODULE_ENTRY_SIZE) as u64;
+    ioctl_buffer.send_response(&Response::success(data_len), data_len)
+}
 
-    Ok(())
+pub fn ioctl_handler_write(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::Write, audit)
+}
+
+pub fn ioctl_handler_compare_exchange(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::CompareExchange, audit)
+}
+
+pub fn ioctl_handler_read_batch(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::ReadBatch, audit)
+}
+
+pub fn ioctl_handler_write_batch(
+    p_stack_location: *mut _IO_STACK_LOCATION,
+    p_irp: PIRP,
+    audit: &mut AuditEntry,
+) -> Result<(), NTSTATUS> {
+    run_memory_op(p_stack_location, p_irp, Opcode::WriteBatch, audit)
 }
@@ -0,0 +1,462 @@
+//! Process, image, and thread events, queued per session and handed out by
+//! `EREBUS_IOCTL_GET_EVENTS`.
+//!
+//! This is the inverted-call pattern: a client keeps a request outstanding, the
+//! driver parks it while the session's queue is empty, and the notify routine
+//! that queues the next event completes it. A parked request carries a cancel
+//! routine. Whoever swaps that routine out of the IRP owns its completion; the
+//! I/O manager swaps it out before cancelling, so every other path that fails
+//! to reclaim it must forget the IRP and leave it to the cancel routine.
+
+#[allow(unused_imports)]
+use alloc::format;
+
+use crate::{
+    complete_request,
+    ffi::{
+        IoGetCurrentIrpStackLocation, IoMarkIrpPending, IoSetCancelRoutine,
+        MmGetSystemAddressForMdlSafe,
+    },
+    logger::LogLevel,
+    println,
+    process::Process,
+    session::Session,
+    sync::SpinLock,
+    RUNDOWN,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use shared::{
+    events::{EventKind, EVENTS_OFFSET, EVENTS_REPLY_SIZE, EVENT_MASK_PROCESS, EVENT_RECORD_SIZE},
+    ipc::{EventRecord, EventsReply, Response},
+    protocol::{ANY_CREATE_TIME, RESPONSE_SIZE},
+};
+use wdk::nt_success;
+use wdk_sys::{
+    ntddk::{
+        IoReleaseCancelSpinLock, KeQuerySystemTimePrecise, PsRemoveCreateThreadNotifyRoutine,
+        PsRemoveLoadImageNotifyRoutine, PsSetCreateProcessNotifyRoutine,
+        PsSetCreateThreadNotifyRoutine, PsSetLoadImageNotifyRoutine, RtlCopyMemoryNonTemporal,
+    },
+    MdlMappingNoExecute,
+    _MM_PAGE_PRIORITY::NormalPagePriority,
+    BOOLEAN, DEVICE_OBJECT, HANDLE, LARGE_INTEGER, NTSTATUS, PIMAGE_INFO, PIRP, PUNICODE_STRING,
+    STATUS_CANCELLED, STATUS_DELETE_PENDING, STATUS_DEVICE_BUSY, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_SUCCESS,
+};
+
+/// Events kept per session until collected. Past this, the newest events are
+/// replaced by an `EventKind::Lost` record counting them.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// An `EREBUS_IOCTL_GET_EVENTS` waiting for the queue to fill.
+struct Parked {
+    irp: PIRP,
+    /// Events its output buffer has room for.
+    room: usize,
+}
+
+struct EventQueue {
+    /// `EVENT_MASK_*` bits of the events to queue.
+    mask: u32,
+    /// PID image and thread events are limited to, if the session is attached.
+    scope: Option<u32>,
+    events: VecDeque<EventRecord>,
+    next_sequence: u64,
+    parked: Option<Parked>,
+}
+
+// Safety: the parked IRP is only touched under the queue's lock, or by whoever
+// reclaimed it from its cancel routine.
+unsafe impl Send for EventQueue {}
+
+impl EventQueue {
+    fn wants(&self, event: &EventRecord) -> bool {
+        let Ok(kind) = event.kind() else {
+            return false;
+        };
+
+        self.mask & kind.mask() != 0
+            && (kind.mask() == EVENT_MASK_PROCESS
+                || self.scope.is_none_or(|pid| pid == event.process_id))
+    }
+
+    fn push(&mut self, mut event: EventRecord) {
+        event.sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        if self.events.len() < QUEUE_CAPACITY {
+            self.events.push_back(event);
+            return;
+        }
+
+        // Full: the newest slot counts what was dropped since, and its sequence
+        // number is that of the first event dropped.
+        match self.events.back_mut() {
+            Some(last) if last.kind == EventKind::Lost as u16 => last.related_id += 1,
+            _ => {
+                let Some(dropped) = self.events.pop_back() else {
+                    return;
+                };
+                let mut lost = EventRecord::new(EventKind::Lost, event.timestamp, 0, 2);
+                lost.sequence = dropped.sequence;
+                self.events.push_back(lost);
+            }
+        }
+    }
+
+    fn take(&mut self, room: usize) -> Vec<EventRecord> {
+        let count = room.min(self.events.len());
+        self.events.drain(..count).collect()
+    }
+
+    /// Takes the parked request along with the events for it, unless its
+    /// cancel routine got to it first.
+    fn unpark(&mut self) -> Option<(PIRP, Vec<EventRecord>)> {
+        let parked = self.parked.take()?;
+        unsafe { IoSetCancelRoutine(parked.irp, None) }?;
+
+        Some((parked.irp, self.take(parked.room)))
+    }
+}
+
+/// The event queue of a session that asked for events.
+pub(crate) struct Subscriber {
+    queue: SpinLock<EventQueue>,
+}
+
+static SUBSCRIBERS: SpinLock<Vec<Arc<Subscriber>>> = SpinLock::new(Vec::new());
+
+/// Lets the notify routines skip taking `SUBSCRIBERS` while nobody listens.
+static SUBSCRIBER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Registers the notify routines. They stay registered until `unregister`, and
+/// cost a check of `SUBSCRIBER_COUNT` while no session asked for events.
+pub fn register() -> NTSTATUS {
+    let status = unsafe { PsSetCreateProcessNotifyRoutine(Some(process_notify), 0) };
+    if !nt_success(status) {
+        return status;
+    }
+
+    let status = unsafe { PsSetLoadImageNotifyRoutine(Some(image_notify)) };
+    if !nt_success(status) {
+        unsafe { PsSetCreateProcessNotifyRoutine(Some(process_notify), 1) };
+        return status;
+    }
+
+    let status = unsafe { PsSetCreateThreadNotifyRoutine(Some(thread_notify)) };
+    if !nt_success(status) {
+        unsafe {
+            PsRemoveLoadImageNotifyRoutine(Some(image_notify));
+            PsSetCreateProcessNotifyRoutine(Some(process_notify), 1);
+        }
+        return status;
+    }
+
+    REGISTERED.store(true, Ordering::Release);
+    STATUS_SUCCESS
+}
+
+/// Removes the notify routines, waiting for any that are running to return.
+pub fn unregister() {
+    if !REGISTERED.swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    unsafe {
+        PsRemoveCreateThreadNotifyRoutine(Some(thread_notify));
+        PsRemoveLoadImageNotifyRoutine(Some(image_notify));
+        PsSetCreateProcessNotifyRoutine(Some(process_notify), 1);
+    }
+}
+
+/// Starts queueing the events in `mask` for `session`, or changes which events
+/// an existing queue takes from now on. `scope` limits image and thread events
+/// to one process.
+pub fn subscribe(session: &Session, mask: u32, scope: Option<u32>) -> Arc<Subscriber> {
+    let update = |subscriber: Arc<Subscriber>| {
+        let mut queue = subscriber.queue.lock();
+        queue.mask = mask;
+        queue.scope = scope;
+        drop(queue);
+
+        subscriber
+    };
+
+    if let Some(subscriber) = session.subscriber() {
+        return update(subscriber);
+    }
+
+    let subscriber = Arc::new(Subscriber {
+        queue: SpinLock::new(EventQueue {
+            mask,
+            scope,
+            events: VecDeque::with_capacity(QUEUE_CAPACITY),
+            next_sequence: 0,
+            parked: None,
+        }),
+    });
+
+    if let Err(existing) = session.install_subscriber(subscriber.clone()) {
+        return update(existing);
+    }
+
+    SUBSCRIBERS.lock().push(subscriber.clone());
+    SUBSCRIBER_COUNT.fetch_add(1, Ordering::AcqRel);
+
+    subscriber
+}
+
+/// Stops queueing events for `session`, and cancels its outstanding request.
+pub fn unsubscribe(session: &Session) {
+    let Some(subscriber) = session.take_subscriber() else {
+        return;
+    };
+
+    SUBSCRIBERS
+        .lock()
+        .retain(|other| !Arc::ptr_eq(other, &subscriber));
+    SUBSCRIBER_COUNT.fetch_sub(1, Ordering::AcqRel);
+
+    let parked = subscriber.queue.lock().parked.take();
+    if let Some(parked) = parked {
+        if unsafe { IoSetCancelRoutine(parked.irp, None) }.is_some() {
+            unsafe { complete_request(parked.irp, STATUS_CANCELLED) };
+        }
+    }
+}
+
+/// Cancels the request every session has waiting, for unload. The queues stay
+/// until their sessions close; once `RUNDOWN` is closed, `wait` parks nothing
+/// new in them.
+pub fn cancel_all() {
+    let mut cancelled = Vec::new();
+
+    for subscriber in SUBSCRIBERS.lock().iter() {
+        let parked = subscriber.queue.lock().parked.take();
+        if let Some(parked) = parked {
+            if unsafe { IoSetCancelRoutine(parked.irp, None) }.is_some() {
+                cancelled.push(parked.irp);
+            }
+        }
+    }
+
+    for p_irp in cancelled {
+        unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+    }
+}
+
+/// What `wait` did with a request.
+pub(crate) enum Wait {
+    /// Events were queued, and are in the output buffer.
+    Completed,
+    /// The request was parked, or cancelled and completed. Either way, the
+    /// dispatch routine must return `STATUS_PENDING` and leave it alone.
+    Pending,
+}
+
+/// Hands `p_irp` up to `room` queued events, or parks it until some arrive.
+/// A session can only have one request waiting at a time.
+///
+/// # Safety
+/// `p_irp` must be an `EREBUS_IOCTL_GET_EVENTS` whose output buffer was checked
+/// to have room for `room` events, and which has not been completed.
+pub unsafe fn wait(subscriber: &Subscriber, p_irp: PIRP, room: usize) -> Result<Wait, NTSTATUS> {
+    let mut queue = subscriber.queue.lock();
+
+    if queue.parked.is_some() {
+        println!(
+            LogLevel::Error,
+            "Session already has a request waiting for events."
+        );
+        return Err(STATUS_DEVICE_BUSY);
+    }
+
+    // checked under the lock, so `cancel_all` cannot miss a request parked here
+    if queue.events.is_empty() && RUNDOWN.is_closing() {
+        return Err(STATUS_DELETE_PENDING);
+    }
+
+    if !queue.events.is_empty() {
+        let events = queue.take(room);
+        drop(queue);
+
+        let status = unsafe { write_events(p_irp, &events) };
+        return if nt_success(status) {
+            Ok(Wait::Completed)
+        } else {
+            Err(status)
+        };
+    }
+
+    unsafe {
+        IoMarkIrpPending(p_irp);
+        IoSetCancelRoutine(p_irp, Some(cancel));
+    }
+
+    // Cancelled before the routine was in place: if we get the routine back,
+    // nobody else will complete the request.
+    if unsafe { (*p_irp).Cancel } != 0 && unsafe { IoSetCancelRoutine(p_irp, None) }.is_some() {
+        drop(queue);
+        unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+        return Ok(Wait::Pending);
+    }
+
+    queue.parked = Some(Parked { irp: p_irp, room });
+    Ok(Wait::Pending)
+}
+
+/// Writes `events` to the output buffer of `p_irp`, behind a `Response` and an
+/// `EventsReply`.
+unsafe fn write_events(p_irp: PIRP, events: &[EventRecord]) -> NTSTATUS {
+    // the dispatch routine mapped the buffer already, so this reuses that mapping
+    let out_buf = unsafe {
+        MmGetSystemAddressForMdlSafe(
+            (*p_irp).MdlAddress,
+            NormalPagePriority as u32 | MdlMappingNoExecute,
+        )
+    } as *mut u8;
+    if out_buf.is_null() {
+        return STATUS_INSUFFICIENT_RESOURCES;
+    }
+
+    let reply = EventsReply {
+        event_count: events.len() as u32,
+        reserved: 0,
+    };
+    let data_len = (EVENTS_REPLY_SIZE + events.len() * EVENT_RECORD_SIZE) as u64;
+
+    let copy = |offset: usize, bytes: &[u8]| unsafe {
+        RtlCopyMemoryNonTemporal(
+            out_buf.add(offset).cast(),
+            bytes.as_ptr().cast_mut().cast(),
+            bytes.len() as u64,
+        )
+    };
+
+    copy(0, &Response::success(data_len).to_bytes());
+    copy(RESPONSE_SIZE, &reply.to_bytes());
+    for (index, event) in events.iter().enumerate() {
+        copy(EVENTS_OFFSET + index * EVENT_RECORD_SIZE, &event.to_bytes());
+    }
+
+    unsafe { (*p_irp).IoStatus.Information = RESPONSE_SIZE as u64 + data_len };
+    STATUS_SUCCESS
+}
+
+/// Completes a parked request with the events taken for it.
+unsafe fn deliver(p_irp: PIRP, events: &[EventRecord]) {
+    let status = unsafe { write_events(p_irp, events) };
+    unsafe { complete_request(p_irp, status) };
+}
+
+unsafe extern "C" fn cancel(_device: *mut DEVICE_OBJECT, p_irp: PIRP) {
+    unsafe { IoReleaseCancelSpinLock((*p_irp).CancelIrql) };
+
+    // Forget the request if it is still parked. If it is not, whoever took it
+    // failed to reclaim it, and forgot it already.
+    let file_object = unsafe { (*IoGetCurrentIrpStackLocation(p_irp)).FileObject };
+    if let Some(subscriber) =
+        unsafe { Session::from_file_object(file_object) }.and_then(Session::subscriber)
+    {
+        let mut queue = subscriber.queue.lock();
+        if queue
+            .parked
+            .as_ref()
+            .is_some_and(|parked| parked.irp == p_irp)
+        {
+            queue.parked = None;
+        }
+    }
+
+    println!(LogLevel::Info, "Cancelled a request waiting for events");
+    unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+}
+
+/// Queues `event` for every session that wants it, completing their parked
+/// requests.
+fn publish(event: EventRecord) {
+    if SUBSCRIBER_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+
+    let mut deliveries = Vec::new();
+    for subscriber in SUBSCRIBERS.lock().iter() {
+        let mut queue = subscriber.queue.lock();
+        if !queue.wants(&event) {
+            continue;
+        }
+
+        queue.push(event);
+        deliveries.extend(queue.unpark());
+    }
+
+    for (p_irp, events) in deliveries {
+        unsafe { deliver(p_irp, &events) };
+    }
+}
+
+fn now() -> u64 {
+    let mut timestamp: LARGE_INTEGER = unsafe { core::mem::zeroed() };
+    unsafe { KeQuerySystemTimePrecise(&mut timestamp) };
+    unsafe { timestamp.QuadPart as u64 }
+}
+
+unsafe extern "C" fn process_notify(parent_id: HANDLE, process_id: HANDLE, create: BOOLEAN) {
+    let kind = if create != 0 {
+        EventKind::ProcessCreate
+    } else {
+        EventKind::ProcessExit
+    };
+
+    let process_id = process_id as usize as u32;
+    let mut event = EventRecord::new(kind, now(), process_id, parent_id as usize as u32);
+
+    // the process object is alive for both notifications
+    if let Ok(process) = Process::by_id(process_id, ANY_CREATE_TIME) {
+        event.create_time = process.create_time() as u64;
+        event.set_name(process.image_file_name());
+    }
+
+    publish(event);
+}
+
+unsafe extern "C" fn image_notify(
+    full_image_name: PUNICODE_STRING,
+    process_id: HANDLE,
+    image_info: PIMAGE_INFO,
+) {
+    let mut event = EventRecord::new(EventKind::ImageLoad, now(), process_id as usize as u32, 0);
+
+    if !image_info.is_null() {
+        event.image_base = unsafe { (*image_info).ImageBase } as u64;
+        event.image_size = unsafe { (*image_info).ImageSize } as u64;
+    }
+
+    if let Some(name) = unsafe { full_image_name.as_ref() } {
+        if !name.Buffer.is_null() {
+            event.set_name_utf16(unsafe {
+                core::slice::from_raw_parts(name.Buffer, name.Length as usize / 2)
+            });
+        }
+    }
+
+    publish(event);
+}
+
+unsafe extern "C" fn thread_notify(process_id: HANDLE, thread_id: HANDLE, create: BOOLEAN) {
+    let kind = if create != 0 {
+        EventKind::ThreadCreate
+    } else {
+        EventKind::ThreadExit
+    };
+
+    publish(EventRecord::new(
+        kind,
+        now(),
+        process_id as usize as u32,
+        thread_id as usize as u32,
+    ));
+}
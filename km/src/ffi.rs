@@ -1,5 +1,15 @@
+use core::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 use wdk_sys::{
-    KPROCESSOR_MODE, NTSTATUS, PEPROCESS, PIO_STACK_LOCATION, PIRP, PSIZE_T, PVOID, SIZE_T,
+    ntddk::MmMapLockedPagesSpecifyCache, _MEMORY_CACHING_TYPE::MmCached, _MODE::KernelMode,
+    ACCESS_MASK, BOOLEAN, DEVICE_TYPE, GUID, HANDLE, KPROCESSOR_MODE, LOCK_OPERATION,
+    MDL_MAPPED_TO_SYSTEM_VA, MDL_SOURCE_IS_NONPAGED_POOL, NTSTATUS, PCUNICODE_STRING,
+    PDEVICE_OBJECT, PDRIVER_CANCEL, PDRIVER_OBJECT, PEPROCESS, PIO_STACK_LOCATION, PIRP, PMDL,
+    POBJECT_TYPE, PSIZE_T, PUNICODE_STRING, PVOID, SIZE_T, SL_PENDING_RETURNED, ULONG,
+    UNICODE_STRING,
 };
 
 #[allow(non_snake_case)]
@@ -13,6 +23,81 @@ pub unsafe fn IoGetCurrentIrpStackLocation(p_irp: PIRP) -> PIO_STACK_LOCATION {
         .CurrentStackLocation
 }
 
+/// `IoMarkIrpPending` is an inline function in wdm.h. A dispatch routine that
+/// calls it must return `STATUS_PENDING`.
+#[allow(non_snake_case)]
+pub unsafe fn IoMarkIrpPending(p_irp: PIRP) {
+    (*IoGetCurrentIrpStackLocation(p_irp)).Control |= SL_PENDING_RETURNED as u8;
+}
+
+/// `IoSetCancelRoutine` is an inline function in wdm.h. Returns the routine it
+/// replaced: whoever swaps a routine out of an IRP owns the IRP's completion,
+/// and the I/O manager swaps it out before calling it.
+#[allow(non_snake_case)]
+pub unsafe fn IoSetCancelRoutine(p_irp: PIRP, routine: PDRIVER_CANCEL) -> PDRIVER_CANCEL {
+    let slot = AtomicPtr::from_ptr((&raw mut (*p_irp).CancelRoutine).cast::<*mut c_void>());
+    let new = routine.map_or(null_mut(), |routine| routine as *mut c_void);
+
+    // Safety: `PDRIVER_CANCEL` is a nullable function pointer.
+    core::mem::transmute::<*mut c_void, PDRIVER_CANCEL>(slot.swap(new, Ordering::SeqCst))
+}
+
+/// `MmGetSystemAddressForMdlSafe` is a macro in wdm.h, so it has no export to bind to.
+/// Returns null if the pages could not be mapped.
+#[allow(non_snake_case)]
+pub unsafe fn MmGetSystemAddressForMdlSafe(mdl: PMDL, priority: ULONG) -> PVOID {
+    let flags = (*mdl).MdlFlags as u32;
+    if flags & (MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL) != 0 {
+        return (*mdl).MappedSystemVa;
+    }
+
+    MmMapLockedPagesSpecifyCache(
+        mdl,
+        KernelMode as KPROCESSOR_MODE,
+        MmCached,
+        null_mut(),
+        0,
+        priority,
+    )
+}
+
+/// `MEMORY_BASIC_INFORMATION` from winnt.h, returned for `MemoryBasicInformation`.
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct MEMORY_BASIC_INFORMATION {
+    pub BaseAddress: PVOID,
+    pub AllocationBase: PVOID,
+    pub AllocationProtect: ULONG,
+    pub PartitionId: u16,
+    pub RegionSize: SIZE_T,
+    pub State: ULONG,
+    pub Protect: ULONG,
+    pub Type: ULONG,
+}
+
+/// The leading fields of `SYSTEM_PROCESS_INFORMATION`, returned for
+/// `SystemProcessInformation`. Each entry is followed by the rest of the
+/// structure and its threads; `NextEntryOffset` skips over them.
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct SYSTEM_PROCESS_INFORMATION {
+    pub NextEntryOffset: ULONG,
+    pub NumberOfThreads: ULONG,
+    pub WorkingSetPrivateSize: i64,
+    pub HardFaultCount: ULONG,
+    pub NumberOfThreadsHighWatermark: ULONG,
+    pub CycleTime: u64,
+    pub CreateTime: i64,
+    pub UserTime: i64,
+    pub KernelTime: i64,
+    pub ImageName: UNICODE_STRING,
+    pub BasePriority: i32,
+    pub UniqueProcessId: HANDLE,
+    pub InheritedFromUniqueProcessId: HANDLE,
+    pub HandleCount: ULONG,
+    pub SessionId: ULONG,
+}
+
 #[allow(non_snake_case)]
 extern "C" {
     pub fn MmCopyVirtualMemory(
@@ -24,4 +109,87 @@ extern "C" {
         PreviousMode: KPROCESSOR_MODE,
         ReturnSize: PSIZE_T,
     ) -> NTSTATUS;
+
+    pub fn MmSecureVirtualMemory(Address: PVOID, Size: SIZE_T, ProbeMode: ULONG) -> HANDLE;
+
+    pub fn MmUnsecureVirtualMemory(SecureHandle: HANDLE);
+
+    pub fn PsGetProcessImageFileName(Process: PEPROCESS) -> *const u8;
+
+    pub fn PsGetProcessCreateTimeQuadPart(Process: PEPROCESS) -> i64;
+
+    pub fn PsGetProcessExitStatus(Process: PEPROCESS) -> NTSTATUS;
+
+    pub fn PsIsProtectedProcess(Process: PEPROCESS) -> BOOLEAN;
+
+    pub fn PsGetProcessPeb(Process: PEPROCESS) -> PVOID;
+
+    pub fn PsGetProcessWow64Process(Process: PEPROCESS) -> PVOID;
+
+    pub fn PsIsProtectedProcessLight(Process: PEPROCESS) -> BOOLEAN;
+
+    pub fn ObOpenObjectByPointer(
+        Object: PVOID,
+        HandleAttributes: ULONG,
+        PassedAccessState: PVOID,
+        DesiredAccess: ACCESS_MASK,
+        ObjectType: POBJECT_TYPE,
+        AccessMode: KPROCESSOR_MODE,
+        Handle: *mut HANDLE,
+    ) -> NTSTATUS;
+
+    pub fn ZwQueryVirtualMemory(
+        ProcessHandle: HANDLE,
+        BaseAddress: PVOID,
+        MemoryInformationClass: u32,
+        MemoryInformation: PVOID,
+        MemoryInformationLength: SIZE_T,
+        ReturnLength: PSIZE_T,
+    ) -> NTSTATUS;
+
+    pub fn ZwQuerySystemInformation(
+        SystemInformationClass: u32,
+        SystemInformation: PVOID,
+        SystemInformationLength: ULONG,
+        ReturnLength: *mut ULONG,
+    ) -> NTSTATUS;
+
+    pub fn ZwQueryInformationProcess(
+        ProcessHandle: HANDLE,
+        ProcessInformationClass: u32,
+        ProcessInformation: PVOID,
+        ProcessInformationLength: ULONG,
+        ReturnLength: *mut ULONG,
+    ) -> NTSTATUS;
+
+    // wdmsec.h maps `IoCreateDeviceSecure` onto the static library's export.
+    #[link_name = "WdmlibIoCreateDeviceSecure"]
+    pub fn IoCreateDeviceSecure(
+        DriverObject: PDRIVER_OBJECT,
+        DeviceExtensionSize: ULONG,
+        DeviceName: PUNICODE_STRING,
+        DeviceType: DEVICE_TYPE,
+        DeviceCharacteristics: ULONG,
+        Exclusive: BOOLEAN,
+        DefaultSDDLString: PCUNICODE_STRING,
+        DeviceClassGuid: *const GUID,
+        DeviceObject: *mut PDEVICE_OBJECT,
+    ) -> NTSTATUS;
+
+    // From seh.c: `MmProbeAndLockPages`, with the exception it raises returned as
+    // the status instead.
+    pub fn ErebusProbeAndLockPages(
+        Mdl: PMDL,
+        AccessMode: KPROCESSOR_MODE,
+        Operation: LOCK_OPERATION,
+    ) -> NTSTATUS;
+}
+
+// Data exports need the import library, so the statics are linked as dllimport.
+#[allow(non_upper_case_globals)]
+#[link(name = "ntoskrnl")]
+extern "C" {
+    pub static PsProcessType: *mut POBJECT_TYPE;
+
+    pub static PsThreadType: *mut POBJECT_TYPE;
 }
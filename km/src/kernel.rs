@@ -0,0 +1,102 @@
+//! The real kernel behind `erebus_core`'s handlers.
+
+use crate::{
+    memory::{ke_compare_exchange, ke_read_virtual_memory, ke_write_virtual_memory},
+    process::{Process, PROCESS_VM_READ, PROCESS_VM_WRITE},
+};
+use core::ffi::c_void;
+use erebus_core::{KernelOps, KernelProcess};
+use shared::protocol::Target;
+use wdk_sys::NTSTATUS;
+
+pub(crate) struct Kernel;
+
+impl KernelProcess for Process {
+    fn id(&self) -> u32 {
+        Process::id(self)
+    }
+
+    fn create_time(&self) -> u64 {
+        Process::create_time(self) as u64
+    }
+
+    fn has_exited(&self) -> bool {
+        Process::has_exited(self)
+    }
+
+    fn image_file_name(&self) -> &[u8] {
+        Process::image_file_name(self)
+    }
+
+    fn critical_reason(&self) -> Option<&'static str> {
+        Process::critical_reason(self)
+    }
+}
+
+impl KernelOps for Kernel {
+    type Process = Process;
+
+    fn open_process(
+        &self,
+        target: Target,
+        write: bool,
+        create_time: u64,
+    ) -> Result<Process, NTSTATUS> {
+        let access = if write {
+            PROCESS_VM_WRITE
+        } else {
+            PROCESS_VM_READ
+        };
+        Process::open(target, access, create_time)
+    }
+
+    fn current_process(&self) -> Process {
+        Process::current()
+    }
+
+    fn read_memory(&self, process: &Process, address: u64, buffer: &mut [u8]) -> (NTSTATUS, u64) {
+        let mut bytes_read = 0;
+        // Safety: `buffer` is system memory, the mapped output buffer of the request.
+        // Faults on the target side come back as a status.
+        let status = unsafe {
+            ke_read_virtual_memory(
+                process.process,
+                address as *mut c_void,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len() as u64,
+                &mut bytes_read,
+            )
+        };
+
+        (status, bytes_read)
+    }
+
+    fn write_memory(&self, process: &Process, address: u64, data: &[u8]) -> (NTSTATUS, u64) {
+        let mut bytes_written = 0;
+        // Safety: as for `read_memory`, the other way around.
+        let status = unsafe {
+            ke_write_virtual_memory(
+                process.process,
+                data.as_ptr() as *mut c_void,
+                address as *mut c_void,
+                data.len() as u64,
+                &mut bytes_written,
+            )
+        };
+
+        (status, bytes_written)
+    }
+
+    fn compare_exchange(
+        &self,
+        process: &Process,
+        address: u64,
+        size: u32,
+        expected: u64,
+        new: u64,
+    ) -> Result<u64, NTSTATUS> {
+        // Safety: the handler checked the value is aligned and inside user space;
+        // whether it is mapped is checked before the page is touched.
+        unsafe { ke_compare_exchange(process.process, address, size, expected, new) }
+    }
+}
@@ -3,16 +3,35 @@
 extern crate alloc;
 extern crate wdk_panic;
 
+mod audit;
 mod device;
+mod events;
 mod ffi;
+mod kernel;
 mod logger;
 mod memory;
+mod modules;
+mod policy;
 mod process;
+mod processes;
+mod regions;
+mod registry;
+mod session;
+mod sync;
 mod utils;
+mod worker;
 
 use crate::{
-    device::{ioctl_handler_read, ioctl_handler_write},
-    ffi::IoGetCurrentIrpStackLocation,
+    device::{
+        ioctl_handler_attach, ioctl_handler_compare_exchange, ioctl_handler_drain_audit,
+        ioctl_handler_get_events, ioctl_handler_hello, ioctl_handler_list_processes,
+        ioctl_handler_query_modules, ioctl_handler_query_regions, ioctl_handler_read,
+        ioctl_handler_read_batch, ioctl_handler_read_chain, ioctl_handler_read_lossy,
+        ioctl_handler_reload_policy, ioctl_handler_write, ioctl_handler_write_batch,
+    },
+    ffi::{IoCreateDeviceSecure, IoGetCurrentIrpStackLocation},
+    session::Session,
+    sync::Rundown,
     utils::{ToU16Vec, ToUnicodeString},
 };
 
@@ -25,25 +44,43 @@ use {
 use core::ptr::null_mut;
 use shared::{
     constants::{DOS_DEVICE_NAME, NT_DEVICE_NAME},
-    ioctl::{EREBUS_IOCTL_READ, EREBUS_IOCTL_WRITE},
+    ioctl::{self, IoctlSpec},
 };
 
 use wdk::nt_success;
 use wdk_alloc::WdkAllocator;
 use wdk_sys::{
-    ntddk::{
-        IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink,
-        IofCompleteRequest,
-    },
+    ntddk::{IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink, IofCompleteRequest},
     DEVICE_OBJECT, DO_BUFFERED_IO, DRIVER_OBJECT, FILE_DEVICE_SECURE_OPEN, FILE_DEVICE_UNKNOWN,
-    IO_NO_INCREMENT, IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, NTSTATUS,
-    PCUNICODE_STRING, PDEVICE_OBJECT, PDRIVER_OBJECT, PIRP, STATUS_INVALID_DEVICE_REQUEST,
+    GUID, IO_NO_INCREMENT, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL,
+    NTSTATUS, NT_ERROR, PCUNICODE_STRING, PDEVICE_OBJECT, PDRIVER_OBJECT, PIRP,
+    STATUS_BUFFER_TOO_SMALL, STATUS_DELETE_PENDING, STATUS_INVALID_DEVICE_REQUEST, STATUS_PENDING,
     STATUS_SUCCESS, STATUS_UNSUCCESSFUL, _IO_STACK_LOCATION,
 };
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
 
+/// Default security descriptor of the device: only SYSTEM and Administrators may
+/// open it (`SDDL_DEVOBJ_SYS_ALL_ADM_ALL`). The policy's `DeviceSddl` replaces it.
+const DEVICE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+/// Device setup class of the Erebus device. An administrator can replace
+/// `DEVICE_SDDL` by setting a `Security` value under this class's `Properties` key,
+/// which the I/O manager applies in place of the default.
+// {6C3E2F5A-1B7D-4E90-A2C4-5F8D9E0B1A37}
+const DEVICE_CLASS_GUID: GUID = GUID {
+    Data1: 0x6c3e_2f5a,
+    Data2: 0x1b7d,
+    Data3: 0x4e90,
+    Data4: [0xa2, 0xc4, 0x5f, 0x8d, 0x9e, 0x0b, 0x1a, 0x37],
+};
+
+/// Held by every request from the moment its dispatch routine takes it until it
+/// is completed, parked and queued ones included, so `driver_exit` can wait for
+/// them all before the device goes away.
+static RUNDOWN: Rundown = Rundown::new();
+
 #[export_name = "DriverEntry"]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "system" fn driver_entry(
@@ -58,10 +95,13 @@ pub unsafe extern "system" fn driver_entry(
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn configure_driver(
     driver: *mut DRIVER_OBJECT,
-    _registry_path: PCUNICODE_STRING,
+    registry_path: PCUNICODE_STRING,
 ) -> NTSTATUS {
     println!("Configuring driver...");
 
+    let policy = policy::init(registry_path);
+    audit::init();
+
     let mut dos_name = DOS_DEVICE_NAME
         .to_unicode_string()
         .expect("unable to encode string to unicode.");
@@ -70,22 +110,34 @@ pub unsafe extern "C" fn configure_driver(
         .to_unicode_string()
         .expect("unable to encode string to unicode.");
 
+    // keep the UTF-16 buffer alive for as long as the `UNICODE_STRING` points into it
+    let sddl_buf = policy
+        .device_sddl
+        .as_deref()
+        .unwrap_or(DEVICE_SDDL)
+        .to_u16_vec();
+    let sddl = sddl_buf
+        .to_unicode_string()
+        .expect("unable to encode string to unicode.");
+
     let mut device_object: PDEVICE_OBJECT = null_mut();
 
-    let status = IoCreateDevice(
+    let status = IoCreateDeviceSecure(
         driver,
         0,
         &mut nt_name,
         FILE_DEVICE_UNKNOWN,
         FILE_DEVICE_SECURE_OPEN,
         0,
+        &sddl,
+        &DEVICE_CLASS_GUID,
         &mut device_object,
     );
 
     if !nt_success(status) {
         println!(
             LogLevel::Error,
-            "Unable to create device via IoCreateDevice. Failed with code: {:x}.", status
+            "Unable to create device via IoCreateDeviceSecure. Failed with code: {:x}.", status
         );
         return status;
     }
@@ -101,8 +153,26 @@ pub unsafe extern "C" fn configure_driver(
         return status;
     }
 
-    (*driver).MajorFunction[IRP_MJ_CREATE as usize] = Some(create_close);
-    (*driver).MajorFunction[IRP_MJ_CLOSE as usize] = Some(create_close);
+    let status = worker::start();
+    if !nt_success(status) {
+        driver_exit(driver);
+        return status;
+    }
+
+    let status = events::register();
+    if !nt_success(status) {
+        println!(
+            LogLevel::Error,
+            "Failed to register notify routines. Error: {:#x}", status
+        );
+
+        driver_exit(driver);
+        return status;
+    }
+
+    (*driver).MajorFunction[IRP_MJ_CREATE as usize] = Some(create);
+    (*driver).MajorFunction[IRP_MJ_CLEANUP as usize] = Some(cleanup);
+    (*driver).MajorFunction[IRP_MJ_CLOSE as usize] = Some(close);
     (*driver).MajorFunction[IRP_MJ_DEVICE_CONTROL as usize] = Some(handle_ioctl);
 
     (*driver).DriverUnload = Some(driver_exit);
@@ -114,6 +184,9 @@ pub unsafe extern "C" fn configure_driver(
     STATUS_SUCCESS
 }
 
+/// Unloads in the reverse order of `configure_driver`: no new handles or
+/// requests, no new events, then every outstanding request is cancelled or
+/// finished before the device is deleted.
 extern "C" fn driver_exit(driver: *mut DRIVER_OBJECT) {
     let mut device_name = DOS_DEVICE_NAME
         .to_u16_vec()
@@ -121,6 +194,15 @@ extern "C" fn driver_exit(driver: *mut DRIVER_OBJECT) {
         .expect("unable to encode string to unicode.");
     let _ = unsafe { IoDeleteSymbolicLink(&mut device_name) };
 
+    RUNDOWN.close();
+
+    events::unregister();
+    events::cancel_all();
+    worker::stop();
+
+    RUNDOWN.wait();
+    println!("All outstanding requests completed");
+
     unsafe {
         IoDeleteDevice((*driver).DeviceObject);
     }
@@ -128,61 +210,238 @@ extern "C" fn driver_exit(driver: *mut DRIVER_OBJECT) {
     println!("Driver exiting!");
 }
 
-#[allow(clippy::cast_possible_truncation)]
-unsafe extern "C" fn create_close(_device: *mut DEVICE_OBJECT, p_irp: PIRP) -> NTSTATUS {
-    (*p_irp).IoStatus.__bindgen_anon_1.Status = STATUS_SUCCESS;
+/// Every open handle gets its own session, see `session`.
+unsafe extern "C" fn create(_device: *mut DEVICE_OBJECT, p_irp: PIRP) -> NTSTATUS {
+    if !RUNDOWN.acquire() {
+        return reject(p_irp);
+    }
+
+    let p_stack_location = IoGetCurrentIrpStackLocation(p_irp);
+    let status = Session::create((*p_stack_location).FileObject);
+
+    println!(LogLevel::Info, "Handle opened: {:#x}", status);
+
+    (*p_irp).IoStatus.Information = 0;
+    complete_request(p_irp, status)
+}
+
+/// The last handle to the file object is gone: let go of the attached process now,
+/// rather than whenever the I/O manager gets around to closing the file object,
+/// stop queueing events for it, and cancel what it left running.
+///
+/// Unlike other requests, this runs even while unloading: the handle is gone
+/// either way, and its session has to let go.
+unsafe extern "C" fn cleanup(_device: *mut DEVICE_OBJECT, p_irp: PIRP) -> NTSTATUS {
+    let guarded = RUNDOWN.acquire();
+
+    let p_stack_location = IoGetCurrentIrpStackLocation(p_irp);
+    worker::cancel_file((*p_stack_location).FileObject);
+
+    if let Some(session) = Session::from_file_object((*p_stack_location).FileObject) {
+        session.detach();
+        events::unsubscribe(session);
+    }
+
     (*p_irp).IoStatus.Information = 0;
+    finish_request(p_irp, STATUS_SUCCESS, guarded)
+}
 
-    IofCompleteRequest(p_irp, IO_NO_INCREMENT as i8);
+/// No IRP references the file object anymore, so its session can go. Runs even
+/// while unloading, like `cleanup`.
+unsafe extern "C" fn close(_device: *mut DEVICE_OBJECT, p_irp: PIRP) -> NTSTATUS {
+    let guarded = RUNDOWN.acquire();
 
-    println!("IRP received...");
+    let p_stack_location = IoGetCurrentIrpStackLocation(p_irp);
+    Session::destroy((*p_stack_location).FileObject);
 
-    STATUS_SUCCESS
+    println!("Handle closed");
+
+    (*p_irp).IoStatus.Information = 0;
+    finish_request(p_irp, STATUS_SUCCESS, guarded)
 }
 
 macro_rules! handle_ioctl_fn {
     ($fn_name:ident, $p_stack_location:expr, $p_irp:expr) => {{
-        if let Err(err) = $fn_name($p_stack_location, $p_irp) {
-            println!(LogLevel::Error, "Error: {:#x}", err);
-            err
-        } else {
-            STATUS_SUCCESS
+        match $fn_name($p_stack_location, $p_irp) {
+            Ok(()) => STATUS_SUCCESS,
+            // a parked request is completed later, by whoever takes it off the queue
+            Err(STATUS_PENDING) => STATUS_PENDING,
+            Err(err) => {
+                println!(LogLevel::Error, "Error: {:#x}", err);
+                err
+            }
         }
     }};
+    // memory operations: the handler fills in an audit entry, recorded with the final status
+    ($fn_name:ident, $p_stack_location:expr, $p_irp:expr, $opcode:expr) => {{
+        let mut entry = audit::entry($opcode);
+        let status = match $fn_name($p_stack_location, $p_irp, &mut entry) {
+            Ok(()) => STATUS_SUCCESS,
+            Err(err) => {
+                println!(LogLevel::Error, "Error: {:#x}", err);
+                err
+            }
+        };
+        audit::record(entry, status);
+        status
+    }};
+}
+
+/// The driver's side of an IOCTL declared in `shared::ioctl`. The dispatcher is
+/// generated from that table, so an IOCTL without a handler does not build.
+trait Handler: IoctlSpec {
+    /// Runs the handler, and returns the status to complete the request with.
+    fn handle(p_stack_location: *mut _IO_STACK_LOCATION, pirp: PIRP) -> NTSTATUS;
+}
+
+macro_rules! handlers {
+    (
+        $($ioctl:ident => $handler:ident,)*
+        audited: $($audited:ident => $audited_handler:ident,)*
+    ) => {
+        $(
+            impl Handler for ioctl::$ioctl {
+                fn handle(p_stack_location: *mut _IO_STACK_LOCATION, pirp: PIRP) -> NTSTATUS {
+                    handle_ioctl_fn!($handler, p_stack_location, pirp)
+                }
+            }
+        )*
+        $(
+            impl Handler for ioctl::$audited {
+                fn handle(p_stack_location: *mut _IO_STACK_LOCATION, pirp: PIRP) -> NTSTATUS {
+                    handle_ioctl_fn!($audited_handler, p_stack_location, pirp, Self::OPCODE)
+                }
+            }
+        )*
+    };
+}
+
+handlers! {
+    Hello => ioctl_handler_hello,
+    ReloadPolicy => ioctl_handler_reload_policy,
+    Attach => ioctl_handler_attach,
+    DrainAudit => ioctl_handler_drain_audit,
+    ListProcesses => ioctl_handler_list_processes,
+    GetEvents => ioctl_handler_get_events,
+    audited:
+    Read => ioctl_handler_read,
+    Write => ioctl_handler_write,
+    ReadBatch => ioctl_handler_read_batch,
+    WriteBatch => ioctl_handler_write_batch,
+    ReadLossy => ioctl_handler_read_lossy,
+    QueryRegions => ioctl_handler_query_regions,
+    QueryModules => ioctl_handler_query_modules,
+    ReadChain => ioctl_handler_read_chain,
+    CompareExchange => ioctl_handler_compare_exchange,
 }
 
-#[allow(clippy::cast_possible_truncation)]
 unsafe extern "C" fn handle_ioctl(_device: *mut DEVICE_OBJECT, pirp: PIRP) -> NTSTATUS {
+    if !RUNDOWN.acquire() {
+        return reject(pirp);
+    }
+
     let p_stack_location: *mut _IO_STACK_LOCATION = IoGetCurrentIrpStackLocation(pirp);
 
     if p_stack_location.is_null() {
         println!("Unable to get stack location for IRP.");
-        return STATUS_UNSUCCESSFUL;
+        return complete_request(pirp, STATUS_UNSUCCESSFUL);
     }
 
-    let control_code = (*p_stack_location).Parameters.DeviceIoControl.IoControlCode;
-    println!(
-        LogLevel::Info,
-        "Received an IOCTL code: {:#x}", control_code
-    );
+    // long-running requests are completed by a worker, see `worker`
+    if worker::is_long_running(p_stack_location) {
+        return worker::defer(pirp);
+    }
 
-    let status: NTSTATUS = match control_code {
-        EREBUS_IOCTL_READ => {
-            handle_ioctl_fn!(ioctl_handler_read, p_stack_location, pirp)
-        }
-        EREBUS_IOCTL_WRITE => {
-            handle_ioctl_fn!(ioctl_handler_write, p_stack_location, pirp)
-        }
-        _ => {
+    match dispatch_ioctl(p_stack_location, pirp) {
+        STATUS_PENDING => STATUS_PENDING,
+        status => complete_request(pirp, status),
+    }
+}
+
+macro_rules! dispatcher {
+    ($($ioctl:ident),*) => {
+        /// Runs the handler of an IOCTL, and returns the status to complete it with.
+        /// `STATUS_PENDING` means the handler kept the request, to complete it later.
+        unsafe fn dispatch_ioctl(
+            p_stack_location: *mut _IO_STACK_LOCATION,
+            pirp: PIRP,
+        ) -> NTSTATUS {
+            let control_code = (*p_stack_location).Parameters.DeviceIoControl.IoControlCode;
             println!(
-                LogLevel::Error,
-                "Unhandled IOCTL control code: {:#x}", control_code
+                LogLevel::Info,
+                "Received an IOCTL code: {:#x}", control_code
             );
-            STATUS_INVALID_DEVICE_REQUEST
+
+            match control_code {
+                $(
+                    <ioctl::$ioctl as IoctlSpec>::CODE => {
+                        dispatch::<ioctl::$ioctl>(p_stack_location, pirp)
+                    }
+                )*
+                _ => {
+                    println!(
+                        LogLevel::Error,
+                        "Unhandled IOCTL control code: {:#x}", control_code
+                    );
+                    STATUS_INVALID_DEVICE_REQUEST
+                }
+            }
         }
     };
+}
+
+shared::for_each_ioctl!(dispatcher);
+
+/// Refuses buffers smaller than `I` declares, before its handler looks at them.
+unsafe fn dispatch<I: Handler>(p_stack_location: *mut _IO_STACK_LOCATION, pirp: PIRP) -> NTSTATUS {
+    let parameters = &(*p_stack_location).Parameters.DeviceIoControl;
+    let input_len = parameters.InputBufferLength as usize;
+    let output_len = parameters.OutputBufferLength as usize;
+
+    if input_len < I::MIN_INPUT || output_len < I::MIN_OUTPUT {
+        println!(
+            LogLevel::Error,
+            "Buffers too small for {}: {} in, {} out",
+            I::NAME,
+            input_len,
+            output_len
+        );
+        return STATUS_BUFFER_TOO_SMALL;
+    }
+
+    I::handle(p_stack_location, pirp)
+}
+
+/// Completes `pirp` with `status`, and releases the `RUNDOWN` reference its
+/// dispatch routine took. Error statuses never return output to user-land, so
+/// any partially written response is discarded along with them.
+unsafe fn complete_request(pirp: PIRP, status: NTSTATUS) -> NTSTATUS {
+    finish_request(pirp, status, true)
+}
+
+/// Completes a request that arrived after the driver started unloading.
+unsafe fn reject(pirp: PIRP) -> NTSTATUS {
+    println!(
+        LogLevel::Warning,
+        "Rejected a request: the driver is unloading"
+    );
+    finish_request(pirp, STATUS_DELETE_PENDING, false)
+}
+
+/// Completes `pirp` with `status`, releasing a `RUNDOWN` reference if `guarded`.
+/// The reference goes last: once released, unload may delete the device.
+#[allow(clippy::cast_possible_truncation)]
+unsafe fn finish_request(pirp: PIRP, status: NTSTATUS, guarded: bool) -> NTSTATUS {
+    (*pirp).IoStatus.__bindgen_anon_1.Status = status;
+    if NT_ERROR(status) {
+        (*pirp).IoStatus.Information = 0;
+    }
 
     IofCompleteRequest(pirp, IO_NO_INCREMENT as i8);
 
+    if guarded {
+        RUNDOWN.release();
+    }
+
     status
 }
@@ -1,6 +1,12 @@
+use core::sync::atomic::{AtomicU8, Ordering};
 use wdk::println as wprintln;
 
+/// Least severe level that still gets logged, see `set_min_level`.
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// Ordered from least to most severe.
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum LogLevel {
     Info,
     Success,
@@ -22,8 +28,18 @@ impl LogLevel {
 pub struct Logger {}
 
 impl Logger {
+    /// Drops every message less severe than `level` (0 logs everything,
+    /// `LogLevel::Error as u8` only errors).
+    pub(crate) fn set_min_level(level: u8) {
+        MIN_LEVEL.store(level, Ordering::Relaxed);
+    }
+
     #[allow(clippy::needless_pass_by_value)]
     pub(crate) fn log(msg: &str, level: LogLevel) {
+        if (level as u8) < MIN_LEVEL.load(Ordering::Relaxed) {
+            return;
+        }
+
         wprintln!("[erebus] {} -> {}", level.as_str(), msg);
     }
 }
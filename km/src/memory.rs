@@ -1,11 +1,32 @@
-use crate::ffi::MmCopyVirtualMemory;
+use crate::ffi::{
+    ErebusProbeAndLockPages, MmCopyVirtualMemory, MmGetSystemAddressForMdlSafe,
+    MmSecureVirtualMemory, MmUnsecureVirtualMemory,
+};
 
-use core::ffi::c_void;
+use core::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering},
+};
 use wdk::nt_success;
 use wdk_sys::{
-    ntddk::IoGetCurrentProcess, NTSTATUS, PEPROCESS, STATUS_ACCESS_DENIED, STATUS_SUCCESS,
+    ntddk::{
+        IoAllocateMdl, IoFreeMdl, IoGetCurrentProcess, KeStackAttachProcess,
+        KeUnstackDetachProcess, MmUnlockPages,
+    },
+    MdlMappingNoExecute,
+    _LOCK_OPERATION::IoWriteAccess,
+    _MM_PAGE_PRIORITY::NormalPagePriority,
+    _MODE::UserMode,
+    KAPC_STATE, KPROCESSOR_MODE, NTSTATUS, PEPROCESS, STATUS_ACCESS_VIOLATION,
+    STATUS_INSUFFICIENT_RESOURCES,
 };
 
+// `PAGE_READWRITE` from winnt.h, as a probe mode for `MmSecureVirtualMemory`
+const PAGE_READWRITE: u32 = 0x04;
+
+/// Copies `size_t` bytes out of `process`. On failure the status is passed on
+/// as is, and `bytes_read` still says how far the copy got.
 pub unsafe fn ke_read_virtual_memory(
     process: PEPROCESS,
     source_address: *mut c_void,
@@ -13,7 +34,7 @@ pub unsafe fn ke_read_virtual_memory(
     size_t: u64,
     bytes_read: &mut u64,
 ) -> NTSTATUS {
-    if nt_success(MmCopyVirtualMemory(
+    MmCopyVirtualMemory(
         process,
         source_address,
         IoGetCurrentProcess(),
@@ -21,11 +42,7 @@ pub unsafe fn ke_read_virtual_memory(
         size_t,
         0,
         bytes_read,
-    )) {
-        STATUS_SUCCESS
-    } else {
-        STATUS_ACCESS_DENIED
-    }
+    )
 }
 
 pub unsafe fn ke_write_virtual_memory(
@@ -35,7 +52,7 @@ pub unsafe fn ke_write_virtual_memory(
     size_t: u64,
     bytes_read: &mut u64,
 ) -> NTSTATUS {
-    if nt_success(MmCopyVirtualMemory(
+    MmCopyVirtualMemory(
         IoGetCurrentProcess(),
         source_address,
         process,
@@ -43,23 +60,101 @@ pub unsafe fn ke_write_virtual_memory(
         size_t,
         0,
         bytes_read,
-    )) {
-        STATUS_SUCCESS
-    } else {
-        STATUS_ACCESS_DENIED
-    }
+    )
+}
+
+/// Replaces the `size`-byte value at `address` in `process` with `new` if it
+/// equals `expected`, and returns the value it held. `size` is 1, 2, 4, or 8
+/// and `address` is aligned to it, so the value sits on a single page.
+///
+/// The page is locked and mapped into system space, where a locked `cmpxchg`
+/// on it is as atomic as one of the target's own interlocked operations.
+pub unsafe fn ke_compare_exchange(
+    process: PEPROCESS,
+    address: u64,
+    size: u32,
+    expected: u64,
+    new: u64,
+) -> Result<u64, NTSTATUS> {
+    let mut apc_state: KAPC_STATE = core::mem::zeroed();
+    KeStackAttachProcess(process.cast(), &mut apc_state);
+
+    let result = compare_exchange_attached(address as *mut c_void, size, expected, new);
+
+    KeUnstackDetachProcess(&mut apc_state);
+    result
 }
 
-pub fn is_valid_user_memory(address: usize, size: usize) -> bool {
-    // Define user-mode memory bounds
-    const USER_MODE_ADDRESS_LOWER_BOUND: usize = 0x00000000_00010000;
-    const USER_MODE_ADDRESS_UPPER_BOUND: usize = 0x000007FF_FFFFFFFF;
+/// `ke_compare_exchange`, attached to the target.
+unsafe fn compare_exchange_attached(
+    address: *mut c_void,
+    size: u32,
+    expected: u64,
+    new: u64,
+) -> Result<u64, NTSTATUS> {
+    // Securing the range fails unless it is committed and writable, and keeps the
+    // target from freeing it or taking write access away until it is unsecured.
+    // Locking the page can still fail, on an in-page error or with the working
+    // set or locked page quota used up. The probe then raises, so it runs in C,
+    // which catches the exception and hands back its code.
+    let secured = MmSecureVirtualMemory(address, size as u64, PAGE_READWRITE);
+    if secured.is_null() {
+        return Err(STATUS_ACCESS_VIOLATION);
+    }
+
+    let mdl = IoAllocateMdl(address, size, 0, 0, null_mut());
+    if mdl.is_null() {
+        MmUnsecureVirtualMemory(secured);
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
 
-    // Ensure address + size doesn't overflow
-    if address.checked_add(size).is_none() {
-        return false;
+    // Probing for write access breaks copy-on-write, as a write by the target
+    // itself would, so the mapping below is of the target's private page.
+    let status = ErebusProbeAndLockPages(mdl, UserMode as KPROCESSOR_MODE, IoWriteAccess);
+    if !nt_success(status) {
+        IoFreeMdl(mdl);
+        MmUnsecureVirtualMemory(secured);
+        return Err(status);
     }
 
-    // Check if the address lies within valid user-mode memory
-    address >= USER_MODE_ADDRESS_LOWER_BOUND && (address + size) <= USER_MODE_ADDRESS_UPPER_BOUND
+    let mapped = MmGetSystemAddressForMdlSafe(mdl, NormalPagePriority as u32 | MdlMappingNoExecute);
+    let result = if mapped.is_null() {
+        Err(STATUS_INSUFFICIENT_RESOURCES)
+    } else {
+        Ok(interlocked_compare_exchange(mapped, size, expected, new))
+    };
+
+    MmUnlockPages(mdl);
+    IoFreeMdl(mdl);
+    MmUnsecureVirtualMemory(secured);
+    result
+}
+
+/// A locked `cmpxchg` of the width `size` at `value`, which must be aligned to
+/// it. The operands were checked to fit.
+unsafe fn interlocked_compare_exchange(
+    value: *mut c_void,
+    size: u32,
+    expected: u64,
+    new: u64,
+) -> u64 {
+    macro_rules! exchange {
+        ($atomic:ty, $int:ty) => {{
+            let (Ok(observed) | Err(observed)) = <$atomic>::from_ptr(value.cast())
+                .compare_exchange(
+                    expected as $int,
+                    new as $int,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+            u64::from(observed)
+        }};
+    }
+
+    match size {
+        1 => exchange!(AtomicU8, u8),
+        2 => exchange!(AtomicU16, u16),
+        4 => exchange!(AtomicU32, u32),
+        _ => exchange!(AtomicU64, u64),
+    }
 }
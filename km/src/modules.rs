@@ -0,0 +1,61 @@
+#[allow(unused_imports)]
+use alloc::format;
+
+use crate::{
+    ffi::{PsGetProcessPeb, PsGetProcessWow64Process},
+    logger::LogLevel,
+    memory::ke_read_virtual_memory,
+    println,
+    process::Process,
+};
+
+use alloc::vec::Vec;
+use shared::{
+    ipc::ModuleEntry,
+    modules::{walk_loader, LoaderLayout, NATIVE_LAYOUT, WOW64_LAYOUT},
+};
+use wdk::nt_success;
+use wdk_sys::{NTSTATUS, STATUS_UNSUCCESSFUL};
+
+/// Modules loaded into `process`: those of the native loader, followed by those
+/// of the 32-bit loader if it runs under WOW64.
+pub fn loaded_modules(process: &Process) -> Result<Vec<ModuleEntry>, NTSTATUS> {
+    let mut modules = Vec::new();
+
+    let peb = unsafe { PsGetProcessPeb(process.process) } as u64;
+    if peb != 0 {
+        modules.extend(walk(process, peb, &NATIVE_LAYOUT)?);
+    }
+
+    let peb32 = unsafe { PsGetProcessWow64Process(process.process) } as u64;
+    if peb32 != 0 {
+        modules.extend(walk(process, peb32, &WOW64_LAYOUT)?);
+    }
+
+    Ok(modules)
+}
+
+fn walk(process: &Process, peb: u64, layout: &LoaderLayout) -> Result<Vec<ModuleEntry>, NTSTATUS> {
+    // `walk_loader` only hands out ranges within user space
+    let read = |address: u64, buf: &mut [u8]| {
+        let mut bytes_read = 0;
+        let status = unsafe {
+            ke_read_virtual_memory(
+                process.process,
+                address as _,
+                buf.as_mut_ptr() as _,
+                buf.len() as u64,
+                &mut bytes_read,
+            )
+        };
+        nt_success(status) && bytes_read == buf.len() as u64
+    };
+
+    walk_loader(peb, layout, read).map_err(|err| {
+        println!(
+            LogLevel::Error,
+            "Could not walk the loader list at {:#x}: {}", peb, err
+        );
+        STATUS_UNSUCCESSFUL
+    })
+}
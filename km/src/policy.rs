@@ -0,0 +1,115 @@
+#[allow(unused_imports)]
+use alloc::format;
+
+use crate::{
+    logger::{LogLevel, Logger},
+    println,
+    process::Process,
+    registry::RegistryKey,
+    sync::SpinLock,
+};
+use alloc::{sync::Arc, vec::Vec};
+use shared::{
+    policy::{Policy, PolicyError, RegValue, VALUE_NAMES},
+    protocol::Target,
+};
+use wdk_sys::{NTSTATUS, PCUNICODE_STRING, STATUS_INVALID_PARAMETER, STATUS_OBJECT_NAME_NOT_FOUND};
+
+/// Policy in force. A request takes one reference up front and keeps using it,
+/// so a reload never changes the rules halfway through a request.
+static POLICY: SpinLock<Option<Arc<Policy>>> = SpinLock::new(None);
+
+/// Service key path `DriverEntry` was called with, kept for reloads.
+static SERVICE_KEY: SpinLock<Vec<u16>> = SpinLock::new(Vec::new());
+
+/// Loads the policy at driver load. Without a `Parameters` key the defaults
+/// apply. A policy that is present but cannot be read or is invalid is logged
+/// and replaced by `Policy::deny_all` until a reload installs a valid one.
+pub fn init(registry_path: PCUNICODE_STRING) -> Arc<Policy> {
+    // Safety: the I/O manager hands `DriverEntry` a valid service key path.
+    let service = unsafe {
+        core::slice::from_raw_parts(
+            (*registry_path).Buffer,
+            usize::from((*registry_path).Length) / 2,
+        )
+    }
+    .to_vec();
+
+    let policy = read(&service).unwrap_or_else(|status| {
+        println!(
+            LogLevel::Error,
+            "Could not load the policy ({:#x}), denying every request", status
+        );
+        Policy::deny_all()
+    });
+
+    *SERVICE_KEY.lock() = service;
+    install(policy)
+}
+
+/// Re-reads the policy. If the registry holds an invalid policy, the one in
+/// force stays.
+pub fn reload() -> Result<(), NTSTATUS> {
+    let service = SERVICE_KEY.lock().clone();
+    install(read(&service)?);
+
+    Ok(())
+}
+
+/// The policy in force, or `Policy::deny_all` before `init` installed one.
+pub fn current() -> Arc<Policy> {
+    let policy = POLICY.lock().clone();
+    policy.unwrap_or_else(|| Arc::new(Policy::deny_all()))
+}
+
+fn install(policy: Policy) -> Arc<Policy> {
+    Logger::set_min_level(policy.log_level as u8);
+    println!(LogLevel::Info, "Policy in force: {:?}", policy);
+
+    let policy = Arc::new(policy);
+    // the old policy is released outside the lock
+    let _old = POLICY.lock().replace(policy.clone());
+
+    policy
+}
+
+fn read(service: &[u16]) -> Result<Policy, NTSTATUS> {
+    let key = match RegistryKey::open_parameters(service) {
+        Ok(key) => key,
+        Err(STATUS_OBJECT_NAME_NOT_FOUND) => return Ok(Policy::default()),
+        Err(status) => return Err(status),
+    };
+
+    let mut values = Vec::with_capacity(VALUE_NAMES.len());
+    for &name in VALUE_NAMES {
+        if let Some((value_type, data)) = key.value(name) {
+            let value = RegValue::decode(name, value_type, &data).map_err(invalid_policy)?;
+            values.push((name, value));
+        }
+    }
+
+    Policy::from_values(values).map_err(invalid_policy)
+}
+
+fn invalid_policy(err: PolicyError) -> NTSTATUS {
+    println!(LogLevel::Error, "Invalid policy: {}", err);
+    STATUS_INVALID_PARAMETER
+}
+
+/// Checks what can be decided before the target is resolved.
+pub fn authorize(policy: &Policy, target: Target, write: bool, size: u64) -> Result<(), NTSTATUS> {
+    erebus_core::policy::authorize(policy, target, write, size).inspect_err(denied)
+}
+
+/// Checks a resolved target: critical processes and the image allowlist.
+pub fn check_process(policy: &Policy, process: &Process) -> Result<(), NTSTATUS> {
+    if let Some(reason) = process.critical_reason() {
+        println!(LogLevel::Warning, "Target is {}", reason);
+    }
+
+    erebus_core::policy::check_process(policy, process).inspect_err(denied)
+}
+
+fn denied(status: &NTSTATUS) {
+    println!(LogLevel::Error, "Denied by policy: {:#x}", status);
+}
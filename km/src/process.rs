@@ -1,27 +1,253 @@
+#[allow(unused_imports)]
+use alloc::format;
+
+use crate::{
+    ffi::{
+        ObOpenObjectByPointer, PsGetProcessCreateTimeQuadPart, PsGetProcessExitStatus,
+        PsGetProcessImageFileName, PsIsProtectedProcess, PsIsProtectedProcessLight, PsProcessType,
+        ZwQueryInformationProcess,
+    },
+    logger::LogLevel,
+    println,
+};
+
+use core::{ffi::CStr, ptr::null_mut};
+use shared::{policy::SYSTEM_PROCESS_ID, protocol::Target};
 use wdk::nt_success;
 use wdk_sys::{
-    ntddk::{ObfDereferenceObject, PsLookupProcessByProcessId},
-    HANDLE, NTSTATUS, PEPROCESS,
+    ntddk::{
+        IoGetCurrentProcess, ObReferenceObjectByHandle, ObfDereferenceObject, ObfReferenceObject,
+        PsGetProcessId, PsLookupProcessByProcessId, ZwClose,
+    },
+    _MODE::{KernelMode, UserMode},
+    ACCESS_MASK, HANDLE, KPROCESSOR_MODE, NTSTATUS, OBJ_KERNEL_HANDLE, PEPROCESS,
+    STATUS_INVALID_PARAMETER, STATUS_PENDING,
 };
 
+pub const PROCESS_VM_READ: ACCESS_MASK = 0x0010;
+pub const PROCESS_VM_WRITE: ACCESS_MASK = 0x0020;
+pub const PROCESS_QUERY_INFORMATION: ACCESS_MASK = 0x0400;
+pub const PROCESS_QUERY_LIMITED_INFORMATION: ACCESS_MASK = 0x1000;
+
+// `PROCESSINFOCLASS::ProcessBreakOnTermination`
+const PROCESS_BREAK_ON_TERMINATION: u32 = 29;
+
 pub(crate) struct Process {
     pub process: PEPROCESS,
 }
 
 impl Process {
-    pub fn by_id(process_id: u32) -> Result<Self, NTSTATUS> {
+    /// Resolves `target`, which must have been created at `create_time` unless that
+    /// is `ANY_CREATE_TIME`. A handle must grant `desired_access` to the caller; a
+    /// PID is looked up without any access check.
+    pub fn open(
+        target: Target,
+        desired_access: ACCESS_MASK,
+        create_time: u64,
+    ) -> Result<Self, NTSTATUS> {
+        match target {
+            Target::Pid(process_id) => Self::by_id(process_id, create_time),
+            Target::Handle(handle) => {
+                let process = Self::by_handle(handle, desired_access)?;
+                process.check_create_time(create_time)?;
+                Ok(process)
+            }
+            // sessions hold their process already, see `session::Attachment`
+            Target::Session => Err(STATUS_INVALID_PARAMETER),
+        }
+    }
+
+    /// Looks up a process by PID. Unless `create_time` is `ANY_CREATE_TIME`, a
+    /// process created at any other time is one that reused the PID, and is refused.
+    pub fn by_id(process_id: u32, create_time: u64) -> Result<Self, NTSTATUS> {
         let mut process = core::ptr::null_mut();
 
         let status = unsafe { PsLookupProcessByProcessId(process_id as HANDLE, &mut process) };
+        if !nt_success(status) {
+            return Err(status);
+        }
+
+        let process = Self { process };
+        process.check_create_time(create_time)?;
+
+        Ok(process)
+    }
+
+    /// References the process that sent the request being handled, which for a
+    /// deferred request is the one its worker attached to.
+    pub fn current() -> Self {
+        let process = unsafe { IoGetCurrentProcess() };
+        unsafe { ObfReferenceObject(process as _) };
+
+        Self { process }
+    }
+
+    /// References the process behind a handle in the calling process's handle table.
+    /// This has to run in the caller's context: our dispatch routines do, and a
+    /// worker attaches to the caller before running a deferred IOCTL.
+    pub fn by_handle(handle: u32, desired_access: ACCESS_MASK) -> Result<Self, NTSTATUS> {
+        let mut process = null_mut();
+
+        // Sign-extend, so `-1` (the current process pseudo-handle) keeps its meaning.
+        // `UserMode` makes the object manager check `desired_access` against the handle
+        // and refuse kernel handles.
+        let status = unsafe {
+            ObReferenceObjectByHandle(
+                handle as i32 as isize as HANDLE,
+                desired_access,
+                *PsProcessType,
+                UserMode as KPROCESSOR_MODE,
+                &mut process,
+                null_mut(),
+            )
+        };
 
         if nt_success(status) {
-            Ok(Self { process })
+            Ok(Self {
+                process: process as PEPROCESS,
+            })
         } else {
             Err(status)
         }
     }
 }
 
+impl Process {
+    pub fn id(&self) -> u32 {
+        unsafe { PsGetProcessId(self.process) as usize as u32 }
+    }
+
+    /// Creation time, in 100ns intervals since 1601-01-01 UTC. Together with the
+    /// PID, this names a process uniquely.
+    pub fn create_time(&self) -> i64 {
+        unsafe { PsGetProcessCreateTimeQuadPart(self.process) }
+    }
+
+    /// Fails with `STATUS_PROCESS_IDENTITY_MISMATCH` unless the process was created
+    /// at `expected`, or `expected` is `ANY_CREATE_TIME`.
+    pub fn check_create_time(&self, expected: u64) -> Result<(), NTSTATUS> {
+        erebus_core::target::check_create_time(self, expected).inspect_err(|_| {
+            println!(
+                LogLevel::Error,
+                "Process {} was created at {}, expected {}",
+                self.id(),
+                self.create_time(),
+                expected
+            );
+        })
+    }
+
+    /// Whether the process has exited. Its object lives on while referenced.
+    pub fn has_exited(&self) -> bool {
+        unsafe { PsGetProcessExitStatus(self.process) != STATUS_PENDING }
+    }
+
+    /// The kernel's (possibly truncated) image name, e.g. `b"lsass.exe"`.
+    pub fn image_file_name(&self) -> &[u8] {
+        let name = unsafe { PsGetProcessImageFileName(self.process) };
+        if name.is_null() {
+            return &[];
+        }
+
+        // Safety: `EPROCESS::ImageFileName` is a NUL-terminated array that lives as
+        // long as the process object, which we hold a reference to.
+        unsafe { CStr::from_ptr(name.cast()) }.to_bytes()
+    }
+
+    /// Why the kernel considers this process critical, or `None` for an ordinary
+    /// process. Image names are left to the policy, which can override them.
+    pub fn critical_reason(&self) -> Option<&'static str> {
+        if self.id() == SYSTEM_PROCESS_ID {
+            return Some("the System process");
+        }
+
+        if unsafe { PsIsProtectedProcess(self.process) } != 0
+            || unsafe { PsIsProtectedProcessLight(self.process) } != 0
+        {
+            return Some("a protected process");
+        }
+
+        // Fail closed: a process we cannot query is treated as critical.
+        if self.break_on_termination().unwrap_or(true) {
+            return Some("a critical process");
+        }
+
+        None
+    }
+
+    /// Opens a kernel handle to the process, for the `Zw*` routines that want one.
+    pub fn open_handle(&self, access: ACCESS_MASK) -> Result<KernelHandle, NTSTATUS> {
+        let mut handle: HANDLE = null_mut();
+        let status = unsafe {
+            ObOpenObjectByPointer(
+                self.process as _,
+                OBJ_KERNEL_HANDLE,
+                null_mut(),
+                access,
+                *PsProcessType,
+                KernelMode as KPROCESSOR_MODE,
+                &mut handle,
+            )
+        };
+
+        if nt_success(status) {
+            Ok(KernelHandle(handle))
+        } else {
+            Err(status)
+        }
+    }
+
+    /// Whether terminating the process bugchecks the machine.
+    fn break_on_termination(&self) -> Result<bool, NTSTATUS> {
+        let handle = self.open_handle(PROCESS_QUERY_LIMITED_INFORMATION)?;
+
+        let mut break_on_termination: u32 = 0;
+        let status = unsafe {
+            ZwQueryInformationProcess(
+                handle.raw(),
+                PROCESS_BREAK_ON_TERMINATION,
+                &mut break_on_termination as *mut u32 as _,
+                size_of::<u32>() as u32,
+                null_mut(),
+            )
+        };
+
+        if nt_success(status) {
+            Ok(break_on_termination != 0)
+        } else {
+            Err(status)
+        }
+    }
+}
+
+/// A kernel handle, closed when dropped.
+pub(crate) struct KernelHandle(HANDLE);
+
+impl KernelHandle {
+    pub fn raw(&self) -> HANDLE {
+        self.0
+    }
+}
+
+impl Drop for KernelHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = ZwClose(self.0);
+        }
+    }
+}
+
+impl Clone for Process {
+    /// Takes another reference to the same process.
+    fn clone(&self) -> Self {
+        unsafe { ObfReferenceObject(self.process as _) };
+
+        Self {
+            process: self.process,
+        }
+    }
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
         if !self.process.is_null() {
@@ -0,0 +1,160 @@
+use crate::{
+    ffi::{
+        PsGetProcessPeb, PsGetProcessWow64Process, ZwQueryInformationProcess,
+        ZwQuerySystemInformation, SYSTEM_PROCESS_INFORMATION,
+    },
+    process::{Process, PROCESS_QUERY_LIMITED_INFORMATION},
+};
+
+use alloc::{vec, vec::Vec};
+use core::{ptr::null_mut, slice};
+use shared::{
+    ipc::ProcessEntry,
+    processes::{MAX_PROCESSES, PROCESS_GONE, PROCESS_WOW64},
+    protocol::ANY_CREATE_TIME,
+};
+use wdk::nt_success;
+use wdk_sys::{NTSTATUS, PVOID, STATUS_INFO_LENGTH_MISMATCH, UNICODE_STRING};
+
+// `SYSTEM_INFORMATION_CLASS::SystemProcessInformation`
+const SYSTEM_PROCESS_INFORMATION_CLASS: u32 = 5;
+
+// `PROCESSINFOCLASS::ProcessImageFileNameWin32`
+const PROCESS_IMAGE_FILE_NAME_WIN32: u32 = 43;
+
+/// Room for a `UNICODE_STRING` and a path of up to 1024 UTF-16 units after it.
+/// Longer paths are left out.
+const PATH_BUFFER_SIZE: usize = size_of::<UNICODE_STRING>() + 1024 * 2;
+
+/// Processes started between sizing the snapshot and taking it are given this
+/// much room, and the query retried if that was not enough.
+const SNAPSHOT_SLACK: usize = 64 * 1024;
+
+/// Takes a snapshot of the running processes and describes each of them.
+pub fn list_processes() -> Result<Vec<ProcessEntry>, NTSTATUS> {
+    let snapshot = snapshot()?;
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            snapshot.as_ptr() as *const u8,
+            snapshot.len() * size_of::<u64>(),
+        )
+    };
+
+    // u64s, so the `UNICODE_STRING` at its start is aligned
+    let mut path_buffer: Vec<u64> = vec![0; PATH_BUFFER_SIZE.div_ceil(8)];
+
+    let mut processes = Vec::new();
+    let mut offset = 0;
+    while processes.len() < MAX_PROCESSES
+        && offset + size_of::<SYSTEM_PROCESS_INFORMATION>() <= bytes.len()
+    {
+        // Safety: in bounds, and entries are aligned for their pointers.
+        let info = unsafe { &*(bytes.as_ptr().add(offset) as *const SYSTEM_PROCESS_INFORMATION) };
+        processes.push(describe(info, &mut path_buffer));
+
+        if info.NextEntryOffset == 0 {
+            break;
+        }
+        offset += info.NextEntryOffset as usize;
+    }
+
+    Ok(processes)
+}
+
+/// The `SystemProcessInformation` snapshot, in u64s to keep its entries aligned.
+fn snapshot() -> Result<Vec<u64>, NTSTATUS> {
+    let mut size = 0;
+    loop {
+        let mut buffer: Vec<u64> = vec![0; size.div_ceil(8)];
+        let mut needed = 0;
+        let status = unsafe {
+            ZwQuerySystemInformation(
+                SYSTEM_PROCESS_INFORMATION_CLASS,
+                buffer.as_mut_ptr() as PVOID,
+                (buffer.len() * size_of::<u64>()) as u32,
+                &mut needed,
+            )
+        };
+
+        if status == STATUS_INFO_LENGTH_MISMATCH {
+            size = needed as usize + SNAPSHOT_SLACK;
+            continue;
+        }
+        if !nt_success(status) {
+            return Err(status);
+        }
+
+        return Ok(buffer);
+    }
+}
+
+/// What the snapshot says about a process, along with what the process itself
+/// says, if it is still the one the snapshot saw.
+fn describe(info: &SYSTEM_PROCESS_INFORMATION, path_buffer: &mut [u64]) -> ProcessEntry {
+    let process_id = info.UniqueProcessId as usize as u32;
+    let mut entry = ProcessEntry::new(
+        process_id,
+        info.InheritedFromUniqueProcessId as usize as u32,
+        info.SessionId,
+        info.CreateTime as u64,
+    );
+
+    if !info.ImageName.Buffer.is_null() {
+        // Safety: the name points into the snapshot.
+        entry.set_name_utf16(unsafe {
+            slice::from_raw_parts(info.ImageName.Buffer, info.ImageName.Length as usize / 2)
+        });
+    }
+
+    // the idle process has no object to look up
+    if process_id == 0 {
+        return entry;
+    }
+
+    let process = match Process::by_id(process_id, ANY_CREATE_TIME) {
+        Ok(process) if process.create_time() == info.CreateTime && !process.has_exited() => process,
+        _ => {
+            entry.flags |= PROCESS_GONE;
+            return entry;
+        }
+    };
+
+    entry.peb_address = unsafe { PsGetProcessPeb(process.process) } as u64;
+    if !unsafe { PsGetProcessWow64Process(process.process) }.is_null() {
+        entry.flags |= PROCESS_WOW64;
+    }
+    if let Some(path) = image_path(&process, path_buffer) {
+        entry.set_path_utf16(path);
+    }
+
+    entry
+}
+
+/// Win32 path of the image of `process`, if it can be queried.
+fn image_path<'a>(process: &Process, buffer: &'a mut [u64]) -> Option<&'a [u16]> {
+    let handle = process
+        .open_handle(PROCESS_QUERY_LIMITED_INFORMATION)
+        .ok()?;
+
+    let status = unsafe {
+        ZwQueryInformationProcess(
+            handle.raw(),
+            PROCESS_IMAGE_FILE_NAME_WIN32,
+            buffer.as_mut_ptr() as PVOID,
+            (buffer.len() * size_of::<u64>()) as u32,
+            null_mut(),
+        )
+    };
+    if !nt_success(status) {
+        return None;
+    }
+
+    // Safety: on success the buffer starts with a `UNICODE_STRING` whose `Buffer`
+    // points into the rest of it.
+    let path = unsafe { &*(buffer.as_ptr() as *const UNICODE_STRING) };
+    if path.Buffer.is_null() {
+        return None;
+    }
+
+    Some(unsafe { slice::from_raw_parts(path.Buffer, path.Length as usize / 2) })
+}
@@ -0,0 +1,118 @@
+use crate::{
+    ffi::{ZwQueryVirtualMemory, MEMORY_BASIC_INFORMATION},
+    process::{KernelHandle, Process, PROCESS_QUERY_INFORMATION},
+};
+
+use alloc::{vec, vec::Vec};
+use core::{mem::zeroed, ptr::null_mut};
+use shared::{
+    address::USER_PROBE_ADDRESS,
+    ipc::MemoryRegion,
+    regions::{MEM_IMAGE, MEM_MAPPED, REGIONS_DONE},
+};
+use wdk::nt_success;
+use wdk_sys::{NTSTATUS, PVOID, STATUS_INVALID_PARAMETER, UNICODE_STRING};
+
+// `MEMORY_INFORMATION_CLASS` values
+const MEMORY_BASIC_INFORMATION_CLASS: u32 = 0;
+const MEMORY_MAPPED_FILENAME_INFORMATION: u32 = 2;
+
+/// Room for a `UNICODE_STRING` and a path of up to 1024 UTF-16 units after it.
+/// Longer paths are left out.
+const NAME_BUFFER_SIZE: usize = size_of::<UNICODE_STRING>() + 1024 * 2;
+
+/// Walks the address space of `process` from the region containing `start`,
+/// handing up to `max_regions` regions to `emit` along with their index.
+/// Returns how many were emitted, and where to continue, or `REGIONS_DONE`
+/// once the end of user space was reached.
+pub fn query_regions(
+    process: &Process,
+    start: u64,
+    max_regions: u32,
+    mut emit: impl FnMut(u32, &MemoryRegion),
+) -> Result<(u32, u64), NTSTATUS> {
+    let handle = process.open_handle(PROCESS_QUERY_INFORMATION)?;
+
+    // u64s, so the `UNICODE_STRING` at its start is aligned
+    let mut name_buffer: Vec<u64> = vec![0; NAME_BUFFER_SIZE.div_ceil(8)];
+
+    let mut address = start;
+    let mut count = 0;
+    while count < max_regions {
+        let mut info: MEMORY_BASIC_INFORMATION = unsafe { zeroed() };
+        let status = unsafe {
+            ZwQueryVirtualMemory(
+                handle.raw(),
+                address as PVOID,
+                MEMORY_BASIC_INFORMATION_CLASS,
+                &mut info as *mut _ as PVOID,
+                size_of::<MEMORY_BASIC_INFORMATION>() as u64,
+                null_mut(),
+            )
+        };
+
+        // past the highest user address
+        if status == STATUS_INVALID_PARAMETER {
+            return Ok((count, REGIONS_DONE));
+        }
+        if !nt_success(status) {
+            return Err(status);
+        }
+
+        let mut region = MemoryRegion::new(
+            info.BaseAddress as u64,
+            info.AllocationBase as u64,
+            info.RegionSize,
+            info.State,
+            info.Protect,
+            info.AllocationProtect,
+            info.Type,
+        );
+
+        if info.Type == MEM_IMAGE || info.Type == MEM_MAPPED {
+            if let Some(name) = mapped_file_name(&handle, address, &mut name_buffer) {
+                region.set_name_utf16(name);
+            }
+        }
+
+        emit(count, &region);
+        count += 1;
+
+        address = region.end_address();
+        if address >= USER_PROBE_ADDRESS {
+            return Ok((count, REGIONS_DONE));
+        }
+    }
+
+    Ok((count, address))
+}
+
+/// NT path of the file mapped at `address`, if any.
+fn mapped_file_name<'a>(
+    handle: &KernelHandle,
+    address: u64,
+    buffer: &'a mut [u64],
+) -> Option<&'a [u16]> {
+    let status = unsafe {
+        ZwQueryVirtualMemory(
+            handle.raw(),
+            address as PVOID,
+            MEMORY_MAPPED_FILENAME_INFORMATION,
+            buffer.as_mut_ptr() as PVOID,
+            (buffer.len() * size_of::<u64>()) as u64,
+            null_mut(),
+        )
+    };
+    if !nt_success(status) {
+        return None;
+    }
+
+    // Safety: on success the buffer starts with a `UNICODE_STRING` whose `Buffer`
+    // points into the rest of it.
+    let name = unsafe { &*(buffer.as_ptr() as *const UNICODE_STRING) };
+    if name.Buffer.is_null() {
+        return None;
+    }
+
+    Some(unsafe { core::slice::from_raw_parts(name.Buffer, name.Length as usize / 2) })
+}
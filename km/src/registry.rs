@@ -0,0 +1,101 @@
+use crate::utils::{ToU16Vec, ToUnicodeString};
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr::null_mut};
+use wdk::nt_success;
+use wdk_sys::{
+    ntddk::{ZwClose, ZwOpenKey, ZwQueryValueKey},
+    _KEY_VALUE_INFORMATION_CLASS::KeyValuePartialInformation,
+    HANDLE, KEY_READ, NTSTATUS, OBJECT_ATTRIBUTES, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+    STATUS_BUFFER_OVERFLOW, STATUS_BUFFER_TOO_SMALL,
+};
+
+// `KEY_VALUE_PARTIAL_INFORMATION` up to its variable-length `Data`
+const PARTIAL_INFORMATION_HEADER: usize = 12;
+
+/// An open registry key, closed on drop.
+pub(crate) struct RegistryKey {
+    handle: HANDLE,
+}
+
+impl RegistryKey {
+    /// Opens the `Parameters` subkey of the driver's service key, given the service
+    /// key path `DriverEntry` was called with.
+    pub fn open_parameters(service: &[u16]) -> Result<Self, NTSTATUS> {
+        let mut path = Vec::with_capacity(service.len() + 12);
+        path.extend_from_slice(service);
+        path.extend("\\Parameters".encode_utf16());
+        path.push(0);
+
+        let mut name = path
+            .to_unicode_string()
+            .expect("unable to encode string to unicode.");
+
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: size_of::<OBJECT_ATTRIBUTES>() as u32,
+            RootDirectory: null_mut(),
+            ObjectName: &mut name,
+            Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            SecurityDescriptor: null_mut(),
+            SecurityQualityOfService: null_mut(),
+        };
+
+        let mut handle = null_mut();
+        let status = unsafe { ZwOpenKey(&mut handle, KEY_READ, &mut attributes) };
+        if !nt_success(status) {
+            return Err(status);
+        }
+
+        Ok(Self { handle })
+    }
+
+    /// Reads a value, returning its registry type and raw data.
+    pub fn value(&self, value_name: &str) -> Option<(u32, Vec<u8>)> {
+        let name_buf = value_name.to_u16_vec();
+        let mut name = name_buf.to_unicode_string()?;
+
+        let mut buf = Vec::new();
+        let mut needed = PARTIAL_INFORMATION_HEADER as u32 + 64;
+
+        // The first call may only report how large the value is.
+        for _ in 0..2 {
+            buf.resize(needed as usize, 0u8);
+
+            let status = unsafe {
+                ZwQueryValueKey(
+                    self.handle,
+                    &mut name,
+                    KeyValuePartialInformation,
+                    buf.as_mut_ptr() as _,
+                    buf.len() as u32,
+                    &mut needed,
+                )
+            };
+
+            if status == STATUS_BUFFER_OVERFLOW || status == STATUS_BUFFER_TOO_SMALL {
+                continue;
+            }
+
+            if !nt_success(status) {
+                return None;
+            }
+
+            let value_type = u32::from_le_bytes(buf[4..8].try_into().ok()?);
+            let data_len = u32::from_le_bytes(buf[8..12].try_into().ok()?) as usize;
+            let data = buf
+                .get(PARTIAL_INFORMATION_HEADER..PARTIAL_INFORMATION_HEADER + data_len)?
+                .to_vec();
+
+            return Some((value_type, data));
+        }
+
+        None
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = ZwClose(self.handle);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// Calls for kernel routines that report failure by raising an exception. Rust
+// has no structured exception handling, and an exception it does not catch
+// bugchecks the machine, so these calls are made from C inside `__try`.
+
+#include <ntddk.h>
+
+// `MmProbeAndLockPages`, returning the exception it raised as the status, or
+// `STATUS_SUCCESS` once the pages are locked.
+NTSTATUS ErebusProbeAndLockPages(PMDL Mdl, KPROCESSOR_MODE AccessMode, LOCK_OPERATION Operation)
+{
+    __try {
+        MmProbeAndLockPages(Mdl, AccessMode, Operation);
+    } __except (EXCEPTION_EXECUTE_HANDLER) {
+        return GetExceptionCode();
+    }
+
+    return STATUS_SUCCESS;
+}
@@ -0,0 +1,104 @@
+//! Per-handle state. `IRP_MJ_CREATE` hangs a `Session` off the file object's
+//! `FsContext`, and `EREBUS_IOCTL_ATTACH` binds it to one process, which memory
+//! operations with `FLAG_SESSION` then act on without naming it again. A session
+//! that asks for events gets its own queue of them, see `events`.
+
+use crate::{events::Subscriber, process::Process, sync::SpinLock};
+use alloc::{boxed::Box, sync::Arc};
+use wdk_sys::{NTSTATUS, PFILE_OBJECT, STATUS_INVALID_PARAMETER, STATUS_SUCCESS};
+
+/// The process a session is bound to, referenced until the session is detached.
+pub(crate) type Attachment = erebus_core::Attachment<Process>;
+
+pub(crate) struct Session {
+    // a request clones the `Arc`, so re-attaching never pulls the process out from under it
+    attachment: SpinLock<Option<Arc<Attachment>>>,
+    subscriber: SpinLock<Option<Arc<Subscriber>>>,
+}
+
+impl Session {
+    /// Allocates a session for a newly opened handle.
+    ///
+    /// # Safety
+    /// `file_object` must be the file object of an `IRP_MJ_CREATE` for our device.
+    pub unsafe fn create(file_object: PFILE_OBJECT) -> NTSTATUS {
+        if file_object.is_null() {
+            return STATUS_INVALID_PARAMETER;
+        }
+
+        let session = Box::new(Self {
+            attachment: SpinLock::new(None),
+            subscriber: SpinLock::new(None),
+        });
+        unsafe { (*file_object).FsContext = Box::into_raw(session).cast() };
+
+        STATUS_SUCCESS
+    }
+
+    /// The session of the handle an IRP was sent on.
+    ///
+    /// # Safety
+    /// `file_object` must belong to our device and must not have been closed.
+    pub unsafe fn from_file_object<'a>(file_object: PFILE_OBJECT) -> Option<&'a Self> {
+        if file_object.is_null() {
+            return None;
+        }
+
+        unsafe { (*file_object).FsContext.cast::<Self>().as_ref() }
+    }
+
+    /// Frees the session once the I/O manager closes the file object.
+    ///
+    /// # Safety
+    /// `file_object` must be the file object of an `IRP_MJ_CLOSE` for our device.
+    /// No other IRP can reference it anymore.
+    pub unsafe fn destroy(file_object: PFILE_OBJECT) {
+        if file_object.is_null() {
+            return;
+        }
+
+        let session = unsafe { (*file_object).FsContext.cast::<Self>() };
+        if !session.is_null() {
+            unsafe {
+                (*file_object).FsContext = core::ptr::null_mut();
+                drop(Box::from_raw(session));
+            }
+        }
+    }
+
+    /// Binds the session to a process, replacing any previous attachment.
+    pub fn attach(&self, attachment: Attachment) {
+        // the old attachment is released outside the lock
+        let _old = self.attachment.lock().replace(Arc::new(attachment));
+    }
+
+    /// Releases the attached process, if any.
+    pub fn detach(&self) {
+        let _old = self.attachment.lock().take();
+    }
+
+    pub fn attachment(&self) -> Option<Arc<Attachment>> {
+        self.attachment.lock().clone()
+    }
+
+    pub fn subscriber(&self) -> Option<Arc<Subscriber>> {
+        self.subscriber.lock().clone()
+    }
+
+    /// Gives the session its event queue, unless a concurrent request did first,
+    /// in which case that queue is returned.
+    pub fn install_subscriber(&self, subscriber: Arc<Subscriber>) -> Result<(), Arc<Subscriber>> {
+        let mut current = self.subscriber.lock();
+        match current.as_ref() {
+            Some(existing) => Err(existing.clone()),
+            None => {
+                *current = Some(subscriber);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn take_subscriber(&self) -> Option<Arc<Subscriber>> {
+        self.subscriber.lock().take()
+    }
+}
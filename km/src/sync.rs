@@ -0,0 +1,117 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use wdk_sys::{
+    ntddk::{
+        ExAcquireRundownProtection, ExReleaseRundownProtection, ExWaitForRundownProtectionRelease,
+        KeAcquireSpinLockRaiseToDpc, KeReleaseSpinLock,
+    },
+    EX_RUNDOWN_REF, KIRQL, KSPIN_LOCK,
+};
+
+/// A `KSPIN_LOCK` guarding a value. A zeroed `KSPIN_LOCK` is a released one, so
+/// this can be built in a `static` without any runtime initialization.
+///
+/// The guard runs at `DISPATCH_LEVEL`: keep critical sections short and never
+/// touch pageable memory while holding it.
+pub(crate) struct SpinLock<T> {
+    lock: UnsafeCell<KSPIN_LOCK>,
+    value: UnsafeCell<T>,
+}
+
+// Safety: access to `value` is serialized by `lock`.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: UnsafeCell::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let old_irql = unsafe { KeAcquireSpinLockRaiseToDpc(self.lock.get()) };
+
+        SpinLockGuard {
+            lock: self,
+            old_irql,
+        }
+    }
+}
+
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    old_irql: KIRQL,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { KeReleaseSpinLock(self.lock.lock.get(), self.old_irql) };
+    }
+}
+
+/// An `EX_RUNDOWN_REF`, counting the work that has to finish before whatever it
+/// guards goes away. Like `SpinLock`, a zeroed one is ready to use.
+///
+/// `close` turns new references away right away; `wait` then blocks until the
+/// last one is released. Between the two, the owner can cancel what holds on to
+/// a reference, which `wait` on its own would only wait for.
+pub(crate) struct Rundown {
+    rundown: UnsafeCell<EX_RUNDOWN_REF>,
+    closing: AtomicBool,
+}
+
+// Safety: the rundown functions synchronize access to `rundown` themselves.
+unsafe impl Sync for Rundown {}
+
+impl Rundown {
+    pub const fn new() -> Self {
+        Self {
+            rundown: UnsafeCell::new(unsafe { core::mem::zeroed() }),
+            closing: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes a reference, unless the rundown was closed. Each reference taken
+    /// must be released exactly once.
+    pub fn acquire(&self) -> bool {
+        !self.is_closing() && unsafe { ExAcquireRundownProtection(self.rundown.get()) } != 0
+    }
+
+    pub fn release(&self) {
+        unsafe { ExReleaseRundownProtection(self.rundown.get()) };
+    }
+
+    /// Turns away every `acquire` from now on.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Closes the rundown, and waits for every reference to be released.
+    /// Must be called at `PASSIVE_LEVEL`.
+    pub fn wait(&self) {
+        self.close();
+        unsafe { ExWaitForRundownProtectionRelease(self.rundown.get()) };
+    }
+}
@@ -0,0 +1,395 @@
+//! Long-running IOCTLs, run on worker threads so the calling thread is not held
+//! up by them, and can cancel them with `CancelIo` or by closing its handle.
+//!
+//! `handle_ioctl` hands such a request to `defer`, which marks it pending and
+//! queues it. A queued request carries a cancel routine, with the same rule as
+//! a parked `EREBUS_IOCTL_GET_EVENTS` (see `events`): whoever swaps the routine
+//! out owns the request's completion. A worker runs the request attached to the
+//! process that sent it, so handles and the audit log resolve against the
+//! caller, and handlers poll the IRP's `Cancel` flag between chunks of work.
+
+#[allow(unused_imports)]
+use alloc::format;
+
+use crate::{
+    complete_request, dispatch_ioctl,
+    ffi::{IoGetCurrentIrpStackLocation, IoMarkIrpPending, IoSetCancelRoutine, PsThreadType},
+    logger::LogLevel,
+    println,
+    sync::SpinLock,
+};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use shared::{
+    ioctl::{
+        EREBUS_IOCTL_LIST_PROCESSES, EREBUS_IOCTL_QUERY_MODULES, EREBUS_IOCTL_QUERY_REGIONS,
+        EREBUS_IOCTL_READ, EREBUS_IOCTL_READ_BATCH, EREBUS_IOCTL_READ_LOSSY,
+        EREBUS_IOCTL_WRITE_BATCH,
+    },
+    protocol::DATA_OFFSET,
+};
+use wdk::nt_success;
+use wdk_sys::{
+    ntddk::{
+        IoCancelIrp, IoGetRequestorProcess, IoReleaseCancelSpinLock, KeInitializeSemaphore,
+        KeReleaseSemaphore, KeStackAttachProcess, KeUnstackDetachProcess, KeWaitForSingleObject,
+        ObReferenceObjectByHandle, ObfDereferenceObject, PsCreateSystemThread,
+        PsTerminateSystemThread, ZwClose, ZwWaitForSingleObject,
+    },
+    _IO_STACK_LOCATION,
+    _KWAIT_REASON::Executive,
+    _MODE::KernelMode,
+    ACCESS_MASK, DEVICE_OBJECT, HANDLE, IO_NO_INCREMENT, KAPC_STATE, KPROCESSOR_MODE, KSEMAPHORE,
+    NTSTATUS, PFILE_OBJECT, PIRP, PVOID, STATUS_CANCELLED, STATUS_DELETE_PENDING, STATUS_PENDING,
+    STATUS_SUCCESS, SYNCHRONIZE,
+};
+
+const THREAD_ALL_ACCESS: ACCESS_MASK = 0x001F_FFFF;
+
+/// Requests that run at once. More than one, so a long dump does not hold up
+/// every other client.
+const WORKER_COUNT: usize = 4;
+
+/// Reads with room for more than this are deferred; smaller ones are done by
+/// the time a worker would have picked them up.
+const DEFER_READ_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Job {
+    irp: PIRP,
+    /// The handle the request was sent on, so closing it can cancel the request.
+    file_object: PFILE_OBJECT,
+}
+
+struct WorkQueue {
+    jobs: VecDeque<Job>,
+    /// Requests the workers took, which closing their handle cancels.
+    running: Vec<Job>,
+    stopping: bool,
+}
+
+// Safety: queued IRPs are only touched under the lock, or by whoever reclaimed
+// them from their cancel routine.
+unsafe impl Send for WorkQueue {}
+
+static QUEUE: SpinLock<WorkQueue> = SpinLock::new(WorkQueue {
+    jobs: VecDeque::new(),
+    running: Vec::new(),
+    stopping: false,
+});
+
+struct Workers {
+    /// Counts the requests queued, plus one per worker once they should stop.
+    semaphore: KSEMAPHORE,
+    /// Referenced thread objects, waited for when stopping.
+    threads: [PVOID; WORKER_COUNT],
+}
+
+static WORKERS: AtomicPtr<Workers> = AtomicPtr::new(null_mut());
+
+/// Starts the worker threads. `stop` must run before the driver unloads.
+pub fn start() -> NTSTATUS {
+    let workers = Box::into_raw(Box::new(Workers {
+        semaphore: unsafe { core::mem::zeroed() },
+        threads: [null_mut(); WORKER_COUNT],
+    }));
+    unsafe { KeInitializeSemaphore(&raw mut (*workers).semaphore, 0, i32::MAX) };
+    WORKERS.store(workers, Ordering::Release);
+
+    for index in 0..WORKER_COUNT {
+        let mut handle = null_mut();
+        let status = unsafe {
+            PsCreateSystemThread(
+                &mut handle,
+                THREAD_ALL_ACCESS,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                Some(run),
+                workers.cast(),
+            )
+        };
+        if !nt_success(status) {
+            println!(
+                LogLevel::Error,
+                "Failed to start worker {}. Error: {:#x}", index, status
+            );
+            stop();
+            return status;
+        }
+
+        let mut thread = null_mut();
+        let status = unsafe {
+            ObReferenceObjectByHandle(
+                handle,
+                SYNCHRONIZE,
+                *PsThreadType,
+                KernelMode as KPROCESSOR_MODE,
+                &mut thread,
+                null_mut(),
+            )
+        };
+        if !nt_success(status) {
+            println!(
+                LogLevel::Error,
+                "Failed to reference worker {}. Error: {:#x}", index, status
+            );
+            // the worker runs, but `threads` has no reference to wait for it by
+            unsafe {
+                stop_waiting_for(handle);
+                ZwClose(handle);
+            }
+            return status;
+        }
+
+        unsafe {
+            ZwClose(handle);
+            (*workers).threads[index] = thread;
+        }
+    }
+
+    STATUS_SUCCESS
+}
+
+/// Cancels what is queued, tells running requests to stop, and waits for the
+/// workers to exit. `defer` turns requests away from then on.
+pub fn stop() {
+    // Safety: there is no handle to wait on
+    unsafe { stop_waiting_for(null_mut()) }
+}
+
+/// `stop`, also waiting for the worker thread `orphan` refers to, if not null,
+/// before the workers are freed.
+///
+/// # Safety
+/// `orphan` must be null or a thread handle, which the caller closes.
+unsafe fn stop_waiting_for(orphan: HANDLE) {
+    let workers = WORKERS.load(Ordering::Acquire);
+    if workers.is_null() {
+        return;
+    }
+
+    let mut cancelled = Vec::new();
+
+    let mut queue = QUEUE.lock();
+    queue.stopping = true;
+    // a request whose cancel routine is already running is left to it
+    for job in queue.jobs.drain(..) {
+        if unsafe { IoSetCancelRoutine(job.irp, None) }.is_some() {
+            cancelled.push(job.irp);
+        }
+    }
+    for job in &queue.running {
+        unsafe { IoCancelIrp(job.irp) };
+    }
+    drop(queue);
+
+    for p_irp in cancelled {
+        unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+    }
+
+    unsafe {
+        KeReleaseSemaphore(
+            &raw mut (*workers).semaphore,
+            IO_NO_INCREMENT as i32,
+            WORKER_COUNT as i32,
+            0,
+        );
+
+        for thread in (*workers).threads {
+            if !thread.is_null() {
+                KeWaitForSingleObject(
+                    thread,
+                    Executive,
+                    KernelMode as KPROCESSOR_MODE,
+                    0,
+                    null_mut(),
+                );
+                ObfDereferenceObject(thread);
+            }
+        }
+        if !orphan.is_null() {
+            ZwWaitForSingleObject(orphan, 0, null_mut());
+        }
+
+        // only now that no worker waits on its semaphore
+        WORKERS.store(null_mut(), Ordering::Release);
+        drop(Box::from_raw(workers));
+    }
+}
+
+/// Whether the request is one to run on a worker: one that walks a process or
+/// the system, or copies more than `DEFER_READ_SIZE`.
+///
+/// # Safety
+/// `p_stack_location` must be the current stack location of an
+/// `IRP_MJ_DEVICE_CONTROL`.
+pub unsafe fn is_long_running(p_stack_location: *mut _IO_STACK_LOCATION) -> bool {
+    let parameters = unsafe { (*p_stack_location).Parameters.DeviceIoControl };
+
+    match parameters.IoControlCode {
+        EREBUS_IOCTL_READ_LOSSY
+        | EREBUS_IOCTL_READ_BATCH
+        | EREBUS_IOCTL_WRITE_BATCH
+        | EREBUS_IOCTL_QUERY_REGIONS
+        | EREBUS_IOCTL_QUERY_MODULES
+        | EREBUS_IOCTL_LIST_PROCESSES => true,
+        // the output buffer is sized to the read, and the handler holds it to that
+        EREBUS_IOCTL_READ => parameters.OutputBufferLength as usize > DATA_OFFSET + DEFER_READ_SIZE,
+        _ => false,
+    }
+}
+
+/// Queues `p_irp` for a worker, or completes it if the workers are stopping. The
+/// dispatch routine must return the status this returns, and leave the request
+/// alone.
+///
+/// # Safety
+/// `p_irp` must be an `IRP_MJ_DEVICE_CONTROL` that has not been completed, sent
+/// while the workers run.
+pub unsafe fn defer(p_irp: PIRP) -> NTSTATUS {
+    let workers = WORKERS.load(Ordering::Acquire);
+    debug_assert!(!workers.is_null());
+
+    let file_object = unsafe { (*IoGetCurrentIrpStackLocation(p_irp)).FileObject };
+    let mut queue = QUEUE.lock();
+
+    if queue.stopping {
+        drop(queue);
+        return unsafe { complete_request(p_irp, STATUS_DELETE_PENDING) };
+    }
+
+    unsafe {
+        IoMarkIrpPending(p_irp);
+        IoSetCancelRoutine(p_irp, Some(cancel));
+    }
+
+    // Cancelled before the routine was in place: if we get the routine back,
+    // nobody else will complete the request.
+    if unsafe { (*p_irp).Cancel } != 0 && unsafe { IoSetCancelRoutine(p_irp, None) }.is_some() {
+        drop(queue);
+        unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+        return STATUS_PENDING;
+    }
+
+    queue.jobs.push_back(Job {
+        irp: p_irp,
+        file_object,
+    });
+
+    // Under the lock, so `stop` cannot free the semaphore in between. Not
+    // waiting, the release is fine at `DISPATCH_LEVEL`.
+    unsafe { KeReleaseSemaphore(&raw mut (*workers).semaphore, IO_NO_INCREMENT as i32, 1, 0) };
+    drop(queue);
+
+    STATUS_PENDING
+}
+
+/// Cancels the requests sent on a handle that is being closed: queued ones are
+/// completed here, running ones are flagged for their handler to stop.
+pub fn cancel_file(file_object: PFILE_OBJECT) {
+    let mut cancelled = Vec::new();
+
+    let mut queue = QUEUE.lock();
+    queue.jobs.retain(|job| {
+        // a request whose cancel routine is already running is left to it
+        if job.file_object != file_object || unsafe { IoSetCancelRoutine(job.irp, None) }.is_none()
+        {
+            return true;
+        }
+
+        cancelled.push(job.irp);
+        false
+    });
+
+    for job in queue
+        .running
+        .iter()
+        .filter(|job| job.file_object == file_object)
+    {
+        unsafe { IoCancelIrp(job.irp) };
+    }
+    drop(queue);
+
+    for p_irp in cancelled {
+        unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+    }
+}
+
+unsafe extern "C" fn cancel(_device: *mut DEVICE_OBJECT, p_irp: PIRP) {
+    unsafe { IoReleaseCancelSpinLock((*p_irp).CancelIrql) };
+
+    // Unless a worker took it and failed to reclaim it, the request is still
+    // queued, and must not be run after all.
+    QUEUE.lock().jobs.retain(|job| job.irp != p_irp);
+
+    println!(LogLevel::Info, "Cancelled a queued request");
+    unsafe { complete_request(p_irp, STATUS_CANCELLED) };
+}
+
+/// Takes the next request that was not cancelled in the meantime.
+fn take() -> Option<Job> {
+    let mut queue = QUEUE.lock();
+
+    while let Some(job) = queue.jobs.pop_front() {
+        if unsafe { IoSetCancelRoutine(job.irp, None) }.is_some() {
+            queue.running.push(job);
+            return Some(job);
+        }
+    }
+
+    None
+}
+
+unsafe extern "C" fn run(context: PVOID) {
+    let workers = context.cast::<Workers>();
+
+    loop {
+        unsafe {
+            KeWaitForSingleObject(
+                (&raw mut (*workers).semaphore).cast(),
+                Executive,
+                KernelMode as KPROCESSOR_MODE,
+                0,
+                null_mut(),
+            )
+        };
+
+        match take() {
+            Some(job) => unsafe { process(job) },
+            None if QUEUE.lock().stopping => break,
+            None => {}
+        }
+    }
+
+    unsafe { PsTerminateSystemThread(STATUS_SUCCESS) };
+}
+
+/// Runs a request in the context of the process that sent it, and completes it.
+unsafe fn process(job: Job) {
+    let requestor = unsafe { IoGetRequestorProcess(job.irp) };
+
+    let mut apc_state: KAPC_STATE = unsafe { core::mem::zeroed() };
+    if !requestor.is_null() {
+        unsafe { KeStackAttachProcess(requestor.cast(), &mut apc_state) };
+    }
+
+    let status = unsafe { dispatch_ioctl(IoGetCurrentIrpStackLocation(job.irp), job.irp) };
+
+    if !requestor.is_null() {
+        unsafe { KeUnstackDetachProcess(&mut apc_state) };
+    }
+
+    // no longer something closing the handle has to cancel
+    QUEUE
+        .lock()
+        .running
+        .retain(|running| running.irp != job.irp);
+
+    // nothing that can pend is deferred, see `is_long_running`
+    debug_assert!(status != STATUS_PENDING);
+    unsafe { complete_request(job.irp, status) };
+}
@@ -0,0 +1,108 @@
+<#
+.SYNOPSIS
+Loads, hammers, and unloads the driver over and over.
+
+.DESCRIPTION
+Each iteration registers and starts the driver as a demand-start kernel service, then runs clients against it in
+parallel: process, region, and module listings, lossy dumps with a timeout, and event watchers. Some clients are
+killed mid-request, so their handles close with requests queued, running, or parked. The service is then stopped
+while event watchers still hold handles, and the iteration waits for the unload to go through once they are gone.
+
+Run it elevated, on a test machine with test signing on and Driver Verifier watching the driver:
+
+    verifier /standard /driver erebus.sys
+
+A bug in the unload path shows up as a bugcheck, or as a service that never reaches STOPPED, which fails the run.
+#>
+param(
+    # The built driver.
+    [Parameter(Mandatory)] [string] $Driver,
+    # The user-mode client.
+    [Parameter(Mandatory)] [string] $Client,
+    [int] $Iterations = 50,
+    # Clients of each kind started per iteration.
+    [int] $Clients = 4,
+    # How long the clients run before the service is stopped.
+    [int] $HammerSeconds = 5
+)
+
+$ErrorActionPreference = 'Stop'
+
+$Service = 'erebus-stress'
+$Driver = (Resolve-Path $Driver).Path
+$Client = (Resolve-Path $Client).Path
+
+function Wait-ServiceState([string] $State, [int] $Seconds = 30) {
+    $deadline = (Get-Date).AddSeconds($Seconds)
+    while ((Get-Date) -lt $deadline) {
+        if ((Get-Service $Service).Status -eq $State) {
+            return
+        }
+        Start-Sleep -Milliseconds 100
+    }
+    throw "Service $Service did not reach $State within $Seconds seconds"
+}
+
+function Start-Client([string[]] $Arguments) {
+    Start-Process -FilePath $Client -ArgumentList $Arguments -PassThru -WindowStyle Hidden `
+        -RedirectStandardOutput ([IO.Path]::GetTempFileName()) `
+        -RedirectStandardError ([IO.Path]::GetTempFileName())
+}
+
+if (Get-Service $Service -ErrorAction SilentlyContinue) {
+    sc.exe delete $Service | Out-Null
+}
+sc.exe create $Service type= kernel start= demand binPath= $Driver | Out-Null
+if ($LASTEXITCODE -ne 0) {
+    throw "Could not create service $Service"
+}
+
+# A process that stays put for the clients to read from.
+$target = Start-Process -FilePath notepad.exe -PassThru -WindowStyle Minimized
+
+try {
+    for ($iteration = 1; $iteration -le $Iterations; $iteration++) {
+        Write-Host "Iteration $iteration of $Iterations"
+
+        sc.exe start $Service | Out-Null
+        Wait-ServiceState Running
+
+        $module = & $Client modules $target.Id --json | ConvertFrom-Json | Select-Object -First 1
+        if (-not $module) {
+            throw "Could not list the modules of $($target.Id)"
+        }
+        # sizes are taken as hexadecimal bytes, so an even number of digits
+        $size = '0x{0:x16}' -f [uint64] $module.size
+
+        $watchers = 1..$Clients | ForEach-Object { Start-Client @('events', '--images', '--threads') }
+
+        $deadline = (Get-Date).AddSeconds($HammerSeconds)
+        while ((Get-Date) -lt $deadline) {
+            $clients = @()
+            $clients += 1..$Clients | ForEach-Object { Start-Client @('ps') }
+            $clients += 1..$Clients | ForEach-Object { Start-Client @('regions', $target.Id) }
+            $clients += 1..$Clients | ForEach-Object { Start-Client @('modules', $target.Id) }
+            $clients += 1..$Clients | ForEach-Object {
+                Start-Client @('dump', $target.Id, $module.base, $size, 'NUL', '--timeout', '1')
+            }
+
+            # Kill half of them before they finish, closing their handles with requests outstanding.
+            Start-Sleep -Milliseconds (Get-Random -Minimum 10 -Maximum 200)
+            $clients | Get-Random -Count ($clients.Count / 2) | Stop-Process -Force -ErrorAction SilentlyContinue
+            $clients | Wait-Process -Timeout 30 -ErrorAction SilentlyContinue
+        }
+
+        # The watchers still hold handles with requests parked, so the unload waits for them.
+        sc.exe stop $Service | Out-Null
+        Start-Sleep -Milliseconds (Get-Random -Minimum 0 -Maximum 500)
+        $watchers | Stop-Process -Force -ErrorAction SilentlyContinue
+        Wait-ServiceState Stopped
+    }
+
+    Write-Host "Loaded and unloaded the driver $Iterations times"
+}
+finally {
+    Stop-Process $target -Force -ErrorAction SilentlyContinue
+    sc.exe stop $Service 2>&1 | Out-Null
+    sc.exe delete $Service | Out-Null
+}
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::batch::{decode_batch, BATCH_HEADER_SIZE, BATCH_ENTRY_SIZE, MAX_BATCH_ENTRIES};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((header, entries)) = decode_batch(data) else {
+        return;
+    };
+
+    // Anything that decodes must be internally consistent.
+    assert!(header.entry_count <= MAX_BATCH_ENTRIES);
+    assert_eq!(entries.len(), header.entry_count as usize);
+    assert!(data.len() >= BATCH_HEADER_SIZE + entries.len() * BATCH_ENTRY_SIZE);
+
+    for entry in entries {
+        assert!(entry.length > 0);
+        assert!(entry.offset + u64::from(entry.length) <= header.buffer_len);
+        assert!(entry.address.checked_add(u64::from(entry.length)).is_some());
+    }
+});
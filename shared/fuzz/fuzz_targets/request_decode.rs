@@ -0,0 +1,38 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::{
+    batch::{decode_batch, decode_write_batch},
+    chain::decode_chain,
+    exchange::decode_exchange,
+    ipc::Request,
+    protocol::{decode_request, HEADER_SIZE, MAX_TRANSFER_SIZE, PAYLOAD_ALIGN},
+};
+
+// Mirrors what the driver does with `SystemBuffer`: split off the header, then
+// decode the payload as whichever structure the opcode implies.
+fuzz_target!(|data: &[u8]| {
+    let Ok((header, payload)) = decode_request(data) else {
+        return;
+    };
+
+    assert_eq!(payload.len(), header.payload_len as usize);
+    assert!(payload.len().is_multiple_of(PAYLOAD_ALIGN));
+    assert!(HEADER_SIZE + payload.len() <= data.len());
+
+    if let Ok(request) = Request::from_bytes(payload) {
+        assert!(request.size > 0 && request.size <= MAX_TRANSFER_SIZE);
+        assert!(request.address != 0);
+    }
+
+    let _ = decode_batch(payload);
+    let _ = decode_chain(payload);
+
+    if let Ok((header, _, data)) = decode_write_batch(payload) {
+        assert_eq!(data.len() as u64, header.buffer_len);
+    }
+
+    if let Ok(exchange) = decode_exchange(payload) {
+        assert!(exchange.address.is_multiple_of(u64::from(exchange.size)));
+    }
+});
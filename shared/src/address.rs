@@ -0,0 +1,124 @@
+//! Validation of target address ranges.
+//!
+//! The driver copies with `MmCopyVirtualMemory` and a previous mode of
+//! `KernelMode`, so the kernel neither probes the target range nor stops it from
+//! naming kernel memory. Faults inside the target's user space are caught by
+//! the copy itself and come back as a status. Everything else has to be refused
+//! here, before the copy: nothing may reach it that could fault outside of
+//! user space or name memory the caller has no business with.
+
+/// Lowest address a user-mode mapping can start at. The first 64 KiB are never
+/// mapped, to catch null pointer dereferences.
+pub const LOWEST_USER_ADDRESS: u64 = 0x1_0000;
+
+/// First address past user space on x64 (`MM_USER_PROBE_ADDRESS`). The 64 KiB
+/// below it are a no-access guard region, and everything from here up belongs
+/// to the kernel.
+pub const USER_PROBE_ADDRESS: u64 = 0x7FFF_FFFF_0000;
+
+/// Why a target range was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    /// The range starts in the never-mapped region around address zero.
+    NullRegion,
+    /// `address + size` does not fit in 64 bits.
+    Overflow,
+    /// The range reaches past user space.
+    KernelAddress,
+}
+
+impl core::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NullRegion => write!(f, "range starts below {LOWEST_USER_ADDRESS:#x}"),
+            Self::Overflow => write!(f, "range wraps around the address space"),
+            Self::KernelAddress => write!(f, "range reaches past {USER_PROBE_ADDRESS:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for AddressError {}
+
+/// Checks that `address..address + size` lies entirely within user space.
+pub const fn check_user_range(address: u64, size: u64) -> Result<(), AddressError> {
+    if address < LOWEST_USER_ADDRESS {
+        return Err(AddressError::NullRegion);
+    }
+
+    let Some(end) = address.checked_add(size) else {
+        return Err(AddressError::Overflow);
+    };
+
+    if end > USER_PROBE_ADDRESS {
+        return Err(AddressError::KernelAddress);
+    }
+
+    Ok(())
+}
+
+pub const fn is_valid_user_memory(address: u64, size: u64) -> bool {
+    check_user_range(address, size).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_addresses_are_refused() {
+        assert_eq!(check_user_range(0, 8), Err(AddressError::NullRegion));
+        assert_eq!(check_user_range(0x10, 8), Err(AddressError::NullRegion));
+        assert_eq!(
+            check_user_range(LOWEST_USER_ADDRESS - 1, 8),
+            Err(AddressError::NullRegion)
+        );
+        assert_eq!(check_user_range(LOWEST_USER_ADDRESS, 8), Ok(()));
+    }
+
+    #[test]
+    fn kernel_addresses_are_refused() {
+        for address in [
+            0xFFFF_8000_0000_0000,
+            0xFFFF_F780_0000_0000, // KUSER_SHARED_DATA
+            0xFFFF_FFFF_FFFF_F000,
+            USER_PROBE_ADDRESS,
+        ] {
+            assert_eq!(
+                check_user_range(address, 8),
+                Err(AddressError::KernelAddress),
+                "{address:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrapping_ranges_are_refused() {
+        assert_eq!(
+            check_user_range(0x7FF0_0000_0000, u64::MAX),
+            Err(AddressError::Overflow)
+        );
+        assert_eq!(check_user_range(u64::MAX, 1), Err(AddressError::Overflow));
+        // the end lands exactly on 2^64
+        assert_eq!(
+            check_user_range(0xFFFF_FFFF_FFFF_F000, 0x1000),
+            Err(AddressError::Overflow)
+        );
+    }
+
+    #[test]
+    fn upper_boundary_is_exclusive() {
+        assert!(is_valid_user_memory(USER_PROBE_ADDRESS - 8, 8));
+        assert!(!is_valid_user_memory(USER_PROBE_ADDRESS - 8, 9));
+        assert!(!is_valid_user_memory(USER_PROBE_ADDRESS, 1));
+        assert!(is_valid_user_memory(USER_PROBE_ADDRESS - 1, 1));
+    }
+
+    #[test]
+    fn ranges_within_user_space_are_accepted() {
+        assert!(is_valid_user_memory(0x7FF6_A000_0000, 0x1000));
+        assert!(is_valid_user_memory(
+            LOWEST_USER_ADDRESS,
+            USER_PROBE_ADDRESS - LOWEST_USER_ADDRESS
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+//! Encoding and hash chaining of the driver's audit log.
+//!
+//! The driver appends one `AuditRecord` per memory operation and seals it with
+//! SHA-256 over its encoding up to and including `prev_hash`. Editing, dropping,
+//! or reordering records therefore breaks the chain. The reply to a drain is an
+//! aggregate `Response` followed by whole records, oldest first.
+
+use crate::{
+    codec::Reader,
+    ipc::AuditRecord,
+    protocol::ProtocolError,
+    sha256::{sha256, DIGEST_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `AuditRecord` on the wire.
+pub const AUDIT_RECORD_SIZE: usize = 144;
+
+/// Size of the image name fields of an `AuditRecord`.
+pub const AUDIT_IMAGE_SIZE: usize = 16;
+
+/// `prev_hash` of the first record the driver writes after it loads.
+pub const GENESIS_HASH: [u8; DIGEST_SIZE] = [0; DIGEST_SIZE];
+
+// bytes covered by `hash`: everything but the hash itself
+const HASHED_LEN: usize = AUDIT_RECORD_SIZE - DIGEST_SIZE;
+
+impl AuditRecord {
+    pub fn to_bytes(&self) -> [u8; AUDIT_RECORD_SIZE] {
+        let mut buf = [0; AUDIT_RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.caller_pid.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.target_pid.to_le_bytes());
+        buf[24..26].copy_from_slice(&self.opcode.to_le_bytes());
+        buf[26..28].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.status.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.address.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.size.to_le_bytes());
+        buf[48..64].copy_from_slice(&self.caller_image);
+        buf[64..80].copy_from_slice(&self.target_image);
+        buf[80..112].copy_from_slice(&self.prev_hash);
+        buf[112..144].copy_from_slice(&self.hash);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            sequence: reader.u64()?,
+            timestamp: reader.u64()?,
+            caller_pid: reader.u32()?,
+            target_pid: reader.u32()?,
+            opcode: reader.u16()?,
+            reserved: reader.u16()?,
+            status: reader.i32()?,
+            address: reader.u64()?,
+            size: reader.u64()?,
+            caller_image: reader.array()?,
+            target_image: reader.array()?,
+            prev_hash: reader.array()?,
+            hash: reader.array()?,
+        })
+    }
+
+    /// The hash this record should carry, given its other fields.
+    pub fn compute_hash(&self) -> [u8; DIGEST_SIZE] {
+        sha256(&self.to_bytes()[..HASHED_LEN])
+    }
+
+    /// Links the record after the one whose hash is `prev_hash` and fills in `hash`.
+    pub fn seal(&mut self, sequence: u64, prev_hash: [u8; DIGEST_SIZE]) {
+        self.sequence = sequence;
+        self.prev_hash = prev_hash;
+        self.hash = self.compute_hash();
+    }
+}
+
+/// Fits a kernel image name into an `AuditRecord` field, truncating it so the
+/// field always keeps a terminating NUL.
+pub fn image_field(name: &[u8]) -> [u8; AUDIT_IMAGE_SIZE] {
+    let mut field = [0; AUDIT_IMAGE_SIZE];
+    let len = name.len().min(AUDIT_IMAGE_SIZE - 1);
+    field[..len].copy_from_slice(&name[..len]);
+    field
+}
+
+/// The image name stored in an `AuditRecord` field, without its NUL padding.
+pub fn image_name(field: &[u8; AUDIT_IMAGE_SIZE]) -> &[u8] {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Decodes the records following the `Response` of a drain.
+pub fn decode_records(buf: &[u8]) -> Result<Vec<AuditRecord>, ProtocolError> {
+    let extra = buf.len() % AUDIT_RECORD_SIZE;
+    if extra != 0 {
+        return Err(ProtocolError::TrailingBytes(extra));
+    }
+
+    buf.chunks_exact(AUDIT_RECORD_SIZE)
+        .map(AuditRecord::from_bytes)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainError {
+    /// The record's contents do not match its hash.
+    BadHash { sequence: u64 },
+    /// The record does not point at the hash of the one before it.
+    BrokenLink { sequence: u64 },
+    /// Records are missing, or out of order, before this one.
+    Gap { expected: u64, actual: u64 },
+}
+
+impl core::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BadHash { sequence } => {
+                write!(f, "record {sequence} does not match its hash")
+            }
+            Self::BrokenLink { sequence } => {
+                write!(
+                    f,
+                    "record {sequence} does not chain to the record before it"
+                )
+            }
+            Self::Gap { expected, actual } => {
+                write!(f, "expected record {expected}, found record {actual}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for ChainError {}
+
+/// Checks records one at a time as they are drained.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChainVerifier {
+    // sequence and `prev_hash` the next record must carry, once known
+    expected: Option<(u64, [u8; DIGEST_SIZE])>,
+}
+
+impl ChainVerifier {
+    /// Trusts whichever record comes first, for a log whose start was already
+    /// drained or has been overwritten.
+    pub const fn new() -> Self {
+        Self { expected: None }
+    }
+
+    /// Requires the log to start at the first record the driver wrote.
+    pub const fn from_genesis() -> Self {
+        Self::after(u64::MAX, GENESIS_HASH)
+    }
+
+    /// Resumes verification after the record with `sequence` and `hash`, the
+    /// last one a previous drain saw.
+    pub const fn after(sequence: u64, hash: [u8; DIGEST_SIZE]) -> Self {
+        Self {
+            expected: Some((sequence.wrapping_add(1), hash)),
+        }
+    }
+
+    /// Checks `record` against its own hash and against the previous record,
+    /// then makes it the one the next record must follow.
+    pub fn push(&mut self, record: &AuditRecord) -> Result<(), ChainError> {
+        let sequence = record.sequence;
+
+        if record.compute_hash() != record.hash {
+            return Err(ChainError::BadHash { sequence });
+        }
+
+        if let Some((expected, prev_hash)) = self.expected {
+            if sequence != expected {
+                return Err(ChainError::Gap {
+                    expected,
+                    actual: sequence,
+                });
+            }
+
+            if record.prev_hash != prev_hash {
+                return Err(ChainError::BrokenLink { sequence });
+            }
+        }
+
+        *self = Self::after(sequence, record.hash);
+        Ok(())
+    }
+}
+
+/// Verifies that `records` form one unbroken chain.
+pub fn verify_chain(records: &[AuditRecord]) -> Result<(), ChainError> {
+    let mut verifier = ChainVerifier::new();
+    records.iter().try_for_each(|record| verifier.push(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Opcode;
+
+    fn record(address: u64) -> AuditRecord {
+        AuditRecord {
+            sequence: 0,
+            timestamp: 133_000_000_000_000_000,
+            caller_pid: 4242,
+            target_pid: 1234,
+            opcode: Opcode::Read as u16,
+            reserved: 0,
+            status: 0,
+            address,
+            size: 0x100,
+            caller_image: image_field(b"um.exe"),
+            target_image: image_field(b"notepad.exe"),
+            prev_hash: [0; DIGEST_SIZE],
+            hash: [0; DIGEST_SIZE],
+        }
+    }
+
+    fn chain(len: u64) -> Vec<AuditRecord> {
+        let mut prev_hash = GENESIS_HASH;
+        (0..len)
+            .map(|sequence| {
+                let mut record = record(0x1000 * (sequence + 1));
+                record.seal(sequence, prev_hash);
+                prev_hash = record.hash;
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let record = chain(1)[0];
+        let bytes = record.to_bytes();
+
+        assert_eq!(AuditRecord::from_bytes(&bytes), Ok(record));
+        assert_eq!(decode_records(&bytes), Ok(alloc::vec![record]));
+        assert_eq!(
+            decode_records(&bytes[..AUDIT_RECORD_SIZE - 1]),
+            Err(ProtocolError::TrailingBytes(AUDIT_RECORD_SIZE - 1))
+        );
+    }
+
+    #[test]
+    fn image_names_are_truncated_and_trimmed() {
+        assert_eq!(image_name(&image_field(b"lsass.exe")), b"lsass.exe");
+        assert_eq!(
+            image_name(&image_field(b"a_very_long_image_name.exe")),
+            b"a_very_long_ima"
+        );
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let records = chain(5);
+
+        assert_eq!(verify_chain(&records), Ok(()));
+
+        let mut verifier = ChainVerifier::from_genesis();
+        assert!(records.iter().all(|record| verifier.push(record).is_ok()));
+    }
+
+    #[test]
+    fn verification_resumes_across_drains() {
+        let records = chain(6);
+        let (first, second) = records.split_at(3);
+        let last = first[2];
+
+        let mut verifier = ChainVerifier::after(last.sequence, last.hash);
+        assert!(second.iter().all(|record| verifier.push(record).is_ok()));
+
+        let mut verifier = ChainVerifier::after(last.sequence, [0xaa; DIGEST_SIZE]);
+        assert_eq!(
+            verifier.push(&second[0]),
+            Err(ChainError::BrokenLink { sequence: 3 })
+        );
+    }
+
+    #[test]
+    fn edited_record_is_detected() {
+        let mut records = chain(4);
+        records[2].address += 8;
+
+        assert_eq!(
+            verify_chain(&records),
+            Err(ChainError::BadHash { sequence: 2 })
+        );
+    }
+
+    #[test]
+    fn resealed_record_breaks_the_next_link() {
+        let mut records = chain(4);
+        records[1].status = -1;
+        let (sequence, prev_hash) = (records[1].sequence, records[1].prev_hash);
+        records[1].seal(sequence, prev_hash);
+
+        assert_eq!(
+            verify_chain(&records),
+            Err(ChainError::BrokenLink { sequence: 2 })
+        );
+    }
+
+    #[test]
+    fn dropped_record_is_a_gap() {
+        let mut records = chain(4);
+        records.remove(1);
+
+        assert_eq!(
+            verify_chain(&records),
+            Err(ChainError::Gap {
+                expected: 1,
+                actual: 2
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_start_needs_genesis_check() {
+        let records = chain(3);
+
+        assert_eq!(verify_chain(&records[1..]), Ok(()));
+        assert_eq!(
+            ChainVerifier::from_genesis().push(&records[1]),
+            Err(ChainError::Gap {
+                expected: 0,
+                actual: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! Encoding and validation of scatter/gather batch requests.
+//!
+//! A batch payload is a `BatchHeader` followed by `entry_count` `BatchEntry`s.
+//! The output buffer holds an aggregate `Response`, then one `BatchEntryResult`
+//! per entry in request order. A read batch follows them with the data area of
+//! `buffer_len` bytes; a write batch carries its data area in the payload
+//! instead, right after the entries and padded to `PAYLOAD_ALIGN`, so the
+//! driver works from its own copy of it.
+
+use crate::{
+    codec::{ensure_len, Reader},
+    ipc::{BatchEntry, BatchEntryResult, BatchHeader, Response},
+    protocol::{ProtocolError, MAX_TRANSFER_SIZE, PAYLOAD_ALIGN, RESPONSE_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `BatchHeader` on the wire.
+pub const BATCH_HEADER_SIZE: usize = 24;
+
+/// Size of an encoded `BatchEntry` on the wire.
+pub const BATCH_ENTRY_SIZE: usize = 24;
+
+/// Size of an encoded `BatchEntryResult` on the wire.
+pub const BATCH_RESULT_SIZE: usize = 16;
+
+/// Upper bound on entries per batch, which also bounds the reply the driver
+/// has to allocate.
+pub const MAX_BATCH_ENTRIES: u32 = 1024;
+
+impl BatchHeader {
+    pub fn to_bytes(&self) -> [u8; BATCH_HEADER_SIZE] {
+        let mut buf = [0; BATCH_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.entry_count.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.buffer_len.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.create_time.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            process_id: reader.u32()?,
+            entry_count: reader.u32()?,
+            buffer_len: reader.u64()?,
+            create_time: reader.u64()?,
+        })
+    }
+}
+
+impl BatchEntry {
+    pub fn to_bytes(&self) -> [u8; BATCH_ENTRY_SIZE] {
+        let mut buf = [0; BATCH_ENTRY_SIZE];
+        buf[0..8].copy_from_slice(&self.address.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.length.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.offset.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            address: reader.u64()?,
+            length: reader.u32()?,
+            reserved: reader.u32()?,
+            offset: reader.u64()?,
+        })
+    }
+}
+
+impl BatchEntryResult {
+    pub fn to_bytes(&self) -> [u8; BATCH_RESULT_SIZE] {
+        let mut buf = [0; BATCH_RESULT_SIZE];
+        buf[0..4].copy_from_slice(&self.status.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.bytes_transferred.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            status: reader.i32()?,
+            reserved: reader.u32()?,
+            bytes_transferred: reader.u64()?,
+        })
+    }
+}
+
+/// Number of bytes the reply to a batch of `entry_count` entries occupies.
+/// The batch's data area starts right after it.
+pub const fn batch_response_size(entry_count: usize) -> usize {
+    RESPONSE_SIZE + entry_count * BATCH_RESULT_SIZE
+}
+
+/// Size of the whole output buffer of a batch: reply plus data area.
+pub const fn batch_output_size(entry_count: usize, buffer_len: usize) -> usize {
+    batch_response_size(entry_count) + buffer_len
+}
+
+/// Lays out `(address, length)` pairs back to back in one data area,
+/// returning the entries and the total data area size needed.
+pub fn pack_entries(items: &[(u64, u32)]) -> (Vec<BatchEntry>, u64) {
+    let mut offset = 0u64;
+    let entries = items
+        .iter()
+        .map(|&(address, length)| {
+            let entry = BatchEntry {
+                address,
+                length,
+                reserved: 0,
+                offset,
+            };
+            offset += u64::from(length);
+            entry
+        })
+        .collect();
+
+    (entries, offset)
+}
+
+pub fn encode_batch(header: &BatchHeader, entries: &[BatchEntry]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(BATCH_HEADER_SIZE + entries.len() * BATCH_ENTRY_SIZE);
+    buf.extend_from_slice(&header.to_bytes());
+    for entry in entries {
+        buf.extend_from_slice(&entry.to_bytes());
+    }
+    buf
+}
+
+/// Encodes a write batch: the batch, then the data its entries write, padded.
+pub fn encode_write_batch(header: &BatchHeader, entries: &[BatchEntry], data: &[u8]) -> Vec<u8> {
+    let mut buf = encode_batch(header, entries);
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().next_multiple_of(PAYLOAD_ALIGN), 0);
+    buf
+}
+
+fn validate_entry(header: &BatchHeader, entry: &BatchEntry) -> bool {
+    if entry.reserved != 0
+        || entry.length == 0
+        || entry.address.checked_add(u64::from(entry.length)).is_none()
+    {
+        return false;
+    }
+
+    entry
+        .offset
+        .checked_add(u64::from(entry.length))
+        .is_some_and(|end| end <= header.buffer_len)
+}
+
+/// Decodes a batch payload and checks every entry against the header: entries
+/// must be non-empty, must not wrap the address space, and must land inside
+/// the data area, which itself is at most `MAX_TRANSFER_SIZE` bytes.
+pub fn decode_batch(payload: &[u8]) -> Result<(BatchHeader, Vec<BatchEntry>), ProtocolError> {
+    let header = BatchHeader::from_bytes(payload)?;
+
+    if header.entry_count > MAX_BATCH_ENTRIES {
+        return Err(ProtocolError::TooManyEntries {
+            count: header.entry_count,
+            max: MAX_BATCH_ENTRIES,
+        });
+    }
+
+    if header.buffer_len > MAX_TRANSFER_SIZE {
+        return Err(ProtocolError::InvalidField("buffer_len"));
+    }
+
+    let count = header.entry_count as usize;
+    ensure_len(payload, BATCH_HEADER_SIZE + count * BATCH_ENTRY_SIZE)?;
+
+    let mut entries = Vec::with_capacity(count);
+    for (index, chunk) in payload[BATCH_HEADER_SIZE..]
+        .chunks_exact(BATCH_ENTRY_SIZE)
+        .take(count)
+        .enumerate()
+    {
+        let entry = BatchEntry::from_bytes(chunk)?;
+        if !validate_entry(&header, &entry) {
+            return Err(ProtocolError::InvalidEntry {
+                index: index as u32,
+            });
+        }
+        entries.push(entry);
+    }
+
+    Ok((header, entries))
+}
+
+/// Decodes a write batch payload like `decode_batch`, and returns the data area
+/// that follows the entries. Only the padding may come after it.
+pub fn decode_write_batch(
+    payload: &[u8],
+) -> Result<(BatchHeader, Vec<BatchEntry>, &[u8]), ProtocolError> {
+    let (header, entries) = decode_batch(payload)?;
+
+    // `decode_batch` capped both, so this cannot overflow
+    let start = BATCH_HEADER_SIZE + entries.len() * BATCH_ENTRY_SIZE;
+    let end = start + header.buffer_len as usize;
+    ensure_len(payload, end)?;
+
+    let padded = end.next_multiple_of(PAYLOAD_ALIGN);
+    if payload.len() > padded {
+        return Err(ProtocolError::TrailingBytes(payload.len() - padded));
+    }
+
+    Ok((header, entries, &payload[start..end]))
+}
+
+pub fn encode_batch_response(response: &Response, results: &[BatchEntryResult]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(batch_response_size(results.len()));
+    buf.extend_from_slice(&response.to_bytes());
+    for result in results {
+        buf.extend_from_slice(&result.to_bytes());
+    }
+    buf
+}
+
+pub fn decode_batch_response(
+    buf: &[u8],
+    entry_count: usize,
+) -> Result<(Response, Vec<BatchEntryResult>), ProtocolError> {
+    ensure_len(buf, batch_response_size(entry_count))?;
+
+    let response = Response::from_bytes(buf)?;
+    let results = buf[RESPONSE_SIZE..]
+        .chunks_exact(BATCH_RESULT_SIZE)
+        .take(entry_count)
+        .map(BatchEntryResult::from_bytes)
+        .collect::<Result<_, _>>()?;
+
+    Ok((response, results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::{STATUS_PARTIAL_COPY, STATUS_SUCCESS};
+
+    fn header(entry_count: u32, buffer_len: u64) -> BatchHeader {
+        BatchHeader {
+            process_id: 1234,
+            entry_count,
+            buffer_len,
+            create_time: 133_000_000_000_000_000,
+        }
+    }
+
+    #[test]
+    fn pack_entries_lays_out_back_to_back() {
+        let (entries, total) = pack_entries(&[(0x1000, 4), (0x2000, 8), (0x3000, 2)]);
+
+        assert_eq!(total, 14);
+        assert_eq!(
+            entries.iter().map(|e| e.offset).collect::<Vec<_>>(),
+            [0, 4, 12]
+        );
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let (entries, total) = pack_entries(&[(0x1000, 4), (0x2000, 8)]);
+        let header = header(entries.len() as u32, total);
+
+        let (decoded_header, decoded) = decode_batch(&encode_batch(&header, &entries)).unwrap();
+        assert_eq!(decoded_header, header);
+        assert_eq!(decoded, entries);
+    }
+
+    #[test]
+    fn empty_batch_is_valid() {
+        let header = header(0, 0);
+        let (_, entries) = decode_batch(&encode_batch(&header, &[])).unwrap();
+
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn rejects_too_many_entries() {
+        let header = header(MAX_BATCH_ENTRIES + 1, 0);
+
+        assert_eq!(
+            decode_batch(&header.to_bytes()),
+            Err(ProtocolError::TooManyEntries {
+                count: MAX_BATCH_ENTRIES + 1,
+                max: MAX_BATCH_ENTRIES
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_entries() {
+        let (entries, total) = pack_entries(&[(0x1000, 4), (0x2000, 8)]);
+        let buf = encode_batch(&header(3, total), &entries);
+
+        assert!(matches!(
+            decode_batch(&buf),
+            Err(ProtocolError::BufferTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_entry_outside_data_area() {
+        let (entries, total) = pack_entries(&[(0x1000, 4), (0x2000, 8)]);
+        let buf = encode_batch(&header(2, total - 1), &entries);
+
+        assert_eq!(
+            decode_batch(&buf),
+            Err(ProtocolError::InvalidEntry { index: 1 })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_entry() {
+        let (entries, total) = pack_entries(&[(0x1000, 4), (0x2000, 0)]);
+        let buf = encode_batch(&header(2, total), &entries);
+
+        assert_eq!(
+            decode_batch(&buf),
+            Err(ProtocolError::InvalidEntry { index: 1 })
+        );
+    }
+
+    #[test]
+    fn rejects_wrapping_address() {
+        let (entries, total) = pack_entries(&[(u64::MAX - 1, 4)]);
+        let buf = encode_batch(&header(1, total), &entries);
+
+        assert_eq!(
+            decode_batch(&buf),
+            Err(ProtocolError::InvalidEntry { index: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_data_area() {
+        let buf = encode_batch(&header(0, MAX_TRANSFER_SIZE + 1), &[]);
+
+        assert_eq!(
+            decode_batch(&buf),
+            Err(ProtocolError::InvalidField("buffer_len"))
+        );
+    }
+
+    #[test]
+    fn rejects_wrapping_offset() {
+        let entry = BatchEntry {
+            address: 0x1000,
+            length: 8,
+            reserved: 0,
+            offset: u64::MAX - 4,
+        };
+        let buf = encode_batch(&header(1, MAX_TRANSFER_SIZE), &[entry]);
+
+        assert!(decode_batch(&buf).is_err());
+    }
+
+    #[test]
+    fn write_batch_carries_its_data() {
+        let (entries, total) = pack_entries(&[(0x1000, 4), (0x2000, 1)]);
+        let header = header(entries.len() as u32, total);
+        let buf = encode_write_batch(&header, &entries, &[1, 2, 3, 4, 5]);
+        assert_eq!(buf.len() % PAYLOAD_ALIGN, 0);
+
+        let (decoded_header, decoded, data) = decode_write_batch(&buf).unwrap();
+        assert_eq!(decoded_header, header);
+        assert_eq!(decoded, entries);
+        assert_eq!(data, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn write_batch_data_must_fill_the_data_area() {
+        let (entries, total) = pack_entries(&[(0x1000, 16)]);
+        let header = header(1, total);
+
+        let short = encode_write_batch(&header, &entries, &[0; 8]);
+        assert!(matches!(
+            decode_write_batch(&short),
+            Err(ProtocolError::BufferTooSmall { .. })
+        ));
+
+        let long = encode_write_batch(&header, &entries, &[0; 24]);
+        assert_eq!(
+            decode_write_batch(&long),
+            Err(ProtocolError::TrailingBytes(8))
+        );
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let results = [
+            BatchEntryResult {
+                status: STATUS_SUCCESS,
+                reserved: 0,
+                bytes_transferred: 4,
+            },
+            BatchEntryResult {
+                status: 0xC000_0005_u32 as i32,
+                reserved: 0,
+                bytes_transferred: 0,
+            },
+        ];
+        let response = Response::failure(STATUS_PARTIAL_COPY, 4, 1);
+
+        let buf = encode_batch_response(&response, &results);
+        assert_eq!(buf.len(), batch_response_size(results.len()));
+
+        let (decoded, decoded_results) = decode_batch_response(&buf, results.len()).unwrap();
+        assert_eq!(decoded, response);
+        assert_eq!(decoded_results, results);
+    }
+
+    #[test]
+    fn response_rejects_missing_results() {
+        let buf = encode_batch_response(&Response::success(0), &[]);
+
+        assert!(decode_batch_response(&buf, 1).is_err());
+    }
+}
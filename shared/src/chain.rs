@@ -0,0 +1,318 @@
+//! Encoding of pointer-chain reads.
+//!
+//! A chain payload is a `ChainHeader` followed by `offset_count` signed 64-bit
+//! offsets, one per hop. Each hop adds its offset to the current address,
+//! starting from `base`. Every hop but the last then reads a pointer of
+//! `pointer_width` bytes there, which becomes the current address; the last
+//! reads the value. Offsets `[0x10, 0x20, 0x8]` thus read `[[base + 0x10] + 0x20] + 0x8`.
+//!
+//! The output buffer holds the `Response`, then a `ChainReply`, then the
+//! `bytes_transferred` bytes of the value read. If a hop fails, the status is
+//! `STATUS_PARTIAL_COPY` and `failing_offset` is the index of that hop.
+
+use crate::{
+    codec::{ensure_len, Reader},
+    ipc::{ChainHeader, ChainReply, Response},
+    protocol::{ProtocolError, RESPONSE_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `ChainHeader` on the wire.
+pub const CHAIN_HEADER_SIZE: usize = 32;
+
+/// Size of one encoded offset.
+pub const CHAIN_OFFSET_SIZE: usize = 8;
+
+/// Size of an encoded `ChainReply` on the wire.
+pub const CHAIN_REPLY_SIZE: usize = 16;
+
+/// Offset of the value in the output buffer of a chain read.
+pub const CHAIN_VALUE_OFFSET: usize = RESPONSE_SIZE + CHAIN_REPLY_SIZE;
+
+/// Upper bound on the hops of one chain.
+pub const MAX_CHAIN_HOPS: u32 = 64;
+
+/// Largest value a chain reads at its end. To read more, resolve the chain and
+/// read the address it ends at.
+pub const MAX_CHAIN_READ_SIZE: u32 = 0x1000;
+
+impl ChainHeader {
+    pub fn to_bytes(&self) -> [u8; CHAIN_HEADER_SIZE] {
+        let mut buf = [0; CHAIN_HEADER_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.offset_count.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.base.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.create_time.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.pointer_width.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.read_size.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            process_id: reader.u32()?,
+            offset_count: reader.u32()?,
+            base: reader.u64()?,
+            create_time: reader.u64()?,
+            pointer_width: reader.u32()?,
+            read_size: reader.u32()?,
+        })
+    }
+}
+
+impl ChainReply {
+    pub fn to_bytes(&self) -> [u8; CHAIN_REPLY_SIZE] {
+        let mut buf = [0; CHAIN_REPLY_SIZE];
+        buf[0..8].copy_from_slice(&self.address.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.status.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.reserved.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            address: reader.u64()?,
+            status: reader.i32()?,
+            reserved: reader.u32()?,
+        })
+    }
+}
+
+/// Size of the whole output buffer of a chain read: reply plus value.
+pub const fn chain_output_size(read_size: u32) -> usize {
+    CHAIN_VALUE_OFFSET + read_size as usize
+}
+
+pub fn encode_chain(header: &ChainHeader, offsets: &[i64]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHAIN_HEADER_SIZE + offsets.len() * CHAIN_OFFSET_SIZE);
+    buf.extend_from_slice(&header.to_bytes());
+    for offset in offsets {
+        buf.extend_from_slice(&offset.to_le_bytes());
+    }
+    buf
+}
+
+/// Decodes a chain payload: between 1 and `MAX_CHAIN_HOPS` offsets, pointers
+/// of 4 or 8 bytes, and a value of `1..=MAX_CHAIN_READ_SIZE` bytes. Where the
+/// hops lead is only known once the driver follows them.
+pub fn decode_chain(payload: &[u8]) -> Result<(ChainHeader, Vec<i64>), ProtocolError> {
+    let header = ChainHeader::from_bytes(payload)?;
+
+    if header.offset_count > MAX_CHAIN_HOPS {
+        return Err(ProtocolError::TooManyEntries {
+            count: header.offset_count,
+            max: MAX_CHAIN_HOPS,
+        });
+    }
+
+    if header.offset_count == 0 {
+        return Err(ProtocolError::InvalidField("offset_count"));
+    }
+
+    if !matches!(header.pointer_width, 4 | 8) {
+        return Err(ProtocolError::InvalidField("pointer_width"));
+    }
+
+    if header.read_size == 0 || header.read_size > MAX_CHAIN_READ_SIZE {
+        return Err(ProtocolError::InvalidField("read_size"));
+    }
+
+    let mut reader = Reader::new(&payload[CHAIN_HEADER_SIZE..]);
+    let offsets = (0..header.offset_count)
+        .map(|_| reader.u64().map(|offset| offset as i64))
+        .collect::<Result<Vec<_>, _>>()?;
+    reader.finish()?;
+
+    Ok((header, offsets))
+}
+
+pub fn encode_chain_response(response: &Response, reply: &ChainReply) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHAIN_VALUE_OFFSET);
+    buf.extend_from_slice(&response.to_bytes());
+    buf.extend_from_slice(&reply.to_bytes());
+    buf
+}
+
+/// Splits the output of a chain read of `read_size` bytes into the reply and
+/// the part of the value that was read.
+pub fn decode_chain_response(
+    buf: &[u8],
+    read_size: u32,
+) -> Result<(Response, ChainReply, &[u8]), ProtocolError> {
+    ensure_len(buf, CHAIN_VALUE_OFFSET)?;
+
+    let response = Response::from_bytes(&buf[..RESPONSE_SIZE])?;
+    let reply = ChainReply::from_bytes(&buf[RESPONSE_SIZE..CHAIN_VALUE_OFFSET])?;
+
+    if response.bytes_transferred > u64::from(read_size) {
+        return Err(ProtocolError::InvalidField("bytes_transferred"));
+    }
+
+    let value_end = CHAIN_VALUE_OFFSET + response.bytes_transferred as usize;
+    ensure_len(buf, value_end)?;
+
+    Ok((response, reply, &buf[CHAIN_VALUE_OFFSET..value_end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::{STATUS_ACCESS_VIOLATION, STATUS_PARTIAL_COPY, STATUS_SUCCESS};
+
+    fn header(offset_count: u32) -> ChainHeader {
+        ChainHeader {
+            process_id: 1234,
+            offset_count,
+            base: 0x7ff6_0000_0000,
+            create_time: 133_000_000_000_000_000,
+            pointer_width: 8,
+            read_size: 4,
+        }
+    }
+
+    fn chain_error(header: &ChainHeader, offsets: &[i64]) -> ProtocolError {
+        decode_chain(&encode_chain(header, offsets)).unwrap_err()
+    }
+
+    #[test]
+    fn chain_round_trips() {
+        let offsets = [0x10, 0x20, -0x8];
+        let header = header(3);
+
+        let buf = encode_chain(&header, &offsets);
+        assert_eq!(buf.len(), CHAIN_HEADER_SIZE + 3 * CHAIN_OFFSET_SIZE);
+
+        let (decoded_header, decoded) = decode_chain(&buf).unwrap();
+        assert_eq!(decoded_header, header);
+        assert_eq!(decoded, offsets);
+    }
+
+    #[test]
+    fn accepts_32_bit_pointers() {
+        let header = ChainHeader {
+            pointer_width: 4,
+            ..header(1)
+        };
+
+        assert!(decode_chain(&encode_chain(&header, &[0])).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_chain() {
+        assert_eq!(
+            chain_error(&header(0), &[]),
+            ProtocolError::InvalidField("offset_count")
+        );
+    }
+
+    #[test]
+    fn rejects_too_many_hops() {
+        let offsets = [0; MAX_CHAIN_HOPS as usize + 1];
+
+        assert_eq!(
+            chain_error(&header(MAX_CHAIN_HOPS + 1), &offsets),
+            ProtocolError::TooManyEntries {
+                count: MAX_CHAIN_HOPS + 1,
+                max: MAX_CHAIN_HOPS
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_other_pointer_widths() {
+        for pointer_width in [0, 2, 6, 16] {
+            let header = ChainHeader {
+                pointer_width,
+                ..header(1)
+            };
+
+            assert_eq!(
+                chain_error(&header, &[0]),
+                ProtocolError::InvalidField("pointer_width")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_read_size() {
+        for read_size in [0, MAX_CHAIN_READ_SIZE + 1] {
+            let header = ChainHeader {
+                read_size,
+                ..header(1)
+            };
+
+            assert_eq!(
+                chain_error(&header, &[0]),
+                ProtocolError::InvalidField("read_size")
+            );
+        }
+    }
+
+    #[test]
+    fn offsets_must_match_the_count() {
+        assert!(matches!(
+            chain_error(&header(3), &[0, 8]),
+            ProtocolError::BufferTooSmall { .. }
+        ));
+        assert_eq!(
+            chain_error(&header(1), &[0, 8]),
+            ProtocolError::TrailingBytes(CHAIN_OFFSET_SIZE)
+        );
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let response = Response::success(4);
+        let reply = ChainReply {
+            address: 0x2000_0018,
+            status: STATUS_SUCCESS,
+            reserved: 0,
+        };
+
+        let mut buf = encode_chain_response(&response, &reply);
+        assert_eq!(buf.len(), CHAIN_VALUE_OFFSET);
+        buf.extend_from_slice(&[1, 2, 3, 4]);
+
+        let (decoded, decoded_reply, value) = decode_chain_response(&buf, 4).unwrap();
+        assert_eq!(decoded, response);
+        assert_eq!(decoded_reply, reply);
+        assert_eq!(value, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn failed_hop_comes_back_without_a_value() {
+        let response = Response::failure(STATUS_PARTIAL_COPY, 0, 1);
+        let reply = ChainReply {
+            address: 0x20,
+            status: STATUS_ACCESS_VIOLATION,
+            reserved: 0,
+        };
+        let buf = encode_chain_response(&response, &reply);
+
+        let (decoded, decoded_reply, value) = decode_chain_response(&buf, 8).unwrap();
+        assert_eq!(decoded.failing_offset, 1);
+        assert_eq!(decoded_reply.status, STATUS_ACCESS_VIOLATION);
+        assert!(value.is_empty());
+    }
+
+    #[test]
+    fn response_cannot_claim_more_than_was_asked() {
+        let reply = ChainReply {
+            address: 0,
+            status: STATUS_SUCCESS,
+            reserved: 0,
+        };
+        let mut buf = encode_chain_response(&Response::success(8), &reply);
+        buf.extend_from_slice(&[0; 8]);
+
+        assert_eq!(
+            decode_chain_response(&buf, 4),
+            Err(ProtocolError::InvalidField("bytes_transferred"))
+        );
+        assert!(decode_chain_response(&buf[..CHAIN_VALUE_OFFSET + 4], 8).is_err());
+    }
+}
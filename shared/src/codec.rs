@@ -0,0 +1,166 @@
+//! Bounds-checked little-endian decoding for everything that crosses the
+//! user/kernel boundary. Nothing in here indexes past the end of its input or
+//! reinterprets raw memory as a struct.
+
+use crate::protocol::ProtocolError;
+
+/// Cursor over an untrusted byte buffer.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub const fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub const fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(ProtocolError::BufferTooSmall {
+                expected: self.pos.saturating_add(len),
+                actual: self.buf.len(),
+            })?;
+
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ProtocolError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ProtocolError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub fn i32(&mut self) -> Result<i32, ProtocolError> {
+        self.array().map(i32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, ProtocolError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    /// Fails unless every byte of the input has been consumed.
+    pub const fn finish(&self) -> Result<(), ProtocolError> {
+        match self.remaining() {
+            0 => Ok(()),
+            extra => Err(ProtocolError::TrailingBytes(extra)),
+        }
+    }
+}
+
+pub(crate) fn ensure_len(buf: &[u8], expected: usize) -> Result<(), ProtocolError> {
+    if buf.len() < expected {
+        return Err(ProtocolError::BufferTooSmall {
+            expected,
+            actual: buf.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Converts a UTF-16 string, as the kernel keeps names, to UTF-8 in `out`, and
+/// zeroes the rest of `out`. Unpaired surrogates become U+FFFD. If it does not
+/// fit, leading characters are dropped: the end of a path says the most about
+/// it. Returns the number of bytes written.
+pub fn utf16_tail_to_utf8(name: &[u16], out: &mut [u8]) -> usize {
+    let chars = || {
+        char::decode_utf16(name.iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    };
+
+    let mut excess = chars()
+        .map(char::len_utf8)
+        .sum::<usize>()
+        .saturating_sub(out.len());
+
+    let mut len = 0;
+    for c in chars() {
+        if excess > 0 {
+            excess = excess.saturating_sub(c.len_utf8());
+            continue;
+        }
+
+        len += c.encode_utf8(&mut out[len..]).len();
+    }
+
+    out[len..].fill(0);
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_little_endian_fields_in_order() {
+        let buf = [
+            0x34, 0x12, 0x78, 0x56, 0x34, 0x12, 1, 0, 0, 0, 0, 0, 0, 0x80,
+        ];
+        let mut reader = Reader::new(&buf);
+
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.u32(), Ok(0x1234_5678));
+        assert_eq!(reader.u64(), Ok(0x8000_0000_0000_0001));
+        assert_eq!(reader.position(), buf.len());
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn short_read_does_not_advance() {
+        let mut reader = Reader::new(&[1, 2, 3]);
+
+        assert_eq!(
+            reader.u32(),
+            Err(ProtocolError::BufferTooSmall {
+                expected: 4,
+                actual: 3
+            })
+        );
+        assert_eq!(reader.remaining(), 3);
+        assert_eq!(reader.u16(), Ok(0x0201));
+    }
+
+    #[test]
+    fn huge_length_does_not_overflow() {
+        let mut reader = Reader::new(&[0; 8]);
+        reader.u32().unwrap();
+
+        assert!(matches!(
+            reader.bytes(usize::MAX),
+            Err(ProtocolError::BufferTooSmall {
+                expected: usize::MAX,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn finish_reports_trailing_bytes() {
+        let mut reader = Reader::new(&[0; 6]);
+        reader.u32().unwrap();
+
+        assert_eq!(reader.finish(), Err(ProtocolError::TrailingBytes(2)));
+    }
+}
@@ -0,0 +1,342 @@
+//! Process, image-load, and thread events.
+//!
+//! The driver's notify routines queue events for every handle that asked for
+//! them. A client collects them with `EREBUS_IOCTL_GET_EVENTS`, which completes
+//! right away if events are queued, and otherwise stays pending until one
+//! arrives or the request is cancelled. The output buffer holds the `Response`,
+//! an `EventsReply`, then `event_count` `EventRecord`s.
+//!
+//! Process events are reported for every process. Image and thread events are
+//! limited to the attached process, if the handle's session has one.
+
+use crate::{
+    codec::{ensure_len, utf16_tail_to_utf8, Reader},
+    ipc::{EventRecord, EventsReply, EventsRequest},
+    protocol::{ProtocolError, RESPONSE_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `EventsRequest` on the wire.
+pub const EVENTS_REQUEST_SIZE: usize = 8;
+
+/// Size of an encoded `EventsReply` on the wire.
+pub const EVENTS_REPLY_SIZE: usize = 8;
+
+/// Size of an encoded `EventRecord` on the wire.
+pub const EVENT_RECORD_SIZE: usize = 312;
+
+/// Size of `EventRecord::name`.
+pub const EVENT_NAME_SIZE: usize = 256;
+
+/// Most events one request hands out, however large its output buffer.
+pub const MAX_EVENTS_PER_REPLY: usize = 256;
+
+/// Offset of the first `EventRecord` in the output buffer.
+pub const EVENTS_OFFSET: usize = RESPONSE_SIZE + EVENTS_REPLY_SIZE;
+
+/* EVENT MASK */
+
+// process creation and exit
+pub const EVENT_MASK_PROCESS: u32 = 1 << 0;
+
+// images mapped into a process
+pub const EVENT_MASK_IMAGE: u32 = 1 << 1;
+
+// thread creation and exit
+pub const EVENT_MASK_THREAD: u32 = 1 << 2;
+
+pub const EVENT_MASK_ALL: u32 = EVENT_MASK_PROCESS | EVENT_MASK_IMAGE | EVENT_MASK_THREAD;
+
+/// Size of the output buffer of a request with room for `max_events` events.
+pub const fn events_output_size(max_events: usize) -> usize {
+    EVENTS_OFFSET + max_events * EVENT_RECORD_SIZE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum EventKind {
+    ProcessCreate = 1,
+    ProcessExit = 2,
+    ImageLoad = 3,
+    ThreadCreate = 4,
+    ThreadExit = 5,
+    /// The queue was full; `related_id` events were dropped before this one.
+    Lost = 6,
+}
+
+impl EventKind {
+    /// The `EVENT_MASK_*` bit that selects this kind. Losses are always reported.
+    pub const fn mask(self) -> u32 {
+        match self {
+            Self::ProcessCreate | Self::ProcessExit => EVENT_MASK_PROCESS,
+            Self::ImageLoad => EVENT_MASK_IMAGE,
+            Self::ThreadCreate | Self::ThreadExit => EVENT_MASK_THREAD,
+            Self::Lost => EVENT_MASK_ALL,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::ProcessCreate => "process-create",
+            Self::ProcessExit => "process-exit",
+            Self::ImageLoad => "image-load",
+            Self::ThreadCreate => "thread-create",
+            Self::ThreadExit => "thread-exit",
+            Self::Lost => "lost",
+        }
+    }
+}
+
+impl TryFrom<u16> for EventKind {
+    type Error = ProtocolError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::ProcessCreate),
+            2 => Ok(Self::ProcessExit),
+            3 => Ok(Self::ImageLoad),
+            4 => Ok(Self::ThreadCreate),
+            5 => Ok(Self::ThreadExit),
+            6 => Ok(Self::Lost),
+            _ => Err(ProtocolError::InvalidField("kind")),
+        }
+    }
+}
+
+impl EventsRequest {
+    pub fn to_bytes(&self) -> [u8; EVENTS_REQUEST_SIZE] {
+        let mut buf = [0; EVENTS_REQUEST_SIZE];
+        buf[0..4].copy_from_slice(&self.mask.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let request = Self {
+            mask: reader.u32()?,
+            reserved: reader.u32()?,
+        };
+        reader.finish()?;
+
+        if request.mask == 0 || request.mask & !EVENT_MASK_ALL != 0 {
+            return Err(ProtocolError::InvalidField("mask"));
+        }
+
+        if request.reserved != 0 {
+            return Err(ProtocolError::InvalidField("reserved"));
+        }
+
+        Ok(request)
+    }
+}
+
+impl EventsReply {
+    pub fn to_bytes(&self) -> [u8; EVENTS_REPLY_SIZE] {
+        let mut buf = [0; EVENTS_REPLY_SIZE];
+        buf[0..4].copy_from_slice(&self.event_count.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            event_count: reader.u32()?,
+            reserved: reader.u32()?,
+        })
+    }
+}
+
+impl EventRecord {
+    /// An event with no sequence number, image, or name yet.
+    pub const fn new(kind: EventKind, timestamp: u64, process_id: u32, related_id: u32) -> Self {
+        Self {
+            sequence: 0,
+            timestamp,
+            kind: kind as u16,
+            name_len: 0,
+            process_id,
+            related_id,
+            reserved: 0,
+            create_time: 0,
+            image_base: 0,
+            image_size: 0,
+            name: [0; EVENT_NAME_SIZE],
+        }
+    }
+
+    /// The kind, which `from_bytes` has already checked.
+    pub fn kind(&self) -> Result<EventKind, ProtocolError> {
+        EventKind::try_from(self.kind)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..(self.name_len as usize).min(EVENT_NAME_SIZE)]
+    }
+
+    /// Stores a name as the kernel keeps it, see `utf16_tail_to_utf8`.
+    pub fn set_name_utf16(&mut self, name: &[u16]) {
+        self.name_len = utf16_tail_to_utf8(name, &mut self.name) as u16;
+    }
+
+    /// Stores a name that is already UTF-8, or close enough, like
+    /// `EPROCESS::ImageFileName`. Names too long to fit keep their start.
+    pub fn set_name(&mut self, name: &[u8]) {
+        let len = name.len().min(EVENT_NAME_SIZE);
+        self.name[..len].copy_from_slice(&name[..len]);
+        self.name[len..].fill(0);
+        self.name_len = len as u16;
+    }
+
+    pub fn to_bytes(&self) -> [u8; EVENT_RECORD_SIZE] {
+        let mut buf = [0; EVENT_RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[16..18].copy_from_slice(&self.kind.to_le_bytes());
+        buf[18..20].copy_from_slice(&self.name_len.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.related_id.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.create_time.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.image_base.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.image_size.to_le_bytes());
+        buf[56..312].copy_from_slice(&self.name);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let record = Self {
+            sequence: reader.u64()?,
+            timestamp: reader.u64()?,
+            kind: reader.u16()?,
+            name_len: reader.u16()?,
+            process_id: reader.u32()?,
+            related_id: reader.u32()?,
+            reserved: reader.u32()?,
+            create_time: reader.u64()?,
+            image_base: reader.u64()?,
+            image_size: reader.u64()?,
+            name: reader.array()?,
+        };
+
+        record.kind()?;
+
+        if record.name_len as usize > EVENT_NAME_SIZE {
+            return Err(ProtocolError::InvalidField("name_len"));
+        }
+
+        Ok(record)
+    }
+}
+
+/// Decodes the output buffer of `EREBUS_IOCTL_GET_EVENTS`.
+pub fn decode_events(buf: &[u8]) -> Result<Vec<EventRecord>, ProtocolError> {
+    ensure_len(buf, EVENTS_OFFSET)?;
+
+    let reply = EventsReply::from_bytes(&buf[RESPONSE_SIZE..])?;
+    if reply.event_count as usize > MAX_EVENTS_PER_REPLY {
+        return Err(ProtocolError::InvalidField("event_count"));
+    }
+
+    let count = reply.event_count as usize;
+    ensure_len(&buf[EVENTS_OFFSET..], count * EVENT_RECORD_SIZE)?;
+
+    buf[EVENTS_OFFSET..]
+        .chunks_exact(EVENT_RECORD_SIZE)
+        .take(count)
+        .map(EventRecord::from_bytes)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn events_round_trip() {
+        let mut created = EventRecord::new(EventKind::ProcessCreate, 1000, 4312, 1200);
+        created.sequence = 7;
+        created.create_time = 133_000_000_000_000_000;
+        created.set_name(b"game.exe");
+
+        let mut loaded = EventRecord::new(EventKind::ImageLoad, 1001, 4312, 0);
+        loaded.sequence = 8;
+        loaded.image_base = 0x7ffd_1000_0000;
+        loaded.image_size = 0x1f_8000;
+        loaded.set_name_utf16(
+            &"\\Windows\\System32\\ntdll.dll"
+                .encode_utf16()
+                .collect::<Vec<_>>(),
+        );
+
+        let reply = EventsReply {
+            event_count: 2,
+            reserved: 0,
+        };
+
+        let mut buf = vec![0; RESPONSE_SIZE];
+        buf.extend_from_slice(&reply.to_bytes());
+        buf.extend_from_slice(&created.to_bytes());
+        buf.extend_from_slice(&loaded.to_bytes());
+        assert_eq!(buf.len(), events_output_size(2));
+
+        let events = decode_events(&buf).unwrap();
+        assert_eq!(events, vec![created, loaded]);
+        assert_eq!(events[0].kind(), Ok(EventKind::ProcessCreate));
+        assert_eq!(events[1].name(), b"\\Windows\\System32\\ntdll.dll");
+    }
+
+    #[test]
+    fn unknown_kinds_are_refused() {
+        let mut record = EventRecord::new(EventKind::ThreadExit, 0, 4, 8);
+        record.kind = 0;
+
+        assert_eq!(
+            EventRecord::from_bytes(&record.to_bytes()),
+            Err(ProtocolError::InvalidField("kind"))
+        );
+    }
+
+    #[test]
+    fn requests_must_ask_for_known_events() {
+        for mask in [0, 1 << 3, EVENT_MASK_ALL | 1 << 31] {
+            let request = EventsRequest { mask, reserved: 0 };
+            assert_eq!(
+                EventsRequest::from_bytes(&request.to_bytes()),
+                Err(ProtocolError::InvalidField("mask")),
+                "{mask:#x}"
+            );
+        }
+
+        let request = EventsRequest {
+            mask: EVENT_MASK_PROCESS | EVENT_MASK_IMAGE,
+            reserved: 0,
+        };
+        assert_eq!(EventsRequest::from_bytes(&request.to_bytes()), Ok(request));
+    }
+
+    #[test]
+    fn losses_are_always_selected() {
+        for mask in [EVENT_MASK_PROCESS, EVENT_MASK_IMAGE, EVENT_MASK_THREAD] {
+            assert_ne!(EventKind::Lost.mask() & mask, 0);
+        }
+        assert_eq!(EventKind::ImageLoad.mask() & EVENT_MASK_PROCESS, 0);
+    }
+
+    #[test]
+    fn long_names_keep_their_start() {
+        let mut record = EventRecord::new(EventKind::ProcessCreate, 0, 4, 0);
+        record.set_name(&[b'a'; EVENT_NAME_SIZE + 10]);
+        assert_eq!(record.name().len(), EVENT_NAME_SIZE);
+
+        record.set_name(b"short.exe");
+        assert_eq!(record.name(), b"short.exe");
+        assert!(record.name.iter().skip(9).all(|&byte| byte == 0));
+    }
+}
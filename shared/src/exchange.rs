@@ -0,0 +1,211 @@
+//! Encoding of compare-exchanges.
+//!
+//! The payload is an `ExchangeRequest`. The driver replaces the `size`-byte
+//! value at `address` with `new` if it equals `expected`, in one locked
+//! instruction on the target's memory, so it is atomic against the target's
+//! own threads too. That limits `size` to 1, 2, 4, or 8 bytes, and `address`
+//! to a multiple of it. Values are zero-extended to 64 bits; their low bytes
+//! are the value as it sits in memory.
+//!
+//! The output buffer holds the `Response`, then an `ExchangeReply` with the
+//! value found. On a mismatch nothing is written, the status is
+//! `STATUS_COMPARE_MISMATCH`, and `failing_offset` is the first byte that
+//! differed.
+
+use crate::{
+    codec::{ensure_len, Reader},
+    ipc::{ExchangeReply, ExchangeRequest, Response},
+    protocol::{ProtocolError, RESPONSE_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `ExchangeRequest` on the wire.
+pub const EXCHANGE_REQUEST_SIZE: usize = 40;
+
+/// Size of an encoded `ExchangeReply` on the wire.
+pub const EXCHANGE_REPLY_SIZE: usize = 8;
+
+/// Size of the whole output buffer of a compare-exchange.
+pub const EXCHANGE_OUTPUT_SIZE: usize = RESPONSE_SIZE + EXCHANGE_REPLY_SIZE;
+
+impl ExchangeRequest {
+    pub fn to_bytes(&self) -> [u8; EXCHANGE_REQUEST_SIZE] {
+        let mut buf = [0; EXCHANGE_REQUEST_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.size.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.address.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.create_time.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.expected.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.new.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            process_id: reader.u32()?,
+            size: reader.u32()?,
+            address: reader.u64()?,
+            create_time: reader.u64()?,
+            expected: reader.u64()?,
+            new: reader.u64()?,
+        })
+    }
+}
+
+impl ExchangeReply {
+    pub fn to_bytes(&self) -> [u8; EXCHANGE_REPLY_SIZE] {
+        self.observed.to_le_bytes()
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            observed: reader.u64()?,
+        })
+    }
+}
+
+/// Decodes a compare-exchange payload: a value of 1, 2, 4, or 8 bytes at an
+/// address aligned to its size, with operands that fit in it.
+pub fn decode_exchange(payload: &[u8]) -> Result<ExchangeRequest, ProtocolError> {
+    let mut reader = Reader::new(payload);
+    let request = ExchangeRequest::from_bytes(reader.bytes(EXCHANGE_REQUEST_SIZE)?)?;
+    reader.finish()?;
+
+    if !matches!(request.size, 1 | 2 | 4 | 8) {
+        return Err(ProtocolError::InvalidField("size"));
+    }
+
+    if !request.address.is_multiple_of(u64::from(request.size)) {
+        return Err(ProtocolError::InvalidField("address"));
+    }
+
+    let bits = request.size * 8;
+    if bits < 64 && (request.expected >> bits != 0) {
+        return Err(ProtocolError::InvalidField("expected"));
+    }
+    if bits < 64 && (request.new >> bits != 0) {
+        return Err(ProtocolError::InvalidField("new"));
+    }
+
+    Ok(request)
+}
+
+/// The index of the lowest byte in which two values differ, if any.
+pub const fn first_difference(a: u64, b: u64) -> Option<u64> {
+    if a == b {
+        None
+    } else {
+        Some(((a ^ b).trailing_zeros() / 8) as u64)
+    }
+}
+
+pub fn encode_exchange_response(response: &Response, reply: &ExchangeReply) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(EXCHANGE_OUTPUT_SIZE);
+    buf.extend_from_slice(&response.to_bytes());
+    buf.extend_from_slice(&reply.to_bytes());
+    buf
+}
+
+/// Splits the output of a compare-exchange into the response and the value
+/// the driver found.
+pub fn decode_exchange_response(buf: &[u8]) -> Result<(Response, ExchangeReply), ProtocolError> {
+    ensure_len(buf, EXCHANGE_OUTPUT_SIZE)?;
+
+    let response = Response::from_bytes(&buf[..RESPONSE_SIZE])?;
+    let reply = ExchangeReply::from_bytes(&buf[RESPONSE_SIZE..EXCHANGE_OUTPUT_SIZE])?;
+
+    Ok((response, reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::STATUS_COMPARE_MISMATCH;
+
+    fn request(size: u32, address: u64, expected: u64, new: u64) -> ExchangeRequest {
+        ExchangeRequest {
+            process_id: 1234,
+            size,
+            address,
+            create_time: 133_000_000_000_000_000,
+            expected,
+            new,
+        }
+    }
+
+    fn exchange_error(request: &ExchangeRequest) -> ProtocolError {
+        decode_exchange(&request.to_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn request_round_trips() {
+        for size in [1, 2, 4, 8] {
+            let request = request(size, 0x7ff6_0000_1000, 1, 0);
+            assert_eq!(decode_exchange(&request.to_bytes()), Ok(request));
+        }
+
+        let full = request(8, 0x1000, u64::MAX, u64::MAX - 1);
+        assert_eq!(decode_exchange(&full.to_bytes()), Ok(full));
+    }
+
+    #[test]
+    fn rejects_other_sizes() {
+        for size in [0, 3, 16, 0x1000] {
+            assert_eq!(
+                exchange_error(&request(size, 0x1000, 0, 0)),
+                ProtocolError::InvalidField("size")
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_misaligned_addresses() {
+        assert_eq!(
+            exchange_error(&request(4, 0x1002, 0, 0)),
+            ProtocolError::InvalidField("address")
+        );
+        assert!(decode_exchange(&request(2, 0x1002, 0, 0).to_bytes()).is_ok());
+    }
+
+    #[test]
+    fn rejects_operands_wider_than_the_value() {
+        assert_eq!(
+            exchange_error(&request(2, 0x1000, 0x1_0000, 0)),
+            ProtocolError::InvalidField("expected")
+        );
+        assert_eq!(
+            exchange_error(&request(1, 0x1000, 0, 0x100)),
+            ProtocolError::InvalidField("new")
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut buf = request(8, 0x1000, 0, 0).to_bytes().to_vec();
+        buf.extend_from_slice(&[0; 8]);
+
+        assert_eq!(decode_exchange(&buf), Err(ProtocolError::TrailingBytes(8)));
+    }
+
+    #[test]
+    fn finds_the_first_differing_byte() {
+        assert_eq!(first_difference(0x1234, 0x1234), None);
+        assert_eq!(first_difference(0x1234, 0x1235), Some(0));
+        assert_eq!(first_difference(0x0100_0000, 0), Some(3));
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let response = Response::failure(STATUS_COMPARE_MISMATCH, 0, 1);
+        let reply = ExchangeReply { observed: 0x0200 };
+
+        let buf = encode_exchange_response(&response, &reply);
+        assert_eq!(buf.len(), EXCHANGE_OUTPUT_SIZE);
+        assert_eq!(decode_exchange_response(&buf), Ok((response, reply)));
+        assert!(decode_exchange_response(&buf[..RESPONSE_SIZE]).is_err());
+    }
+}
@@ -1,9 +1,32 @@
 extern crate alloc;
 
+use crate::{
+    batch::{batch_response_size, encode_batch, encode_write_batch, BATCH_HEADER_SIZE},
+    chain::{encode_chain, CHAIN_HEADER_SIZE, CHAIN_OFFSET_SIZE, CHAIN_VALUE_OFFSET},
+    events::{events_output_size, EVENTS_REQUEST_SIZE},
+    exchange::{EXCHANGE_OUTPUT_SIZE, EXCHANGE_REQUEST_SIZE},
+    ipc::{
+        AttachRequest, AttachResponse, BatchEntry, BatchHeader, ChainHeader, EventsRequest,
+        ExchangeRequest, HelloResponse, ModuleQuery, RegionQuery, Request, Response,
+    },
+    modules::{modules_output_size, MODULE_QUERY_SIZE},
+    processes::PROCESSES_OFFSET,
+    protocol::{
+        Opcode, ProtocolError, ATTACH_REQUEST_SIZE, ATTACH_RESPONSE_SIZE, DATA_OFFSET, HEADER_SIZE,
+        HELLO_RESPONSE_SIZE, REQUEST_SIZE, RESPONSE_SIZE,
+    },
+    regions::{regions_output_size, REGION_QUERY_SIZE},
+};
+use alloc::vec::Vec;
+
 const FILE_DEVICE_UNKNOWN: u32 = 34u32;
 // const METHOD_NEITHER: u32 = 3u32;
 const METHOD_BUFFERED: u32 = 0u32;
+const METHOD_IN_DIRECT: u32 = 1u32;
+const METHOD_OUT_DIRECT: u32 = 2u32;
 const FILE_ANY_ACCESS: u32 = 0u32;
+const FILE_READ_ACCESS: u32 = 1u32;
+const FILE_WRITE_ACCESS: u32 = 2u32;
 
 macro_rules! ctl_code {
     ($DeviceType:expr, $Function:expr, $Method:expr, $Access:expr) => {
@@ -11,12 +34,584 @@ macro_rules! ctl_code {
     };
 }
 
+/// One IOCTL of the driver, as declared in the table below. Each has a marker
+/// type, named after its `Opcode`, that implements this.
+pub trait IoctlSpec {
+    const NAME: &'static str;
+    const OPCODE: Opcode;
+    const CODE: u32;
+    const FUNCTION: u32;
+    const METHOD: u32;
+    /// Access bits the I/O manager requires on the handle.
+    const ACCESS: u32;
+    /// Smallest input the driver accepts: the request header and the fixed part
+    /// of the payload.
+    const MIN_INPUT: usize;
+    /// Smallest output the driver accepts, which holds at least the response.
+    /// For `METHOD_IN_DIRECT` the output is data the driver only reads.
+    const MIN_OUTPUT: usize;
+
+    /// What follows the request header in the input.
+    type Request: Payload;
+    /// What the output starts with, or `()` for an IOCTL answered through the
+    /// I/O status block alone.
+    type Response: Reply;
+}
+
+/// A request payload.
+pub trait Payload {
+    fn encode(&self) -> Vec<u8>;
+}
+
+/// The response an output buffer starts with.
+pub trait Reply: Sized {
+    fn decode(buf: &[u8]) -> Result<Self, ProtocolError>;
+}
+
+impl Payload for () {
+    fn encode(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// A batch: its header, and the entries that follow it.
+impl Payload for (BatchHeader, Vec<BatchEntry>) {
+    fn encode(&self) -> Vec<u8> {
+        encode_batch(&self.0, &self.1)
+    }
+}
+
+/// A write batch: its header, its entries, and the data they write.
+impl Payload for (BatchHeader, Vec<BatchEntry>, Vec<u8>) {
+    fn encode(&self) -> Vec<u8> {
+        encode_write_batch(&self.0, &self.1, &self.2)
+    }
+}
+
+/// A pointer chain: its header, and the offset of every hop.
+impl Payload for (ChainHeader, Vec<i64>) {
+    fn encode(&self) -> Vec<u8> {
+        encode_chain(&self.0, &self.1)
+    }
+}
+
+macro_rules! payloads {
+    ($($payload:ty),*) => {
+        $(
+            impl Payload for $payload {
+                fn encode(&self) -> Vec<u8> {
+                    self.to_bytes().to_vec()
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! replies {
+    ($($reply:ty),*) => {
+        $(
+            impl Reply for $reply {
+                fn decode(buf: &[u8]) -> Result<Self, ProtocolError> {
+                    Self::from_bytes(buf)
+                }
+            }
+        )*
+    };
+}
+
+payloads!(
+    Request,
+    AttachRequest,
+    RegionQuery,
+    ModuleQuery,
+    EventsRequest,
+    ExchangeRequest
+);
+replies!(Response, HelloResponse, AttachResponse);
+
+/// The reply of a `METHOD_IN_DIRECT` IOCTL, which has no output to carry one:
+/// its status and `IoStatus.Information` say how it went.
+impl Reply for () {
+    fn decode(_: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(())
+    }
+}
+
+/// What the table declares about an IOCTL, for code that walks all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoctlInfo {
+    pub name: &'static str,
+    pub opcode: Opcode,
+    pub code: u32,
+    pub function: u32,
+    pub method: u32,
+    pub access: u32,
+    pub min_input: usize,
+    pub min_output: usize,
+}
+
+impl IoctlInfo {
+    pub const fn of<I: IoctlSpec>() -> Self {
+        Self {
+            name: I::NAME,
+            opcode: I::OPCODE,
+            code: I::CODE,
+            function: I::FUNCTION,
+            method: I::METHOD,
+            access: I::ACCESS,
+            min_input: I::MIN_INPUT,
+            min_output: I::MIN_OUTPUT,
+        }
+    }
+}
+
+/// Declares the IOCTLs: each gets its `EREBUS_IOCTL_*` code, a marker type
+/// implementing `IoctlSpec`, and an entry in `IOCTLS`. `for_each_ioctl!` hands
+/// the marker types to the driver's dispatcher. The leading `$` lets this define
+/// that macro.
+macro_rules! ioctls {
+    ($d:tt $(
+        $(#[doc = $doc:literal])*
+        $code:ident = $marker:ident {
+            function: $function:expr,
+            method: $method:expr,
+            access: $access:expr,
+            request: $request:ty,
+            response: $response:ty,
+            min_input: $min_input:expr,
+            min_output: $min_output:expr $(,)?
+        }
+    )*) => {
+        $(
+            $(#[doc = $doc])*
+            pub const $code: u32 = ctl_code!(FILE_DEVICE_UNKNOWN, $function, $method, $access);
+
+            $(#[doc = $doc])*
+            #[derive(Debug)]
+            pub struct $marker;
+
+            impl IoctlSpec for $marker {
+                const NAME: &'static str = stringify!($code);
+                const OPCODE: Opcode = Opcode::$marker;
+                const CODE: u32 = $code;
+                const FUNCTION: u32 = $function;
+                const METHOD: u32 = $method;
+                const ACCESS: u32 = $access;
+                const MIN_INPUT: usize = $min_input;
+                const MIN_OUTPUT: usize = $min_output;
+
+                type Request = $request;
+                type Response = $response;
+            }
+        )*
+
+        /// Every IOCTL of the driver, in declaration order.
+        pub const IOCTLS: &[IoctlInfo] = &[$(IoctlInfo::of::<$marker>()),*];
+
+        /// Invokes `$callback!` with the marker type of every IOCTL, by name, e.g.
+        /// `$callback! { Hello, Read, ... }`. The callback names them through
+        /// `shared::ioctl`.
+        #[macro_export]
+        macro_rules! for_each_ioctl {
+            ($d callback:ident) => {
+                $d callback! { $($marker),* }
+            };
+        }
+    };
+}
+
 /* IOCTL CODES */
 
-// read from process memory
-pub const EREBUS_IOCTL_READ: u32 =
-    ctl_code!(FILE_DEVICE_UNKNOWN, 0x1, METHOD_BUFFERED, FILE_ANY_ACCESS);
+// Memory operations use METHOD_OUT_DIRECT: the request travels in the input
+// buffer, and the reply and any data read go into the output buffer's MDL.
+// Data to write must never sit in that MDL, which the I/O manager probes for
+// write access and the driver may write to. `WRITE` uses METHOD_IN_DIRECT
+// instead, with the data in an MDL probed for reading only, and replies through
+// the I/O status block alone. `WRITE_BATCH` and `COMPARE_EXCHANGE` reply in
+// their output, so they carry their data in the buffered input, which the
+// driver gets as its own copy.
+//
+// The I/O manager checks the access bits against the handle before the driver
+// ever sees the IRP: reads need a handle opened for reading, writes one opened
+// for writing. Only `HELLO` works on any handle.
+
+ioctls! {
+    $
+
+    /// Queries the protocol version and capabilities. The input is ignored, so a
+    /// client of any version can ask.
+    EREBUS_IOCTL_HELLO = Hello {
+        function: 0x0,
+        method: METHOD_BUFFERED,
+        access: FILE_ANY_ACCESS,
+        request: (),
+        response: HelloResponse,
+        min_input: 0,
+        min_output: HELLO_RESPONSE_SIZE,
+    }
+
+    /// Reads process memory.
+    EREBUS_IOCTL_READ = Read {
+        function: 0x1,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: Request,
+        response: Response,
+        min_input: HEADER_SIZE + REQUEST_SIZE,
+        min_output: DATA_OFFSET,
+    }
+
+    /// Writes process memory. The output buffer holds the data to write, and
+    /// `IoStatus.Information` says how much of it was written.
+    EREBUS_IOCTL_WRITE = Write {
+        function: 0x2,
+        method: METHOD_IN_DIRECT,
+        access: FILE_WRITE_ACCESS,
+        request: Request,
+        response: (),
+        min_input: HEADER_SIZE + REQUEST_SIZE,
+        min_output: 0,
+    }
 
-// write to process memory
-pub const EREBUS_IOCTL_WRITE: u32 =
-    ctl_code!(FILE_DEVICE_UNKNOWN, 0x2, METHOD_BUFFERED, FILE_ANY_ACCESS);
+    /// Scatter/gather read from process memory.
+    EREBUS_IOCTL_READ_BATCH = ReadBatch {
+        function: 0x3,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: (BatchHeader, Vec<BatchEntry>),
+        response: Response,
+        min_input: HEADER_SIZE + BATCH_HEADER_SIZE,
+        min_output: batch_response_size(0),
+    }
+
+    /// Scatter/gather write to process memory. The data follows the entries in
+    /// the input.
+    EREBUS_IOCTL_WRITE_BATCH = WriteBatch {
+        function: 0x4,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_WRITE_ACCESS,
+        request: (BatchHeader, Vec<BatchEntry>, Vec<u8>),
+        response: Response,
+        min_input: HEADER_SIZE + BATCH_HEADER_SIZE,
+        min_output: batch_response_size(0),
+    }
+
+    /// Re-reads the driver policy from the registry.
+    EREBUS_IOCTL_RELOAD_POLICY = ReloadPolicy {
+        function: 0x5,
+        method: METHOD_BUFFERED,
+        access: FILE_WRITE_ACCESS,
+        request: (),
+        response: Response,
+        min_input: HEADER_SIZE,
+        min_output: RESPONSE_SIZE,
+    }
+
+    /// Drains the audit log. Draining discards records, so it takes both access
+    /// bits.
+    EREBUS_IOCTL_DRAIN_AUDIT = DrainAudit {
+        function: 0x6,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS | FILE_WRITE_ACCESS,
+        request: (),
+        response: Response,
+        min_input: HEADER_SIZE,
+        min_output: DATA_OFFSET,
+    }
+
+    /// Binds the device handle's session to a target process.
+    EREBUS_IOCTL_ATTACH = Attach {
+        function: 0x7,
+        method: METHOD_BUFFERED,
+        access: FILE_READ_ACCESS,
+        request: AttachRequest,
+        response: AttachResponse,
+        min_input: HEADER_SIZE + ATTACH_REQUEST_SIZE,
+        min_output: ATTACH_RESPONSE_SIZE,
+    }
+
+    /// Reads process memory page by page, zero-filling pages that cannot be read.
+    EREBUS_IOCTL_READ_LOSSY = ReadLossy {
+        function: 0x8,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: Request,
+        response: Response,
+        min_input: HEADER_SIZE + REQUEST_SIZE,
+        min_output: DATA_OFFSET,
+    }
+
+    /// Enumerates the regions of a process's address space.
+    EREBUS_IOCTL_QUERY_REGIONS = QueryRegions {
+        function: 0x9,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: RegionQuery,
+        response: Response,
+        min_input: HEADER_SIZE + REGION_QUERY_SIZE,
+        min_output: regions_output_size(1),
+    }
+
+    /// Lists the modules loaded into a process.
+    EREBUS_IOCTL_QUERY_MODULES = QueryModules {
+        function: 0xA,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: ModuleQuery,
+        response: Response,
+        min_input: HEADER_SIZE + MODULE_QUERY_SIZE,
+        min_output: modules_output_size(0),
+    }
+
+    /// Lists the running processes.
+    EREBUS_IOCTL_LIST_PROCESSES = ListProcesses {
+        function: 0xB,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: (),
+        response: Response,
+        min_input: HEADER_SIZE,
+        min_output: PROCESSES_OFFSET,
+    }
+
+    /// Waits for process, image, and thread events; pends until one is queued.
+    EREBUS_IOCTL_GET_EVENTS = GetEvents {
+        function: 0xC,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: EventsRequest,
+        response: Response,
+        min_input: HEADER_SIZE + EVENTS_REQUEST_SIZE,
+        min_output: events_output_size(1),
+    }
+
+    /// Follows a pointer chain in process memory and reads the value it ends at.
+    EREBUS_IOCTL_READ_CHAIN = ReadChain {
+        function: 0xD,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_READ_ACCESS,
+        request: (ChainHeader, Vec<i64>),
+        response: Response,
+        min_input: HEADER_SIZE + CHAIN_HEADER_SIZE + CHAIN_OFFSET_SIZE,
+        min_output: CHAIN_VALUE_OFFSET,
+    }
+
+    /// Atomically replaces a value in process memory if it still holds the
+    /// expected one.
+    EREBUS_IOCTL_COMPARE_EXCHANGE = CompareExchange {
+        function: 0xE,
+        method: METHOD_OUT_DIRECT,
+        access: FILE_WRITE_ACCESS,
+        request: ExchangeRequest,
+        response: Response,
+        min_input: HEADER_SIZE + EXCHANGE_REQUEST_SIZE,
+        min_output: EXCHANGE_OUTPUT_SIZE,
+    }
+}
+
+/// Device type `code` is for.
+pub const fn device_type(code: u32) -> u32 {
+    code >> 16
+}
+
+/// Access bits the I/O manager requires on the handle for `code`.
+pub const fn required_access(code: u32) -> u32 {
+    (code >> 14) & 0b11
+}
+
+/// Function number of `code`, which for our IOCTLs is the opcode.
+pub const fn function(code: u32) -> u32 {
+    (code >> 2) & 0xFFF
+}
+
+/// Buffering method of `code`.
+pub const fn method(code: u32) -> u32 {
+    code & 0b11
+}
+
+/// Whether the output buffer of `code` is data the driver only reads, probed
+/// by the I/O manager for reading alone.
+pub const fn is_in_direct(code: u32) -> bool {
+    method(code) == METHOD_IN_DIRECT
+}
+
+/// The IOCTL `code` stands for, if it is one of ours.
+pub fn lookup(code: u32) -> Option<&'static IoctlInfo> {
+    IOCTLS.iter().find(|ioctl| ioctl.code == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeSet;
+
+    #[test]
+    fn memory_ioctls_require_matching_access() {
+        assert_eq!(required_access(EREBUS_IOCTL_HELLO), FILE_ANY_ACCESS);
+        assert_eq!(required_access(EREBUS_IOCTL_READ), FILE_READ_ACCESS);
+        assert_eq!(required_access(EREBUS_IOCTL_READ_BATCH), FILE_READ_ACCESS);
+        assert_eq!(required_access(EREBUS_IOCTL_READ_LOSSY), FILE_READ_ACCESS);
+        assert_eq!(
+            required_access(EREBUS_IOCTL_QUERY_REGIONS),
+            FILE_READ_ACCESS
+        );
+        assert_eq!(
+            required_access(EREBUS_IOCTL_QUERY_MODULES),
+            FILE_READ_ACCESS
+        );
+        assert_eq!(
+            required_access(EREBUS_IOCTL_LIST_PROCESSES),
+            FILE_READ_ACCESS
+        );
+        assert_eq!(required_access(EREBUS_IOCTL_GET_EVENTS), FILE_READ_ACCESS);
+        assert_eq!(required_access(EREBUS_IOCTL_READ_CHAIN), FILE_READ_ACCESS);
+        assert_eq!(required_access(EREBUS_IOCTL_WRITE), FILE_WRITE_ACCESS);
+        assert_eq!(required_access(EREBUS_IOCTL_WRITE_BATCH), FILE_WRITE_ACCESS);
+        assert_eq!(
+            required_access(EREBUS_IOCTL_COMPARE_EXCHANGE),
+            FILE_WRITE_ACCESS
+        );
+        assert_eq!(
+            required_access(EREBUS_IOCTL_RELOAD_POLICY),
+            FILE_WRITE_ACCESS
+        );
+        assert_eq!(required_access(EREBUS_IOCTL_ATTACH), FILE_READ_ACCESS);
+        assert_eq!(
+            required_access(EREBUS_IOCTL_DRAIN_AUDIT),
+            FILE_READ_ACCESS | FILE_WRITE_ACCESS
+        );
+    }
+
+    #[test]
+    fn the_driver_never_gets_write_data_in_a_buffer_it_writes_to() {
+        assert!(is_in_direct(EREBUS_IOCTL_WRITE));
+        assert_eq!(method(EREBUS_IOCTL_WRITE_BATCH), METHOD_OUT_DIRECT);
+        assert_eq!(method(EREBUS_IOCTL_COMPARE_EXCHANGE), METHOD_OUT_DIRECT);
+
+        // reads fill in the output, so it must be probed for writing
+        for ioctl in IOCTLS {
+            if ioctl.access == FILE_READ_ACCESS {
+                assert_ne!(ioctl.method, METHOD_IN_DIRECT, "{ioctl:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn codes_decode_to_the_declared_fields() {
+        for ioctl in IOCTLS {
+            assert_eq!(device_type(ioctl.code), FILE_DEVICE_UNKNOWN, "{ioctl:?}");
+            assert_eq!(function(ioctl.code), ioctl.function, "{ioctl:?}");
+            assert_eq!(method(ioctl.code), ioctl.method, "{ioctl:?}");
+            assert_eq!(required_access(ioctl.code), ioctl.access, "{ioctl:?}");
+            assert_eq!(function(ioctl.code), ioctl.opcode as u32, "{ioctl:?}");
+            assert_eq!(lookup(ioctl.code), Some(ioctl));
+        }
+
+        assert_eq!(IoctlInfo::of::<Read>().code, EREBUS_IOCTL_READ);
+        assert_eq!(lookup(EREBUS_IOCTL_READ + 4), None);
+    }
+
+    #[test]
+    fn every_opcode_has_one_distinct_code() {
+        let codes = IOCTLS
+            .iter()
+            .map(|ioctl| ioctl.code)
+            .collect::<BTreeSet<_>>();
+        let functions = IOCTLS
+            .iter()
+            .map(|ioctl| ioctl.function)
+            .collect::<BTreeSet<_>>();
+        let opcodes = IOCTLS
+            .iter()
+            .map(|ioctl| ioctl.opcode as u16)
+            .collect::<BTreeSet<_>>();
+
+        assert_eq!(codes.len(), IOCTLS.len());
+        assert_eq!(functions.len(), IOCTLS.len());
+        assert_eq!(opcodes.len(), IOCTLS.len());
+
+        // and none is missing from the table
+        for opcode in 0..=u16::MAX {
+            if let Ok(opcode) = Opcode::try_from(opcode) {
+                assert!(opcodes.contains(&(opcode as u16)), "{opcode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_ioctl_walks_the_table() {
+        macro_rules! table {
+            ($($ioctl:ident),*) => {
+                [$(IoctlInfo::of::<$ioctl>()),*]
+            };
+        }
+
+        assert_eq!(for_each_ioctl!(table), IOCTLS);
+    }
+
+    #[test]
+    fn buffers_hold_at_least_the_fixed_parts() {
+        for ioctl in IOCTLS {
+            match ioctl.method {
+                // the output is data to write, and the reply is the status
+                METHOD_IN_DIRECT => assert_eq!(ioctl.min_output, 0, "{ioctl:?}"),
+                METHOD_OUT_DIRECT => assert!(ioctl.min_output >= RESPONSE_SIZE, "{ioctl:?}"),
+                _ => assert!(ioctl.min_output >= HELLO_RESPONSE_SIZE, "{ioctl:?}"),
+            }
+            if ioctl.opcode != Opcode::Hello {
+                assert!(ioctl.min_input >= HEADER_SIZE, "{ioctl:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn payloads_encode_to_their_wire_size() {
+        let request = Request {
+            process_id: 4,
+            reserved: 0,
+            address: 0,
+            size: 8,
+            create_time: 0,
+        };
+        assert_eq!(
+            <Read as IoctlSpec>::Request::encode(&request).len() + HEADER_SIZE,
+            Read::MIN_INPUT
+        );
+        assert!(().encode().is_empty());
+
+        let header = BatchHeader {
+            process_id: 4,
+            entry_count: 0,
+            buffer_len: 0,
+            create_time: 0,
+        };
+        let batch = (header, Vec::new());
+        assert_eq!(batch.encode().len() + HEADER_SIZE, ReadBatch::MIN_INPUT);
+        let batch = (header, Vec::new(), Vec::new());
+        assert_eq!(batch.encode().len() + HEADER_SIZE, WriteBatch::MIN_INPUT);
+
+        let header = ChainHeader {
+            process_id: 4,
+            offset_count: 1,
+            base: 0,
+            create_time: 0,
+            pointer_width: 8,
+            read_size: 8,
+        };
+        let chain = (header, alloc::vec![0]);
+        assert_eq!(chain.encode().len() + HEADER_SIZE, ReadChain::MIN_INPUT);
+
+        let exchange = ExchangeRequest {
+            process_id: 4,
+            size: 4,
+            address: 0,
+            create_time: 0,
+            expected: 0,
+            new: 1,
+        };
+        assert_eq!(
+            exchange.encode().len() + HEADER_SIZE,
+            CompareExchange::MIN_INPUT
+        );
+    }
+}
@@ -1,13 +1,452 @@
-use core::ffi::c_void;
+/// Prefix of every request sent to the driver. See `protocol` for encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RequestHeader {
+    pub magic: u32,
+    pub version: u16,
+    pub opcode: u16,
+    pub flags: u32,
+    pub payload_len: u32,
+}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 // use C ABI due to Rust's ABI instability
 #[repr(C)]
 pub struct Request {
     pub process_id: u32,
+    /// Explicit padding, must be zero.
+    pub reserved: u32,
+
+    /// Target address. 64 bits wide whatever the client's or target's pointer
+    /// width, so 32-bit clients send the same bytes as 64-bit ones.
+    pub address: u64,
+
+    pub size: u64,
+
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+}
+
+/// Payload of `EREBUS_IOCTL_ATTACH`, whose header flags say how `process_id`
+/// names the process to bind the device handle's session to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct AttachRequest {
+    pub process_id: u32,
+    /// `ATTACH_*` flags.
+    pub flags: u32,
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+}
+
+/// Reply to `EREBUS_IOCTL_ATTACH`: the process the session is now bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct AttachResponse {
+    pub process_id: u32,
+    pub reserved: u32,
+    /// Creation time of the process, in 100ns intervals since 1601-01-01 UTC.
+    pub create_time: u64,
+}
+
+/// Reply to `EREBUS_IOCTL_HELLO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct HelloResponse {
+    pub magic: u32,
+    pub version: u16,
+    pub reserved: u16,
+    pub capabilities: u64,
+}
+
+/// Reply to every memory operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Response {
+    /// Final NTSTATUS of the operation.
+    pub status: i32,
+    pub reserved: u32,
+    pub bytes_transferred: u64,
+    /// Offset into the requested range where the operation stopped, or
+    /// `NO_FAILING_OFFSET` if it completed.
+    pub failing_offset: u64,
+}
+
+/// Fixed part of a batch payload, followed by `entry_count` `BatchEntry`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BatchHeader {
+    pub process_id: u32,
+    pub entry_count: u32,
+    /// Size of the data area every entry reads into or writes from.
+    pub buffer_len: u64,
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+}
 
-    pub address: *mut c_void,
-    pub buffer: *mut c_void,
+/// One scatter/gather element: `length` bytes at `address` in the target,
+/// mapped to `offset` in the batch's data area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BatchEntry {
+    pub address: u64,
+    pub length: u32,
+    pub reserved: u32,
+    pub offset: u64,
+}
 
+/// Per-entry outcome, returned after the batch's aggregate `Response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BatchEntryResult {
+    pub status: i32,
+    pub reserved: u32,
+    pub bytes_transferred: u64,
+}
+
+/// One entry of the driver's audit log. Records are hash-chained: `hash` covers
+/// every field before it, including `prev_hash`, the `hash` of the previous record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct AuditRecord {
+    /// Position in the log since the driver loaded, starting at 0.
+    pub sequence: u64,
+    /// System time of the request, in 100ns intervals since 1601-01-01 UTC.
+    pub timestamp: u64,
+    pub caller_pid: u32,
+    /// PID of the target process, or 0 if it was never resolved.
+    pub target_pid: u32,
+    pub opcode: u16,
+    pub reserved: u16,
+    /// Final NTSTATUS of the request.
+    pub status: i32,
+    /// Target address; for batches, the address of the first entry.
+    pub address: u64,
+    /// Bytes requested; for batches, the size of the data area.
     pub size: u64,
+    /// NUL-padded image names, as truncated by the kernel.
+    pub caller_image: [u8; 16],
+    pub target_image: [u8; 16],
+    pub prev_hash: [u8; 32],
+    pub hash: [u8; 32],
+}
+
+/// Payload of `EREBUS_IOCTL_QUERY_REGIONS`: describe up to `max_regions` regions
+/// of the target, starting with the one containing `start_address`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RegionQuery {
+    pub process_id: u32,
+    pub max_regions: u32,
+    pub start_address: u64,
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+}
+
+/// Follows the `Response` of a region query, ahead of its `MemoryRegion`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RegionsReply {
+    pub region_count: u32,
+    pub reserved: u32,
+    /// `start_address` of the query that continues this one, or `REGIONS_DONE`
+    /// once the end of user space was reached.
+    pub next_address: u64,
+}
+
+/// One region of a target's address space, as `VirtualQueryEx` describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub base_address: u64,
+    pub allocation_base: u64,
+    pub region_size: u64,
+    /// `MEM_COMMIT`, `MEM_RESERVE`, or `MEM_FREE`.
+    pub state: u32,
+    /// `PAGE_*` protection of the region.
+    pub protect: u32,
+    /// `PAGE_*` protection the allocation was created with.
+    pub allocation_protect: u32,
+    /// `MEM_PRIVATE`, `MEM_MAPPED`, or `MEM_IMAGE`; 0 for free regions.
+    pub region_type: u32,
+    /// Bytes of `name` in use.
+    pub name_len: u32,
+    pub reserved: u32,
+    /// UTF-8 path of the file mapped into the region, empty if there is none.
+    /// Paths too long to fit keep their end.
+    pub name: [u8; 256],
+}
+
+/// Payload of `EREBUS_IOCTL_QUERY_MODULES`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ModuleQuery {
+    pub process_id: u32,
+    pub reserved: u32,
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+}
+
+/// Follows the `Response` of a module query, ahead of its `ModuleEntry`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ModulesReply {
+    /// Entries that follow.
+    pub module_count: u32,
+    /// Modules the target has loaded. More than `module_count` if the output
+    /// buffer was too small for all of them.
+    pub total_count: u32,
+}
+
+/// One module in a target's loader lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ModuleEntry {
+    pub base_address: u64,
+    pub size: u64,
+    /// `MODULE_*` flags.
+    pub flags: u32,
+    /// Bytes of `name` in use.
+    pub name_len: u16,
+    /// Bytes of `path` in use.
+    pub path_len: u16,
+    /// UTF-8 file name, e.g. `kernel32.dll`.
+    pub name: [u8; 128],
+    /// UTF-8 full path. Paths too long to fit keep their end.
+    pub path: [u8; 256],
+}
+
+/// Follows the `Response` of a process listing, ahead of its `ProcessEntry`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProcessesReply {
+    /// Entries that follow.
+    pub process_count: u32,
+    /// Processes running when the listing was taken. More than `process_count`
+    /// if the output buffer was too small for all of them.
+    pub total_count: u32,
+}
+
+/// One running process, as the kernel knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProcessEntry {
+    pub process_id: u32,
+    /// PID of the process that created this one. That process may have exited,
+    /// and its PID may even have been reused since.
+    pub parent_process_id: u32,
+    pub session_id: u32,
+    /// `PROCESS_*` flags.
+    pub flags: u32,
+    /// In 100ns intervals since 1601-01-01 UTC; see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+    /// Address of the native PEB, or 0 for processes without one.
+    pub peb_address: u64,
+    /// Bytes of `name` in use.
+    pub name_len: u16,
+    /// Bytes of `path` in use.
+    pub path_len: u16,
+    pub reserved: u32,
+    /// UTF-8 image file name, e.g. `explorer.exe`.
+    pub name: [u8; 128],
+    /// UTF-8 Win32 path of the image. Paths too long to fit keep their end.
+    pub path: [u8; 256],
+}
+
+/// Payload of `EREBUS_IOCTL_GET_EVENTS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct EventsRequest {
+    /// `EVENT_MASK_*` bits of the events to queue from now on.
+    pub mask: u32,
+    pub reserved: u32,
+}
+
+/// Follows the `Response` of `EREBUS_IOCTL_GET_EVENTS`, ahead of its `EventRecord`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct EventsReply {
+    pub event_count: u32,
+    pub reserved: u32,
+}
+
+/// Something that happened to a process, as reported by the kernel's notify
+/// routines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct EventRecord {
+    /// Position in the session's stream, starting at 0. Events dropped while
+    /// the queue was full are announced by an `EventKind::Lost` record instead.
+    pub sequence: u64,
+    /// System time of the event, in 100ns intervals since 1601-01-01 UTC.
+    pub timestamp: u64,
+    /// An `EventKind`.
+    pub kind: u16,
+    /// Bytes of `name` in use.
+    pub name_len: u16,
+    pub process_id: u32,
+    /// Parent PID for process creation, thread ID for thread events, number of
+    /// dropped events for `EventKind::Lost`.
+    pub related_id: u32,
+    pub reserved: u32,
+    /// Process creation time, for process events; see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+    /// Where an image was mapped, for image loads.
+    pub image_base: u64,
+    pub image_size: u64,
+    /// UTF-8 image name of a new process, or full path of a loaded image.
+    /// Paths too long to fit keep their end.
+    pub name: [u8; 256],
+}
+
+/// Payload of `EREBUS_IOCTL_READ_CHAIN`, followed by `offset_count` `i64`
+/// offsets, one per hop. See `chain` for how they are followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ChainHeader {
+    pub process_id: u32,
+    pub offset_count: u32,
+    /// Address the first offset is added to.
+    pub base: u64,
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+    /// Size of the pointers along the chain: 8, or 4 in a 32-bit process.
+    pub pointer_width: u32,
+    /// Bytes to read at the end of the chain.
+    pub read_size: u32,
+}
+
+/// Follows the `Response` of a pointer-chain read, ahead of the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ChainReply {
+    /// Where the value was read, or, if a hop failed, the address that hop could
+    /// not read.
+    pub address: u64,
+    /// NTSTATUS of the failing hop's read, `STATUS_SUCCESS` if none failed.
+    pub status: i32,
+    pub reserved: u32,
+}
+
+/// Payload of a compare-exchange. See `exchange`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExchangeRequest {
+    pub process_id: u32,
+    /// Width of the value: 1, 2, 4, or 8 bytes.
+    pub size: u32,
+    /// Where the value sits, a multiple of `size`.
+    pub address: u64,
+    /// Creation time the target must have, see `ANY_CREATE_TIME`.
+    pub create_time: u64,
+    /// Value the target must hold for the write to happen, zero-extended.
+    pub expected: u64,
+    /// Value written in its place, zero-extended.
+    pub new: u64,
+}
+
+/// Follows the `Response` of a compare-exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExchangeReply {
+    /// Value the target held, zero-extended. Equals `expected` if it was replaced.
+    pub observed: u64,
+}
+
+/// Checks at compile time that a wire type's size and field offsets match its
+/// encoding, so the `repr(C)` layout is the same on 32- and 64-bit targets and
+/// has no implicit padding.
+macro_rules! wire_layout {
+    ($($ty:ident = $size:path { $($field:ident: $offset:literal),* $(,)? })*) => {
+        $(
+            const _: () = {
+                assert!(core::mem::size_of::<$ty>() == $size);
+                $(assert!(core::mem::offset_of!($ty, $field) == $offset);)*
+            };
+        )*
+    };
+}
+
+wire_layout! {
+    RequestHeader = crate::protocol::HEADER_SIZE {
+        magic: 0, version: 4, opcode: 6, flags: 8, payload_len: 12,
+    }
+    Request = crate::protocol::REQUEST_SIZE {
+        process_id: 0, reserved: 4, address: 8, size: 16, create_time: 24,
+    }
+    AttachRequest = crate::protocol::ATTACH_REQUEST_SIZE {
+        process_id: 0, flags: 4, create_time: 8,
+    }
+    AttachResponse = crate::protocol::ATTACH_RESPONSE_SIZE {
+        process_id: 0, reserved: 4, create_time: 8,
+    }
+    HelloResponse = crate::protocol::HELLO_RESPONSE_SIZE {
+        magic: 0, version: 4, reserved: 6, capabilities: 8,
+    }
+    Response = crate::protocol::RESPONSE_SIZE {
+        status: 0, reserved: 4, bytes_transferred: 8, failing_offset: 16,
+    }
+    BatchHeader = crate::batch::BATCH_HEADER_SIZE {
+        process_id: 0, entry_count: 4, buffer_len: 8, create_time: 16,
+    }
+    BatchEntry = crate::batch::BATCH_ENTRY_SIZE {
+        address: 0, length: 8, reserved: 12, offset: 16,
+    }
+    BatchEntryResult = crate::batch::BATCH_RESULT_SIZE {
+        status: 0, reserved: 4, bytes_transferred: 8,
+    }
+    AuditRecord = crate::audit::AUDIT_RECORD_SIZE {
+        sequence: 0, timestamp: 8, caller_pid: 16, target_pid: 20, opcode: 24,
+        reserved: 26, status: 28, address: 32, size: 40, caller_image: 48,
+        target_image: 64, prev_hash: 80, hash: 112,
+    }
+    RegionQuery = crate::regions::REGION_QUERY_SIZE {
+        process_id: 0, max_regions: 4, start_address: 8, create_time: 16,
+    }
+    RegionsReply = crate::regions::REGIONS_REPLY_SIZE {
+        region_count: 0, reserved: 4, next_address: 8,
+    }
+    MemoryRegion = crate::regions::MEMORY_REGION_SIZE {
+        base_address: 0, allocation_base: 8, region_size: 16, state: 24, protect: 28,
+        allocation_protect: 32, region_type: 36, name_len: 40, reserved: 44, name: 48,
+    }
+    ModuleQuery = crate::modules::MODULE_QUERY_SIZE {
+        process_id: 0, reserved: 4, create_time: 8,
+    }
+    ModulesReply = crate::modules::MODULES_REPLY_SIZE {
+        module_count: 0, total_count: 4,
+    }
+    ModuleEntry = crate::modules::MODULE_ENTRY_SIZE {
+        base_address: 0, size: 8, flags: 16, name_len: 20, path_len: 22, name: 24, path: 152,
+    }
+    ProcessesReply = crate::processes::PROCESSES_REPLY_SIZE {
+        process_count: 0, total_count: 4,
+    }
+    ProcessEntry = crate::processes::PROCESS_ENTRY_SIZE {
+        process_id: 0, parent_process_id: 4, session_id: 8, flags: 12, create_time: 16,
+        peb_address: 24, name_len: 32, path_len: 34, reserved: 36, name: 40, path: 168,
+    }
+    EventsRequest = crate::events::EVENTS_REQUEST_SIZE {
+        mask: 0, reserved: 4,
+    }
+    EventsReply = crate::events::EVENTS_REPLY_SIZE {
+        event_count: 0, reserved: 4,
+    }
+    EventRecord = crate::events::EVENT_RECORD_SIZE {
+        sequence: 0, timestamp: 8, kind: 16, name_len: 18, process_id: 20, related_id: 24,
+        reserved: 28, create_time: 32, image_base: 40, image_size: 48, name: 56,
+    }
+    ChainHeader = crate::chain::CHAIN_HEADER_SIZE {
+        process_id: 0, offset_count: 4, base: 8, create_time: 16, pointer_width: 24,
+        read_size: 28,
+    }
+    ChainReply = crate::chain::CHAIN_REPLY_SIZE {
+        address: 0, status: 8, reserved: 12,
+    }
+    ExchangeRequest = crate::exchange::EXCHANGE_REQUEST_SIZE {
+        process_id: 0, size: 4, address: 8, create_time: 16, expected: 24, new: 32,
+    }
+    ExchangeReply = crate::exchange::EXCHANGE_REPLY_SIZE {
+        observed: 0,
+    }
 }
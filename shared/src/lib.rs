@@ -1,6 +1,21 @@
 #![no_std]
 extern crate alloc;
 
+pub mod address;
+pub mod audit;
+pub mod batch;
+pub mod chain;
+pub mod codec;
 pub mod constants;
+pub mod events;
+pub mod exchange;
 pub mod ioctl;
 pub mod ipc;
+pub mod lossy;
+pub mod modules;
+pub mod policy;
+pub mod processes;
+pub mod protocol;
+pub mod regions;
+pub mod sha256;
+pub mod status;
@@ -0,0 +1,246 @@
+//! Layout of lossy region reads.
+//!
+//! A lossy read walks its range one page at a time. Pages that cannot be read
+//! are zero-filled instead of failing the whole request. The output buffer holds
+//! the `Response`, then a bitmap with one bit per page the range touches (set if
+//! the page was read), padded to `PAYLOAD_ALIGN`, then the `size` bytes of data.
+
+use crate::{
+    codec::ensure_len,
+    ipc::Response,
+    protocol::{ProtocolError, PAYLOAD_ALIGN, RESPONSE_SIZE},
+    status::STATUS_PARTIAL_COPY,
+};
+use alloc::{vec, vec::Vec};
+
+/// Granularity at which a lossy read succeeds or fails.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Number of pages the range `address..address + size` touches.
+pub const fn page_count(address: u64, size: u64) -> u64 {
+    if size == 0 {
+        return 0;
+    }
+
+    (address + (size - 1)) / PAGE_SIZE - address / PAGE_SIZE + 1
+}
+
+/// Size of the bitmap for `pages` pages, padded so the data behind it stays aligned.
+pub const fn bitmap_size(pages: u64) -> usize {
+    (pages.div_ceil(8) as usize).next_multiple_of(PAYLOAD_ALIGN)
+}
+
+/// Size of the whole output buffer of a lossy read: reply, bitmap, and data.
+pub const fn lossy_output_size(address: u64, size: u64) -> usize {
+    RESPONSE_SIZE + bitmap_size(page_count(address, size)) + size as usize
+}
+
+/// Splits `address..address + size` at page boundaries, yielding the
+/// `(offset, len)` of each piece relative to `address`, one per page.
+pub fn page_spans(address: u64, size: u64) -> impl Iterator<Item = (u64, u64)> {
+    let mut offset = 0;
+
+    core::iter::from_fn(move || {
+        if offset >= size {
+            return None;
+        }
+
+        let len = (PAGE_SIZE - (address + offset) % PAGE_SIZE).min(size - offset);
+        let span = (offset, len);
+        offset += len;
+        Some(span)
+    })
+}
+
+/// Which pages of a lossy read could be read. Page `n` is bit `n % 8` of byte `n / 8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageMap {
+    bits: Vec<u8>,
+    pages: u64,
+}
+
+impl PageMap {
+    /// A map of `pages` pages, none of them readable yet.
+    pub fn new(pages: u64) -> Self {
+        Self {
+            bits: vec![0; bitmap_size(pages)],
+            pages,
+        }
+    }
+
+    /// Decodes the bitmap of a read touching `pages` pages. Bits past the last
+    /// page must be clear.
+    pub fn from_bytes(buf: &[u8], pages: u64) -> Result<Self, ProtocolError> {
+        let size = bitmap_size(pages);
+        ensure_len(buf, size)?;
+
+        let map = Self {
+            bits: buf[..size].to_vec(),
+            pages,
+        };
+
+        let padding = (pages..size as u64 * 8).any(|page| map.bit(page));
+        if padding {
+            return Err(ProtocolError::InvalidField("bitmap"));
+        }
+
+        Ok(map)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub const fn page_count(&self) -> u64 {
+        self.pages
+    }
+
+    pub fn set_readable(&mut self, page: u64) {
+        debug_assert!(page < self.pages);
+        self.bits[(page / 8) as usize] |= 1 << (page % 8);
+    }
+
+    pub fn is_readable(&self, page: u64) -> bool {
+        page < self.pages && self.bit(page)
+    }
+
+    /// Number of pages that were read.
+    pub fn readable_count(&self) -> u64 {
+        self.bits
+            .iter()
+            .map(|byte| u64::from(byte.count_ones()))
+            .sum()
+    }
+
+    /// Index of the first page that could not be read, if any.
+    pub fn first_unreadable(&self) -> Option<u64> {
+        (0..self.pages).find(|&page| !self.bit(page))
+    }
+
+    fn bit(&self, page: u64) -> bool {
+        self.bits[(page / 8) as usize] & (1 << (page % 8)) != 0
+    }
+}
+
+/// Reply to a lossy read of `address..address + size` that read the pages in
+/// `pages`. `bytes_transferred` counts the bytes that were actually read. If a
+/// page was missed the status is `STATUS_PARTIAL_COPY`, and `failing_offset`
+/// is where the first missed page starts within the range.
+pub fn lossy_response(address: u64, size: u64, pages: &PageMap) -> Response {
+    let mut read = 0;
+    let mut first_failure = None;
+
+    for (page, (offset, len)) in page_spans(address, size).enumerate() {
+        if pages.is_readable(page as u64) {
+            read += len;
+        } else if first_failure.is_none() {
+            first_failure = Some(offset);
+        }
+    }
+
+    match first_failure {
+        None => Response::success(read),
+        Some(offset) => Response::failure(STATUS_PARTIAL_COPY, read, offset),
+    }
+}
+
+/// Splits the output buffer of a lossy read into the reply, the page map, and
+/// the data area.
+pub fn decode_lossy_response(
+    buf: &[u8],
+    address: u64,
+    size: u64,
+) -> Result<(Response, PageMap, &[u8]), ProtocolError> {
+    ensure_len(buf, lossy_output_size(address, size))?;
+
+    let response = Response::from_bytes(buf)?;
+    let pages = PageMap::from_bytes(&buf[RESPONSE_SIZE..], page_count(address, size))?;
+    let data_offset = RESPONSE_SIZE + pages.as_bytes().len();
+
+    Ok((
+        response,
+        pages,
+        &buf[data_offset..data_offset + size as usize],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::NO_FAILING_OFFSET;
+
+    #[test]
+    fn page_count_follows_boundaries() {
+        assert_eq!(page_count(0x1000, 0), 0);
+        assert_eq!(page_count(0x1000, 1), 1);
+        assert_eq!(page_count(0x1000, 0x1000), 1);
+        assert_eq!(page_count(0x1fff, 2), 2);
+        assert_eq!(page_count(0x1800, 0x2000), 3);
+    }
+
+    #[test]
+    fn spans_split_at_page_boundaries() {
+        let spans: Vec<_> = page_spans(0x1800, 0x2000).collect();
+
+        assert_eq!(spans, [(0, 0x800), (0x800, 0x1000), (0x1800, 0x800)]);
+    }
+
+    #[test]
+    fn bitmap_is_padded_to_payload_alignment() {
+        assert_eq!(bitmap_size(0), 0);
+        assert_eq!(bitmap_size(1), PAYLOAD_ALIGN);
+        assert_eq!(bitmap_size(64), 8);
+        assert_eq!(bitmap_size(65), 16);
+    }
+
+    #[test]
+    fn response_reports_first_missed_page() {
+        let mut pages = PageMap::new(3);
+        pages.set_readable(0);
+        pages.set_readable(2);
+
+        let response = lossy_response(0x1800, 0x2000, &pages);
+        assert_eq!(response.status, STATUS_PARTIAL_COPY);
+        assert_eq!(response.bytes_transferred, 0x1000);
+        assert_eq!(response.failing_offset, 0x800);
+
+        pages.set_readable(1);
+        let response = lossy_response(0x1800, 0x2000, &pages);
+        assert!(response.is_success());
+        assert_eq!(response.bytes_transferred, 0x2000);
+        assert_eq!(response.failing_offset, NO_FAILING_OFFSET);
+    }
+
+    #[test]
+    fn lossy_output_round_trips() {
+        let (address, size) = (0x1ffc, 8);
+        let mut pages = PageMap::new(page_count(address, size));
+        pages.set_readable(1);
+
+        let response = lossy_response(address, size, &pages);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&response.to_bytes());
+        buf.extend_from_slice(pages.as_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0, 1, 2, 3, 4]);
+        assert_eq!(buf.len(), lossy_output_size(address, size));
+
+        let (decoded, decoded_pages, data) = decode_lossy_response(&buf, address, size).unwrap();
+        assert_eq!(decoded, response);
+        assert_eq!(decoded_pages, pages);
+        assert_eq!(decoded_pages.first_unreadable(), Some(0));
+        assert_eq!(decoded_pages.readable_count(), 1);
+        assert_eq!(data, [0, 0, 0, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_bits_past_the_last_page() {
+        let mut buf = [0u8; 8];
+        buf[0] = 1 << 3;
+
+        assert_eq!(
+            PageMap::from_bytes(&buf, 3),
+            Err(ProtocolError::InvalidField("bitmap"))
+        );
+        assert!(PageMap::from_bytes(&buf, 4).is_ok());
+    }
+}
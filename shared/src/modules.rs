@@ -0,0 +1,581 @@
+//! Loaded module enumeration.
+//!
+//! The driver walks the loader's in-load-order list of the target: the native
+//! one, and for a WOW64 process the 32-bit one as well. The output buffer holds
+//! the `Response`, a `ModulesReply`, then `module_count` `ModuleEntry`s.
+//!
+//! The lists live in the target's user memory, which the target can scribble
+//! over at will, so the walk trusts none of it: every read is checked against
+//! user space first, and a list that does not close within `MAX_MODULES`
+//! entries is abandoned.
+
+use crate::{
+    address::check_user_range,
+    codec::{ensure_len, utf16_tail_to_utf8, Reader},
+    ipc::{ModuleEntry, ModuleQuery, ModulesReply},
+    protocol::{ProtocolError, RESPONSE_SIZE},
+};
+use alloc::{vec, vec::Vec};
+
+/// Size of an encoded `ModuleQuery` on the wire.
+pub const MODULE_QUERY_SIZE: usize = 16;
+
+/// Size of an encoded `ModulesReply` on the wire.
+pub const MODULES_REPLY_SIZE: usize = 8;
+
+/// Size of an encoded `ModuleEntry` on the wire.
+pub const MODULE_ENTRY_SIZE: usize = 408;
+
+/// Size of `ModuleEntry::name`.
+pub const MODULE_NAME_SIZE: usize = 128;
+
+/// Size of `ModuleEntry::path`.
+pub const MODULE_PATH_SIZE: usize = 256;
+
+/// Upper bound on the modules of one loader list.
+pub const MAX_MODULES: usize = 4096;
+
+/// Longest name the walk reads, in UTF-16 units.
+const MAX_NAME_UNITS: usize = 1024;
+
+/// Offset of the first `ModuleEntry` in the output buffer.
+pub const MODULES_OFFSET: usize = RESPONSE_SIZE + MODULES_REPLY_SIZE;
+
+/* MODULE FLAGS */
+
+// the module comes from the 32-bit loader list of a WOW64 process
+pub const MODULE_WOW64: u32 = 1 << 0;
+
+/// Size of the output buffer of a query with room for `max_modules` modules.
+pub const fn modules_output_size(max_modules: usize) -> usize {
+    MODULES_OFFSET + max_modules * MODULE_ENTRY_SIZE
+}
+
+impl ModuleQuery {
+    pub fn to_bytes(&self) -> [u8; MODULE_QUERY_SIZE] {
+        let mut buf = [0; MODULE_QUERY_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.create_time.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let query = Self {
+            process_id: reader.u32()?,
+            reserved: reader.u32()?,
+            create_time: reader.u64()?,
+        };
+        reader.finish()?;
+
+        if query.reserved != 0 {
+            return Err(ProtocolError::InvalidField("reserved"));
+        }
+
+        Ok(query)
+    }
+}
+
+impl ModulesReply {
+    pub fn to_bytes(&self) -> [u8; MODULES_REPLY_SIZE] {
+        let mut buf = [0; MODULES_REPLY_SIZE];
+        buf[0..4].copy_from_slice(&self.module_count.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.total_count.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            module_count: reader.u32()?,
+            total_count: reader.u32()?,
+        })
+    }
+}
+
+impl ModuleEntry {
+    /// A module with no name or path.
+    pub const fn new(base_address: u64, size: u64, flags: u32) -> Self {
+        Self {
+            base_address,
+            size,
+            flags,
+            name_len: 0,
+            path_len: 0,
+            name: [0; MODULE_NAME_SIZE],
+            path: [0; MODULE_PATH_SIZE],
+        }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..(self.name_len as usize).min(MODULE_NAME_SIZE)]
+    }
+
+    pub fn path(&self) -> &[u8] {
+        &self.path[..(self.path_len as usize).min(MODULE_PATH_SIZE)]
+    }
+
+    /// Stores the name as the loader keeps it, see `utf16_tail_to_utf8`.
+    pub fn set_name_utf16(&mut self, name: &[u16]) {
+        self.name_len = utf16_tail_to_utf8(name, &mut self.name) as u16;
+    }
+
+    /// Stores the path as the loader keeps it, see `utf16_tail_to_utf8`.
+    pub fn set_path_utf16(&mut self, path: &[u16]) {
+        self.path_len = utf16_tail_to_utf8(path, &mut self.path) as u16;
+    }
+
+    pub const fn is_wow64(&self) -> bool {
+        self.flags & MODULE_WOW64 != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; MODULE_ENTRY_SIZE] {
+        let mut buf = [0; MODULE_ENTRY_SIZE];
+        buf[0..8].copy_from_slice(&self.base_address.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.size.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.flags.to_le_bytes());
+        buf[20..22].copy_from_slice(&self.name_len.to_le_bytes());
+        buf[22..24].copy_from_slice(&self.path_len.to_le_bytes());
+        buf[24..152].copy_from_slice(&self.name);
+        buf[152..408].copy_from_slice(&self.path);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let entry = Self {
+            base_address: reader.u64()?,
+            size: reader.u64()?,
+            flags: reader.u32()?,
+            name_len: reader.u16()?,
+            path_len: reader.u16()?,
+            name: reader.array()?,
+            path: reader.array()?,
+        };
+
+        if entry.name_len as usize > MODULE_NAME_SIZE {
+            return Err(ProtocolError::InvalidField("name_len"));
+        }
+
+        if entry.path_len as usize > MODULE_PATH_SIZE {
+            return Err(ProtocolError::InvalidField("path_len"));
+        }
+
+        Ok(entry)
+    }
+}
+
+/// Decodes the output buffer of a module query.
+pub fn decode_modules(buf: &[u8]) -> Result<(ModulesReply, Vec<ModuleEntry>), ProtocolError> {
+    ensure_len(buf, MODULES_OFFSET)?;
+
+    let reply = ModulesReply::from_bytes(&buf[RESPONSE_SIZE..])?;
+    if reply.module_count > reply.total_count || reply.total_count as usize > 2 * MAX_MODULES {
+        return Err(ProtocolError::InvalidField("module_count"));
+    }
+
+    let count = reply.module_count as usize;
+    ensure_len(&buf[MODULES_OFFSET..], count * MODULE_ENTRY_SIZE)?;
+
+    let modules = buf[MODULES_OFFSET..]
+        .chunks_exact(MODULE_ENTRY_SIZE)
+        .take(count)
+        .map(ModuleEntry::from_bytes)
+        .collect::<Result<_, _>>()?;
+
+    Ok((reply, modules))
+}
+
+/// The module of `modules` named `name`, compared without regard to case the way
+/// the loader does. In a WOW64 process, modules that both loaders map, like
+/// `ntdll.dll`, resolve to the 32-bit copy, the one the process's own code uses.
+/// Otherwise the first one listed wins, the one the loader would find first.
+pub fn find_module<'a>(modules: &'a [ModuleEntry], name: &str) -> Option<&'a ModuleEntry> {
+    let mut named = modules
+        .iter()
+        .filter(|module| module.name().eq_ignore_ascii_case(name.as_bytes()));
+
+    named
+        .clone()
+        .find(|module| module.is_wow64())
+        .or_else(|| named.next())
+}
+
+/// Offsets of the loader structures for one pointer size.
+#[derive(Debug)]
+pub struct LoaderLayout {
+    pointer_size: usize,
+    /// `PEB::Ldr`
+    peb_ldr: u64,
+    /// `PEB_LDR_DATA::InLoadOrderModuleList`
+    ldr_load_order: u64,
+    /// `LDR_DATA_TABLE_ENTRY::DllBase`; the entry starts with its `InLoadOrderLinks`.
+    entry_dll_base: u64,
+    /// `LDR_DATA_TABLE_ENTRY::SizeOfImage`
+    entry_size_of_image: u64,
+    /// `LDR_DATA_TABLE_ENTRY::FullDllName`
+    entry_full_name: u64,
+    /// `LDR_DATA_TABLE_ENTRY::BaseDllName`
+    entry_base_name: u64,
+    /// `UNICODE_STRING::Buffer`
+    string_buffer: u64,
+    /// Flags of every module found through this layout.
+    flags: u32,
+}
+
+/// The native 64-bit loader structures, found from `PsGetProcessPeb`.
+pub const NATIVE_LAYOUT: LoaderLayout = LoaderLayout {
+    pointer_size: 8,
+    peb_ldr: 0x18,
+    ldr_load_order: 0x10,
+    entry_dll_base: 0x30,
+    entry_size_of_image: 0x40,
+    entry_full_name: 0x48,
+    entry_base_name: 0x58,
+    string_buffer: 8,
+    flags: 0,
+};
+
+/// The 32-bit loader structures of a WOW64 process, found from
+/// `PsGetProcessWow64Process`.
+pub const WOW64_LAYOUT: LoaderLayout = LoaderLayout {
+    pointer_size: 4,
+    peb_ldr: 0x0C,
+    ldr_load_order: 0x0C,
+    entry_dll_base: 0x18,
+    entry_size_of_image: 0x20,
+    entry_full_name: 0x24,
+    entry_base_name: 0x2C,
+    string_buffer: 4,
+    flags: MODULE_WOW64,
+};
+
+/// Why a loader list could not be walked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkError {
+    /// A structure lies outside user space, or could not be read.
+    Unreadable { address: u64 },
+    /// The list did not close within `MAX_MODULES` entries.
+    TooManyModules,
+}
+
+impl core::fmt::Display for WalkError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Unreadable { address } => {
+                write!(f, "loader data at {address:#x} is unreadable")
+            }
+            Self::TooManyModules => {
+                write!(f, "loader list has more than {MAX_MODULES} entries")
+            }
+        }
+    }
+}
+
+impl core::error::Error for WalkError {}
+
+/// Walks the in-load-order module list of the loader whose PEB is at `peb`.
+/// `read` copies target memory at an address into a buffer and reports
+/// whether it could; it is only ever handed ranges within user space. A PEB
+/// whose loader is not set up yet has no modules.
+pub fn walk_loader(
+    peb: u64,
+    layout: &LoaderLayout,
+    read: impl FnMut(u64, &mut [u8]) -> bool,
+) -> Result<Vec<ModuleEntry>, WalkError> {
+    let mut walker = Walker { layout, read };
+
+    let ldr = walker.pointer(peb, layout.peb_ldr)?;
+    if ldr == 0 {
+        return Ok(Vec::new());
+    }
+
+    let head = offset(ldr, layout.ldr_load_order)?;
+    let mut link = walker.pointer(head, 0)?;
+    let mut modules = Vec::new();
+
+    while link != head {
+        if modules.len() == MAX_MODULES {
+            return Err(WalkError::TooManyModules);
+        }
+
+        let base_address = walker.pointer(link, layout.entry_dll_base)?;
+        let size = walker.u32(link, layout.entry_size_of_image)?;
+        let mut module = ModuleEntry::new(base_address, u64::from(size), layout.flags);
+        module.set_name_utf16(&walker.string(link, layout.entry_base_name)?);
+        module.set_path_utf16(&walker.string(link, layout.entry_full_name)?);
+        modules.push(module);
+
+        // `InLoadOrderLinks.Flink`
+        link = walker.pointer(link, 0)?;
+    }
+
+    Ok(modules)
+}
+
+fn offset(address: u64, offset: u64) -> Result<u64, WalkError> {
+    address
+        .checked_add(offset)
+        .ok_or(WalkError::Unreadable { address })
+}
+
+struct Walker<'a, F> {
+    layout: &'a LoaderLayout,
+    read: F,
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> bool> Walker<'_, F> {
+    fn bytes(&mut self, address: u64, buf: &mut [u8]) -> Result<(), WalkError> {
+        if check_user_range(address, buf.len() as u64).is_err() || !(self.read)(address, buf) {
+            return Err(WalkError::Unreadable { address });
+        }
+
+        Ok(())
+    }
+
+    fn u16(&mut self, base: u64, field: u64) -> Result<u16, WalkError> {
+        let mut buf = [0; 2];
+        self.bytes(offset(base, field)?, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn u32(&mut self, base: u64, field: u64) -> Result<u32, WalkError> {
+        let mut buf = [0; 4];
+        self.bytes(offset(base, field)?, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn pointer(&mut self, base: u64, field: u64) -> Result<u64, WalkError> {
+        let mut buf = [0; 8];
+        let size = self.layout.pointer_size;
+        self.bytes(offset(base, field)?, &mut buf[..size])?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// The `UNICODE_STRING` at `base + field`, cut at `MAX_NAME_UNITS`.
+    fn string(&mut self, base: u64, field: u64) -> Result<Vec<u16>, WalkError> {
+        let length = self.u16(base, field)? as usize / 2;
+        let buffer = self.pointer(base, field + self.layout.string_buffer)?;
+        if length == 0 || buffer == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut bytes = vec![0; length.min(MAX_NAME_UNITS) * 2];
+        self.bytes(buffer, &mut bytes)?;
+
+        Ok(bytes
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMORY_BASE: u64 = 0x10_0000;
+
+    /// A flat stretch of simulated target memory.
+    struct Memory(Vec<u8>);
+
+    impl Memory {
+        fn new() -> Self {
+            Self(vec![0; 0x4000])
+        }
+
+        fn put(&mut self, address: u64, bytes: &[u8]) {
+            let start = (address - MEMORY_BASE) as usize;
+            self.0[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+
+        fn put_pointer(&mut self, layout: &LoaderLayout, address: u64, value: u64) {
+            self.put(address, &value.to_le_bytes()[..layout.pointer_size]);
+        }
+
+        fn put_string(&mut self, layout: &LoaderLayout, address: u64, buffer: u64, s: &str) {
+            let units: Vec<u8> = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+            self.put(address, &(units.len() as u16).to_le_bytes());
+            self.put_pointer(layout, address + layout.string_buffer, buffer);
+            self.put(buffer, &units);
+        }
+
+        fn read(&self, address: u64, buf: &mut [u8]) -> bool {
+            let Some(start) = address.checked_sub(MEMORY_BASE) else {
+                return false;
+            };
+            match self.0.get(start as usize..start as usize + buf.len()) {
+                Some(bytes) => {
+                    buf.copy_from_slice(bytes);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    const PEB: u64 = MEMORY_BASE;
+    const LDR: u64 = MEMORY_BASE + 0x100;
+
+    /// Lays out a PEB and a loader list of `modules`, each `(name, base, size)`.
+    fn loader(layout: &LoaderLayout, modules: &[(&str, u64, u32)]) -> Memory {
+        let mut memory = Memory::new();
+        memory.put_pointer(layout, PEB + layout.peb_ldr, LDR);
+
+        let head = LDR + layout.ldr_load_order;
+        let entry = |index: usize| MEMORY_BASE + 0x1000 + index as u64 * 0x200;
+
+        let mut previous = head;
+        for (index, &(name, base, size)) in modules.iter().enumerate() {
+            let address = entry(index);
+            memory.put_pointer(layout, previous, address);
+            memory.put_pointer(layout, address + layout.entry_dll_base, base);
+            memory.put(address + layout.entry_size_of_image, &size.to_le_bytes());
+
+            let path = alloc::format!("C:\\Windows\\System32\\{name}");
+            memory.put_string(
+                layout,
+                address + layout.entry_base_name,
+                address + 0x100,
+                name,
+            );
+            memory.put_string(
+                layout,
+                address + layout.entry_full_name,
+                address + 0x140,
+                &path,
+            );
+            previous = address;
+        }
+        memory.put_pointer(layout, previous, head);
+
+        memory
+    }
+
+    #[test]
+    fn walks_native_loader() {
+        let memory = loader(
+            &NATIVE_LAYOUT,
+            &[
+                ("game.exe", 0x7ff6_a000_0000, 0x20_0000),
+                ("ntdll.dll", 0x7ffd_1000_0000, 0x1f_8000),
+            ],
+        );
+
+        let modules = walk_loader(PEB, &NATIVE_LAYOUT, |a, b| memory.read(a, b)).unwrap();
+        assert_eq!(modules.len(), 2);
+        assert_eq!(modules[0].name(), b"game.exe");
+        assert_eq!(modules[0].base_address, 0x7ff6_a000_0000);
+        assert_eq!(modules[1].name(), b"ntdll.dll");
+        assert_eq!(modules[1].path(), b"C:\\Windows\\System32\\ntdll.dll");
+        assert_eq!(modules[1].size, 0x1f_8000);
+        assert!(!modules[1].is_wow64());
+    }
+
+    #[test]
+    fn walks_wow64_loader() {
+        let memory = loader(&WOW64_LAYOUT, &[("game.dll", 0x6f00_0000, 0x8000)]);
+
+        let modules = walk_loader(PEB, &WOW64_LAYOUT, |a, b| memory.read(a, b)).unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name(), b"game.dll");
+        assert_eq!(modules[0].base_address, 0x6f00_0000);
+        assert!(modules[0].is_wow64());
+    }
+
+    #[test]
+    fn uninitialized_loader_has_no_modules() {
+        let memory = Memory::new();
+
+        let modules = walk_loader(PEB, &NATIVE_LAYOUT, |a, b| memory.read(a, b)).unwrap();
+        assert!(modules.is_empty());
+    }
+
+    #[test]
+    fn kernel_pointers_are_never_read() {
+        let mut memory = loader(&NATIVE_LAYOUT, &[("game.exe", 0x7ff6_a000_0000, 0x1000)]);
+        memory.put_pointer(&NATIVE_LAYOUT, MEMORY_BASE + 0x1000, 0xffff_f780_0000_0000);
+
+        let result = walk_loader(PEB, &NATIVE_LAYOUT, |address, buf| {
+            assert!(address < 0x8000_0000_0000, "read {address:#x}");
+            memory.read(address, buf)
+        });
+        assert_eq!(
+            result,
+            Err(WalkError::Unreadable {
+                address: 0xffff_f780_0000_0000 + NATIVE_LAYOUT.entry_dll_base
+            })
+        );
+    }
+
+    #[test]
+    fn endless_lists_are_abandoned() {
+        let mut memory = loader(&NATIVE_LAYOUT, &[("game.exe", 0x7ff6_a000_0000, 0x1000)]);
+        // the entry links back to itself instead of to the head
+        memory.put_pointer(&NATIVE_LAYOUT, MEMORY_BASE + 0x1000, MEMORY_BASE + 0x1000);
+
+        assert_eq!(
+            walk_loader(PEB, &NATIVE_LAYOUT, |a, b| memory.read(a, b)),
+            Err(WalkError::TooManyModules)
+        );
+    }
+
+    #[test]
+    fn modules_round_trip() {
+        let mut module = ModuleEntry::new(0x6f00_0000, 0x8000, MODULE_WOW64);
+        module.set_name_utf16(&"game.dll".encode_utf16().collect::<Vec<_>>());
+
+        let reply = ModulesReply {
+            module_count: 1,
+            total_count: 3,
+        };
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&[0; RESPONSE_SIZE]);
+        buf.extend_from_slice(&reply.to_bytes());
+        buf.extend_from_slice(&module.to_bytes());
+        assert_eq!(buf.len(), modules_output_size(1));
+
+        assert_eq!(decode_modules(&buf), Ok((reply, vec![module])));
+    }
+
+    fn named(name: &str, base_address: u64, flags: u32) -> ModuleEntry {
+        let mut module = ModuleEntry::new(base_address, 0x1000, flags);
+        module.set_name_utf16(&name.encode_utf16().collect::<Vec<_>>());
+        module
+    }
+
+    #[test]
+    fn find_module_prefers_wow64_copy() {
+        let modules = [
+            named("game.exe", 0x40_0000, MODULE_WOW64),
+            named("ntdll.dll", 0x7ffd_1000_0000, 0),
+            named("wow64.dll", 0x7ffd_2000_0000, 0),
+            named("NTDLL.DLL", 0x7760_0000, MODULE_WOW64),
+            named("ntdll.dll", 0x7770_0000, MODULE_WOW64),
+        ];
+
+        let ntdll = find_module(&modules, "ntdll.dll").unwrap();
+        assert_eq!(ntdll.base_address, 0x7760_0000);
+
+        let wow64 = find_module(&modules, "Wow64.dll").unwrap();
+        assert_eq!(wow64.base_address, 0x7ffd_2000_0000);
+
+        assert_eq!(find_module(&modules, "kernel32.dll"), None);
+    }
+
+    #[test]
+    fn find_module_takes_first_native_match() {
+        let modules = [
+            named("ntdll.dll", 0x7ffd_1000_0000, 0),
+            named("ntdll.dll", 0x7ffd_3000_0000, 0),
+        ];
+
+        let ntdll = find_module(&modules, "ntdll.dll").unwrap();
+        assert_eq!(ntdll.base_address, 0x7ffd_1000_0000);
+    }
+}
@@ -0,0 +1,628 @@
+//! The driver's target policy: what it reads from the service's `Parameters`
+//! registry key, and the decisions it makes from it. Nothing in here touches
+//! the registry or the kernel, so all of it can be tested on any host.
+
+use crate::protocol::{Target, MAX_TRANSFER_SIZE};
+use alloc::{string::String, vec, vec::Vec};
+
+/// Image names of processes the driver refuses to touch unless overridden. One
+/// bad write into any of these takes the whole machine down.
+pub const CRITICAL_IMAGE_NAMES: &[&str] = &[
+    "System",
+    "Secure System",
+    "Registry",
+    "MemCompression",
+    "smss.exe",
+    "csrss.exe",
+    "wininit.exe",
+    "winlogon.exe",
+    "services.exe",
+    "lsass.exe",
+    "lsaiso.exe",
+];
+
+/// PID of the System process.
+pub const SYSTEM_PROCESS_ID: u32 = 4;
+
+/// Length the kernel truncates `EPROCESS::ImageFileName` to.
+pub const IMAGE_FILE_NAME_LEN: usize = 15;
+
+/* REGISTRY VALUES */
+
+// registry types, from winnt.h
+pub const REG_SZ: u32 = 1;
+pub const REG_EXPAND_SZ: u32 = 2;
+pub const REG_DWORD: u32 = 4;
+pub const REG_MULTI_SZ: u32 = 7;
+pub const REG_QWORD: u32 = 11;
+
+// REG_MULTI_SZ: only these image names may be targeted; empty or missing allows any
+pub const VALUE_ALLOWED_IMAGES: &str = "AllowedImages";
+
+// REG_DWORD: non-zero refuses every write
+pub const VALUE_READ_ONLY: &str = "ReadOnly";
+
+// REG_DWORD or REG_QWORD: largest transfer per request, at most `MAX_TRANSFER_SIZE`
+pub const VALUE_MAX_TRANSFER_SIZE: &str = "MaxTransferSize";
+
+// REG_SZ: security descriptor of the device, applied when the driver loads
+pub const VALUE_DEVICE_SDDL: &str = "DeviceSddl";
+
+// REG_DWORD: least severe message logged, 0 (info) through `MAX_LOG_LEVEL` (errors only)
+pub const VALUE_LOG_LEVEL: &str = "LogLevel";
+
+// REG_DWORD: non-zero requires writes to name their target by process handle
+pub const VALUE_REQUIRE_HANDLE_FOR_WRITE: &str = "RequireHandleForWrite";
+
+// REG_DWORD: non-zero lifts the critical process denylist entirely
+pub const VALUE_ALLOW_CRITICAL_PROCESSES: &str = "AllowCriticalProcesses";
+
+// REG_MULTI_SZ: image names exempted from the critical process denylist
+pub const VALUE_CRITICAL_OVERRIDES: &str = "CriticalOverrides";
+
+/// Every value the policy is read from.
+pub const VALUE_NAMES: &[&str] = &[
+    VALUE_ALLOWED_IMAGES,
+    VALUE_READ_ONLY,
+    VALUE_MAX_TRANSFER_SIZE,
+    VALUE_DEVICE_SDDL,
+    VALUE_LOG_LEVEL,
+    VALUE_REQUIRE_HANDLE_FOR_WRITE,
+    VALUE_ALLOW_CRITICAL_PROCESSES,
+    VALUE_CRITICAL_OVERRIDES,
+];
+
+/// Highest `LogLevel` value: only errors are logged.
+pub const MAX_LOG_LEVEL: u32 = 3;
+
+/// A registry value, decoded from its type and raw data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegValue {
+    Dword(u32),
+    Qword(u64),
+    String(String),
+    MultiString(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    /// The value's registry type is not one the setting accepts.
+    WrongType { name: &'static str, value_type: u32 },
+    /// The value's data does not match its registry type.
+    Malformed { name: &'static str },
+    /// The value is outside the setting's permitted range.
+    OutOfRange { name: &'static str },
+}
+
+impl core::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::WrongType { name, value_type } => {
+                write!(
+                    f,
+                    "value `{name}` has unsupported registry type {value_type}"
+                )
+            }
+            Self::Malformed { name } => write!(f, "value `{name}` is malformed"),
+            Self::OutOfRange { name } => write!(f, "value `{name}` is out of range"),
+        }
+    }
+}
+
+impl core::error::Error for PolicyError {}
+
+impl RegValue {
+    /// Decodes the raw data of the value `name`, as returned by `ZwQueryValueKey`.
+    pub fn decode(name: &'static str, value_type: u32, data: &[u8]) -> Result<Self, PolicyError> {
+        let malformed = PolicyError::Malformed { name };
+
+        match value_type {
+            REG_DWORD => data
+                .try_into()
+                .map(|bytes| Self::Dword(u32::from_le_bytes(bytes)))
+                .map_err(|_| malformed),
+            REG_QWORD => data
+                .try_into()
+                .map(|bytes| Self::Qword(u64::from_le_bytes(bytes)))
+                .map_err(|_| malformed),
+            REG_SZ | REG_EXPAND_SZ => {
+                let mut strings = decode_strings(data).ok_or(malformed)?;
+                // anything after the first terminator is not part of the string
+                Ok(Self::String(if strings.is_empty() {
+                    String::new()
+                } else {
+                    strings.swap_remove(0)
+                }))
+            }
+            REG_MULTI_SZ => decode_strings(data).map(Self::MultiString).ok_or(malformed),
+            value_type => Err(PolicyError::WrongType { name, value_type }),
+        }
+    }
+}
+
+/// Splits UTF-16LE data at its NUL terminators, dropping empty strings.
+fn decode_strings(data: &[u8]) -> Option<Vec<String>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect::<Vec<_>>();
+
+    units
+        .split(|&unit| unit == 0)
+        .filter(|string| !string.is_empty())
+        .map(|string| char::decode_utf16(string.iter().copied()).collect::<Result<String, _>>())
+        .collect::<Result<_, _>>()
+        .ok()
+}
+
+/// Why the policy refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// The driver is in read-only mode and the request writes.
+    ReadOnly,
+    /// The request moves more bytes than `MaxTransferSize` allows.
+    TransferTooLarge,
+    /// Writes must name their target by process handle.
+    HandleRequired,
+    /// The target's image is not in `AllowedImages`.
+    ImageNotAllowed,
+    /// The target is critical to the system and not overridden.
+    CriticalProcess,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub allowed_images: Vec<String>,
+    pub read_only: bool,
+    pub max_transfer_size: u64,
+    pub device_sddl: Option<String>,
+    pub log_level: u32,
+    pub require_handle_for_write: bool,
+    pub allow_critical_processes: bool,
+    pub critical_overrides: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            allowed_images: Vec::new(),
+            read_only: false,
+            max_transfer_size: MAX_TRANSFER_SIZE,
+            device_sddl: None,
+            log_level: 0,
+            require_handle_for_write: false,
+            allow_critical_processes: false,
+            critical_overrides: Vec::new(),
+        }
+    }
+}
+
+/// An `AllowedImages` entry no process matches: image names end at their first
+/// NUL, so none holds one.
+const NO_IMAGE: &str = "\0";
+
+impl Policy {
+    /// The policy in force while the configured one is invalid: read-only, and no
+    /// process is allowed as a target. It fails closed, so a mistyped value never
+    /// loosens what the administrator asked for.
+    pub fn deny_all() -> Self {
+        Self {
+            allowed_images: vec![NO_IMAGE.into()],
+            read_only: true,
+            require_handle_for_write: true,
+            ..Self::default()
+        }
+    }
+
+    /// Builds a policy from `(name, value)` pairs. Missing settings keep their
+    /// defaults, unknown names are ignored, and any invalid value fails the whole
+    /// policy rather than silently loosening or tightening part of it.
+    pub fn from_values<I>(values: I) -> Result<Self, PolicyError>
+    where
+        I: IntoIterator<Item = (&'static str, RegValue)>,
+    {
+        let mut policy = Self::default();
+        for (name, value) in values {
+            policy.set(name, value)?;
+        }
+
+        Ok(policy)
+    }
+
+    /// Applies one registry value to the policy.
+    pub fn set(&mut self, name: &'static str, value: RegValue) -> Result<(), PolicyError> {
+        let wrong_type = |value: &RegValue| PolicyError::WrongType {
+            name,
+            value_type: match value {
+                RegValue::Dword(_) => REG_DWORD,
+                RegValue::Qword(_) => REG_QWORD,
+                RegValue::String(_) => REG_SZ,
+                RegValue::MultiString(_) => REG_MULTI_SZ,
+            },
+        };
+
+        match (name, value) {
+            (VALUE_ALLOWED_IMAGES, RegValue::MultiString(images)) => self.allowed_images = images,
+            (VALUE_CRITICAL_OVERRIDES, RegValue::MultiString(images)) => {
+                self.critical_overrides = images;
+            }
+            (VALUE_READ_ONLY, RegValue::Dword(value)) => self.read_only = value != 0,
+            (VALUE_REQUIRE_HANDLE_FOR_WRITE, RegValue::Dword(value)) => {
+                self.require_handle_for_write = value != 0;
+            }
+            (VALUE_ALLOW_CRITICAL_PROCESSES, RegValue::Dword(value)) => {
+                self.allow_critical_processes = value != 0;
+            }
+            (VALUE_MAX_TRANSFER_SIZE, RegValue::Dword(value)) => {
+                self.set_max_transfer_size(name, u64::from(value))?;
+            }
+            (VALUE_MAX_TRANSFER_SIZE, RegValue::Qword(value)) => {
+                self.set_max_transfer_size(name, value)?;
+            }
+            (VALUE_LOG_LEVEL, RegValue::Dword(value)) => {
+                if value > MAX_LOG_LEVEL {
+                    return Err(PolicyError::OutOfRange { name });
+                }
+                self.log_level = value;
+            }
+            (VALUE_DEVICE_SDDL, RegValue::String(sddl)) => {
+                if sddl.is_empty() || !sddl.bytes().all(|byte| byte.is_ascii_graphic()) {
+                    return Err(PolicyError::OutOfRange { name });
+                }
+                self.device_sddl = Some(sddl);
+            }
+            (name, value) if VALUE_NAMES.contains(&name) => return Err(wrong_type(&value)),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn set_max_transfer_size(&mut self, name: &'static str, size: u64) -> Result<(), PolicyError> {
+        if size == 0 || size > MAX_TRANSFER_SIZE {
+            return Err(PolicyError::OutOfRange { name });
+        }
+
+        self.max_transfer_size = size;
+        Ok(())
+    }
+
+    /// Checks what can be decided before the target is resolved: whether writes
+    /// are allowed at all, how they must name their target, and the size.
+    pub fn check_request(&self, target: Target, write: bool, size: u64) -> Result<(), Denial> {
+        if write && self.read_only {
+            return Err(Denial::ReadOnly);
+        }
+
+        if write && self.require_handle_for_write && matches!(target, Target::Pid(_)) {
+            return Err(Denial::HandleRequired);
+        }
+
+        if size > self.max_transfer_size {
+            return Err(Denial::TransferTooLarge);
+        }
+
+        Ok(())
+    }
+
+    /// Checks a resolved target by its image name. `critical` tells whether the
+    /// kernel considers the process critical, protected, or a system process.
+    pub fn check_image(&self, image: &[u8], critical: bool) -> Result<(), Denial> {
+        let critical = critical || is_critical_image_name(image);
+        if critical && !self.allows_critical(image) {
+            return Err(Denial::CriticalProcess);
+        }
+
+        if !self.allowed_images.is_empty()
+            && !self
+                .allowed_images
+                .iter()
+                .any(|allowed| image_name_matches(image, allowed))
+        {
+            return Err(Denial::ImageNotAllowed);
+        }
+
+        Ok(())
+    }
+
+    fn allows_critical(&self, image: &[u8]) -> bool {
+        self.allow_critical_processes
+            || self
+                .critical_overrides
+                .iter()
+                .any(|allowed| image_name_matches(image, allowed))
+    }
+}
+
+/// Returns `true` if `name` (as reported by `PsGetProcessImageFileName`, so possibly
+/// truncated to `IMAGE_FILE_NAME_LEN` bytes) is one of `CRITICAL_IMAGE_NAMES`.
+pub fn is_critical_image_name(name: &[u8]) -> bool {
+    CRITICAL_IMAGE_NAMES
+        .iter()
+        .any(|critical| image_name_matches(name, critical))
+}
+
+/// Case-insensitive comparison that accounts for the kernel truncating long names.
+pub fn image_name_matches(name: &[u8], expected: &str) -> bool {
+    let expected = expected.as_bytes();
+    let expected = &expected[..expected.len().min(IMAGE_FILE_NAME_LEN)];
+
+    name.eq_ignore_ascii_case(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn utf16(strings: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for string in strings {
+            for unit in string.encode_utf16().chain([0]) {
+                data.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    #[test]
+    fn critical_names_match_case_insensitively() {
+        assert!(is_critical_image_name(b"lsass.exe"));
+        assert!(is_critical_image_name(b"CSRSS.EXE"));
+        assert!(is_critical_image_name(b"System"));
+        assert!(!is_critical_image_name(b"notepad.exe"));
+        assert!(!is_critical_image_name(b"lsass.exe.bak"));
+        assert!(!is_critical_image_name(b""));
+    }
+
+    #[test]
+    fn truncated_names_match() {
+        let long = "averyveryverylongname.exe";
+
+        assert!(image_name_matches(b"averyveryverylo", long));
+        assert!(!image_name_matches(b"averyveryveryl", long));
+        assert!(!image_name_matches(long.as_bytes(), long));
+    }
+
+    #[test]
+    fn decodes_registry_values() {
+        assert_eq!(
+            RegValue::decode("n", REG_DWORD, &7u32.to_le_bytes()),
+            Ok(RegValue::Dword(7))
+        );
+        assert_eq!(
+            RegValue::decode("n", REG_QWORD, &(1u64 << 40).to_le_bytes()),
+            Ok(RegValue::Qword(1 << 40))
+        );
+        assert_eq!(
+            RegValue::decode("n", REG_SZ, &utf16(&["D:P(A;;GA;;;SY)"])),
+            Ok(RegValue::String("D:P(A;;GA;;;SY)".to_string()))
+        );
+        assert_eq!(
+            RegValue::decode("n", REG_MULTI_SZ, &utf16(&["a.exe", "b.exe"])),
+            Ok(RegValue::MultiString(vec![
+                "a.exe".to_string(),
+                "b.exe".to_string()
+            ]))
+        );
+        assert_eq!(
+            RegValue::decode("n", REG_MULTI_SZ, &[0, 0]),
+            Ok(RegValue::MultiString(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_registry_values() {
+        assert_eq!(
+            RegValue::decode("n", REG_DWORD, &[1, 2, 3]),
+            Err(PolicyError::Malformed { name: "n" })
+        );
+        assert_eq!(
+            RegValue::decode("n", REG_SZ, &[b'a', 0, b'b']),
+            Err(PolicyError::Malformed { name: "n" })
+        );
+        // unpaired surrogate
+        assert_eq!(
+            RegValue::decode("n", REG_MULTI_SZ, &[0x00, 0xd8, 0, 0]),
+            Err(PolicyError::Malformed { name: "n" })
+        );
+        assert_eq!(
+            RegValue::decode("n", 3, &[]),
+            Err(PolicyError::WrongType {
+                name: "n",
+                value_type: 3
+            })
+        );
+    }
+
+    #[test]
+    fn missing_values_keep_defaults() {
+        assert_eq!(Policy::from_values([]), Ok(Policy::default()));
+    }
+
+    #[test]
+    fn parses_every_setting() {
+        let policy = Policy::from_values([
+            (
+                VALUE_ALLOWED_IMAGES,
+                RegValue::MultiString(vec!["game.exe".to_string()]),
+            ),
+            (VALUE_READ_ONLY, RegValue::Dword(1)),
+            (VALUE_MAX_TRANSFER_SIZE, RegValue::Qword(4096)),
+            (
+                VALUE_DEVICE_SDDL,
+                RegValue::String("D:P(A;;GA;;;SY)".to_string()),
+            ),
+            (VALUE_LOG_LEVEL, RegValue::Dword(2)),
+            (VALUE_REQUIRE_HANDLE_FOR_WRITE, RegValue::Dword(1)),
+            (VALUE_ALLOW_CRITICAL_PROCESSES, RegValue::Dword(0)),
+            (
+                VALUE_CRITICAL_OVERRIDES,
+                RegValue::MultiString(vec!["csrss.exe".to_string()]),
+            ),
+            ("SomethingElse", RegValue::Dword(5)),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            policy,
+            Policy {
+                allowed_images: vec!["game.exe".to_string()],
+                read_only: true,
+                max_transfer_size: 4096,
+                device_sddl: Some("D:P(A;;GA;;;SY)".to_string()),
+                log_level: 2,
+                require_handle_for_write: true,
+                allow_critical_processes: false,
+                critical_overrides: vec!["csrss.exe".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let cases = [
+            (
+                VALUE_READ_ONLY,
+                RegValue::String("yes".to_string()),
+                PolicyError::WrongType {
+                    name: VALUE_READ_ONLY,
+                    value_type: REG_SZ,
+                },
+            ),
+            (
+                VALUE_MAX_TRANSFER_SIZE,
+                RegValue::Dword(0),
+                PolicyError::OutOfRange {
+                    name: VALUE_MAX_TRANSFER_SIZE,
+                },
+            ),
+            (
+                VALUE_MAX_TRANSFER_SIZE,
+                RegValue::Qword(MAX_TRANSFER_SIZE + 1),
+                PolicyError::OutOfRange {
+                    name: VALUE_MAX_TRANSFER_SIZE,
+                },
+            ),
+            (
+                VALUE_LOG_LEVEL,
+                RegValue::Dword(MAX_LOG_LEVEL + 1),
+                PolicyError::OutOfRange {
+                    name: VALUE_LOG_LEVEL,
+                },
+            ),
+            (
+                VALUE_DEVICE_SDDL,
+                RegValue::String("D:P (A;;GA;;;SY)".to_string()),
+                PolicyError::OutOfRange {
+                    name: VALUE_DEVICE_SDDL,
+                },
+            ),
+        ];
+
+        for (name, value, err) in cases {
+            assert_eq!(Policy::from_values([(name, value)]), Err(err));
+        }
+    }
+
+    #[test]
+    fn deny_all_refuses_everything() {
+        let policy = Policy::deny_all();
+
+        assert_eq!(
+            policy.check_request(Target::Handle(8), true, 1),
+            Err(Denial::ReadOnly)
+        );
+        assert_eq!(
+            policy.check_request(Target::Pid(8), true, 1),
+            Err(Denial::ReadOnly)
+        );
+        assert!(policy.require_handle_for_write);
+
+        for image in [&b"game.exe"[..], b""] {
+            assert_eq!(
+                policy.check_image(image, false),
+                Err(Denial::ImageNotAllowed)
+            );
+        }
+        assert_eq!(
+            policy.check_image(b"lsass.exe", false),
+            Err(Denial::CriticalProcess)
+        );
+    }
+
+    #[test]
+    fn request_checks() {
+        let policy = Policy {
+            read_only: true,
+            max_transfer_size: 16,
+            ..Policy::default()
+        };
+
+        assert_eq!(policy.check_request(Target::Pid(8), false, 16), Ok(()));
+        assert_eq!(
+            policy.check_request(Target::Pid(8), false, 17),
+            Err(Denial::TransferTooLarge)
+        );
+        assert_eq!(
+            policy.check_request(Target::Handle(8), true, 1),
+            Err(Denial::ReadOnly)
+        );
+
+        let policy = Policy {
+            require_handle_for_write: true,
+            ..Policy::default()
+        };
+
+        assert_eq!(policy.check_request(Target::Pid(8), false, 1), Ok(()));
+        assert_eq!(policy.check_request(Target::Handle(8), true, 1), Ok(()));
+        assert_eq!(
+            policy.check_request(Target::Pid(8), true, 1),
+            Err(Denial::HandleRequired)
+        );
+    }
+
+    #[test]
+    fn image_checks() {
+        let policy = Policy::default();
+
+        assert_eq!(policy.check_image(b"game.exe", false), Ok(()));
+        assert_eq!(
+            policy.check_image(b"game.exe", true),
+            Err(Denial::CriticalProcess)
+        );
+        assert_eq!(
+            policy.check_image(b"LSASS.exe", false),
+            Err(Denial::CriticalProcess)
+        );
+
+        let policy = Policy {
+            allowed_images: vec!["game.exe".to_string(), "csrss.exe".to_string()],
+            critical_overrides: vec!["csrss.exe".to_string()],
+            ..Policy::default()
+        };
+
+        assert_eq!(policy.check_image(b"Game.exe", false), Ok(()));
+        assert_eq!(policy.check_image(b"csrss.exe", false), Ok(()));
+        assert_eq!(
+            policy.check_image(b"other.exe", false),
+            Err(Denial::ImageNotAllowed)
+        );
+        assert_eq!(
+            policy.check_image(b"lsass.exe", false),
+            Err(Denial::CriticalProcess)
+        );
+
+        let policy = Policy {
+            allow_critical_processes: true,
+            ..Policy::default()
+        };
+
+        assert_eq!(policy.check_image(b"lsass.exe", true), Ok(()));
+    }
+}
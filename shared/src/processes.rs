@@ -0,0 +1,261 @@
+//! Process listing.
+//!
+//! The driver takes a snapshot of the running processes and fills in what only
+//! the kernel knows reliably: creation times, parents, sessions, PEBs. The
+//! output buffer holds the `Response`, a `ProcessesReply`, then `process_count`
+//! `ProcessEntry`s. A client whose buffer was too small learns how many there
+//! were, and asks again.
+
+use crate::{
+    codec::{ensure_len, utf16_tail_to_utf8, Reader},
+    ipc::{ProcessEntry, ProcessesReply},
+    protocol::{ProtocolError, RESPONSE_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `ProcessesReply` on the wire.
+pub const PROCESSES_REPLY_SIZE: usize = 8;
+
+/// Size of an encoded `ProcessEntry` on the wire.
+pub const PROCESS_ENTRY_SIZE: usize = 424;
+
+/// Size of `ProcessEntry::name`.
+pub const PROCESS_NAME_SIZE: usize = 128;
+
+/// Size of `ProcessEntry::path`.
+pub const PROCESS_PATH_SIZE: usize = 256;
+
+/// Upper bound on the processes of one listing. PIDs are multiples of four
+/// below 2^18 in practice, so this leaves plenty of room.
+pub const MAX_PROCESSES: usize = 1 << 16;
+
+/// Offset of the first `ProcessEntry` in the output buffer.
+pub const PROCESSES_OFFSET: usize = RESPONSE_SIZE + PROCESSES_REPLY_SIZE;
+
+/* PROCESS FLAGS */
+
+// the process runs under WOW64; its 32-bit PEB is not `peb_address`
+pub const PROCESS_WOW64: u32 = 1 << 0;
+
+// the process exited, or its PID was reused, before the driver could look at
+// it; only the fields of the snapshot are filled in
+pub const PROCESS_GONE: u32 = 1 << 1;
+
+/// Size of the output buffer of a listing with room for `max_processes` processes.
+pub const fn processes_output_size(max_processes: usize) -> usize {
+    PROCESSES_OFFSET + max_processes * PROCESS_ENTRY_SIZE
+}
+
+impl ProcessesReply {
+    pub fn to_bytes(&self) -> [u8; PROCESSES_REPLY_SIZE] {
+        let mut buf = [0; PROCESSES_REPLY_SIZE];
+        buf[0..4].copy_from_slice(&self.process_count.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.total_count.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            process_count: reader.u32()?,
+            total_count: reader.u32()?,
+        })
+    }
+}
+
+impl ProcessEntry {
+    /// A process with no name, path, or PEB.
+    pub const fn new(
+        process_id: u32,
+        parent_process_id: u32,
+        session_id: u32,
+        create_time: u64,
+    ) -> Self {
+        Self {
+            process_id,
+            parent_process_id,
+            session_id,
+            flags: 0,
+            create_time,
+            peb_address: 0,
+            name_len: 0,
+            path_len: 0,
+            reserved: 0,
+            name: [0; PROCESS_NAME_SIZE],
+            path: [0; PROCESS_PATH_SIZE],
+        }
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[..(self.name_len as usize).min(PROCESS_NAME_SIZE)]
+    }
+
+    pub fn path(&self) -> &[u8] {
+        &self.path[..(self.path_len as usize).min(PROCESS_PATH_SIZE)]
+    }
+
+    /// Stores the name as the kernel keeps it, see `utf16_tail_to_utf8`.
+    pub fn set_name_utf16(&mut self, name: &[u16]) {
+        self.name_len = utf16_tail_to_utf8(name, &mut self.name) as u16;
+    }
+
+    /// Stores the path as the kernel keeps it, see `utf16_tail_to_utf8`.
+    pub fn set_path_utf16(&mut self, path: &[u16]) {
+        self.path_len = utf16_tail_to_utf8(path, &mut self.path) as u16;
+    }
+
+    pub const fn is_wow64(&self) -> bool {
+        self.flags & PROCESS_WOW64 != 0
+    }
+
+    pub const fn is_gone(&self) -> bool {
+        self.flags & PROCESS_GONE != 0
+    }
+
+    /// Whether the image is called `name`, compared without regard to ASCII
+    /// case the way Windows compares file names.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name.as_bytes())
+    }
+
+    pub fn to_bytes(&self) -> [u8; PROCESS_ENTRY_SIZE] {
+        let mut buf = [0; PROCESS_ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.parent_process_id.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.session_id.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.create_time.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.peb_address.to_le_bytes());
+        buf[32..34].copy_from_slice(&self.name_len.to_le_bytes());
+        buf[34..36].copy_from_slice(&self.path_len.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[40..168].copy_from_slice(&self.name);
+        buf[168..424].copy_from_slice(&self.path);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let entry = Self {
+            process_id: reader.u32()?,
+            parent_process_id: reader.u32()?,
+            session_id: reader.u32()?,
+            flags: reader.u32()?,
+            create_time: reader.u64()?,
+            peb_address: reader.u64()?,
+            name_len: reader.u16()?,
+            path_len: reader.u16()?,
+            reserved: reader.u32()?,
+            name: reader.array()?,
+            path: reader.array()?,
+        };
+
+        if entry.name_len as usize > PROCESS_NAME_SIZE {
+            return Err(ProtocolError::InvalidField("name_len"));
+        }
+
+        if entry.path_len as usize > PROCESS_PATH_SIZE {
+            return Err(ProtocolError::InvalidField("path_len"));
+        }
+
+        Ok(entry)
+    }
+}
+
+/// Decodes the output buffer of a process listing.
+pub fn decode_processes(buf: &[u8]) -> Result<(ProcessesReply, Vec<ProcessEntry>), ProtocolError> {
+    ensure_len(buf, PROCESSES_OFFSET)?;
+
+    let reply = ProcessesReply::from_bytes(&buf[RESPONSE_SIZE..])?;
+    if reply.process_count > reply.total_count || reply.total_count as usize > MAX_PROCESSES {
+        return Err(ProtocolError::InvalidField("process_count"));
+    }
+
+    let count = reply.process_count as usize;
+    ensure_len(&buf[PROCESSES_OFFSET..], count * PROCESS_ENTRY_SIZE)?;
+
+    let processes = buf[PROCESSES_OFFSET..]
+        .chunks_exact(PROCESS_ENTRY_SIZE)
+        .take(count)
+        .map(ProcessEntry::from_bytes)
+        .collect::<Result<_, _>>()?;
+
+    Ok((reply, processes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn processes_round_trip() {
+        let mut process = ProcessEntry::new(4312, 1200, 1, 133_000_000_000_000_000);
+        process.flags = PROCESS_WOW64;
+        process.peb_address = 0x2f1000;
+        process.set_name_utf16(&utf16("game.exe"));
+        process.set_path_utf16(&utf16("C:\\Games\\game.exe"));
+
+        let reply = ProcessesReply {
+            process_count: 1,
+            total_count: 2,
+        };
+
+        let mut buf = vec![0; RESPONSE_SIZE];
+        buf.extend_from_slice(&reply.to_bytes());
+        buf.extend_from_slice(&process.to_bytes());
+        assert_eq!(buf.len(), processes_output_size(1));
+
+        let (decoded_reply, decoded) = decode_processes(&buf).unwrap();
+        assert_eq!(decoded_reply, reply);
+        assert_eq!(decoded, vec![process]);
+        assert_eq!(decoded[0].path(), b"C:\\Games\\game.exe");
+        assert!(decoded[0].is_wow64());
+        assert!(!decoded[0].is_gone());
+    }
+
+    #[test]
+    fn names_compare_without_case() {
+        let mut process = ProcessEntry::new(4, 0, 0, 0);
+        process.set_name_utf16(&utf16("Game.EXE"));
+
+        assert!(process.has_name("game.exe"));
+        assert!(process.has_name("GAME.exe"));
+        assert!(!process.has_name("game"));
+        assert!(!process.has_name("game.exe.bak"));
+    }
+
+    #[test]
+    fn more_entries_than_processes_are_refused() {
+        let reply = ProcessesReply {
+            process_count: 2,
+            total_count: 1,
+        };
+
+        let mut buf = vec![0; RESPONSE_SIZE];
+        buf.extend_from_slice(&reply.to_bytes());
+        buf.resize(processes_output_size(2), 0);
+
+        assert_eq!(
+            decode_processes(&buf),
+            Err(ProtocolError::InvalidField("process_count"))
+        );
+    }
+
+    #[test]
+    fn oversized_lengths_are_refused() {
+        let mut process = ProcessEntry::new(4, 0, 0, 0);
+        process.path_len = PROCESS_PATH_SIZE as u16 + 1;
+
+        assert_eq!(
+            ProcessEntry::from_bytes(&process.to_bytes()),
+            Err(ProtocolError::InvalidField("path_len"))
+        );
+    }
+}
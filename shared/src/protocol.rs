@@ -0,0 +1,857 @@
+use crate::{
+    codec::Reader,
+    ipc::{AttachRequest, AttachResponse, HelloResponse, Request, RequestHeader, Response},
+    status::{nt_success, STATUS_SUCCESS},
+};
+use alloc::vec::Vec;
+
+/// Magic value every request header starts with ("EREB" in little-endian).
+pub const PROTOCOL_MAGIC: u32 = u32::from_le_bytes(*b"EREB");
+
+/// Version of the wire protocol. Bump this whenever the layout of any type in
+/// `ipc` changes, so mismatched client and driver builds refuse to talk.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Size of an encoded `RequestHeader` on the wire.
+pub const HEADER_SIZE: usize = 16;
+
+/// Every payload length is a multiple of this, so each structure inside it
+/// starts on a naturally aligned offset.
+pub const PAYLOAD_ALIGN: usize = 8;
+
+/// Size of an encoded `Request` on the wire.
+pub const REQUEST_SIZE: usize = 32;
+
+/// Largest `Request::size` the driver accepts in one operation.
+pub const MAX_TRANSFER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Size of an encoded `AttachRequest` on the wire.
+pub const ATTACH_REQUEST_SIZE: usize = 16;
+
+/// Size of an encoded `AttachResponse` on the wire.
+pub const ATTACH_RESPONSE_SIZE: usize = 16;
+
+/// Size of an encoded `HelloResponse` on the wire.
+pub const HELLO_RESPONSE_SIZE: usize = 16;
+
+/// Size of an encoded `Response` on the wire.
+pub const RESPONSE_SIZE: usize = 24;
+
+/// Offset of the data area in the output buffer of a read or write. Memory
+/// operations use direct I/O: the output buffer starts with the `Response`,
+/// and the bytes read (or to be written) follow it.
+pub const DATA_OFFSET: usize = RESPONSE_SIZE;
+
+/// `create_time` of a request that does not care when its target was created.
+/// Any other value must match the target's creation time, in 100ns intervals
+/// since 1601-01-01 UTC, or the request fails with
+/// `STATUS_PROCESS_IDENTITY_MISMATCH`. That catches a PID that was reused
+/// between looking it up and sending the request.
+pub const ANY_CREATE_TIME: u64 = 0;
+
+/// `Response::failing_offset` of an operation that did not fail.
+pub const NO_FAILING_OFFSET: u64 = u64::MAX;
+
+/* REQUEST FLAGS */
+
+// `process_id` holds a handle to the target process, opened by the caller, instead of a PID
+pub const FLAG_PROCESS_HANDLE: u32 = 1 << 0;
+
+// the target is the process the device handle's session is attached to; `process_id` must be 0
+pub const FLAG_SESSION: u32 = 1 << 1;
+
+// every flag this build understands; `decode_request` rejects the rest
+const KNOWN_FLAGS: u32 = FLAG_PROCESS_HANDLE | FLAG_SESSION;
+
+/* ATTACH FLAGS */
+
+// allow writes through the session; a handle target must grant `PROCESS_VM_WRITE`
+pub const ATTACH_WRITE: u32 = 1 << 0;
+
+const KNOWN_ATTACH_FLAGS: u32 = ATTACH_WRITE;
+
+/* CAPABILITIES */
+
+// driver supports `Opcode::Read`
+pub const CAP_READ: u64 = 1 << 0;
+
+// driver supports `Opcode::Write`
+pub const CAP_WRITE: u64 = 1 << 1;
+
+// driver supports `Opcode::ReadBatch` and `Opcode::WriteBatch`
+pub const CAP_BATCH: u64 = 1 << 2;
+
+// driver accepts `FLAG_PROCESS_HANDLE`
+pub const CAP_PROCESS_HANDLE: u64 = 1 << 3;
+
+// driver supports `Opcode::ReloadPolicy`
+pub const CAP_RELOAD_POLICY: u64 = 1 << 4;
+
+// driver keeps an audit log, drained with `Opcode::DrainAudit`
+pub const CAP_AUDIT: u64 = 1 << 5;
+
+// driver supports `Opcode::Attach` and `FLAG_SESSION`
+pub const CAP_SESSION: u64 = 1 << 6;
+
+// driver supports `Opcode::ReadLossy`
+pub const CAP_READ_LOSSY: u64 = 1 << 7;
+
+// driver supports `Opcode::QueryRegions`
+pub const CAP_REGIONS: u64 = 1 << 8;
+
+// driver supports `Opcode::QueryModules`
+pub const CAP_MODULES: u64 = 1 << 9;
+
+// driver supports `Opcode::ListProcesses`
+pub const CAP_PROCESSES: u64 = 1 << 10;
+
+// driver supports `Opcode::GetEvents`
+pub const CAP_EVENTS: u64 = 1 << 11;
+
+// driver supports `Opcode::ReadChain`
+pub const CAP_READ_CHAIN: u64 = 1 << 12;
+
+// driver supports `Opcode::CompareExchange`
+pub const CAP_COMPARE_EXCHANGE: u64 = 1 << 13;
+
+/// The process a memory operation acts on, as named by the `process_id` field of
+/// its payload together with the header flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Looked up by the driver, with no access check against the caller.
+    Pid(u32),
+    /// A process handle in the caller's handle table. Handles only ever carry 32
+    /// significant bits, even in 64-bit processes, so they fit the PID field.
+    Handle(u32),
+    /// The process the device handle's session was attached to with `Opcode::Attach`.
+    Session,
+}
+
+impl Target {
+    /// Header flags announcing how `raw` is to be interpreted.
+    pub const fn flags(self) -> u32 {
+        match self {
+            Self::Pid(_) => 0,
+            Self::Handle(_) => FLAG_PROCESS_HANDLE,
+            Self::Session => FLAG_SESSION,
+        }
+    }
+
+    /// Value to put in the payload's `process_id` field.
+    pub const fn raw(self) -> u32 {
+        match self {
+            Self::Pid(value) | Self::Handle(value) => value,
+            Self::Session => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Opcode {
+    Hello = 0,
+    Read = 1,
+    Write = 2,
+    ReadBatch = 3,
+    WriteBatch = 4,
+    ReloadPolicy = 5,
+    DrainAudit = 6,
+    Attach = 7,
+    ReadLossy = 8,
+    QueryRegions = 9,
+    QueryModules = 10,
+    ListProcesses = 11,
+    GetEvents = 12,
+    ReadChain = 13,
+    CompareExchange = 14,
+}
+
+impl TryFrom<u16> for Opcode {
+    type Error = ProtocolError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Hello),
+            1 => Ok(Self::Read),
+            2 => Ok(Self::Write),
+            3 => Ok(Self::ReadBatch),
+            4 => Ok(Self::WriteBatch),
+            5 => Ok(Self::ReloadPolicy),
+            6 => Ok(Self::DrainAudit),
+            7 => Ok(Self::Attach),
+            8 => Ok(Self::ReadLossy),
+            9 => Ok(Self::QueryRegions),
+            10 => Ok(Self::QueryModules),
+            11 => Ok(Self::ListProcesses),
+            12 => Ok(Self::GetEvents),
+            13 => Ok(Self::ReadChain),
+            14 => Ok(Self::CompareExchange),
+            _ => Err(ProtocolError::UnknownOpcode(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The buffer is shorter than the structure being decoded.
+    BufferTooSmall { expected: usize, actual: usize },
+    /// The header does not start with `PROTOCOL_MAGIC`.
+    BadMagic(u32),
+    /// The peer speaks a different protocol version.
+    VersionMismatch { expected: u16, actual: u16 },
+    /// The header carries an opcode this build does not know about.
+    UnknownOpcode(u16),
+    /// `payload_len` claims more bytes than the buffer holds.
+    PayloadLength { declared: usize, available: usize },
+    /// A batch or pointer chain holds more entries than the driver accepts.
+    TooManyEntries { count: u32, max: u32 },
+    /// A batch entry is empty, overflows, or points outside the client buffer.
+    InvalidEntry { index: u32 },
+    /// A payload length is not a multiple of `PAYLOAD_ALIGN`.
+    Misaligned { len: usize },
+    /// The structure was decoded but bytes were left over.
+    TrailingBytes(usize),
+    /// A field holds a value outside its permitted range.
+    InvalidField(&'static str),
+}
+
+impl core::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::BufferTooSmall { expected, actual } => {
+                write!(
+                    f,
+                    "buffer too small: expected {expected} bytes, got {actual}"
+                )
+            }
+            Self::BadMagic(magic) => write!(f, "bad protocol magic {magic:#x}"),
+            Self::VersionMismatch { expected, actual } => write!(
+                f,
+                "protocol version mismatch: expected {expected}, got {actual}"
+            ),
+            Self::UnknownOpcode(opcode) => write!(f, "unknown opcode {opcode}"),
+            Self::PayloadLength {
+                declared,
+                available,
+            } => write!(
+                f,
+                "payload length {declared} exceeds the {available} bytes available"
+            ),
+            Self::TooManyEntries { count, max } => {
+                write!(f, "batch has {count} entries, at most {max} are allowed")
+            }
+            Self::InvalidEntry { index } => write!(f, "batch entry {index} is invalid"),
+            Self::Misaligned { len } => {
+                write!(
+                    f,
+                    "payload length {len} is not a multiple of {PAYLOAD_ALIGN}"
+                )
+            }
+            Self::TrailingBytes(extra) => write!(f, "{extra} unexpected trailing bytes"),
+            Self::InvalidField(field) => write!(f, "field `{field}` is out of range"),
+        }
+    }
+}
+
+impl core::error::Error for ProtocolError {}
+
+/// Returns `true` if a peer speaking `version` can be talked to.
+pub const fn is_compatible(version: u16) -> bool {
+    version == PROTOCOL_VERSION
+}
+
+impl RequestHeader {
+    pub const fn new(opcode: Opcode, flags: u32, payload_len: u32) -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            opcode: opcode as u16,
+            flags,
+            payload_len,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.opcode.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.payload_len.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a header, checking the magic and protocol version.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let header = Self {
+            magic: reader.u32()?,
+            version: reader.u16()?,
+            opcode: reader.u16()?,
+            flags: reader.u32()?,
+            payload_len: reader.u32()?,
+        };
+
+        if header.magic != PROTOCOL_MAGIC {
+            return Err(ProtocolError::BadMagic(header.magic));
+        }
+
+        if !is_compatible(header.version) {
+            return Err(ProtocolError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                actual: header.version,
+            });
+        }
+
+        Ok(header)
+    }
+
+    pub fn opcode(&self) -> Result<Opcode, ProtocolError> {
+        Opcode::try_from(self.opcode)
+    }
+
+    /// Interprets the `process_id` field of this request's payload. It must be
+    /// 0 for a session target, and anything but 0 otherwise.
+    pub const fn target(&self, process_id: u32) -> Result<Target, ProtocolError> {
+        let target = match self.flags & (FLAG_PROCESS_HANDLE | FLAG_SESSION) {
+            0 => Target::Pid(process_id),
+            FLAG_PROCESS_HANDLE => Target::Handle(process_id),
+            FLAG_SESSION => Target::Session,
+            _ => return Err(ProtocolError::InvalidField("flags")),
+        };
+
+        if (process_id == 0) != matches!(target, Target::Session) {
+            return Err(ProtocolError::InvalidField("process_id"));
+        }
+
+        Ok(target)
+    }
+}
+
+impl HelloResponse {
+    pub const fn current(capabilities: u64) -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            reserved: 0,
+            capabilities,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HELLO_RESPONSE_SIZE] {
+        let mut buf = [0; HELLO_RESPONSE_SIZE];
+        buf[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.capabilities.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a hello response. Only the magic is checked here; the caller
+    /// decides what to do with a driver speaking a different version.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let response = Self {
+            magic: reader.u32()?,
+            version: reader.u16()?,
+            reserved: reader.u16()?,
+            capabilities: reader.u64()?,
+        };
+
+        if response.magic != PROTOCOL_MAGIC {
+            return Err(ProtocolError::BadMagic(response.magic));
+        }
+
+        Ok(response)
+    }
+}
+
+impl Response {
+    pub const fn success(bytes_transferred: u64) -> Self {
+        Self {
+            status: STATUS_SUCCESS,
+            reserved: 0,
+            bytes_transferred,
+            failing_offset: NO_FAILING_OFFSET,
+        }
+    }
+
+    pub const fn failure(status: i32, bytes_transferred: u64, failing_offset: u64) -> Self {
+        Self {
+            status,
+            reserved: 0,
+            bytes_transferred,
+            failing_offset,
+        }
+    }
+
+    pub const fn is_success(&self) -> bool {
+        nt_success(self.status)
+    }
+
+    pub fn to_bytes(&self) -> [u8; RESPONSE_SIZE] {
+        let mut buf = [0; RESPONSE_SIZE];
+        buf[0..4].copy_from_slice(&self.status.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.bytes_transferred.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.failing_offset.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            status: reader.i32()?,
+            reserved: reader.u32()?,
+            bytes_transferred: reader.u64()?,
+            failing_offset: reader.u64()?,
+        })
+    }
+}
+
+impl Request {
+    pub fn to_bytes(&self) -> [u8; REQUEST_SIZE] {
+        let mut buf = [0; REQUEST_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.address.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.create_time.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a request payload and checks every field's range: the payload
+    /// must be exactly `REQUEST_SIZE` bytes, the target range must be non-null
+    /// and must not wrap, and `size` must be within `1..=MAX_TRANSFER_SIZE`.
+    /// `process_id` is checked against the header flags by `RequestHeader::target`.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let process_id = reader.u32()?;
+        let reserved = reader.u32()?;
+        let address = reader.u64()?;
+        let size = reader.u64()?;
+        let create_time = reader.u64()?;
+        reader.finish()?;
+
+        if reserved != 0 {
+            return Err(ProtocolError::InvalidField("reserved"));
+        }
+
+        if size == 0 || size > MAX_TRANSFER_SIZE {
+            return Err(ProtocolError::InvalidField("size"));
+        }
+
+        if address == 0 || address.checked_add(size).is_none() {
+            return Err(ProtocolError::InvalidField("address"));
+        }
+
+        Ok(Self {
+            process_id,
+            reserved,
+            address,
+            size,
+            create_time,
+        })
+    }
+}
+
+impl AttachRequest {
+    pub fn to_bytes(&self) -> [u8; ATTACH_REQUEST_SIZE] {
+        let mut buf = [0; ATTACH_REQUEST_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.flags.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.create_time.to_le_bytes());
+        buf
+    }
+
+    /// Decodes an attach payload, which must be exactly `ATTACH_REQUEST_SIZE`
+    /// bytes and carry no unknown flags.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let request = Self {
+            process_id: reader.u32()?,
+            flags: reader.u32()?,
+            create_time: reader.u64()?,
+        };
+        reader.finish()?;
+
+        if request.flags & !KNOWN_ATTACH_FLAGS != 0 {
+            return Err(ProtocolError::InvalidField("flags"));
+        }
+
+        Ok(request)
+    }
+
+    pub const fn write(&self) -> bool {
+        self.flags & ATTACH_WRITE != 0
+    }
+}
+
+impl AttachResponse {
+    pub fn to_bytes(&self) -> [u8; ATTACH_RESPONSE_SIZE] {
+        let mut buf = [0; ATTACH_RESPONSE_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.create_time.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            process_id: reader.u32()?,
+            reserved: reader.u32()?,
+            create_time: reader.u64()?,
+        })
+    }
+}
+
+/// Builds a complete request: an encoded header followed by `payload`. Refuses a
+/// payload the header could not describe: one whose length is not a multiple of
+/// `PAYLOAD_ALIGN`, or does not fit `payload_len`.
+pub fn encode_request(
+    opcode: Opcode,
+    flags: u32,
+    payload: &[u8],
+) -> Result<Vec<u8>, ProtocolError> {
+    if !payload.len().is_multiple_of(PAYLOAD_ALIGN) {
+        return Err(ProtocolError::Misaligned { len: payload.len() });
+    }
+    let payload_len =
+        u32::try_from(payload.len()).map_err(|_| ProtocolError::InvalidField("payload_len"))?;
+
+    let header = RequestHeader::new(opcode, flags, payload_len);
+
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&header.to_bytes());
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+/// Splits a request into its header and payload, validating the header, its
+/// flags, the payload alignment, and that the declared payload fits inside `buf`.
+pub fn decode_request(buf: &[u8]) -> Result<(RequestHeader, &[u8]), ProtocolError> {
+    let mut reader = Reader::new(buf);
+    let header = RequestHeader::from_bytes(reader.bytes(HEADER_SIZE)?)?;
+
+    if header.flags & !KNOWN_FLAGS != 0 {
+        return Err(ProtocolError::InvalidField("flags"));
+    }
+
+    let declared = header.payload_len as usize;
+    if !declared.is_multiple_of(PAYLOAD_ALIGN) {
+        return Err(ProtocolError::Misaligned { len: declared });
+    }
+
+    let available = reader.remaining();
+    let payload = reader
+        .bytes(declared)
+        .map_err(|_| ProtocolError::PayloadLength {
+            declared,
+            available,
+        })?;
+
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = RequestHeader::new(Opcode::Write, 0xdead_beef, 42);
+        let decoded = RequestHeader::from_bytes(&header.to_bytes()).unwrap();
+
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.opcode(), Ok(Opcode::Write));
+    }
+
+    #[test]
+    fn target_round_trips_through_flags() {
+        for target in [Target::Pid(4), Target::Handle(0x1a4)] {
+            let buf = encode_request(Opcode::Read, target.flags(), &[]).unwrap();
+            let (header, _) = decode_request(&buf).unwrap();
+
+            assert_eq!(header.target(target.raw()), Ok(target));
+        }
+    }
+
+    #[test]
+    fn target_checks_process_id_against_flags() {
+        let pid = RequestHeader::new(Opcode::Read, 0, 0);
+        let session = RequestHeader::new(Opcode::Read, FLAG_SESSION, 0);
+        let both = RequestHeader::new(Opcode::Read, FLAG_SESSION | FLAG_PROCESS_HANDLE, 0);
+
+        assert_eq!(session.target(0), Ok(Target::Session));
+        assert_eq!(
+            session.target(1234),
+            Err(ProtocolError::InvalidField("process_id"))
+        );
+        assert_eq!(
+            pid.target(0),
+            Err(ProtocolError::InvalidField("process_id"))
+        );
+        assert_eq!(both.target(4), Err(ProtocolError::InvalidField("flags")));
+    }
+
+    #[test]
+    fn attach_round_trips() {
+        let request = AttachRequest {
+            process_id: 1234,
+            flags: ATTACH_WRITE,
+            create_time: 133_000_000_000_000_000,
+        };
+        let decoded = AttachRequest::from_bytes(&request.to_bytes()).unwrap();
+        assert_eq!(decoded, request);
+        assert!(decoded.write());
+
+        let response = AttachResponse {
+            process_id: 1234,
+            reserved: 0,
+            create_time: 133_000_000_000_000_000,
+        };
+        assert_eq!(
+            AttachResponse::from_bytes(&response.to_bytes()),
+            Ok(response)
+        );
+    }
+
+    #[test]
+    fn attach_rejects_unknown_flags() {
+        let request = AttachRequest {
+            process_id: 1234,
+            flags: 1 << 7,
+            create_time: ANY_CREATE_TIME,
+        };
+
+        assert_eq!(
+            AttachRequest::from_bytes(&request.to_bytes()),
+            Err(ProtocolError::InvalidField("flags"))
+        );
+    }
+
+    #[test]
+    fn request_rejects_unknown_flags() {
+        let buf = encode_request(Opcode::Read, FLAG_PROCESS_HANDLE | 1 << 31, &[]).unwrap();
+
+        assert_eq!(
+            decode_request(&buf),
+            Err(ProtocolError::InvalidField("flags"))
+        );
+    }
+
+    #[test]
+    fn header_rejects_short_buffer() {
+        let bytes = RequestHeader::new(Opcode::Read, 0, 0).to_bytes();
+
+        assert_eq!(
+            RequestHeader::from_bytes(&bytes[..HEADER_SIZE - 1]),
+            Err(ProtocolError::BufferTooSmall {
+                expected: HEADER_SIZE,
+                actual: HEADER_SIZE - 1
+            })
+        );
+    }
+
+    #[test]
+    fn header_rejects_bad_magic() {
+        let mut bytes = RequestHeader::new(Opcode::Read, 0, 0).to_bytes();
+        bytes[0] ^= 0xff;
+
+        assert!(matches!(
+            RequestHeader::from_bytes(&bytes),
+            Err(ProtocolError::BadMagic(_))
+        ));
+    }
+
+    #[test]
+    fn header_rejects_other_version() {
+        let mut header = RequestHeader::new(Opcode::Read, 0, 0);
+        header.version = PROTOCOL_VERSION + 1;
+
+        assert_eq!(
+            RequestHeader::from_bytes(&header.to_bytes()),
+            Err(ProtocolError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                actual: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_opcode_is_reported() {
+        let mut header = RequestHeader::new(Opcode::Read, 0, 0);
+        header.opcode = 0xffff;
+
+        let decoded = RequestHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(decoded.opcode(), Err(ProtocolError::UnknownOpcode(0xffff)));
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let buf = encode_request(Opcode::Read, 0, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let (header, payload) = decode_request(&buf).unwrap();
+
+        assert_eq!(header.opcode(), Ok(Opcode::Read));
+        assert_eq!(payload, &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn request_ignores_trailing_bytes() {
+        let mut buf = encode_request(Opcode::Read, 0, &[1; 8]).unwrap();
+        buf.extend_from_slice(&[0xff; 8]);
+
+        let (_, payload) = decode_request(&buf).unwrap();
+        assert_eq!(payload, &[1; 8]);
+    }
+
+    #[test]
+    fn request_rejects_truncated_payload() {
+        let buf = encode_request(Opcode::Write, 0, &[0; 32]).unwrap();
+
+        assert_eq!(
+            decode_request(&buf[..HEADER_SIZE + 8]),
+            Err(ProtocolError::PayloadLength {
+                declared: 32,
+                available: 8
+            })
+        );
+    }
+
+    #[test]
+    fn encode_refuses_misaligned_payload() {
+        assert_eq!(
+            encode_request(Opcode::Read, 0, &[0; 5]),
+            Err(ProtocolError::Misaligned { len: 5 })
+        );
+    }
+
+    #[test]
+    fn request_rejects_misaligned_payload() {
+        let mut buf = RequestHeader::new(Opcode::Read, 0, 5).to_bytes().to_vec();
+        buf.extend_from_slice(&[0; 8]);
+
+        assert_eq!(
+            decode_request(&buf),
+            Err(ProtocolError::Misaligned { len: 5 })
+        );
+    }
+
+    fn valid_request() -> Request {
+        Request {
+            process_id: 1234,
+            reserved: 0,
+            address: 0x7ff6_0000_1000,
+            size: 8,
+            create_time: 133_000_000_000_000_000,
+        }
+    }
+
+    fn request_error(mutate: impl FnOnce(&mut [u8; REQUEST_SIZE])) -> ProtocolError {
+        let mut bytes = valid_request().to_bytes();
+        mutate(&mut bytes);
+        Request::from_bytes(&bytes).unwrap_err()
+    }
+
+    #[test]
+    fn request_payload_round_trips() {
+        let request = valid_request();
+        let decoded = Request::from_bytes(&request.to_bytes()).unwrap();
+
+        assert_eq!(decoded.process_id, request.process_id);
+        assert_eq!(decoded.address, request.address);
+        assert_eq!(decoded.size, request.size);
+        assert_eq!(decoded.create_time, request.create_time);
+    }
+
+    #[test]
+    fn request_payload_must_be_exact() {
+        let bytes = valid_request().to_bytes();
+
+        assert!(matches!(
+            Request::from_bytes(&bytes[..REQUEST_SIZE - 1]),
+            Err(ProtocolError::BufferTooSmall { .. })
+        ));
+
+        let mut long = bytes.to_vec();
+        long.extend_from_slice(&[0; 8]);
+        assert!(matches!(
+            Request::from_bytes(&long),
+            Err(ProtocolError::TrailingBytes(8))
+        ));
+    }
+
+    #[test]
+    fn request_payload_field_ranges() {
+        assert_eq!(
+            request_error(|b| b[4] = 1),
+            ProtocolError::InvalidField("reserved")
+        );
+        assert_eq!(
+            request_error(|b| b[16..24].fill(0)),
+            ProtocolError::InvalidField("size")
+        );
+        assert_eq!(
+            request_error(|b| b[16..24].copy_from_slice(&(MAX_TRANSFER_SIZE + 1).to_le_bytes())),
+            ProtocolError::InvalidField("size")
+        );
+        assert_eq!(
+            request_error(|b| b[8..16].fill(0)),
+            ProtocolError::InvalidField("address")
+        );
+        assert_eq!(
+            request_error(|b| b[8..16].fill(0xff)),
+            ProtocolError::InvalidField("address")
+        );
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let hello = HelloResponse::current(CAP_READ | CAP_WRITE);
+        let decoded = HelloResponse::from_bytes(&hello.to_bytes()).unwrap();
+
+        assert_eq!(decoded, hello);
+        assert!(is_compatible(decoded.version));
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let success = Response::success(0x1000);
+        assert_eq!(Response::from_bytes(&success.to_bytes()), Ok(success));
+        assert!(success.is_success());
+        assert_eq!(success.failing_offset, NO_FAILING_OFFSET);
+
+        let partial = Response::failure(crate::status::STATUS_PARTIAL_COPY, 0x80, 0x80);
+        assert_eq!(Response::from_bytes(&partial.to_bytes()), Ok(partial));
+        assert!(!partial.is_success());
+    }
+
+    #[test]
+    fn response_rejects_short_buffer() {
+        assert_eq!(
+            Response::from_bytes(&[0; RESPONSE_SIZE - 1]),
+            Err(ProtocolError::BufferTooSmall {
+                expected: RESPONSE_SIZE,
+                actual: RESPONSE_SIZE - 1
+            })
+        );
+    }
+
+    #[test]
+    fn hello_rejects_bad_magic() {
+        let mut bytes = HelloResponse::current(0).to_bytes();
+        bytes[3] = 0;
+
+        assert!(matches!(
+            HelloResponse::from_bytes(&bytes),
+            Err(ProtocolError::BadMagic(_))
+        ));
+    }
+}
@@ -0,0 +1,394 @@
+//! Encoding of virtual memory region queries.
+//!
+//! A query walks the target's address space like a `VirtualQueryEx` loop. The
+//! output buffer holds the `Response`, a `RegionsReply`, then `region_count`
+//! `MemoryRegion`s in address order. A query that stops early for lack of room
+//! says where to continue in `RegionsReply::next_address`.
+
+use crate::{
+    address::USER_PROBE_ADDRESS,
+    codec::{ensure_len, utf16_tail_to_utf8, Reader},
+    ipc::{MemoryRegion, RegionQuery, RegionsReply},
+    protocol::{ProtocolError, RESPONSE_SIZE},
+};
+use alloc::vec::Vec;
+
+/// Size of an encoded `RegionQuery` on the wire.
+pub const REGION_QUERY_SIZE: usize = 24;
+
+/// Size of an encoded `RegionsReply` on the wire.
+pub const REGIONS_REPLY_SIZE: usize = 16;
+
+/// Size of an encoded `MemoryRegion` on the wire.
+pub const MEMORY_REGION_SIZE: usize = 304;
+
+/// Size of `MemoryRegion::name`.
+pub const REGION_NAME_SIZE: usize = 256;
+
+/// Upper bound on `RegionQuery::max_regions`.
+pub const MAX_REGIONS_PER_QUERY: u32 = 1024;
+
+/// `RegionsReply::next_address` once there is nothing left to query.
+pub const REGIONS_DONE: u64 = 0;
+
+/* REGION STATES */
+
+pub const MEM_COMMIT: u32 = 0x1000;
+pub const MEM_RESERVE: u32 = 0x2000;
+pub const MEM_FREE: u32 = 0x1_0000;
+
+/* REGION TYPES */
+
+pub const MEM_PRIVATE: u32 = 0x2_0000;
+pub const MEM_MAPPED: u32 = 0x4_0000;
+pub const MEM_IMAGE: u32 = 0x100_0000;
+
+/* PROTECTION MODIFIERS */
+
+pub const PAGE_GUARD: u32 = 0x100;
+pub const PAGE_NOCACHE: u32 = 0x200;
+pub const PAGE_WRITECOMBINE: u32 = 0x400;
+
+/// Offset of the first `MemoryRegion` in the output buffer.
+pub const REGIONS_OFFSET: usize = RESPONSE_SIZE + REGIONS_REPLY_SIZE;
+
+/// Size of the output buffer of a query for up to `max_regions` regions.
+pub const fn regions_output_size(max_regions: u32) -> usize {
+    REGIONS_OFFSET + max_regions as usize * MEMORY_REGION_SIZE
+}
+
+impl RegionQuery {
+    pub fn to_bytes(&self) -> [u8; REGION_QUERY_SIZE] {
+        let mut buf = [0; REGION_QUERY_SIZE];
+        buf[0..4].copy_from_slice(&self.process_id.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.max_regions.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.start_address.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.create_time.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a query payload. `max_regions` must be within
+    /// `1..=MAX_REGIONS_PER_QUERY`, and `start_address` must be in user space.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let query = Self {
+            process_id: reader.u32()?,
+            max_regions: reader.u32()?,
+            start_address: reader.u64()?,
+            create_time: reader.u64()?,
+        };
+        reader.finish()?;
+
+        if query.max_regions == 0 || query.max_regions > MAX_REGIONS_PER_QUERY {
+            return Err(ProtocolError::InvalidField("max_regions"));
+        }
+
+        if query.start_address >= USER_PROBE_ADDRESS {
+            return Err(ProtocolError::InvalidField("start_address"));
+        }
+
+        Ok(query)
+    }
+}
+
+impl RegionsReply {
+    pub fn to_bytes(&self) -> [u8; REGIONS_REPLY_SIZE] {
+        let mut buf = [0; REGIONS_REPLY_SIZE];
+        buf[0..4].copy_from_slice(&self.region_count.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.next_address.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        Ok(Self {
+            region_count: reader.u32()?,
+            reserved: reader.u32()?,
+            next_address: reader.u64()?,
+        })
+    }
+}
+
+impl MemoryRegion {
+    /// A region with no mapped file name.
+    pub const fn new(
+        base_address: u64,
+        allocation_base: u64,
+        region_size: u64,
+        state: u32,
+        protect: u32,
+        allocation_protect: u32,
+        region_type: u32,
+    ) -> Self {
+        Self {
+            base_address,
+            allocation_base,
+            region_size,
+            state,
+            protect,
+            allocation_protect,
+            region_type,
+            name_len: 0,
+            reserved: 0,
+            name: [0; REGION_NAME_SIZE],
+        }
+    }
+
+    /// The mapped file name, as UTF-8.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..(self.name_len as usize).min(REGION_NAME_SIZE)]
+    }
+
+    /// Stores the file name as the kernel reports it, see `utf16_tail_to_utf8`.
+    pub fn set_name_utf16(&mut self, name: &[u16]) {
+        self.name_len = utf16_tail_to_utf8(name, &mut self.name) as u32;
+    }
+
+    pub fn end_address(&self) -> u64 {
+        self.base_address.saturating_add(self.region_size)
+    }
+
+    pub fn to_bytes(&self) -> [u8; MEMORY_REGION_SIZE] {
+        let mut buf = [0; MEMORY_REGION_SIZE];
+        buf[0..8].copy_from_slice(&self.base_address.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.allocation_base.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.region_size.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.state.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.protect.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.allocation_protect.to_le_bytes());
+        buf[36..40].copy_from_slice(&self.region_type.to_le_bytes());
+        buf[40..44].copy_from_slice(&self.name_len.to_le_bytes());
+        buf[44..48].copy_from_slice(&self.reserved.to_le_bytes());
+        buf[48..304].copy_from_slice(&self.name);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(buf);
+
+        let region = Self {
+            base_address: reader.u64()?,
+            allocation_base: reader.u64()?,
+            region_size: reader.u64()?,
+            state: reader.u32()?,
+            protect: reader.u32()?,
+            allocation_protect: reader.u32()?,
+            region_type: reader.u32()?,
+            name_len: reader.u32()?,
+            reserved: reader.u32()?,
+            name: reader.array()?,
+        };
+
+        if region.name_len as usize > REGION_NAME_SIZE {
+            return Err(ProtocolError::InvalidField("name_len"));
+        }
+
+        Ok(region)
+    }
+}
+
+/// Decodes the output buffer of a region query.
+pub fn decode_regions(buf: &[u8]) -> Result<(RegionsReply, Vec<MemoryRegion>), ProtocolError> {
+    ensure_len(buf, REGIONS_OFFSET)?;
+
+    let reply = RegionsReply::from_bytes(&buf[RESPONSE_SIZE..])?;
+    if reply.region_count > MAX_REGIONS_PER_QUERY {
+        return Err(ProtocolError::InvalidField("region_count"));
+    }
+
+    let count = reply.region_count as usize;
+    ensure_len(&buf[REGIONS_OFFSET..], count * MEMORY_REGION_SIZE)?;
+
+    let regions = buf[REGIONS_OFFSET..]
+        .chunks_exact(MEMORY_REGION_SIZE)
+        .take(count)
+        .map(MemoryRegion::from_bytes)
+        .collect::<Result<_, _>>()?;
+
+    Ok((reply, regions))
+}
+
+/// Short name of a region state, e.g. `"commit"`.
+pub const fn state_name(state: u32) -> &'static str {
+    match state {
+        MEM_COMMIT => "commit",
+        MEM_RESERVE => "reserve",
+        MEM_FREE => "free",
+        _ => "?",
+    }
+}
+
+/// Short name of a region type, e.g. `"image"`.
+pub const fn type_name(region_type: u32) -> &'static str {
+    match region_type {
+        MEM_PRIVATE => "private",
+        MEM_MAPPED => "mapped",
+        MEM_IMAGE => "image",
+        0 => "",
+        _ => "?",
+    }
+}
+
+/// Access a protection grants, in `ls -l` style: `"r-x"`, or `"rwc"` for
+/// copy-on-write. Modifiers such as `PAGE_GUARD` are ignored.
+pub const fn protection_name(protect: u32) -> &'static str {
+    match protect & 0xff {
+        0 => "",
+        0x01 => "---",
+        0x02 => "r--",
+        0x04 => "rw-",
+        0x08 => "rc-",
+        0x10 => "--x",
+        0x20 => "r-x",
+        0x40 => "rwx",
+        0x80 => "rcx",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::Response;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn query_round_trips() {
+        let query = RegionQuery {
+            process_id: 1234,
+            max_regions: 64,
+            start_address: 0x7ff6_0000_0000,
+            create_time: 133_000_000_000_000_000,
+        };
+
+        assert_eq!(RegionQuery::from_bytes(&query.to_bytes()), Ok(query));
+    }
+
+    #[test]
+    fn query_checks_its_fields() {
+        let query = RegionQuery {
+            process_id: 1234,
+            max_regions: 0,
+            start_address: 0,
+            create_time: 0,
+        };
+        assert_eq!(
+            RegionQuery::from_bytes(&query.to_bytes()),
+            Err(ProtocolError::InvalidField("max_regions"))
+        );
+
+        let query = RegionQuery {
+            max_regions: MAX_REGIONS_PER_QUERY + 1,
+            ..query
+        };
+        assert_eq!(
+            RegionQuery::from_bytes(&query.to_bytes()),
+            Err(ProtocolError::InvalidField("max_regions"))
+        );
+
+        let query = RegionQuery {
+            max_regions: 1,
+            start_address: USER_PROBE_ADDRESS,
+            ..query
+        };
+        assert_eq!(
+            RegionQuery::from_bytes(&query.to_bytes()),
+            Err(ProtocolError::InvalidField("start_address"))
+        );
+    }
+
+    #[test]
+    fn short_names_are_kept_whole() {
+        let mut region =
+            MemoryRegion::new(0x1000, 0x1000, 0x1000, MEM_COMMIT, 0x20, 0x80, MEM_IMAGE);
+        region.set_name_utf16(&utf16(
+            "\\Device\\HarddiskVolume3\\Windows\\System32\\ntdll.dll",
+        ));
+
+        assert_eq!(
+            region.name(),
+            b"\\Device\\HarddiskVolume3\\Windows\\System32\\ntdll.dll"
+        );
+    }
+
+    #[test]
+    fn long_names_keep_their_end() {
+        let path = alloc::format!("\\{}\\game.dll", "é".repeat(200));
+        let mut region = MemoryRegion::new(0, 0, 0, MEM_FREE, 1, 0, 0);
+        region.set_name_utf16(&utf16(&path));
+
+        let name = core::str::from_utf8(region.name()).unwrap();
+        assert!(name.len() <= REGION_NAME_SIZE);
+        assert!(name.ends_with("\\game.dll"));
+        assert!(path.ends_with(name));
+    }
+
+    #[test]
+    fn unpaired_surrogates_are_replaced() {
+        let mut region = MemoryRegion::new(0, 0, 0, MEM_FREE, 1, 0, 0);
+        region.set_name_utf16(&[u16::from(b'a'), 0xd800, u16::from(b'b')]);
+
+        assert_eq!(region.name(), "a\u{fffd}b".as_bytes());
+    }
+
+    #[test]
+    fn regions_round_trip() {
+        let mut image = MemoryRegion::new(
+            0x7ff6_a000_0000,
+            0x7ff6_a000_0000,
+            0x1000,
+            MEM_COMMIT,
+            0x02,
+            0x80,
+            MEM_IMAGE,
+        );
+        image.set_name_utf16(&utf16("game.exe"));
+        let free = MemoryRegion::new(0x7ff6_a000_1000, 0, 0x10_0000, MEM_FREE, 0x01, 0, 0);
+
+        let reply = RegionsReply {
+            region_count: 2,
+            reserved: 0,
+            next_address: free.end_address(),
+        };
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&Response::success(2 * MEMORY_REGION_SIZE as u64).to_bytes());
+        buf.extend_from_slice(&reply.to_bytes());
+        buf.extend_from_slice(&image.to_bytes());
+        buf.extend_from_slice(&free.to_bytes());
+        assert_eq!(buf.len(), regions_output_size(2));
+
+        assert_eq!(decode_regions(&buf), Ok((reply, alloc::vec![image, free])));
+    }
+
+    #[test]
+    fn decode_rejects_more_regions_than_fit() {
+        let reply = RegionsReply {
+            region_count: 3,
+            reserved: 0,
+            next_address: REGIONS_DONE,
+        };
+
+        let mut buf = alloc::vec![0; regions_output_size(2)];
+        buf[RESPONSE_SIZE..REGIONS_OFFSET].copy_from_slice(&reply.to_bytes());
+
+        assert!(matches!(
+            decode_regions(&buf),
+            Err(ProtocolError::BufferTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn names_describe_protection() {
+        assert_eq!(protection_name(0x20), "r-x");
+        assert_eq!(protection_name(0x04 | PAGE_GUARD), "rw-");
+        assert_eq!(state_name(MEM_RESERVE), "reserve");
+        assert_eq!(type_name(MEM_MAPPED), "mapped");
+    }
+}
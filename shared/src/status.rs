@@ -0,0 +1,89 @@
+//! NTSTATUS values and helpers shared by the driver and the client.
+
+pub const STATUS_SUCCESS: i32 = 0;
+pub const STATUS_PARTIAL_COPY: i32 = 0x8000_000D_u32 as i32;
+
+// Windows statuses the driver's request handlers fail with, from ntstatus.h.
+pub const STATUS_DATATYPE_MISALIGNMENT: i32 = 0x8000_0002_u32 as i32;
+pub const STATUS_ACCESS_VIOLATION: i32 = 0xC000_0005_u32 as i32;
+pub const STATUS_INVALID_PARAMETER: i32 = 0xC000_000D_u32 as i32;
+pub const STATUS_INVALID_DEVICE_REQUEST: i32 = 0xC000_0010_u32 as i32;
+pub const STATUS_ACCESS_DENIED: i32 = 0xC000_0022_u32 as i32;
+pub const STATUS_BUFFER_TOO_SMALL: i32 = 0xC000_0023_u32 as i32;
+pub const STATUS_REVISION_MISMATCH: i32 = 0xC000_0059_u32 as i32;
+pub const STATUS_PROCESS_IS_TERMINATING: i32 = 0xC000_010A_u32 as i32;
+pub const STATUS_CANCELLED: i32 = 0xC000_0120_u32 as i32;
+pub const STATUS_INVALID_DEVICE_STATE: i32 = 0xC000_0184_u32 as i32;
+
+/// Facility of the driver's own statuses. They also set the customer bit, so
+/// they can never collide with a status defined by Windows.
+pub const FACILITY_EREBUS: u32 = 0x0EB;
+
+/// The target is a critical, protected, or system process and the driver's
+/// policy does not allow touching it.
+pub const STATUS_CRITICAL_PROCESS_DENIED: i32 = erebus_error(1);
+
+/// The driver's policy does not allow the request: it writes in read-only mode,
+/// is too large, names its target the wrong way, or targets an image that is
+/// not allowed.
+pub const STATUS_POLICY_DENIED: i32 = erebus_error(2);
+
+/// The target process exists, but was not created at the time the request
+/// expected: its PID now belongs to a different process.
+pub const STATUS_PROCESS_IDENTITY_MISMATCH: i32 = erebus_error(3);
+
+/// A compare-exchange found other bytes than it expected and wrote nothing.
+/// A warning, so the observed bytes still reach the caller.
+pub const STATUS_COMPARE_MISMATCH: i32 = erebus_warning(4);
+
+/// Builds an error-severity, customer-defined NTSTATUS in `FACILITY_EREBUS`.
+const fn erebus_error(code: u16) -> i32 {
+    (0xE000_0000 | FACILITY_EREBUS << 16 | code as u32) as i32
+}
+
+/// Builds a warning-severity, customer-defined NTSTATUS in `FACILITY_EREBUS`.
+const fn erebus_warning(code: u16) -> i32 {
+    (0xA000_0000 | FACILITY_EREBUS << 16 | code as u32) as i32
+}
+
+/// Mirrors `NT_SUCCESS` from ntdef.h.
+pub const fn nt_success(status: i32) -> bool {
+    status >= 0
+}
+
+/// Mirrors `NT_ERROR` from ntdef.h.
+pub const fn nt_error(status: i32) -> bool {
+    (status as u32 >> 30) == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severity_helpers() {
+        assert!(nt_success(STATUS_SUCCESS));
+        assert!(!nt_error(STATUS_SUCCESS));
+
+        // warnings are neither successes nor errors
+        assert!(!nt_success(STATUS_PARTIAL_COPY));
+        assert!(!nt_error(STATUS_PARTIAL_COPY));
+
+        assert!(nt_error(0xC000_0005_u32 as i32));
+    }
+
+    #[test]
+    fn driver_statuses_are_customer_errors() {
+        assert_eq!(STATUS_CRITICAL_PROCESS_DENIED as u32, 0xE0EB_0001);
+        assert!(nt_error(STATUS_CRITICAL_PROCESS_DENIED));
+        assert_eq!(STATUS_POLICY_DENIED as u32, 0xE0EB_0002);
+        assert_eq!(STATUS_PROCESS_IDENTITY_MISMATCH as u32, 0xE0EB_0003);
+    }
+
+    #[test]
+    fn compare_mismatch_is_a_customer_warning() {
+        assert_eq!(STATUS_COMPARE_MISMATCH as u32, 0xA0EB_0004);
+        assert!(!nt_success(STATUS_COMPARE_MISMATCH));
+        assert!(!nt_error(STATUS_COMPARE_MISMATCH));
+    }
+}
@@ -0,0 +1,252 @@
+//! Property tests for the decoding layer the driver runs on untrusted input.
+
+use proptest::prelude::*;
+use shared::{
+    address::{check_user_range, LOWEST_USER_ADDRESS, USER_PROBE_ADDRESS},
+    batch::{
+        decode_batch, decode_write_batch, encode_batch, pack_entries, BATCH_ENTRY_SIZE,
+        BATCH_HEADER_SIZE, MAX_BATCH_ENTRIES,
+    },
+    chain::{decode_chain, decode_chain_response, encode_chain, MAX_CHAIN_HOPS},
+    events::{decode_events, MAX_EVENTS_PER_REPLY},
+    exchange::{decode_exchange, decode_exchange_response},
+    ipc::{BatchHeader, ChainHeader, Request, RequestHeader},
+    lossy::{decode_lossy_response, page_count, page_spans, PAGE_SIZE},
+    modules::{decode_modules, MODULE_NAME_SIZE, MODULE_PATH_SIZE},
+    processes::{decode_processes, MAX_PROCESSES},
+    protocol::{
+        decode_request, encode_request, Opcode, ProtocolError, HEADER_SIZE, MAX_TRANSFER_SIZE,
+        PAYLOAD_ALIGN, REQUEST_SIZE,
+    },
+    regions::{decode_regions, MAX_REGIONS_PER_QUERY},
+};
+
+fn valid_request() -> impl Strategy<Value = Request> {
+    (1u32.., 1..=MAX_TRANSFER_SIZE, any::<u64>()).prop_flat_map(
+        |(process_id, size, create_time)| {
+            (1..=u64::MAX - size).prop_map(move |address| Request {
+                process_id,
+                reserved: 0,
+                address,
+                size,
+                create_time,
+            })
+        },
+    )
+}
+
+proptest! {
+    #[test]
+    fn decode_request_never_panics(buf in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let _ = decode_request(&buf);
+    }
+
+    #[test]
+    fn valid_header_with_arbitrary_body_never_panics(
+        payload_len in any::<u32>(),
+        body in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        let mut buf = RequestHeader::new(Opcode::Read, 0, payload_len).to_bytes().to_vec();
+        buf.extend_from_slice(&body);
+
+        if let Ok((_, payload)) = decode_request(&buf) {
+            let _ = Request::from_bytes(payload);
+            let _ = decode_batch(payload);
+        }
+    }
+
+    #[test]
+    fn request_payload_never_panics(buf in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let _ = Request::from_bytes(&buf);
+    }
+
+    #[test]
+    fn batch_payload_never_panics(buf in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let _ = decode_batch(&buf);
+        let _ = decode_write_batch(&buf);
+    }
+
+    #[test]
+    fn chain_payload_never_panics(buf in proptest::collection::vec(any::<u8>(), 0..640)) {
+        let _ = decode_chain(&buf);
+    }
+
+    #[test]
+    fn chain_response_never_panics(
+        buf in proptest::collection::vec(any::<u8>(), 0..128),
+        read_size in any::<u32>(),
+    ) {
+        let _ = decode_chain_response(&buf, read_size);
+    }
+
+    #[test]
+    fn exchange_payload_never_panics(buf in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let _ = decode_exchange(&buf);
+    }
+
+    #[test]
+    fn exchange_response_never_panics(buf in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let _ = decode_exchange_response(&buf);
+    }
+
+    #[test]
+    fn valid_chains_round_trip(
+        offsets in proptest::collection::vec(any::<i64>(), 1..=MAX_CHAIN_HOPS as usize),
+        base in any::<u64>(),
+        wide in any::<bool>(),
+    ) {
+        let header = ChainHeader {
+            process_id: 4,
+            offset_count: offsets.len() as u32,
+            base,
+            create_time: 0,
+            pointer_width: if wide { 8 } else { 4 },
+            read_size: 8,
+        };
+
+        let (decoded_header, decoded) = decode_chain(&encode_chain(&header, &offsets)).unwrap();
+        prop_assert_eq!(decoded_header, header);
+        prop_assert_eq!(decoded, offsets);
+    }
+
+    #[test]
+    fn valid_requests_round_trip(request in valid_request()) {
+        let buf = encode_request(Opcode::Read, 0, &request.to_bytes()).unwrap();
+        let (header, payload) = decode_request(&buf).unwrap();
+        let decoded = Request::from_bytes(payload).unwrap();
+
+        prop_assert_eq!(header.opcode(), Ok(Opcode::Read));
+        prop_assert_eq!(decoded.process_id, request.process_id);
+        prop_assert_eq!(decoded.address, request.address);
+        prop_assert_eq!(decoded.size, request.size);
+        prop_assert_eq!(decoded.create_time, request.create_time);
+    }
+
+    #[test]
+    fn truncated_requests_are_rejected(request in valid_request(), cut in 1..=REQUEST_SIZE) {
+        let buf = encode_request(Opcode::Write, 0, &request.to_bytes()).unwrap();
+        let truncated = &buf[..buf.len() - cut];
+
+        prop_assert!(decode_request(truncated).is_err());
+        prop_assert!(Request::from_bytes(&request.to_bytes()[..REQUEST_SIZE - cut]).is_err());
+    }
+
+    #[test]
+    fn declared_payload_is_bounded_by_buffer(buf in proptest::collection::vec(any::<u8>(), HEADER_SIZE..256)) {
+        if let Ok((header, payload)) = decode_request(&buf) {
+            prop_assert_eq!(payload.len(), header.payload_len as usize);
+            prop_assert!(payload.len().is_multiple_of(PAYLOAD_ALIGN));
+            prop_assert!(HEADER_SIZE + payload.len() <= buf.len());
+        }
+    }
+
+    #[test]
+    fn oversized_transfers_are_rejected(request in valid_request(), extra in 1..u32::MAX as u64) {
+        let mut bytes = request.to_bytes();
+        bytes[16..24].copy_from_slice(&(MAX_TRANSFER_SIZE + extra).to_le_bytes());
+
+        prop_assert_eq!(
+            Request::from_bytes(&bytes).map(|_| ()),
+            Err(ProtocolError::InvalidField("size"))
+        );
+    }
+
+    #[test]
+    fn packed_batches_round_trip(
+        items in proptest::collection::vec((0..u64::MAX / 2, 1..4096u32), 0..64),
+        process_id in any::<u32>(),
+        create_time in any::<u64>(),
+    ) {
+        let (entries, total) = pack_entries(&items);
+        let header = BatchHeader {
+            process_id,
+            entry_count: entries.len() as u32,
+            buffer_len: total,
+            create_time,
+        };
+
+        let (decoded_header, decoded) = decode_batch(&encode_batch(&header, &entries)).unwrap();
+        prop_assert_eq!(decoded_header, header);
+        prop_assert_eq!(decoded, entries);
+    }
+
+    #[test]
+    fn batches_never_exceed_declared_count(buf in proptest::collection::vec(any::<u8>(), BATCH_HEADER_SIZE..BATCH_HEADER_SIZE + 8 * BATCH_ENTRY_SIZE)) {
+        if let Ok((header, entries)) = decode_batch(&buf) {
+            prop_assert!(header.entry_count <= MAX_BATCH_ENTRIES);
+            prop_assert_eq!(entries.len(), header.entry_count as usize);
+        }
+    }
+
+    #[test]
+    fn page_spans_tile_the_range(address in 0..u64::MAX / 2, size in 0..=MAX_TRANSFER_SIZE) {
+        let mut next = 0;
+        let mut spans = 0;
+        for (offset, len) in page_spans(address, size) {
+            prop_assert_eq!(offset, next);
+            prop_assert!(len > 0);
+            // each span stays within one page
+            prop_assert_eq!((address + offset) / PAGE_SIZE, (address + offset + len - 1) / PAGE_SIZE);
+            next += len;
+            spans += 1;
+        }
+
+        prop_assert_eq!(next, size);
+        prop_assert_eq!(spans, page_count(address, size));
+    }
+
+    #[test]
+    fn lossy_response_never_panics(
+        buf in proptest::collection::vec(any::<u8>(), 0..256),
+        address in any::<u32>(),
+        size in 0..128u64,
+    ) {
+        let _ = decode_lossy_response(&buf, u64::from(address), size);
+    }
+
+    #[test]
+    fn accepted_ranges_stay_in_user_space(address in any::<u64>(), size in any::<u64>()) {
+        if check_user_range(address, size).is_ok() {
+            prop_assert!(address >= LOWEST_USER_ADDRESS);
+            prop_assert!(address.checked_add(size).is_some_and(|end| end <= USER_PROBE_ADDRESS));
+        }
+    }
+
+    #[test]
+    fn regions_never_exceed_reply_count(buf in proptest::collection::vec(any::<u8>(), 0..1024)) {
+        if let Ok((reply, regions)) = decode_regions(&buf) {
+            prop_assert!(reply.region_count <= MAX_REGIONS_PER_QUERY);
+            prop_assert_eq!(regions.len(), reply.region_count as usize);
+        }
+    }
+
+    #[test]
+    fn modules_never_exceed_reply_count(buf in proptest::collection::vec(any::<u8>(), 0..2048)) {
+        if let Ok((reply, modules)) = decode_modules(&buf) {
+            prop_assert!(reply.module_count <= reply.total_count);
+            prop_assert_eq!(modules.len(), reply.module_count as usize);
+            for module in &modules {
+                prop_assert!(module.name().len() <= MODULE_NAME_SIZE);
+                prop_assert!(module.path().len() <= MODULE_PATH_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn processes_never_exceed_reply_count(buf in proptest::collection::vec(any::<u8>(), 0..2048)) {
+        if let Ok((reply, processes)) = decode_processes(&buf) {
+            prop_assert!(reply.total_count as usize <= MAX_PROCESSES);
+            prop_assert_eq!(processes.len(), reply.process_count as usize);
+        }
+    }
+
+    #[test]
+    fn events_never_exceed_reply_limit(buf in proptest::collection::vec(any::<u8>(), 0..2048)) {
+        if let Ok(events) = decode_events(&buf) {
+            prop_assert!(events.len() <= MAX_EVENTS_PER_REPLY);
+            for event in &events {
+                prop_assert!(event.kind().is_ok());
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! The in-memory layout of every wire type must match its encoding on each
+//! architecture a client or the driver is built for. Run these on both
+//! `x86_64` and `i686`; see the README.
+
+use shared::{
+    audit::AUDIT_RECORD_SIZE,
+    batch::{BATCH_ENTRY_SIZE, BATCH_HEADER_SIZE, BATCH_RESULT_SIZE},
+    chain::{CHAIN_HEADER_SIZE, CHAIN_REPLY_SIZE},
+    events::{EVENTS_REPLY_SIZE, EVENTS_REQUEST_SIZE, EVENT_RECORD_SIZE},
+    exchange::{EXCHANGE_REPLY_SIZE, EXCHANGE_REQUEST_SIZE},
+    ipc::{
+        AttachRequest, AttachResponse, AuditRecord, BatchEntry, BatchEntryResult, BatchHeader,
+        ChainHeader, ChainReply, EventRecord, EventsReply, EventsRequest, ExchangeReply,
+        ExchangeRequest, HelloResponse, MemoryRegion, ModuleEntry, ModuleQuery, ModulesReply,
+        ProcessEntry, ProcessesReply, RegionQuery, RegionsReply, Request, RequestHeader, Response,
+    },
+    modules::{MODULES_REPLY_SIZE, MODULE_ENTRY_SIZE, MODULE_QUERY_SIZE},
+    processes::{PROCESSES_REPLY_SIZE, PROCESS_ENTRY_SIZE},
+    protocol::{
+        ATTACH_REQUEST_SIZE, ATTACH_RESPONSE_SIZE, HEADER_SIZE, HELLO_RESPONSE_SIZE, REQUEST_SIZE,
+        RESPONSE_SIZE,
+    },
+    regions::{MEMORY_REGION_SIZE, REGIONS_REPLY_SIZE, REGION_QUERY_SIZE},
+};
+use std::mem::{align_of, offset_of, size_of};
+
+/// Views `bytes` as a `T`, the way a peer that copies the struct verbatim would.
+fn view<T: Copy>(bytes: &[u8]) -> T {
+    assert_eq!(bytes.len(), size_of::<T>());
+    // Safety: the length matches, and every wire type is plain integers and
+    // byte arrays, for which any bit pattern is valid.
+    unsafe { bytes.as_ptr().cast::<T>().read_unaligned() }
+}
+
+/// A buffer whose every byte differs from its neighbours, so a field read
+/// from the wrong offset shows up in the re-encoding.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 1) as u8).collect()
+}
+
+macro_rules! layout_tests {
+    ($($name:ident: $ty:ty = $size:expr;)*) => {
+        $(
+            #[test]
+            fn $name() {
+                assert_eq!(size_of::<$ty>(), $size);
+                assert!(align_of::<$ty>() <= 8);
+
+                let bytes = pattern($size);
+                assert_eq!(view::<$ty>(&bytes).to_bytes().as_slice(), bytes.as_slice());
+            }
+        )*
+    };
+}
+
+layout_tests! {
+    request_header: RequestHeader = HEADER_SIZE;
+    request: Request = REQUEST_SIZE;
+    attach_request: AttachRequest = ATTACH_REQUEST_SIZE;
+    attach_response: AttachResponse = ATTACH_RESPONSE_SIZE;
+    hello_response: HelloResponse = HELLO_RESPONSE_SIZE;
+    response: Response = RESPONSE_SIZE;
+    batch_header: BatchHeader = BATCH_HEADER_SIZE;
+    batch_entry: BatchEntry = BATCH_ENTRY_SIZE;
+    batch_entry_result: BatchEntryResult = BATCH_RESULT_SIZE;
+    audit_record: AuditRecord = AUDIT_RECORD_SIZE;
+    region_query: RegionQuery = REGION_QUERY_SIZE;
+    regions_reply: RegionsReply = REGIONS_REPLY_SIZE;
+    memory_region: MemoryRegion = MEMORY_REGION_SIZE;
+    module_query: ModuleQuery = MODULE_QUERY_SIZE;
+    modules_reply: ModulesReply = MODULES_REPLY_SIZE;
+    module_entry: ModuleEntry = MODULE_ENTRY_SIZE;
+    processes_reply: ProcessesReply = PROCESSES_REPLY_SIZE;
+    process_entry: ProcessEntry = PROCESS_ENTRY_SIZE;
+    events_request: EventsRequest = EVENTS_REQUEST_SIZE;
+    events_reply: EventsReply = EVENTS_REPLY_SIZE;
+    event_record: EventRecord = EVENT_RECORD_SIZE;
+    chain_header: ChainHeader = CHAIN_HEADER_SIZE;
+    chain_reply: ChainReply = CHAIN_REPLY_SIZE;
+    exchange_request: ExchangeRequest = EXCHANGE_REQUEST_SIZE;
+    exchange_reply: ExchangeReply = EXCHANGE_REPLY_SIZE;
+}
+
+#[test]
+fn request_addresses_are_64_bit_everywhere() {
+    assert_eq!(offset_of!(Request, address), 8);
+    assert_eq!(offset_of!(Request, size), 16);
+
+    // An address above 4 GiB survives a 32-bit client or driver intact.
+    let request = Request {
+        process_id: 4,
+        reserved: 0,
+        address: 0x7ff6_0000_1000,
+        size: 8,
+        create_time: 0,
+    };
+    assert_eq!(Request::from_bytes(&request.to_bytes()), Ok(request));
+}
+
+#[test]
+fn wow64_addresses_round_trip() {
+    // The top of a large-address-aware 32-bit process.
+    let request = Request {
+        process_id: 4,
+        reserved: 0,
+        address: 0xffff_e000,
+        size: 0x1000,
+        create_time: 0,
+    };
+    let bytes = request.to_bytes();
+    assert_eq!(&bytes[8..16], &0xffff_e000u64.to_le_bytes());
+    assert_eq!(view::<Request>(&bytes), request);
+}
@@ -0,0 +1,92 @@
+use crate::driver::{Access, Driver};
+use serde_json::json;
+use shared::{
+    audit::{image_name, ChainVerifier},
+    constants::DRIVER_UM_NAME,
+    ipc::AuditRecord,
+    protocol::Opcode,
+    sha256::DIGEST_SIZE,
+};
+
+/// Drains the driver's audit log, prints every record as one line of JSON on
+/// stdout, and verifies the hash chain. `--after <sequence>:<hash>` continues
+/// the chain from the last record of a previous export.
+pub(crate) fn export(args: &[String]) -> Result<(), String> {
+    let mut verifier = match args {
+        [] => ChainVerifier::new(),
+        [flag, anchor] if flag == "--after" => parse_anchor(anchor)?,
+        _ => return Err("Usage: audit [--after <sequence>:<hash>]".to_string()),
+    };
+
+    let driver = Driver::new(DRIVER_UM_NAME, Access::ReadWrite)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+
+    let records = driver
+        .drain_audit()
+        .map_err(|err| format!("Could not drain the audit log: {err}"))?;
+
+    // Print everything, even past a broken link: the records are gone from the driver.
+    let mut broken = 0;
+    for record in &records {
+        println!("{}", to_json(record));
+
+        if let Err(err) = verifier.push(record) {
+            eprintln!("Audit chain broken: {err}");
+            broken += 1;
+            verifier = ChainVerifier::after(record.sequence, record.hash);
+        }
+    }
+
+    match records.last() {
+        Some(last) => eprintln!(
+            "Exported {} records, resume with --after {}:{}",
+            records.len(),
+            last.sequence,
+            hex::encode(last.hash)
+        ),
+        None => eprintln!("The audit log is empty."),
+    }
+
+    if broken > 0 {
+        return Err(format!(
+            "Audit chain verification failed in {broken} places"
+        ));
+    }
+
+    Ok(())
+}
+
+fn parse_anchor(anchor: &str) -> Result<ChainVerifier, String> {
+    let invalid = || format!("Invalid anchor {anchor}, expected <sequence>:<hash>");
+
+    let (sequence, hash) = anchor.split_once(':').ok_or_else(invalid)?;
+    let sequence = sequence.parse().map_err(|_| invalid())?;
+
+    let mut digest = [0u8; DIGEST_SIZE];
+    hex::decode_to_slice(hash, &mut digest).map_err(|_| invalid())?;
+
+    Ok(ChainVerifier::after(sequence, digest))
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn to_json(record: &AuditRecord) -> serde_json::Value {
+    let opcode = Opcode::try_from(record.opcode).map_or_else(
+        |_| record.opcode.to_string(),
+        |opcode| format!("{opcode:?}"),
+    );
+
+    json!({
+        "sequence": record.sequence,
+        "timestamp": record.timestamp,
+        "caller_pid": record.caller_pid,
+        "caller_image": String::from_utf8_lossy(image_name(&record.caller_image)),
+        "target_pid": record.target_pid,
+        "target_image": String::from_utf8_lossy(image_name(&record.target_image)),
+        "opcode": opcode,
+        "address": format!("{:#x}", record.address),
+        "size": record.size,
+        "status": format!("{:#010x}", record.status as u32),
+        "prev_hash": hex::encode(record.prev_hash),
+        "hash": hex::encode(record.hash),
+    })
+}
@@ -0,0 +1,74 @@
+use crate::{
+    driver::{Access, Driver},
+    session::Session,
+    utils::{find_process, Address},
+};
+use shared::{constants::DRIVER_UM_NAME, protocol::Target};
+
+/// Follows a pointer chain in a process and prints the value it ends at:
+/// `chain game.exe game.dll+0x10 0x20 -0x8` reads `[[game.dll+0x10] + 0x20] - 0x8`.
+/// The driver walks the whole chain in one request. Pointers are 4 bytes wide in
+/// a WOW64 process. `--size` is the number of bytes read at the end, 8 by default.
+pub(crate) fn follow(args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "Usage: chain <process> <address> [<offset>...] [--size <bytes>]";
+
+    let (args, size) = match args {
+        [args @ .., flag, size] if flag == "--size" => {
+            let size = size
+                .parse()
+                .map_err(|err| format!("Invalid size {size}: {err}"))?;
+            (args, size)
+        }
+        args => (args, 8),
+    };
+    let [process_name, address_str, offsets @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+    let address = Address::parse(address_str)?;
+
+    // hop 0 reads the pointer at the address itself
+    let offsets = std::iter::once(Ok(0))
+        .chain(offsets.iter().map(|offset| parse_offset(offset)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let driver = Driver::new(DRIVER_UM_NAME, Access::Read)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+    let process = find_process(&driver, process_name)?;
+    let pid = process.pid;
+    let session = Session::attach(driver, Target::Pid(pid), process, Access::Read)
+        .map_err(|err| format!("Could not attach to process {pid}: {err}"))?;
+    let base = session
+        .resolve(&address)
+        .map_err(|err| format!("Could not resolve {address_str}: {err}"))?;
+
+    let pointer_width = if process.wow64 { 4 } else { 8 };
+    let read = session
+        .read_chain(base, &offsets, pointer_width, size)
+        .map_err(|err| format!("Could not follow the chain: {err}"))?;
+
+    if let Some((hop, err)) = read.failure {
+        return Err(format!(
+            "Hop {hop} could not read {:#x}: {err}",
+            read.address
+        ));
+    }
+    println!("{address_str} -> {:#x} = {:02x?}", read.address, read.value);
+
+    Ok(())
+}
+
+/// Parses a hexadecimal offset, which may be negative, as in `-0x10`.
+fn parse_offset(str: &str) -> Result<i64, String> {
+    let (negative, digits) = match str.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, str),
+    };
+    let digits = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .unwrap_or(digits);
+
+    let offset =
+        i64::from_str_radix(digits, 16).map_err(|err| format!("Invalid offset {str}: {err}"))?;
+    Ok(if negative { -offset } else { offset })
+}
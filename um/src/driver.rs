@@ -1,127 +1,1198 @@
+use crate::error::DriverError;
 use shared::{
-    ioctl::{EREBUS_IOCTL_READ, EREBUS_IOCTL_WRITE},
-    ipc::Request,
+    audit::{decode_records, AUDIT_RECORD_SIZE},
+    batch::{batch_output_size, batch_response_size, decode_batch_response, pack_entries},
+    chain::{chain_output_size, decode_chain_response},
+    events::{decode_events, events_output_size, MAX_EVENTS_PER_REPLY},
+    exchange::{decode_exchange_response, EXCHANGE_OUTPUT_SIZE},
+    ioctl::{self, IoctlSpec, Payload, Reply},
+    ipc::{
+        AttachRequest, AttachResponse, AuditRecord, BatchEntry, BatchHeader, ChainHeader,
+        EventRecord, EventsRequest, ExchangeRequest, HelloResponse, MemoryRegion, ModuleEntry,
+        ModuleQuery, ProcessEntry, RegionQuery, Request, Response,
+    },
+    lossy::{decode_lossy_response, lossy_output_size, PageMap},
+    modules::{decode_modules, find_module, modules_output_size},
+    processes::{decode_processes, processes_output_size},
+    protocol::{
+        encode_request, is_compatible, Target, ANY_CREATE_TIME, ATTACH_RESPONSE_SIZE, ATTACH_WRITE,
+        DATA_OFFSET, HELLO_RESPONSE_SIZE, PROTOCOL_VERSION, RESPONSE_SIZE,
+    },
+    regions::{decode_regions, regions_output_size, REGIONS_DONE},
+    status::{nt_error, nt_success, STATUS_COMPARE_MISMATCH},
+};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    ptr::from_ref,
+    time::{Duration, Instant},
 };
-use std::ffi::c_void;
-use std::{error::Error, mem::MaybeUninit, ptr::from_ref};
 use windows::{
     core::HSTRING,
+    Wdk::{Storage::FileSystem::NtCancelIoFileEx, System::IO::NtDeviceIoControlFile},
     Win32::{
-        Foundation::{CloseHandle, GENERIC_READ, GENERIC_WRITE, HANDLE},
-        Storage::FileSystem::{CreateFileW, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_MODE, OPEN_EXISTING},
-        System::IO::DeviceIoControl,
+        Foundation::{
+            CloseHandle, GENERIC_READ, GENERIC_WRITE, HANDLE, NTSTATUS, STATUS_PENDING,
+            WAIT_OBJECT_0,
+        },
+        Storage::FileSystem::{
+            CreateFileW, FILE_ATTRIBUTE_NORMAL, FILE_FLAGS_AND_ATTRIBUTES, FILE_FLAG_OVERLAPPED,
+            FILE_SHARE_MODE, OPEN_EXISTING,
+        },
+        System::{
+            Threading::{CreateEventW, WaitForSingleObject, INFINITE},
+            IO::IO_STATUS_BLOCK,
+        },
     },
 };
 
+/// Audit records requested per drain round trip.
+const AUDIT_DRAIN_CHUNK: usize = 256;
+
+/// Memory regions requested per query round trip.
+const REGIONS_CHUNK: u32 = 256;
+
+/// Modules room is made for on the first query. Processes with more are asked
+/// again with room for all of them.
+const MODULES_CHUNK: usize = 256;
+
+/// Processes room is made for on the first listing, see `MODULES_CHUNK`.
+const PROCESSES_CHUNK: usize = 512;
+
+/// Rights the device handle is opened with. The driver's IOCTL codes carry the
+/// access they need, so a `Read` handle is refused writes by the I/O manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Read,
+    ReadWrite,
+}
+
+impl Access {
+    const fn desired_access(self) -> u32 {
+        match self {
+            Self::Read => GENERIC_READ.0,
+            Self::ReadWrite => GENERIC_READ.0 | GENERIC_WRITE.0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Driver {
     pub handle: HANDLE,
+    /// Opened by `new_overlapped`.
+    overlapped: bool,
 }
 
 impl Driver {
-    pub(crate) fn new(device_name: &str) -> Result<Self, String> {
+    pub(crate) fn new(device_name: &str, access: Access) -> Result<Self, String> {
+        Self::open(device_name, access, false)
+    }
+
+    /// Opens the device for overlapped I/O. Requests the driver runs on a worker
+    /// then return before they complete, see `begin_ioctl`, and can be cancelled
+    /// while they run. The blocking methods work all the same, waiting for each
+    /// request to complete.
+    pub(crate) fn new_overlapped(device_name: &str, access: Access) -> Result<Self, String> {
+        Self::open(device_name, access, true)
+    }
+
+    fn open(device_name: &str, access: Access, overlapped: bool) -> Result<Self, String> {
+        let flags = if overlapped {
+            FILE_ATTRIBUTE_NORMAL | FILE_FLAG_OVERLAPPED
+        } else {
+            FILE_ATTRIBUTE_NORMAL
+        };
+
         let handle_result = unsafe {
             CreateFileW(
                 &HSTRING::from(device_name),
-                GENERIC_WRITE.0 | GENERIC_READ.0,
+                access.desired_access(),
                 FILE_SHARE_MODE(0),
                 None,
                 OPEN_EXISTING,
-                FILE_ATTRIBUTE_NORMAL,
+                FILE_FLAGS_AND_ATTRIBUTES(flags.0),
                 None,
             )
         };
 
-        match handle_result {
-            Ok(handle) if !handle.is_invalid() => Ok(Self { handle }),
-            _ => Err("Could not open driver device!".to_string()),
+        let driver = match handle_result {
+            Ok(handle) if !handle.is_invalid() => Self { handle, overlapped },
+            _ => return Err("Could not open driver device!".to_string()),
+        };
+
+        let hello = driver
+            .hello()
+            .map_err(|err| format!("Driver did not answer hello: {err}"))?;
+
+        if !is_compatible(hello.version) {
+            return Err(format!(
+                "Incompatible driver protocol version {} (expected {PROTOCOL_VERSION})",
+                hello.version
+            ));
         }
+
+        Ok(driver)
+    }
+
+    /// Queries the driver's protocol version and supported operations.
+    pub(crate) fn hello(&self) -> Result<HelloResponse, DriverError> {
+        let mut output = [0u8; HELLO_RESPONSE_SIZE];
+        let (hello, _) = self.call::<ioctl::Hello>(0, &(), &mut output)?;
+
+        Ok(hello)
     }
 
-    pub(crate) fn issue_ioctl(
+    /// Makes the driver re-read its policy from the registry. Needs a handle
+    /// opened for writing.
+    pub(crate) fn reload_policy(&self) -> Result<(), DriverError> {
+        let mut output = [0u8; RESPONSE_SIZE];
+        let (response, _) = self.call::<ioctl::ReloadPolicy>(0, &(), &mut output)?;
+
+        if !response.is_success() {
+            return Err(DriverError::Incomplete(response));
+        }
+
+        Ok(())
+    }
+
+    /// Binds this handle's session to `target`, which later requests can then name
+    /// as `Target::Session`. With `write`, a handle target must grant write access.
+    /// The driver refuses a target not created at `create_time`, unless that is
+    /// `ANY_CREATE_TIME`.
+    pub(crate) fn attach(
         &self,
-        ioctl_code: u32,
-        request: Request,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        #[cfg(debug_assertions)]
-        println!("Issuing IOCTL {ioctl_code:#x}");
+        target: Target,
+        create_time: u64,
+        write: bool,
+    ) -> Result<AttachResponse, DriverError> {
+        let request = AttachRequest {
+            process_id: target.raw(),
+            flags: if write { ATTACH_WRITE } else { 0 },
+            create_time,
+        };
 
-        let mut output_buffer: Vec<u8> = Vec::with_capacity(1024);
-        let mut bytes_returned: u32 = 0;
+        let mut output = [0u8; ATTACH_RESPONSE_SIZE];
+        let (response, _) = self.call::<ioctl::Attach>(target.flags(), &request, &mut output)?;
 
-        unsafe {
-            let result = DeviceIoControl(
-                self.handle,
-                ioctl_code,
-                Some(std::ptr::addr_of!(request).cast()),
-                size_of::<Request>().try_into()?,
-                Some(output_buffer.as_mut_ptr().cast()),
-                output_buffer.capacity().try_into()?,
-                Some(&mut bytes_returned),
-                None,
-            );
+        Ok(response)
+    }
+
+    /// Takes every record out of the driver's audit log, oldest first. Needs a
+    /// handle opened for reading and writing, since drained records are gone.
+    pub(crate) fn drain_audit(&self) -> Result<Vec<AuditRecord>, DriverError> {
+        let mut records = Vec::new();
 
-            if result.is_err() {
-                return Err("DeviceIoControl returned an error!".into());
+        // Stop at the first short drain, so a busy driver cannot keep us here forever.
+        loop {
+            let mut output = vec![0u8; DATA_OFFSET + AUDIT_DRAIN_CHUNK * AUDIT_RECORD_SIZE];
+            let (response, bytes_returned) = self.call::<ioctl::DrainAudit>(0, &(), &mut output)?;
+
+            if !response.is_success() {
+                return Err(DriverError::Incomplete(response));
             }
 
-            output_buffer.set_len(bytes_returned as usize);
+            let chunk = decode_records(&output[DATA_OFFSET..bytes_returned])?;
+            let done = chunk.len() < AUDIT_DRAIN_CHUNK;
+            records.extend(chunk);
+
+            if done {
+                return Ok(records);
+            }
         }
+    }
+
+    /// Describes every region of the target's address space, in address order, the
+    /// way a `VirtualQueryEx` loop would.
+    pub(crate) fn regions(&self, target: Target) -> Result<Vec<MemoryRegion>, DriverError> {
+        self.begin_regions(target)?.finish()
+    }
+
+    /// `regions`, without waiting for the driver to finish, see `begin_ioctl`.
+    pub(crate) fn begin_regions(
+        &self,
+        target: Target,
+    ) -> Result<Pending<'_, Vec<MemoryRegion>>, DriverError> {
+        let begin = move |start_address| {
+            let query = RegionQuery {
+                process_id: target.raw(),
+                max_regions: REGIONS_CHUNK,
+                start_address,
+                create_time: ANY_CREATE_TIME,
+            };
+            let output = vec![0u8; regions_output_size(REGIONS_CHUNK)];
+            self.begin_ioctl::<ioctl::QueryRegions>(target.flags(), &query, output)
+        };
+
+        let mut regions = Vec::new();
+        let mut start_address = 0;
+
+        Ok(Pending::new(
+            begin(start_address)?,
+            move |output, bytes_returned| {
+                let output = &output[..bytes_returned];
+                successful(output)?;
+
+                let (reply, chunk) = decode_regions(output)?;
+                regions.extend(chunk);
+
+                // Addresses only grow, so a driver cannot keep us here forever.
+                if reply.next_address == REGIONS_DONE || reply.next_address <= start_address {
+                    return Ok(Round::Done(std::mem::take(&mut regions)));
+                }
+                start_address = reply.next_address;
+                Ok(Round::Next(begin(start_address)?))
+            },
+        ))
+    }
+
+    /// Lists the modules loaded into the target, those of its 32-bit loader
+    /// included.
+    pub(crate) fn modules(&self, target: Target) -> Result<Vec<ModuleEntry>, DriverError> {
+        self.begin_modules(target)?.finish()
+    }
+
+    /// `modules`, without waiting for the driver to finish, see `begin_ioctl`.
+    pub(crate) fn begin_modules(
+        &self,
+        target: Target,
+    ) -> Result<Pending<'_, Vec<ModuleEntry>>, DriverError> {
+        let query = ModuleQuery {
+            process_id: target.raw(),
+            reserved: 0,
+            create_time: ANY_CREATE_TIME,
+        };
+        let begin = move |room| {
+            let output = vec![0u8; modules_output_size(room)];
+            self.begin_ioctl::<ioctl::QueryModules>(target.flags(), &query, output)
+        };
+
+        let mut room = MODULES_CHUNK;
+
+        Ok(Pending::new(begin(room)?, move |output, bytes_returned| {
+            let output = &output[..bytes_returned];
+            successful(output)?;
+
+            let (reply, modules) = decode_modules(output)?;
+
+            // Modules may be loaded between the two queries, so this can take
+            // another round; `decode_modules` bounds how far `room` can grow.
+            if reply.module_count == reply.total_count || room >= reply.total_count as usize {
+                return Ok(Round::Done(modules));
+            }
+            room = reply.total_count as usize;
+            Ok(Round::Next(begin(room)?))
+        }))
+    }
+
+    /// Base address of the module of the target named `name`, see `find_module`.
+    pub(crate) fn module_base(&self, target: Target, name: &str) -> Result<u64, DriverError> {
+        find_module(&self.modules(target)?, name)
+            .map(|module| module.base_address)
+            .ok_or_else(|| DriverError::ModuleNotFound(name.to_string()))
+    }
+
+    /// Lists the running processes, as the kernel sees them.
+    pub(crate) fn processes(&self) -> Result<Vec<ProcessEntry>, DriverError> {
+        self.begin_processes()?.finish()
+    }
+
+    /// `processes`, without waiting for the driver to finish, see `begin_ioctl`.
+    pub(crate) fn begin_processes(&self) -> Result<Pending<'_, Vec<ProcessEntry>>, DriverError> {
+        let begin = move |room| {
+            let output = vec![0u8; processes_output_size(room)];
+            self.begin_ioctl::<ioctl::ListProcesses>(0, &(), output)
+        };
+
+        let mut room = PROCESSES_CHUNK;
+
+        Ok(Pending::new(begin(room)?, move |output, bytes_returned| {
+            let output = &output[..bytes_returned];
+            successful(output)?;
+
+            let (reply, processes) = decode_processes(output)?;
+
+            // Processes may start between the two listings, so this can take
+            // another round; `decode_processes` bounds how far `room` can grow.
+            if reply.process_count == reply.total_count || room >= reply.total_count as usize {
+                return Ok(Round::Done(processes));
+            }
+            room = reply.total_count as usize;
+            Ok(Round::Next(begin(room)?))
+        }))
+    }
+
+    /// Waits for the next events in `mask` (`EVENT_MASK_*` bits) and returns those
+    /// queued so far, oldest first. The first call starts the session's queue;
+    /// later calls change which events it takes from then on. An attached session
+    /// only gets the image and thread events of its process.
+    ///
+    /// This blocks until an event arrives, and a synchronous handle runs one
+    /// request at a time, so events are best read on a handle of their own. On a
+    /// handle opened by `new_overlapped`, `begin_next_events` can give up waiting.
+    pub(crate) fn next_events(&self, mask: u32) -> Result<Vec<EventRecord>, DriverError> {
+        self.begin_next_events(mask)?.finish()
+    }
+
+    /// `next_events`, without waiting for an event, see `begin_ioctl`. Cancelling
+    /// it is the way to stop waiting.
+    pub(crate) fn begin_next_events(
+        &self,
+        mask: u32,
+    ) -> Result<Pending<'_, Vec<EventRecord>>, DriverError> {
+        let request = EventsRequest { mask, reserved: 0 };
+        let output = vec![0u8; events_output_size(MAX_EVENTS_PER_REPLY)];
+        let ioctl = self.begin_ioctl::<ioctl::GetEvents>(0, &request, output)?;
+
+        Ok(Pending::new(ioctl, |output, bytes_returned| {
+            let output = &output[..bytes_returned];
+            successful(output)?;
+
+            Ok(Round::Done(decode_events(output)?))
+        }))
+    }
+
+    /// Endless stream of the events in `mask`, see `next_events`.
+    pub(crate) fn events(&self, mask: u32) -> Events<'_> {
+        Events {
+            driver: self,
+            mask,
+            pending: VecDeque::new(),
+            waiting: None,
+        }
+    }
+
+    /// Issues the IOCTL `I` with `request`, naming the target through `flags`, and
+    /// decodes the response at the front of `output`. Returns it along with how
+    /// many bytes of `output` the driver filled in.
+    fn call<I: IoctlSpec>(
+        &self,
+        flags: u32,
+        request: &I::Request,
+        output: &mut [u8],
+    ) -> Result<(I::Response, usize), DriverError> {
+        debug_assert!(
+            output.len() >= I::MIN_OUTPUT,
+            "output too small for {}",
+            I::NAME
+        );
+
+        let input = encode_request(I::OPCODE, flags, &request.encode())?;
+        let bytes_returned = self.issue_ioctl(I::CODE, &input, output)?;
+
+        let response = I::Response::decode(&output[..bytes_returned])?;
+        Ok((response, bytes_returned))
+    }
+
+    /// Issues the `METHOD_IN_DIRECT` IOCTL `I` with `request`, handing the driver
+    /// `data` to read. There is no output to reply in, so the `Response` is made
+    /// up from the status and how many bytes of `data` the driver used.
+    fn call_in_direct<I: IoctlSpec<Response = ()>>(
+        &self,
+        flags: u32,
+        request: &I::Request,
+        data: &[u8],
+    ) -> Result<Response, DriverError> {
+        let input = encode_request(I::OPCODE, flags, &request.encode())?;
+        // Safety: the I/O manager only probes a `METHOD_IN_DIRECT` buffer for
+        // reading, and the driver never writes to it.
+        let (status, information) =
+            unsafe { self.complete_ioctl(I::CODE, &input, from_ref(data).cast_mut()) }?;
+
+        let done = information as u64;
+        Ok(if nt_success(status.0) {
+            Response::success(done)
+        } else {
+            Response::failure(status.0, done, done)
+        })
+    }
+
+    /// Sends `input` and lets the driver fill in `output`, returning how many bytes
+    /// of it the driver reported as written. For the memory IOCTLs `output` is
+    /// locked and mapped by the driver directly, so it also carries the data.
+    fn issue_ioctl(
+        &self,
+        ioctl_code: u32,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, DriverError> {
+        // Safety: `output` is borrowed until the request completes.
+        let (_, information) = unsafe { self.complete_ioctl(ioctl_code, input, output) }?;
+        let bytes_returned = information.min(output.len());
 
         #[cfg(debug_assertions)]
         println!(
-            "Sent IOCTL {ioctl_code:#x}. Kernel response: {:?}",
-            &output_buffer[..bytes_returned as usize]
+            "Sent IOCTL {ioctl_code:#x}. Kernel returned {bytes_returned} bytes: {:?}",
+            &output[..bytes_returned.min(DATA_OFFSET)]
+        );
+
+        Ok(bytes_returned)
+    }
+
+    /// Sends `input` and waits for the driver, returning the status it completed
+    /// the request with and its `IoStatus.Information`. An error status fails.
+    ///
+    /// # Safety
+    /// `output` must stay valid until this returns, and writable unless the IOCTL
+    /// is `METHOD_IN_DIRECT`.
+    unsafe fn complete_ioctl(
+        &self,
+        ioctl_code: u32,
+        input: &[u8],
+        output: *mut [u8],
+    ) -> Result<(NTSTATUS, usize), DriverError> {
+        let mut io_status = IO_STATUS_BLOCK::default();
+        let event = if self.overlapped {
+            Some(Event::new()?)
+        } else {
+            None
+        };
+
+        let mut status = unsafe {
+            self.send_ioctl(
+                ioctl_code,
+                input,
+                output,
+                &raw mut io_status,
+                event.as_ref(),
+            )
+        }?;
+
+        // only an overlapped handle gets here; wait, as a synchronous one would have
+        if let (STATUS_PENDING, Some(event)) = (status, &event) {
+            event.wait(None);
+            status = unsafe { io_status.Anonymous.Status };
+        }
+
+        if nt_error(status.0) {
+            return Err(status.0.into());
+        }
+
+        Ok((status, io_status.Information))
+    }
+
+    /// Issues the IOCTL `I` like `call`, but returns at once with the request in
+    /// flight, on a handle opened by `new_overlapped`. On any other handle this
+    /// blocks until the request completes.
+    fn begin_ioctl<I: IoctlSpec>(
+        &self,
+        flags: u32,
+        request: &I::Request,
+        mut output: Vec<u8>,
+    ) -> Result<PendingIoctl<'_>, DriverError> {
+        debug_assert!(
+            output.len() >= I::MIN_OUTPUT,
+            "output too small for {}",
+            I::NAME
         );
 
-        Ok(output_buffer)
+        let input = encode_request(I::OPCODE, flags, &request.encode())?;
+        // boxed, so it stays put while the kernel has yet to write to it
+        let mut io_status = Box::new(IO_STATUS_BLOCK::default());
+        let event = Event::new()?;
+
+        let status = unsafe {
+            self.send_ioctl(
+                I::CODE,
+                &input,
+                output.as_mut_slice(),
+                &raw mut *io_status,
+                Some(&event),
+            )
+        }?;
+
+        if nt_error(status.0) {
+            return Err(status.0.into());
+        }
+
+        Ok(PendingIoctl {
+            driver: self,
+            io_status,
+            event,
+            status: (status != STATUS_PENDING).then_some(status),
+            output,
+        })
+    }
+
+    /// Hands a request to the I/O manager. On an overlapped handle this can return
+    /// `STATUS_PENDING`, and the driver keeps using `output` and `io_status`
+    /// until `event` is signaled.
+    ///
+    /// # Safety
+    /// `io_status` must stay valid until the request completes, as must `output`,
+    /// which must be writable unless the IOCTL is `METHOD_IN_DIRECT`.
+    unsafe fn send_ioctl(
+        &self,
+        ioctl_code: u32,
+        input: &[u8],
+        output: *mut [u8],
+        io_status: *mut IO_STATUS_BLOCK,
+        event: Option<&Event>,
+    ) -> Result<NTSTATUS, DriverError> {
+        #[cfg(debug_assertions)]
+        println!("Issuing IOCTL {ioctl_code:#x}");
+
+        // `NtDeviceIoControlFile` rather than `DeviceIoControl`, so we get the driver's real
+        // NTSTATUS instead of a lossy Win32 error, and warning statuses still return output.
+        Ok(unsafe {
+            NtDeviceIoControlFile(
+                self.handle,
+                event.map_or(HANDLE::default(), |event| event.0),
+                None,
+                None,
+                io_status,
+                ioctl_code,
+                Some(input.as_ptr().cast()),
+                input.len().try_into()?,
+                Some(output.cast()),
+                output.len().try_into()?,
+            )
+        })
     }
 
     pub(crate) fn read_process_memory<T>(
         &self,
-        process_id: u32,
-        address: *mut T,
-    ) -> Result<T, Box<dyn Error>>
+        target: Target,
+        address: u64,
+    ) -> Result<T, DriverError>
     where
         T: Copy + Sized,
     {
-        let mut buffer = MaybeUninit::<T>::uninit();
+        self.begin_read_process_memory(target, address)?.finish()
+    }
 
+    /// `read_process_memory`, without waiting for the driver to finish, see
+    /// `begin_ioctl`. The driver only runs large reads on a worker, so only those
+    /// return before they complete.
+    pub(crate) fn begin_read_process_memory<T>(
+        &self,
+        target: Target,
+        address: u64,
+    ) -> Result<Pending<'_, T>, DriverError>
+    where
+        T: Copy + Sized,
+    {
         let request = Request {
-            process_id,
-            address: address.cast(),
-            buffer: buffer.as_mut_ptr().cast(),
+            process_id: target.raw(),
+            reserved: 0,
+            address,
             size: size_of::<T>() as u64,
+            create_time: ANY_CREATE_TIME,
         };
 
-        self.issue_ioctl(EREBUS_IOCTL_READ, request)?;
+        let output = vec![0u8; DATA_OFFSET + size_of::<T>()];
+        let ioctl = self.begin_ioctl::<ioctl::Read>(target.flags(), &request, output)?;
+
+        Ok(Pending::new(ioctl, |output, bytes_returned| {
+            successful(&output[..bytes_returned])?;
 
-        // Safety: `buffer` should be initialized after `issue_ioctl` succeeds.
-        Ok(unsafe { buffer.assume_init() })
+            // Safety: the data area holds `size_of::<T>()` bytes the driver copied out
+            // of a `T` once it reports success. It need not be aligned for `T`.
+            Ok(Round::Done(unsafe {
+                output
+                    .as_ptr()
+                    .add(DATA_OFFSET)
+                    .cast::<T>()
+                    .read_unaligned()
+            }))
+        }))
     }
 
     pub(crate) fn write_process_memory<T>(
         &self,
-        process_id: u32,
-        address: *mut T,
+        target: Target,
+        address: u64,
         buffer: &T,
-    ) -> Result<(), Box<dyn Error>>
+    ) -> Result<(), DriverError>
     where
         T: Copy + Sized,
     {
+        // Safety: any initialized `T` can be viewed as `size_of::<T>()` bytes.
+        let data = unsafe {
+            std::slice::from_raw_parts(from_ref::<T>(buffer).cast::<u8>(), size_of::<T>())
+        };
+
+        let request = Request {
+            process_id: target.raw(),
+            reserved: 0,
+            address,
+            size: data.len() as u64,
+            create_time: ANY_CREATE_TIME,
+        };
+
+        let response = self.call_in_direct::<ioctl::Write>(target.flags(), &request, data)?;
+        if !response.is_success() {
+            return Err(DriverError::Incomplete(response));
+        }
+
+        Ok(())
+    }
+
+    /// Writes `new` at `address` only if the bytes there still equal `expected`,
+    /// like `AtomicU64::compare_exchange`: `Ok(Err(observed))` means nothing was
+    /// written because the target held `observed` instead. Values are 1, 2, 4, or
+    /// 8 bytes at an address aligned to their size, which the driver swaps in one
+    /// locked instruction, so the exchange is atomic against the target too.
+    pub(crate) fn compare_exchange(
+        &self,
+        target: Target,
+        address: u64,
+        expected: &[u8],
+        new: &[u8],
+    ) -> Result<Result<(), Vec<u8>>, DriverError> {
+        if expected.len() != new.len() {
+            return Err(DriverError::InvalidInput(format!(
+                "expected {} bytes but got {} to write",
+                expected.len(),
+                new.len()
+            )));
+        }
+
+        let size = expected.len();
+        let width = match u32::try_from(size) {
+            Ok(width @ (1 | 2 | 4 | 8)) if address.is_multiple_of(u64::from(width)) => width,
+            _ => {
+                return Err(DriverError::InvalidInput(format!(
+                    "can only exchange 1, 2, 4, or 8 bytes at an address aligned to them, \
+                     not {size} at {address:#x}"
+                )))
+            }
+        };
+
+        let widen = |bytes: &[u8]| {
+            let mut value = [0u8; 8];
+            value[..size].copy_from_slice(bytes);
+            u64::from_le_bytes(value)
+        };
+        let request = ExchangeRequest {
+            process_id: target.raw(),
+            size: width,
+            address,
+            create_time: ANY_CREATE_TIME,
+            expected: widen(expected),
+            new: widen(new),
+        };
+
+        let mut output = vec![0u8; EXCHANGE_OUTPUT_SIZE];
+        let (_, bytes_returned) =
+            self.call::<ioctl::CompareExchange>(target.flags(), &request, &mut output)?;
+
+        let (response, reply) = decode_exchange_response(&output[..bytes_returned])?;
+        match response.status {
+            STATUS_COMPARE_MISMATCH => Ok(Err(reply.observed.to_le_bytes()[..size].to_vec())),
+            _ if response.is_success() => Ok(Ok(())),
+            _ => Err(DriverError::Incomplete(response)),
+        }
+    }
+
+    /// Reads `size` bytes at `address` one page at a time. Pages that cannot be read
+    /// come back zero-filled and clear in the returned `PageMap`, rather than failing
+    /// the whole read, which suits dumping ranges with guard or decommitted pages.
+    pub(crate) fn read_region_lossy(
+        &self,
+        target: Target,
+        address: u64,
+        size: usize,
+    ) -> Result<(Vec<u8>, PageMap), DriverError> {
+        self.begin_read_region_lossy(target, address, size)?
+            .finish()
+    }
+
+    /// `read_region_lossy`, without waiting for the driver to finish, see
+    /// `begin_ioctl`.
+    pub(crate) fn begin_read_region_lossy(
+        &self,
+        target: Target,
+        address: u64,
+        size: usize,
+    ) -> Result<Pending<'_, (Vec<u8>, PageMap)>, DriverError> {
         let request = Request {
-            process_id,
-            address: address.cast(),
-            buffer: from_ref::<T>(buffer) as *mut c_void,
-            size: size_of_val(buffer) as u64,
+            process_id: target.raw(),
+            reserved: 0,
+            address,
+            size: size as u64,
+            create_time: ANY_CREATE_TIME,
         };
 
-        self.issue_ioctl(EREBUS_IOCTL_WRITE, request)?;
+        let output = vec![0u8; lossy_output_size(address, size as u64)];
+        let ioctl = self.begin_ioctl::<ioctl::ReadLossy>(target.flags(), &request, output)?;
+
+        Ok(Pending::new(ioctl, move |output, _| {
+            // A `STATUS_PARTIAL_COPY` response is expected here; the page map says
+            // what was missed.
+            let (_, pages, data) = decode_lossy_response(&output, address, size as u64)?;
+            Ok(Round::Done((data.to_vec(), pages)))
+        }))
+    }
+
+    /// Reads several `(address, length)` ranges of one process in a single round trip.
+    /// Each element of the result holds the bytes of the matching range, or why it failed.
+    pub(crate) fn read_many(
+        &self,
+        target: Target,
+        reads: &[(u64, usize)],
+    ) -> Result<Vec<Result<Vec<u8>, DriverError>>, DriverError> {
+        self.begin_read_many(target, reads)?.finish()
+    }
+
+    /// `read_many`, without waiting for the driver to finish, see `begin_ioctl`.
+    pub(crate) fn begin_read_many(
+        &self,
+        target: Target,
+        reads: &[(u64, usize)],
+    ) -> Result<Pending<'_, BatchReads>, DriverError> {
+        let items = reads
+            .iter()
+            .map(|&(address, len)| Ok((address, u32::try_from(len)?)))
+            .collect::<Result<Vec<_>, DriverError>>()?;
+
+        let (header, entries) = pack_batch(target, &items)?;
+        let data_len = usize::try_from(header.buffer_len)?;
+        let reply_len = batch_response_size(entries.len());
+        let entry_count = entries.len();
+
+        let output = vec![0u8; batch_output_size(entry_count, data_len)];
+        let ioctl =
+            self.begin_ioctl::<ioctl::ReadBatch>(target.flags(), &(header, entries), output)?;
+
+        let lens = reads.iter().map(|&(_, len)| len).collect::<Vec<_>>();
+
+        Ok(Pending::new(ioctl, move |mut output, _| {
+            let (_, results) = decode_batch_response(&output, entry_count)?;
+            let buffer = output.split_off(reply_len);
+
+            let mut offset = 0;
+            Ok(Round::Done(
+                lens.iter()
+                    .zip(results)
+                    .map(|(&len, result)| {
+                        let data = &buffer[offset..offset + len];
+                        offset += len;
+
+                        if nt_success(result.status) {
+                            Ok(data.to_vec())
+                        } else {
+                            Err(result.status.into())
+                        }
+                    })
+                    .collect(),
+            ))
+        }))
+    }
+
+    /// Follows a pointer chain from `base` inside the target in a single round trip,
+    /// and reads `read_size` bytes where it ends. Each offset is one hop; every hop
+    /// but the last dereferences a pointer of `pointer_width` bytes, 4 for a 32-bit
+    /// target. See `shared::chain`.
+    pub(crate) fn read_chain(
+        &self,
+        target: Target,
+        base: u64,
+        offsets: &[i64],
+        pointer_width: u32,
+        read_size: u32,
+    ) -> Result<ChainRead, DriverError> {
+        let header = ChainHeader {
+            process_id: target.raw(),
+            offset_count: u32::try_from(offsets.len())?,
+            base,
+            create_time: ANY_CREATE_TIME,
+            pointer_width,
+            read_size,
+        };
+
+        let mut output = vec![0u8; chain_output_size(read_size)];
+        let (_, bytes_returned) = self.call::<ioctl::ReadChain>(
+            target.flags(),
+            &(header, offsets.to_vec()),
+            &mut output,
+        )?;
+
+        let (response, reply, value) = decode_chain_response(&output[..bytes_returned], read_size)?;
+        let failure = (!response.is_success()).then(|| {
+            // `failing_offset` is the index of the failing hop
+            (response.failing_offset, DriverError::from(reply.status))
+        });
+
+        Ok(ChainRead {
+            address: reply.address,
+            value: value.to_vec(),
+            failure,
+        })
+    }
+
+    /// Writes several `(address, data)` pairs into one process in a single round trip.
+    /// Each element of the result tells whether the matching write succeeded.
+    pub(crate) fn write_many(
+        &self,
+        target: Target,
+        writes: &[(u64, &[u8])],
+    ) -> Result<Vec<Result<(), DriverError>>, DriverError> {
+        self.begin_write_many(target, writes)?.finish()
+    }
+
+    /// `write_many`, without waiting for the driver to finish, see `begin_ioctl`.
+    pub(crate) fn begin_write_many(
+        &self,
+        target: Target,
+        writes: &[(u64, &[u8])],
+    ) -> Result<Pending<'_, Vec<Result<(), DriverError>>>, DriverError> {
+        let items = writes
+            .iter()
+            .map(|&(address, data)| Ok((address, u32::try_from(data.len())?)))
+            .collect::<Result<Vec<_>, DriverError>>()?;
+
+        let data = writes
+            .iter()
+            .flat_map(|(_, data)| *data)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let (header, entries) = pack_batch(target, &items)?;
+        let entry_count = entries.len();
+
+        // the data travels in the input, so the output only holds the results
+        let output = vec![0u8; batch_response_size(entry_count)];
+        let ioctl = self.begin_ioctl::<ioctl::WriteBatch>(
+            target.flags(),
+            &(header, entries, data),
+            output,
+        )?;
+
+        Ok(Pending::new(ioctl, move |output, _| {
+            let (_, results) = decode_batch_response(&output, entry_count)?;
+
+            Ok(Round::Done(
+                results
+                    .into_iter()
+                    .map(|result| {
+                        if nt_success(result.status) {
+                            Ok(())
+                        } else {
+                            Err(result.status.into())
+                        }
+                    })
+                    .collect(),
+            ))
+        }))
+    }
+}
+
+/// Builds a batch whose entries are laid out back to back in the data area.
+fn pack_batch(
+    target: Target,
+    items: &[(u64, u32)],
+) -> Result<(BatchHeader, Vec<BatchEntry>), DriverError> {
+    let (entries, total) = pack_entries(items);
+
+    let header = BatchHeader {
+        process_id: target.raw(),
+        entry_count: u32::try_from(entries.len())?,
+        buffer_len: total,
+        create_time: ANY_CREATE_TIME,
+    };
+
+    Ok((header, entries))
+}
+
+/// What `read_many` returns for each range: its bytes, or why it failed.
+pub(crate) type BatchReads = Vec<Result<Vec<u8>, DriverError>>;
+
+/// Decodes the `Response` at the front of `output`, and fails unless it reports
+/// success.
+fn successful(output: &[u8]) -> Result<Response, DriverError> {
+    let response = Response::from_bytes(output)?;
+    if !response.is_success() {
+        return Err(DriverError::Incomplete(response));
+    }
+
+    Ok(response)
+}
+
+/// Iterator over the events of a session, waiting for more whenever the last
+/// batch is used up. It only ends by the caller dropping it; errors are yielded,
+/// and the next call tries again.
+#[derive(Debug)]
+pub(crate) struct Events<'a> {
+    driver: &'a Driver,
+    mask: u32,
+    pending: VecDeque<EventRecord>,
+    /// The request `next_timeout` gave up waiting for, still in flight.
+    waiting: Option<Pending<'a, Vec<EventRecord>>>,
+}
+
+impl Events<'_> {
+    /// Like `next`, but returns `None` if no event arrived within `timeout`. The
+    /// request waiting for one stays in flight for the next call, and dropping
+    /// the iterator cancels it. Only a handle opened by `new_overlapped` can give
+    /// up waiting; any other blocks until an event arrives.
+    pub(crate) fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Option<Result<EventRecord, DriverError>> {
+        let deadline = Instant::now() + timeout;
+
+        while self.pending.is_empty() {
+            let waiting = match self.waiting.take() {
+                Some(waiting) => waiting,
+                None => match self.driver.begin_next_events(self.mask) {
+                    Ok(waiting) => waiting,
+                    Err(err) => return Some(Err(err)),
+                },
+            };
+            let waiting = self.waiting.insert(waiting);
+
+            if !waiting.wait_timeout(deadline.saturating_duration_since(Instant::now())) {
+                return None;
+            }
+            if let Err(err) = self.take_waiting() {
+                return Some(Err(err));
+            }
+        }
+
+        self.pending.pop_front().map(Ok)
+    }
+
+    /// Queues the events of the request in flight, which must have completed.
+    fn take_waiting(&mut self) -> Result<(), DriverError> {
+        if let Some(waiting) = self.waiting.take() {
+            self.pending.extend(waiting.finish()?);
+        }
 
         Ok(())
     }
 }
 
+impl Iterator for Events<'_> {
+    type Item = Result<EventRecord, DriverError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            let events = match self.waiting.take() {
+                Some(waiting) => waiting.finish(),
+                None => self.driver.next_events(self.mask),
+            };
+            match events {
+                Ok(events) => self.pending.extend(events),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// A manual-reset event, signaled when an overlapped request completes.
+#[derive(Debug)]
+struct Event(HANDLE);
+
+impl Event {
+    fn new() -> Result<Self, DriverError> {
+        Ok(Self(unsafe { CreateEventW(None, true, false, None) }?))
+    }
+
+    /// Whether the event is signaled within `timeout`, or ever with `None`.
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let millis = timeout.map_or(INFINITE, |timeout| {
+            u32::try_from(timeout.as_millis()).unwrap_or(INFINITE - 1)
+        });
+
+        let event = unsafe { WaitForSingleObject(self.0, millis) };
+        event == WAIT_OBJECT_0
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0).ok() };
+    }
+}
+
+/// A request sent by `Driver::begin_ioctl` that may still be in flight. Dropping
+/// it cancels the request, and waits for the driver to let go of its buffers.
+pub(crate) struct PendingIoctl<'a> {
+    driver: &'a Driver,
+    io_status: Box<IO_STATUS_BLOCK>,
+    event: Event,
+    /// Final status, once the request completed.
+    status: Option<NTSTATUS>,
+    output: Vec<u8>,
+}
+
+impl fmt::Debug for PendingIoctl<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingIoctl")
+            .field("driver", self.driver)
+            .field("status", &self.status)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PendingIoctl<'_> {
+    /// Whether the request completed within `timeout`.
+    pub(crate) fn wait_timeout(&mut self, timeout: Duration) -> bool {
+        self.poll(Some(timeout))
+    }
+
+    /// Asks the driver to stop the request. It still has to complete, with
+    /// `STATUS_CANCELLED` unless it finished first, so `wait` for it.
+    pub(crate) fn cancel(&self) {
+        if self.status.is_none() {
+            let mut io_status = IO_STATUS_BLOCK::default();
+            // `STATUS_NOT_FOUND` if it completed in the meantime
+            let _ = unsafe {
+                NtCancelIoFileEx(
+                    self.driver.handle,
+                    Some(&raw const *self.io_status),
+                    &raw mut io_status,
+                )
+            };
+        }
+    }
+
+    /// Waits for the request to complete, and returns the output buffer along
+    /// with how many bytes of it the driver wrote.
+    pub(crate) fn wait(mut self) -> Result<(Vec<u8>, usize), DriverError> {
+        self.poll(None);
+
+        let status = self.status.unwrap_or(STATUS_PENDING);
+        if nt_error(status.0) {
+            return Err(status.0.into());
+        }
+
+        let bytes_returned = self.io_status.Information.min(self.output.len());
+        Ok((std::mem::take(&mut self.output), bytes_returned))
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> bool {
+        if self.status.is_none() && self.event.wait(timeout) {
+            self.status = Some(unsafe { self.io_status.Anonymous.Status });
+        }
+
+        self.status.is_some()
+    }
+}
+
+impl Drop for PendingIoctl<'_> {
+    fn drop(&mut self) {
+        if self.status.is_none() {
+            self.cancel();
+            self.poll(None);
+        }
+    }
+}
+
+/// Where a pointer chain led, see `Driver::read_chain`.
+#[derive(Debug)]
+pub(crate) struct ChainRead {
+    /// Where the value was read, or the address the failing hop could not read.
+    pub(crate) address: u64,
+    /// The bytes read at `address`. Short if the last hop failed partway.
+    pub(crate) value: Vec<u8>,
+    /// Index of the hop that failed, and why.
+    pub(crate) failure: Option<(u64, DriverError)>,
+}
+
+/// What became of one request of a `Pending` operation.
+enum Round<'a, T> {
+    /// The operation is done.
+    Done(T),
+    /// It takes another request, already in flight.
+    Next(PendingIoctl<'a>),
+}
+
+/// Makes the output of one request of a `Pending` operation into its result.
+type Step<'a, T> = dyn FnMut(Vec<u8>, usize) -> Result<Round<'a, T>, DriverError> + 'a;
+
+/// An operation started by one of the `begin_*` methods of `Driver`. It may take
+/// several requests, one at a time, each of them in flight like a `PendingIoctl`
+/// until it completes. Dropping this cancels the one in flight.
+pub(crate) struct Pending<'a, T> {
+    ioctl: Option<PendingIoctl<'a>>,
+    /// Makes the output of a request into the result, or sends the next one.
+    step: Box<Step<'a, T>>,
+    result: Option<Result<T, DriverError>>,
+    cancelled: Cell<bool>,
+}
+
+impl<T> fmt::Debug for Pending<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pending")
+            .field("ioctl", &self.ioctl)
+            .field("done", &self.result.is_some())
+            .field("cancelled", &self.cancelled.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, T> Pending<'a, T> {
+    fn new(
+        ioctl: PendingIoctl<'a>,
+        step: impl FnMut(Vec<u8>, usize) -> Result<Round<'a, T>, DriverError> + 'a,
+    ) -> Self {
+        Self {
+            ioctl: Some(ioctl),
+            step: Box::new(step),
+            result: None,
+            cancelled: Cell::new(false),
+        }
+    }
+
+    /// Whether the whole operation finished within `timeout`.
+    pub(crate) fn wait_timeout(&mut self, timeout: Duration) -> bool {
+        self.run(Some(Instant::now() + timeout))
+    }
+
+    /// Asks the driver to stop the request in flight, see `PendingIoctl::cancel`.
+    /// No request follows it, so the operation fails with `STATUS_CANCELLED`
+    /// unless it finished first.
+    pub(crate) fn cancel(&self) {
+        self.cancelled.set(true);
+        if let Some(ioctl) = &self.ioctl {
+            ioctl.cancel();
+        }
+    }
+
+    /// Waits for the operation to finish, and returns its result.
+    pub(crate) fn finish(mut self) -> Result<T, DriverError> {
+        self.run(None);
+        self.result
+            .take()
+            .unwrap_or(Err(DriverError::Status(STATUS_PENDING.0)))
+    }
+
+    /// Sends one request after the other until the operation finishes, or
+    /// `deadline` passes. Returns whether it finished.
+    fn run(&mut self, deadline: Option<Instant>) -> bool {
+        while let Some(ioctl) = &mut self.ioctl {
+            if let Some(deadline) = deadline {
+                if !ioctl.wait_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    return false;
+                }
+            }
+
+            let Some(ioctl) = self.ioctl.take() else {
+                break;
+            };
+            match ioctl
+                .wait()
+                .and_then(|(output, len)| (self.step)(output, len))
+            {
+                Ok(Round::Next(next)) => {
+                    if self.cancelled.get() {
+                        next.cancel();
+                    }
+                    self.ioctl = Some(next);
+                }
+                Ok(Round::Done(value)) => self.result = Some(Ok(value)),
+                Err(err) => self.result = Some(Err(err)),
+            }
+        }
+
+        true
+    }
+}
+
 impl Drop for Driver {
     fn drop(&mut self) {
         unsafe { CloseHandle(self.handle).ok() };
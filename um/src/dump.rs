@@ -0,0 +1,104 @@
+use crate::{
+    driver::{Access, Driver},
+    error::DriverError,
+    session::Session,
+    utils::{find_process, str_to_address, Address},
+};
+use shared::{
+    constants::DRIVER_UM_NAME,
+    lossy::{PageMap, PAGE_SIZE},
+    protocol::{Target, MAX_TRANSFER_SIZE},
+};
+use std::{fs::File, io::Write, time::Duration};
+
+/// Bytes read per request. A whole number of pages, so chunks of an aligned
+/// range never split a page between them.
+#[allow(clippy::cast_possible_truncation)]
+const DUMP_CHUNK: usize = MAX_TRANSFER_SIZE as usize;
+
+/// Copies `size` bytes of a process, starting at `address`, into a file. Pages
+/// that cannot be read end up as zeros in the file and are listed on stderr.
+/// With `--timeout <seconds>`, a chunk the driver takes longer than that for is
+/// cancelled, and the dump stops there.
+pub(crate) fn dump(args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "Usage: dump <process> <address> <size> <file> [--timeout <seconds>]";
+
+    let (args, timeout) = match args {
+        [args @ .., flag, seconds] if flag == "--timeout" => {
+            let seconds = seconds
+                .parse()
+                .map_err(|err| format!("Invalid timeout {seconds}: {err}"))?;
+            (args, Some(Duration::from_secs(seconds)))
+        }
+        args => (args, None),
+    };
+    let [process_name, address, size_str, path] = args else {
+        return Err(USAGE.to_string());
+    };
+    let address_str = address;
+    let address = Address::parse(address)?;
+    let size = usize::try_from(str_to_address(size_str)?)
+        .map_err(|err| format!("Invalid size {size_str}: {err}"))?;
+
+    // overlapped, so a chunk can be given up on while the driver works on it
+    let driver = Driver::new_overlapped(DRIVER_UM_NAME, Access::Read)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+    let process = find_process(&driver, process_name)?;
+    let session = Session::attach(driver, Target::Pid(process.pid), process, Access::Read)
+        .map_err(|err| format!("Could not attach to process {}: {err}", process.pid))?;
+    let address = session
+        .resolve(&address)
+        .map_err(|err| format!("Could not resolve {address_str}: {err}"))?;
+    if address.checked_add(size as u64).is_none() {
+        return Err(format!("Range {address:#x}+{size:#x} overflows"));
+    }
+
+    let mut file = File::create(path).map_err(|err| format!("Could not create {path}: {err}"))?;
+
+    let mut readable = 0;
+    let mut pages_total = 0;
+    let mut offset = 0;
+    while offset < size {
+        let chunk_address = address + offset as u64;
+        let len = (size - offset).min(DUMP_CHUNK);
+
+        let (data, pages) = match timeout {
+            None => session.read_region_lossy(chunk_address, len),
+            Some(timeout) => read_with_timeout(&session, chunk_address, len, timeout),
+        }
+        .map_err(|err| format!("Could not read {chunk_address:#x}: {err}"))?;
+
+        let first_page = chunk_address & !(PAGE_SIZE - 1);
+        for page in (0..pages.page_count()).filter(|&page| !pages.is_readable(page)) {
+            eprintln!("Unreadable page at {:#x}", first_page + page * PAGE_SIZE);
+        }
+        readable += pages.readable_count();
+        pages_total += pages.page_count();
+
+        file.write_all(&data)
+            .map_err(|err| format!("Could not write {path}: {err}"))?;
+        offset += len;
+    }
+
+    println!("Dumped {size:#x} bytes to {path}, {readable} of {pages_total} pages readable");
+
+    Ok(())
+}
+
+/// Reads a chunk like `Session::read_region_lossy`, but cancels the read if
+/// the driver has not finished it within `timeout`.
+fn read_with_timeout(
+    session: &Session,
+    address: u64,
+    size: usize,
+    timeout: Duration,
+) -> Result<(Vec<u8>, PageMap), DriverError> {
+    let mut read = session.begin_read_region_lossy(address, size)?;
+    if !read.wait_timeout(timeout) {
+        eprintln!("Reading {address:#x} takes longer than {timeout:?}, cancelling");
+        read.cancel();
+    }
+
+    // a read that finished before the cancel arrived still counts
+    read.finish()
+}
@@ -0,0 +1,95 @@
+use shared::{
+    ipc::Response,
+    protocol::ProtocolError,
+    status::{
+        STATUS_CRITICAL_PROCESS_DENIED, STATUS_POLICY_DENIED, STATUS_PROCESS_IDENTITY_MISMATCH,
+    },
+};
+use std::{fmt, num::TryFromIntError};
+use windows::Win32::Foundation::STATUS_CANCELLED;
+
+#[derive(Debug)]
+pub(crate) enum DriverError {
+    /// The driver failed the request outright with this NTSTATUS.
+    Status(i32),
+    /// The driver refused to touch a critical, protected, or system process.
+    CriticalProcess,
+    /// The driver's policy does not allow the request.
+    PolicyDenied,
+    /// The target PID now belongs to a different process than the one looked up.
+    IdentityMismatch,
+    /// The target has no module of this name loaded.
+    ModuleNotFound(String),
+    /// The operation ran but did not complete; the response says how far it got.
+    Incomplete(Response),
+    /// The driver's reply could not be decoded.
+    Protocol(ProtocolError),
+    /// The request could not be built.
+    InvalidInput(String),
+    /// The request was cancelled before it completed.
+    Cancelled,
+    /// A system call on the client side failed.
+    Windows(windows::core::Error),
+}
+
+impl fmt::Display for DriverError {
+    #[allow(clippy::cast_sign_loss)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => {
+                write!(f, "driver returned NTSTATUS {:#010x}", *status as u32)
+            }
+            Self::CriticalProcess => write!(
+                f,
+                "driver refused to touch a critical, protected, or system process"
+            ),
+            Self::PolicyDenied => write!(f, "request denied by the driver policy"),
+            Self::IdentityMismatch => write!(
+                f,
+                "target process is gone, its PID now belongs to another process"
+            ),
+            Self::ModuleNotFound(name) => write!(f, "no module named {name} is loaded"),
+            Self::Incomplete(response) => write!(
+                f,
+                "operation stopped at offset {:#x} after {} bytes (NTSTATUS {:#010x})",
+                response.failing_offset, response.bytes_transferred, response.status as u32
+            ),
+            Self::Protocol(err) => write!(f, "malformed driver response: {err}"),
+            Self::InvalidInput(msg) => write!(f, "invalid request: {msg}"),
+            Self::Cancelled => write!(f, "request cancelled"),
+            Self::Windows(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for DriverError {}
+
+impl From<i32> for DriverError {
+    fn from(status: i32) -> Self {
+        match status {
+            STATUS_CRITICAL_PROCESS_DENIED => Self::CriticalProcess,
+            STATUS_POLICY_DENIED => Self::PolicyDenied,
+            STATUS_PROCESS_IDENTITY_MISMATCH => Self::IdentityMismatch,
+            status if status == STATUS_CANCELLED.0 => Self::Cancelled,
+            status => Self::Status(status),
+        }
+    }
+}
+
+impl From<ProtocolError> for DriverError {
+    fn from(err: ProtocolError) -> Self {
+        Self::Protocol(err)
+    }
+}
+
+impl From<TryFromIntError> for DriverError {
+    fn from(err: TryFromIntError) -> Self {
+        Self::InvalidInput(err.to_string())
+    }
+}
+
+impl From<windows::core::Error> for DriverError {
+    fn from(err: windows::core::Error) -> Self {
+        Self::Windows(err)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{
+    driver::{Access, Driver, Events},
+    session::Session,
+    utils::find_process,
+};
+use serde_json::json;
+use shared::{
+    constants::DRIVER_UM_NAME,
+    events::{EventKind, EVENT_MASK_IMAGE, EVENT_MASK_PROCESS, EVENT_MASK_THREAD},
+    ipc::EventRecord,
+    protocol::Target,
+};
+use std::time::Duration;
+
+/// Prints process events as they happen, until interrupted: process creation and
+/// exit, and with `--images` and `--threads` image loads and thread starts and
+/// exits. Naming a process limits image and thread events to it. With
+/// `--timeout <seconds>`, it stops once no event arrived for that long.
+pub(crate) fn watch(args: &[String]) -> Result<(), String> {
+    const USAGE: &str =
+        "Usage: events [<process>] [--images] [--threads] [--json] [--timeout <seconds>]";
+
+    let mut process = None;
+    let mut mask = EVENT_MASK_PROCESS;
+    let mut json = false;
+    let mut timeout = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--images" => mask |= EVENT_MASK_IMAGE,
+            "--threads" => mask |= EVENT_MASK_THREAD,
+            "--timeout" => {
+                let seconds = args.next().ok_or_else(|| USAGE.to_string())?;
+                let seconds = seconds
+                    .parse()
+                    .map_err(|err| format!("Invalid timeout {seconds}: {err}"))?;
+                timeout = Some(Duration::from_secs(seconds));
+            }
+            name if !name.starts_with("--") && process.is_none() => process = Some(name),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    // overlapped, so the request waiting for events can be given up on
+    let driver = if timeout.is_some() {
+        Driver::new_overlapped(DRIVER_UM_NAME, Access::Read)
+    } else {
+        Driver::new(DRIVER_UM_NAME, Access::Read)
+    }
+    .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+
+    let Some(process) = process else {
+        return print_events(driver.events(mask), json, timeout);
+    };
+
+    let process = find_process(&driver, process)?;
+    let pid = process.pid;
+    let session = Session::attach(driver, Target::Pid(pid), process, Access::Read)
+        .map_err(|err| format!("Could not attach to process {pid}: {err}"))?;
+
+    print_events(session.events(mask), json, timeout)
+}
+
+fn print_events(
+    mut events: Events<'_>,
+    json: bool,
+    timeout: Option<Duration>,
+) -> Result<(), String> {
+    if !json {
+        println!(
+            "{:>8} {:<18} {:<14} {:>6} {:>6} Name",
+            "Sequence", "Timestamp", "Event", "PID", "Other"
+        );
+    }
+
+    loop {
+        let event = match timeout {
+            Some(timeout) => events.next_timeout(timeout),
+            None => events.next(),
+        };
+        // `Events` itself never ends, so this is the timeout passing
+        let Some(event) = event else {
+            break;
+        };
+        let event = event.map_err(|err| format!("Could not read events: {err}"))?;
+
+        if json {
+            println!("{}", to_json(&event));
+        } else {
+            println!(
+                "{:>8} {:<18} {:<14} {:>6} {:>6} {}",
+                event.sequence,
+                event.timestamp,
+                kind_name(&event),
+                event.process_id,
+                event.related_id,
+                describe(&event)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn kind_name(event: &EventRecord) -> &'static str {
+    event.kind().map_or("unknown", EventKind::name)
+}
+
+/// The name, and for image loads where the image landed.
+fn describe(event: &EventRecord) -> String {
+    let name = String::from_utf8_lossy(event.name());
+    match event.kind() {
+        Ok(EventKind::ImageLoad) => format!(
+            "{name} at {:#x} ({:#x} bytes)",
+            event.image_base, event.image_size
+        ),
+        Ok(EventKind::Lost) => format!("{} events dropped", event.related_id),
+        _ => name.into_owned(),
+    }
+}
+
+fn to_json(event: &EventRecord) -> serde_json::Value {
+    json!({
+        "sequence": event.sequence,
+        "timestamp": event.timestamp,
+        "event": kind_name(event),
+        "pid": event.process_id,
+        "related_id": event.related_id,
+        "create_time": event.create_time,
+        "image_base": format!("{:#x}", event.image_base),
+        "image_size": event.image_size,
+        "name": String::from_utf8_lossy(event.name()),
+    })
+}
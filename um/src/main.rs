@@ -6,14 +6,25 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 #![deny(clippy::pedantic)]
 
+mod audit;
+mod chain;
 mod driver;
+mod dump;
+mod error;
+mod events;
+mod modules;
+mod patch;
+mod ps;
+mod regions;
+mod session;
 mod utils;
 
 use crate::{
-    driver::Driver,
-    utils::{get_process_id, str_to_address},
+    driver::{Access, Driver},
+    session::Session,
+    utils::{find_process, Address, ProcessHandle},
 };
-use shared::constants::DRIVER_UM_NAME;
+use shared::{constants::DRIVER_UM_NAME, protocol::Target};
 use std::path::Path;
 
 fn main() {
@@ -23,7 +34,27 @@ fn main() {
     }
 }
 
-fn parse_args(args: &[String]) -> Result<(&str, &str), String> {
+#[derive(Debug)]
+struct Options {
+    access: Access,
+    // name the target by a process handle we opened, instead of by PID
+    by_handle: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<(&str, &str, Options), String> {
+    let mut options = Options {
+        access: Access::ReadWrite,
+        by_handle: false,
+    };
+
+    for arg in args.iter().skip(3) {
+        match arg.as_str() {
+            "--read-only" => options.access = Access::Read,
+            "--by-handle" => options.by_handle = true,
+            other => return Err(format!("Unknown option {other}")),
+        }
+    }
+
     if args.len() < 3 {
         let filename = Path::new(&args[0])
             .file_name()
@@ -31,49 +62,128 @@ fn parse_args(args: &[String]) -> Result<(&str, &str), String> {
             .unwrap_or("unknown");
 
         return Err(format!(
-            "Usage: {filename} <process_name> <address>\n\
-            Example: {filename} test-binary.exe 0x12345678"
+            "Usage: {filename} <process> <address> [--read-only] [--by-handle]\n\
+            \x20      {filename} reload-policy\n\
+            \x20      {filename} audit [--after <sequence>:<hash>]\n\
+            \x20      {filename} dump <process> <address> <size> <file> [--timeout <seconds>]\n\
+            \x20      {filename} chain <process> <address> [<offset>...] [--size <bytes>]\n\
+            \x20      {filename} patch <process> <address> <expected> <new>\n\
+            \x20      {filename} regions <process> [--json]\n\
+            \x20      {filename} modules <process> [--json]\n\
+            \x20      {filename} ps [<name>] [--session <id>] [--wow64] [--json]\n\
+            \x20      {filename} events [<process>] [--images] [--threads] [--json]\n\
+            Processes are named by image name or PID. Addresses are absolute, or relative\n\
+            to a module of the target.\n\
+            Example: {filename} test-binary.exe 0x12345678\n\
+            Example: {filename} test-binary.exe test-binary.exe+0x1A2B"
         ));
     }
 
-    Ok((&args[1], &args[2]))
+    Ok((&args[1], &args[2], options))
+}
+
+/// Makes the driver re-read its policy from the registry.
+fn reload_policy() -> Result<(), String> {
+    let driver = Driver::new(DRIVER_UM_NAME, Access::ReadWrite)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+
+    driver
+        .reload_policy()
+        .map_err(|err| format!("Could not reload the driver policy: {err}"))?;
+    println!("Driver policy reloaded.");
+
+    Ok(())
 }
 
 fn run() -> Result<(), String> {
     let args: Vec<String> = std::env::args().collect();
-    let (process_name, address_str) = parse_args(&args)?;
+    match args.get(1).map(String::as_str) {
+        Some("reload-policy") => return reload_policy(),
+        Some("audit") => return audit::export(&args[2..]),
+        Some("dump") => return dump::dump(&args[2..]),
+        Some("chain") => return chain::follow(&args[2..]),
+        Some("patch") => return patch::patch(&args[2..]),
+        Some("regions") => return regions::list(&args[2..]),
+        Some("modules") => return modules::list(&args[2..]),
+        Some("ps") => return ps::list(&args[2..]),
+        Some("events") => return events::watch(&args[2..]),
+        _ => {}
+    }
+
+    let (process_name, address_str, options) = parse_args(&args)?;
+    let access = options.access;
+    let address = Address::parse(address_str)?;
+
+    let driver = Driver::new(DRIVER_UM_NAME, access)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
 
-    // Retrieve the process ID using the process name.
-    let process_id = get_process_id(process_name)
-        .map_err(|err| format!("Failed to find process id for {process_name}! Error: {err}"))?;
+    // Ask the driver which process the name or PID stands for.
+    let process = find_process(&driver, process_name)?;
+    let process_id = process.pid;
 
-    // Open the driver.
-    let driver = Driver::new(DRIVER_UM_NAME)
-        .map_err(|_| format!("Failed to open driver device {DRIVER_UM_NAME}!"))?;
+    // Either let the driver look the PID up, or hand it a handle so Windows checks
+    // that we may access the process.
+    let handle = if options.by_handle {
+        Some(ProcessHandle::open(process_id, access)?)
+    } else {
+        None
+    };
+    let target = handle
+        .as_ref()
+        .map_or(Target::Pid(process_id), ProcessHandle::target);
 
-    // Parse the address string argument into a `usize`, then cast it to a mutable pointer
-    // of the required type for reading or writing.
-    let address: *mut i32 = str_to_address(address_str)? as _;
+    // Bind the device handle to the target, so later requests need not name it.
+    let session = Session::attach(driver, target, process, access)
+        .map_err(|err| format!("Could not attach to process {process_id}: {err}"))?;
+    println!("Attached to process {}", session.process_id());
+
+    // Resolve the address against the target's modules. It stays a `u64`, as the
+    // target's pointers may be wider than ours.
+    let address = session
+        .resolve(&address)
+        .map_err(|err| format!("Could not resolve {address_str}: {err}"))?;
 
     // Read a value from the process memory at the specified address.
-    let read_value = driver
-        .read_process_memory(process_id, address)
+    let read_value = session
+        .read::<i32>(address)
         .map_err(|err| format!("Could not read process memory: {err}"))?;
-    println!("Value at {address:p} = {read_value:?}");
+    println!("Value at {address:#x} = {read_value:?}");
+
+    // A read-only handle cannot issue writes, so stop here.
+    if access == Access::Read {
+        return Ok(());
+    }
 
     // Write a value to the process memory at the specified address.
     let write_value = 1337;
-    driver
-        .write_process_memory(process_id, address, &write_value)
+    session
+        .write(address, &write_value)
         .map_err(|err| format!("Could not write process memory: {err}"))?;
     println!("Finished writing, read again.");
 
     // Read the value from the process memory at the specified address again after writing,
     // to verify if it has been updated.
-    let read_values = driver
-        .read_process_memory(process_id, address)
+    let read_values = session
+        .read::<i32>(address)
         .map_err(|err| format!("Could not read process memory: {err}"))?;
-    println!("Value at {address:p} = {read_values:?}");
+    println!("Value at {address:#x} = {read_values:?}");
+
+    // Restore the original value and read it back, this time through the batch API.
+    let original = read_value.to_ne_bytes();
+    for result in session
+        .write_many(&[(address, &original)])
+        .map_err(|err| format!("Could not issue batch write: {err}"))?
+    {
+        result.map_err(|err| format!("Could not restore process memory: {err}"))?;
+    }
+
+    for result in session
+        .read_many(&[(address, original.len())])
+        .map_err(|err| format!("Could not issue batch read: {err}"))?
+    {
+        let bytes = result.map_err(|err| format!("Could not read process memory: {err}"))?;
+        println!("Restored value at {address:#x} = {bytes:02x?}");
+    }
 
     Ok(())
 }
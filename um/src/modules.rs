@@ -0,0 +1,70 @@
+use crate::{
+    driver::{Access, Driver},
+    session::Session,
+    utils::find_process,
+};
+use serde_json::json;
+use shared::{constants::DRIVER_UM_NAME, ipc::ModuleEntry, protocol::Target};
+
+/// Prints the modules loaded into a process: a table, or with `--json` one JSON
+/// object per module.
+pub(crate) fn list(args: &[String]) -> Result<(), String> {
+    let (process, json) = match args {
+        [process] => (process, false),
+        [process, flag] if flag == "--json" => (process, true),
+        _ => return Err("Usage: modules <process> [--json]".to_string()),
+    };
+
+    let driver = Driver::new(DRIVER_UM_NAME, Access::Read)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+    let process = find_process(&driver, process)?;
+    let pid = process.pid;
+    let session = Session::attach(driver, Target::Pid(pid), process, Access::Read)
+        .map_err(|err| format!("Could not attach to process {pid}: {err}"))?;
+
+    let modules = session
+        .modules()
+        .map_err(|err| format!("Could not list the modules of process {pid}: {err}"))?;
+
+    if json {
+        for module in &modules {
+            println!("{}", to_json(module));
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{:<18} {:>10} {:<5} {:<24} Path",
+        "Base", "Size", "Arch", "Name"
+    );
+    for module in &modules {
+        println!(
+            "{:#018x} {:>#10x} {:<5} {:<24} {}",
+            module.base_address,
+            module.size,
+            arch(module),
+            String::from_utf8_lossy(module.name()),
+            String::from_utf8_lossy(module.path())
+        );
+    }
+
+    Ok(())
+}
+
+fn arch(module: &ModuleEntry) -> &'static str {
+    if module.is_wow64() {
+        "x86"
+    } else {
+        "x64"
+    }
+}
+
+fn to_json(module: &ModuleEntry) -> serde_json::Value {
+    json!({
+        "base": format!("{:#x}", module.base_address),
+        "size": module.size,
+        "arch": arch(module),
+        "name": String::from_utf8_lossy(module.name()),
+        "path": String::from_utf8_lossy(module.path()),
+    })
+}
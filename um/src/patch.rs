@@ -0,0 +1,59 @@
+use crate::{
+    driver::{Access, Driver},
+    session::Session,
+    utils::{find_process, Address},
+};
+use shared::{constants::DRIVER_UM_NAME, protocol::Target};
+
+/// Patches bytes in a process only if they still hold what the caller expects:
+/// `patch game.exe game.dll+0x10 01000000 00000000` clears a 32-bit flag, and
+/// fails without writing if something else changed it first. Both values are
+/// hexadecimal byte strings in memory order, of the same length of 1, 2, 4, or
+/// 8 bytes, at an address aligned to it.
+pub(crate) fn patch(args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "Usage: patch <process> <address> <expected> <new>";
+
+    let [process_name, address_str, expected, new] = args else {
+        return Err(USAGE.to_string());
+    };
+    let address = Address::parse(address_str)?;
+    let expected = parse_bytes(expected)?;
+    let new = parse_bytes(new)?;
+
+    let driver = Driver::new(DRIVER_UM_NAME, Access::ReadWrite)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+    let process = find_process(&driver, process_name)?;
+    let pid = process.pid;
+    let session = Session::attach(driver, Target::Pid(pid), process, Access::ReadWrite)
+        .map_err(|err| format!("Could not attach to process {pid}: {err}"))?;
+    let address = session
+        .resolve(&address)
+        .map_err(|err| format!("Could not resolve {address_str}: {err}"))?;
+
+    session
+        .compare_exchange(address, &expected, &new)
+        .map_err(|err| format!("Could not patch {address:#x}: {err}"))?
+        .map_err(|observed| {
+            format!(
+                "Not patched: {address:#x} holds {} instead of {}",
+                hex::encode(observed),
+                hex::encode(&expected)
+            )
+        })?;
+    println!(
+        "Patched {address:#x}: {} -> {}",
+        hex::encode(&expected),
+        hex::encode(&new)
+    );
+
+    Ok(())
+}
+
+fn parse_bytes(str: &str) -> Result<Vec<u8>, String> {
+    let bytes = hex::decode(str.trim_start_matches("0x"))
+        .map_err(|err| format!("Invalid hexadecimal bytes {str}: {err}"))?;
+    if bytes.is_empty() {
+        return Err("Nothing to patch".to_string());
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,112 @@
+use crate::driver::{Access, Driver};
+use serde_json::json;
+use shared::{constants::DRIVER_UM_NAME, ipc::ProcessEntry};
+
+/// Which processes `ps` prints.
+#[derive(Debug, Default)]
+struct Filter {
+    /// Part of the image name, compared without regard to case.
+    name: Option<String>,
+    session_id: Option<u32>,
+    wow64_only: bool,
+}
+
+impl Filter {
+    fn matches(&self, process: &ProcessEntry) -> bool {
+        let name_matches = self.name.as_ref().is_none_or(|part| {
+            String::from_utf8_lossy(process.name())
+                .to_lowercase()
+                .contains(part.as_str())
+        });
+
+        name_matches
+            && self.session_id.is_none_or(|id| process.session_id == id)
+            && (!self.wow64_only || process.is_wow64())
+    }
+}
+
+/// Lists the running processes as the driver sees them: a table, or with
+/// `--json` one JSON object per process.
+pub(crate) fn list(args: &[String]) -> Result<(), String> {
+    const USAGE: &str = "Usage: ps [<name>] [--session <id>] [--wow64] [--json]";
+
+    let mut filter = Filter::default();
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--wow64" => filter.wow64_only = true,
+            "--session" => {
+                let id = args.next().ok_or(USAGE)?;
+                let id = id
+                    .parse()
+                    .map_err(|err| format!("Invalid session ID {id}: {err}"))?;
+                filter.session_id = Some(id);
+            }
+            name if !name.starts_with("--") && filter.name.is_none() => {
+                filter.name = Some(name.to_lowercase());
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let driver = Driver::new(DRIVER_UM_NAME, Access::Read)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+    let processes = driver
+        .processes()
+        .map_err(|err| format!("Could not list processes: {err}"))?;
+
+    let shown = processes.iter().filter(|process| filter.matches(process));
+
+    if json {
+        for process in shown {
+            println!("{}", to_json(process));
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{:>6} {:>6} {:>7} {:<4} {:<18} {:<24} Path",
+        "PID", "PPID", "Session", "Arch", "PEB", "Name"
+    );
+    for process in shown {
+        println!(
+            "{:>6} {:>6} {:>7} {:<4} {:#018x} {:<24} {}",
+            process.process_id,
+            process.parent_process_id,
+            process.session_id,
+            arch(process),
+            process.peb_address,
+            String::from_utf8_lossy(process.name()),
+            String::from_utf8_lossy(process.path())
+        );
+    }
+
+    Ok(())
+}
+
+fn arch(process: &ProcessEntry) -> &'static str {
+    if process.is_gone() {
+        "-"
+    } else if process.is_wow64() {
+        "x86"
+    } else {
+        "x64"
+    }
+}
+
+fn to_json(process: &ProcessEntry) -> serde_json::Value {
+    json!({
+        "pid": process.process_id,
+        "parent_pid": process.parent_process_id,
+        "session": process.session_id,
+        "create_time": process.create_time,
+        "wow64": process.is_wow64(),
+        "exited": process.is_gone(),
+        "peb": format!("{:#x}", process.peb_address),
+        "name": String::from_utf8_lossy(process.name()),
+        "path": String::from_utf8_lossy(process.path()),
+    })
+}
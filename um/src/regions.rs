@@ -0,0 +1,94 @@
+use crate::{
+    driver::{Access, Driver},
+    session::Session,
+    utils::find_process,
+};
+use serde_json::json;
+use shared::{
+    constants::DRIVER_UM_NAME,
+    ipc::MemoryRegion,
+    protocol::Target,
+    regions::{
+        protection_name, state_name, type_name, MEM_FREE, PAGE_GUARD, PAGE_NOCACHE,
+        PAGE_WRITECOMBINE,
+    },
+};
+
+/// Prints the memory map of a process: a table, or with `--json` one JSON object
+/// per region.
+pub(crate) fn list(args: &[String]) -> Result<(), String> {
+    let (process, json) = match args {
+        [process] => (process, false),
+        [process, flag] if flag == "--json" => (process, true),
+        _ => return Err("Usage: regions <process> [--json]".to_string()),
+    };
+
+    let driver = Driver::new(DRIVER_UM_NAME, Access::Read)
+        .map_err(|err| format!("Failed to open driver device {DRIVER_UM_NAME}! Error: {err}"))?;
+    let process = find_process(&driver, process)?;
+    let pid = process.pid;
+    let session = Session::attach(driver, Target::Pid(pid), process, Access::Read)
+        .map_err(|err| format!("Could not attach to process {pid}: {err}"))?;
+
+    let regions = session
+        .regions()
+        .map_err(|err| format!("Could not query the regions of process {pid}: {err}"))?;
+
+    if json {
+        for region in &regions {
+            println!("{}", to_json(region));
+        }
+        return Ok(());
+    }
+
+    println!(
+        "{:<18} {:>14} {:<8} {:<12} {:<8} Name",
+        "Base", "Size", "State", "Protect", "Type"
+    );
+    for region in &regions {
+        println!(
+            "{:#018x} {:>#14x} {:<8} {:<12} {:<8} {}",
+            region.base_address,
+            region.region_size,
+            state_name(region.state),
+            protection(region),
+            type_name(region.region_type),
+            String::from_utf8_lossy(region.name())
+        );
+    }
+
+    Ok(())
+}
+
+/// `protection_name` of the region, with its modifiers appended.
+fn protection(region: &MemoryRegion) -> String {
+    // free regions report `PAGE_NOACCESS`, which says nothing
+    if region.state == MEM_FREE {
+        return String::new();
+    }
+
+    let mut text = protection_name(region.protect).to_string();
+    for (flag, name) in [
+        (PAGE_GUARD, "+guard"),
+        (PAGE_NOCACHE, "+nocache"),
+        (PAGE_WRITECOMBINE, "+wc"),
+    ] {
+        if region.protect & flag != 0 {
+            text.push_str(name);
+        }
+    }
+    text
+}
+
+fn to_json(region: &MemoryRegion) -> serde_json::Value {
+    json!({
+        "base": format!("{:#x}", region.base_address),
+        "allocation_base": format!("{:#x}", region.allocation_base),
+        "size": region.region_size,
+        "state": state_name(region.state),
+        "protect": format!("{:#x}", region.protect),
+        "allocation_protect": format!("{:#x}", region.allocation_protect),
+        "type": type_name(region.region_type),
+        "name": String::from_utf8_lossy(region.name()),
+    })
+}
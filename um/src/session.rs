@@ -0,0 +1,151 @@
+use crate::{
+    driver::{Access, ChainRead, Driver, Events, Pending},
+    error::DriverError,
+    utils::{Address, ProcessId},
+};
+use shared::{
+    ipc::{MemoryRegion, ModuleEntry},
+    lossy::PageMap,
+    protocol::Target,
+};
+
+/// A device handle bound to one target process. The driver keeps the process
+/// referenced for as long as the handle is open, so requests no longer name it,
+/// and a PID that gets reused in the meantime cannot redirect them.
+#[derive(Debug)]
+pub(crate) struct Session {
+    driver: Driver,
+    process_id: u32,
+}
+
+impl Session {
+    /// Attaches the handle behind `driver` to `process`, named by `target`. If the
+    /// PID was reused since `process` was looked up, this fails with
+    /// `DriverError::IdentityMismatch`. Writes are only allowed if `driver` was
+    /// opened with `Access::ReadWrite`.
+    pub(crate) fn attach(
+        driver: Driver,
+        target: Target,
+        process: ProcessId,
+        access: Access,
+    ) -> Result<Self, DriverError> {
+        let response = driver.attach(target, process.create_time, access == Access::ReadWrite)?;
+
+        Ok(Self {
+            driver,
+            process_id: response.process_id,
+        })
+    }
+
+    /// PID of the attached process.
+    pub(crate) fn process_id(&self) -> u32 {
+        self.process_id
+    }
+
+    pub(crate) fn read<T>(&self, address: u64) -> Result<T, DriverError>
+    where
+        T: Copy + Sized,
+    {
+        self.driver.read_process_memory(Target::Session, address)
+    }
+
+    pub(crate) fn write<T>(&self, address: u64, value: &T) -> Result<(), DriverError>
+    where
+        T: Copy + Sized,
+    {
+        self.driver
+            .write_process_memory(Target::Session, address, value)
+    }
+
+    /// See `Driver::compare_exchange`.
+    pub(crate) fn compare_exchange(
+        &self,
+        address: u64,
+        expected: &[u8],
+        new: &[u8],
+    ) -> Result<Result<(), Vec<u8>>, DriverError> {
+        self.driver
+            .compare_exchange(Target::Session, address, expected, new)
+    }
+
+    /// See `Driver::read_region_lossy`.
+    pub(crate) fn read_region_lossy(
+        &self,
+        address: u64,
+        size: usize,
+    ) -> Result<(Vec<u8>, PageMap), DriverError> {
+        self.driver
+            .read_region_lossy(Target::Session, address, size)
+    }
+
+    /// See `Driver::begin_read_region_lossy`.
+    pub(crate) fn begin_read_region_lossy(
+        &self,
+        address: u64,
+        size: usize,
+    ) -> Result<Pending<'_, (Vec<u8>, PageMap)>, DriverError> {
+        self.driver
+            .begin_read_region_lossy(Target::Session, address, size)
+    }
+
+    /// See `Driver::regions`.
+    pub(crate) fn regions(&self) -> Result<Vec<MemoryRegion>, DriverError> {
+        self.driver.regions(Target::Session)
+    }
+
+    /// See `Driver::events`. Image and thread events are those of the attached
+    /// process only.
+    pub(crate) fn events(&self, mask: u32) -> Events<'_> {
+        self.driver.events(mask)
+    }
+
+    /// See `Driver::modules`.
+    pub(crate) fn modules(&self) -> Result<Vec<ModuleEntry>, DriverError> {
+        self.driver.modules(Target::Session)
+    }
+
+    /// See `Driver::module_base`.
+    pub(crate) fn module_base(&self, name: &str) -> Result<u64, DriverError> {
+        self.driver.module_base(Target::Session, name)
+    }
+
+    /// Turns `address` into an absolute address, looking up the module it is
+    /// relative to, if any.
+    pub(crate) fn resolve(&self, address: &Address) -> Result<u64, DriverError> {
+        match address {
+            Address::Absolute(address) => Ok(*address),
+            Address::Module { name, offset } => self
+                .module_base(name)?
+                .checked_add(*offset)
+                .ok_or_else(|| DriverError::InvalidInput(format!("{name}+{offset:#x} overflows"))),
+        }
+    }
+
+    /// See `Driver::read_many`.
+    pub(crate) fn read_many(
+        &self,
+        reads: &[(u64, usize)],
+    ) -> Result<Vec<Result<Vec<u8>, DriverError>>, DriverError> {
+        self.driver.read_many(Target::Session, reads)
+    }
+
+    /// See `Driver::read_chain`.
+    pub(crate) fn read_chain(
+        &self,
+        base: u64,
+        offsets: &[i64],
+        pointer_width: u32,
+        read_size: u32,
+    ) -> Result<ChainRead, DriverError> {
+        self.driver
+            .read_chain(Target::Session, base, offsets, pointer_width, read_size)
+    }
+
+    /// See `Driver::write_many`.
+    pub(crate) fn write_many(
+        &self,
+        writes: &[(u64, &[u8])],
+    ) -> Result<Vec<Result<(), DriverError>>, DriverError> {
+        self.driver.write_many(Target::Session, writes)
+    }
+}
@@ -1,17 +1,98 @@
-use sysinfo::System;
+use crate::driver::{Access, Driver};
+use shared::{ipc::ProcessEntry, protocol::Target};
+use windows::Win32::{
+    Foundation::{CloseHandle, HANDLE},
+    System::Threading::{OpenProcess, PROCESS_VM_READ, PROCESS_VM_WRITE},
+};
 
-pub(crate) fn get_process_id(process_name: &str) -> Result<u32, String> {
-    System::new_all()
-        .processes_by_name(process_name.as_ref())
-        .next()
-        .map(|process| process.pid().as_u32())
-        .ok_or_else(|| format!("No process found with name '{process_name}'"))
+/// A handle to a target process, opened with only the rights the driver will check
+/// for `access`. Passing it as the target lets Windows decide whether we may touch
+/// the process at all.
+#[derive(Debug)]
+pub(crate) struct ProcessHandle(HANDLE);
+
+impl ProcessHandle {
+    pub(crate) fn open(process_id: u32, access: Access) -> Result<Self, String> {
+        let rights = match access {
+            Access::Read => PROCESS_VM_READ,
+            Access::ReadWrite => PROCESS_VM_READ | PROCESS_VM_WRITE,
+        };
+
+        unsafe { OpenProcess(rights, false, process_id) }
+            .map(Self)
+            .map_err(|err| format!("Could not open process {process_id}: {err}"))
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub(crate) fn target(&self) -> Target {
+        // handles only carry 32 significant bits
+        Target::Handle(self.0 .0 as u32)
+    }
 }
 
-pub(crate) fn vec_to_usize(vec: Vec<u8>) -> Result<usize, String> {
-    // Ensure the value doesn't exceed the size of `usize`
-    if vec.len() > size_of::<usize>() {
-        return Err("Value is too large to fit in usize".to_string());
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0).ok() };
+    }
+}
+
+/// A process found by name or PID. The creation time tells it apart from any
+/// later process that reuses its PID; the driver refuses requests that mix them up.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProcessId {
+    pub(crate) pid: u32,
+    /// In 100ns intervals since 1601-01-01 UTC, as the kernel keeps it.
+    pub(crate) create_time: u64,
+    /// A 32-bit process running under WOW64, whose pointers are 4 bytes wide.
+    pub(crate) wow64: bool,
+}
+
+/// Finds the process `process` names: a PID, or an image name. A name that
+/// matches several processes is refused with a list of them, so the caller can
+/// pick one by PID.
+pub(crate) fn find_process(driver: &Driver, process: &str) -> Result<ProcessId, String> {
+    let processes = driver
+        .processes()
+        .map_err(|err| format!("Could not list processes: {err}"))?;
+
+    let matches = processes.iter().filter(|entry| !entry.is_gone());
+    let matches: Vec<&ProcessEntry> = match process.parse::<u32>() {
+        Ok(pid) => matches.filter(|entry| entry.process_id == pid).collect(),
+        Err(_) => matches.filter(|entry| entry.has_name(process)).collect(),
+    };
+
+    match matches.as_slice() {
+        [] => Err(format!("No process found with name or PID '{process}'")),
+        [entry] => Ok(ProcessId {
+            pid: entry.process_id,
+            create_time: entry.create_time,
+            wow64: entry.is_wow64(),
+        }),
+        several => {
+            let candidates: Vec<String> = several
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "  {:>6}  session {}  {}",
+                        entry.process_id,
+                        entry.session_id,
+                        String::from_utf8_lossy(entry.path())
+                    )
+                })
+                .collect();
+            Err(format!(
+                "'{process}' matches {} processes, pass a PID:\n{}",
+                several.len(),
+                candidates.join("\n")
+            ))
+        }
+    }
+}
+
+pub(crate) fn vec_to_u64(vec: Vec<u8>) -> Result<u64, String> {
+    // Ensure the value doesn't exceed the size of `u64`
+    if vec.len() > size_of::<u64>() {
+        return Err("Value is too large to fit in u64".to_string());
     }
 
     // Perform the conversion
@@ -19,15 +100,51 @@ pub(crate) fn vec_to_usize(vec: Vec<u8>) -> Result<usize, String> {
         .into_iter()
         .rev() // Reverse to ensure little-endian order
         .enumerate()
-        .map(|(i, byte)| (byte as usize) << (i * 8))
+        .map(|(i, byte)| u64::from(byte) << (i * 8))
         .sum();
 
     Ok(result)
 }
 
-pub(crate) fn str_to_address(str: &str) -> Result<usize, String> {
+/// Parses a hexadecimal address. Addresses are 64-bit whatever the client's
+/// pointer width, so a 32-bit build can still name any address of a target.
+pub(crate) fn str_to_address(str: &str) -> Result<u64, String> {
     let bytes = hex::decode(str.trim_start_matches("0x"))
         .map_err(|err| format!("Invalid hexadecimal string: {err}"))?;
 
-    vec_to_usize(bytes).map_err(|err| format!("Address out of bounds: {err}"))
+    vec_to_u64(bytes).map_err(|err| format!("Address out of bounds: {err}"))
+}
+
+/// An address as given on the command line: either absolute, or relative to the
+/// base of a module of the target, as in `game.dll+0x1A2B`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Address {
+    Absolute(u64),
+    Module { name: String, offset: u64 },
+}
+
+impl Address {
+    pub(crate) fn parse(str: &str) -> Result<Self, String> {
+        let Some((name, offset)) = str.split_once('+') else {
+            return str_to_address(str).map(Self::Absolute);
+        };
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("Missing module name in {str}"));
+        }
+
+        let digits = offset.trim();
+        let digits = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+            .unwrap_or(digits);
+        let offset = u64::from_str_radix(digits, 16)
+            .map_err(|err| format!("Invalid module offset {offset}: {err}"))?;
+
+        Ok(Self::Module {
+            name: name.to_string(),
+            offset,
+        })
+    }
 }